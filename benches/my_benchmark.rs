@@ -1,4 +1,4 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{Criterion, criterion_group, criterion_main};
 use lox::Lox;
 
 fn run_code(src: &str) {
@@ -67,6 +67,25 @@ fn simple_call() {
     run_code(src);
 }
 
+fn strings() {
+    let src = r#"
+        fun pick(a, b) {
+            if (a == b) return a;
+            return b;
+        }
+
+        var name = "a string long enough to live on the heap";
+        var other = "another string long enough to live on the heap";
+        var picked = name;
+        for (var i = 0; i < 20000; i = i + 1) {
+            picked = pick(picked, other);
+            picked = pick(name, picked);
+        }
+    "#;
+
+    run_code(src);
+}
+
 fn fib_benchmark(c: &mut Criterion) {
     c.bench_function("fib", |b| b.iter(fibonacci));
 }
@@ -79,5 +98,9 @@ fn simple_call_benchmark(c: &mut Criterion) {
     c.bench_function("simple-call", |b| b.iter(simple_call));
 }
 
-criterion_group!(benches, fib_benchmark, zoo_benchmark, simple_call_benchmark);
+fn strings_benchmark(c: &mut Criterion) {
+    c.bench_function("strings", |b| b.iter(strings));
+}
+
+criterion_group!(benches, fib_benchmark, zoo_benchmark, simple_call_benchmark, strings_benchmark);
 criterion_main!(benches);
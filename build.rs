@@ -6,13 +6,25 @@ use walkdir::WalkDir;
 
 static TEST_DATA: &str = "./tests/data/";
 static TEST_TEMPLATE: &str = r#"
-    #[test]
+    #[test]{ignore}
     fn {test_name}() {
         let filename = Path::new("{filename}");
-        do_test(filename);
+        do_test(filename, &{options}, true);
     }
 "#;
 
+static VM_TEST_TEMPLATE: &str = r#"
+    #[test]{ignore}
+    fn {test_name}_vm() {
+        let filename = Path::new("{filename}");
+        do_test(filename, &{vm_options}, {vm_blesses});
+    }
+"#;
+
+/// The language features that test files can require with a `// requires:`
+/// comment, besides the target family (e.g. `unix`).
+static FEATURES: &[&str] = &["classes", "closures", "inheritance", "lists"];
+
 fn main() {
     let out_dir = env::var_os("OUT_DIR").unwrap();
     let dest_path = Path::new(&out_dir).join("test_files.rs");
@@ -36,10 +48,28 @@ fn main() {
             .replace(".lox", "")
             .replace("tests_data_", "");
 
-        let test_case =
-            TEST_TEMPLATE.replace("{test_name}", &test_name).replace("{filename}", filename);
-
-        write!(&mut buf, "{test_case}").unwrap();
+        let templates = if is_vm_only(filename) {
+            vec![VM_TEST_TEMPLATE]
+        } else {
+            vec![TEST_TEMPLATE, VM_TEST_TEMPLATE]
+        };
+        let ignore = match ignore_reason(entry.path()) {
+            Some(reason) => format!("\n    #[ignore = {reason:?}]"),
+            None => String::new(),
+        };
+        let options = options(entry.path());
+        let vm_options = [vec!["--vm".to_owned()], options.clone()].concat();
+        for template in templates {
+            let test_case = template
+                .replace("{test_name}", &test_name)
+                .replace("{filename}", filename)
+                .replace("{ignore}", &ignore)
+                .replace("{options}", &format!("{options:?}"))
+                .replace("{vm_options}", &format!("{vm_options:?}"))
+                // Only one test of a file rewrites it, so two don't write it at the same time
+                .replace("{vm_blesses}", &is_vm_only(filename).to_string());
+            write!(&mut buf, "{test_case}").unwrap();
+        }
     }
 }
 
@@ -52,10 +82,10 @@ fn get_all_files() -> Vec<walkdir::DirEntry> {
 }
 
 fn should_skip(filename: &str) -> bool {
-    // We don't run benchmarks as part of the tests. Also, tests in 'limit' are Java
-    // specific and need changes. For example, there is no "Stack overflow"
-    // exception in Rust and we need to deal with Rust's stack overflow panics.
-    let skip_list = vec!["benchmark/", "limit/"];
+    // We don't run benchmarks as part of the tests. The size of a loop body is
+    // limited by the bytes of clox's jump instructions, which our bytecode
+    // doesn't have.
+    let skip_list = vec!["benchmark/", "limit/loop_too_large.lox"];
 
     for s in skip_list {
         if filename.contains(s) {
@@ -65,3 +95,49 @@ fn should_skip(filename: &str) -> bool {
 
     false
 }
+
+/// Why the test can't run, from the comments it starts with: `// skip: reason`,
+/// or `// requires: feature, ...` naming a feature that isn't implemented or a
+/// platform other than the target's.
+fn ignore_reason(path: &Path) -> Option<String> {
+    let content = fs::read_to_string(path).unwrap();
+    let family = env::var("CARGO_CFG_TARGET_FAMILY").unwrap_or_default();
+
+    for line in content.lines().take_while(|line| line.starts_with("//")) {
+        if let Some(reason) = line.strip_prefix("// skip:") {
+            return Some(reason.trim().to_owned());
+        }
+        if let Some(features) = line.strip_prefix("// requires:") {
+            let missing = features
+                .split(',')
+                .map(str::trim)
+                .filter(|feature| !FEATURES.contains(feature) && *feature != family)
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                return Some(format!("requires {}", missing.join(", ")));
+            }
+        }
+    }
+
+    None
+}
+
+/// The command line options the test runs with, from the comments it starts
+/// with: `// options: --truthiness=strict ...`.
+fn options(path: &Path) -> Vec<String> {
+    let content = fs::read_to_string(path).unwrap();
+    content
+        .lines()
+        .take_while(|line| line.starts_with("//"))
+        .filter_map(|line| line.strip_prefix("// options:"))
+        .flat_map(str::split_whitespace)
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Whether the test is about the bytecode, so it only runs on the VM.
+fn is_vm_only(filename: &str) -> bool {
+    ["limit/too_many_constants.lox", "limit/no_reuse_constants.lox"]
+        .iter()
+        .any(|name| filename.ends_with(name))
+}
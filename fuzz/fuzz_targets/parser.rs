@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lox::prelude::{Parser, Resolver, Scanner};
+
+fuzz_target!(|source: &str| {
+    let Ok(tokens) = Scanner::new(source).with_comment_tokens().scan_tokens() else {
+        return;
+    };
+
+    let (ast, errors) = Parser::new(tokens).with_trailing_expression(true).parse();
+    if errors.iter().all(|e| e.is_warning()) {
+        let _ = Resolver::new().resolve(&ast);
+    }
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lox::prelude::Scanner;
+
+fuzz_target!(|data: &[u8]| {
+    // Invalid UTF-8 is an error, like any other malformed input
+    let _ = Scanner::from_reader(data).with_comment_tokens().scan_tokens();
+});
@@ -0,0 +1,68 @@
+/* The C API of the Lox interpreter, built with the `lox-ffi` feature. */
+
+#ifndef LOX_H
+#define LOX_H
+
+#include <stdbool.h>
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* An interpreter. The globals defined by a run stay for the next runs. */
+typedef struct LoxHandle LoxHandle;
+
+typedef enum {
+    LOX_NIL = 0,
+    LOX_BOOL = 1,
+    LOX_NUMBER = 2,
+    LOX_STRING = 3,
+} LoxValueType;
+
+/* A value passed to and returned by native functions. Only the field of its
+ * type is meaningful. Strings are NUL-terminated UTF-8; the ones passed to a
+ * native function are only valid during the call, and the one it returns is
+ * copied. Functions, classes and instances are passed as nil. */
+typedef struct {
+    LoxValueType value_type;
+    bool boolean;
+    double number;
+    const char *string;
+} LoxValue;
+
+/* A native function gets the data it was registered with and its arguments,
+ * writes its result and returns 0, or returns another number to fail with a
+ * runtime error. */
+typedef int (*LoxNativeFn)(void *data, const LoxValue *arguments, size_t count,
+                           LoxValue *result);
+
+/* Make an interpreter. Free it with lox_free. */
+LoxHandle *lox_new(void);
+
+/* Free an interpreter. Does nothing if it is NULL. */
+void lox_free(LoxHandle *lox);
+
+/* Run the source code. Returns 0 on success, 65 if the code doesn't compile
+ * and 70 if it fails while running, or -1 if lox or source is NULL. */
+int lox_run(LoxHandle *lox, const char *source);
+
+/* The errors of the last failed run, or NULL if it succeeded or lox is NULL.
+ * Valid until the next run. */
+const char *lox_last_error(const LoxHandle *lox);
+
+/* Write the value of a global number to out and return 0, or return -1 if
+ * there is no such global or it isn't a number, or if a pointer is NULL. */
+int lox_get_number(const LoxHandle *lox, const char *name, double *out);
+
+/* Define a global native function that takes arity arguments. data must stay
+ * valid as long as the interpreter. Returns 0, or -1 if lox or name is NULL or
+ * the name isn't valid UTF-8. */
+int lox_register_native(LoxHandle *lox, const char *name, size_t arity,
+                        LoxNativeFn function, void *data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -1,72 +1,373 @@
-use std::hash::Hash;
-use std::rc::Rc;
+use std::collections::HashMap;
+use std::ops::Index;
 
 use crate::prelude::*;
+use crate::sync::{Cell, Rc};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct UniqueId(pub usize);
 
-#[derive(Debug)]
-pub enum Expr {
-    Binary { left: Box<Expr>, operator: Token, right: Box<Expr> },
-    Call { callee: Box<Expr>, paren: Token, arguments: Vec<Expr> },
-    Get { object: Box<Expr>, name: Token },
-    Set { object: Box<Expr>, name: Token, value: Box<Expr> },
-    Super { keyword: Token, method: Token },
-    This { keyword: Token },
-    Grouping { expr: Box<Expr> },
-    Literal { value: Literal },
-    Unary { operator: Token, right: Box<Expr> },
-    Variable { name: Token },
-    Assignment { name: Token, value: Box<Expr> },
-    Logical { left: Box<Expr>, operator: Token, right: Box<Expr> },
+/// Identifies an expression in its [`Ast`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ExprId(u32);
+
+/// Identifies a statement in its [`Ast`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StmtId(u32);
+
+impl ExprId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl StmtId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Where the resolver found a local variable: `depth` environments above the
+/// current one, at position `slot` among the variables of that environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution {
+    pub depth: usize,
+    pub slot: usize,
 }
 
-impl Hash for Expr {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        // Hash based on memory location
-        self.unique_id().hash(state);
+/// The part of a variable expression filled in by the resolver. A binding
+/// without a resolution refers to a global variable.
+#[derive(Debug, Clone, Default)]
+pub struct Binding {
+    resolution: Cell<Option<Resolution>>,
+    /// Where the interpreter found the global variable last
+    global: Cell<Option<usize>>,
+}
+
+impl Binding {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn resolve(&self, resolution: Resolution) {
+        self.resolution.set(Some(resolution));
+    }
+
+    pub fn resolution(&self) -> Option<Resolution> {
+        self.resolution.get()
+    }
+
+    pub(crate) fn global(&self) -> Option<usize> {
+        self.global.get()
+    }
+
+    pub(crate) fn remember_global(&self, index: usize) {
+        self.global.set(Some(index));
     }
 }
 
-impl PartialEq for Expr {
+impl PartialEq for Binding {
+    /// Bindings are equal if the resolver resolved them the same, whatever
+    /// the interpreter remembered.
     fn eq(&self, other: &Self) -> bool {
-        self.unique_id() == other.unique_id()
+        self.resolution() == other.resolution()
     }
 }
 
-impl Eq for Expr {}
+impl Eq for Binding {}
 
-impl Expr {
-    pub fn int_literal(v: f64) -> Expr {
-        Expr::Literal { value: Literal::Number(v) }
+/// Where a statement is in the source code.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StmtLocation {
+    /// The line of its first token
+    pub line: u32,
+    /// The line of its last token
+    pub end_line: u32,
+    /// Whether the parser made the statement up, e.g. the blocks a `for`
+    /// loop is turned into
+    pub synthetic: bool,
+}
+
+/// Owns all the nodes of a program. Nodes refer to their children by ID, so
+/// the whole tree lives in two vectors instead of a box per node.
+///
+/// Functions keep the tree they were declared in alive, which lets the REPL
+/// drop the tree of a line once nothing defined by it is used anymore.
+#[derive(Debug, Clone, Default)]
+pub struct Ast {
+    exprs: Vec<Expr>,
+    stmts: Vec<Stmt>,
+    /// Where each statement is in the source code
+    locations: Vec<StmtLocation>,
+    /// The top-level statements, in source order
+    statements: Vec<StmtId>,
+    /// The expression the program ends with, if it is kept apart from the
+    /// statements, see [`Parser::with_trailing_expression`]
+    ///
+    /// [`Parser::with_trailing_expression`]: crate::prelude::Parser::with_trailing_expression
+    result: Option<ExprId>,
+    /// The doc comments of functions and classes
+    docs: HashMap<StmtId, Rc<str>>,
+    /// The variables captured by the local functions
+    captures: HashMap<StmtId, Rc<[Resolution]>>,
+    /// The names of the local variables in scope where global variables are
+    /// used
+    scope_names: HashMap<ExprId, Rc<[Rc<str>]>>,
+}
+
+impl Ast {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    pub fn str_literal(s: &str) -> Expr {
-        Expr::Literal { value: Literal::String(s.to_owned()) }
+    pub fn add_expr(&mut self, expr: Expr) -> ExprId {
+        self.exprs.push(expr);
+        ExprId((self.exprs.len() - 1) as u32)
+    }
+
+    pub fn add_stmt(&mut self, stmt: Stmt, location: StmtLocation) -> StmtId {
+        self.stmts.push(stmt);
+        self.locations.push(location);
+        StmtId((self.stmts.len() - 1) as u32)
+    }
+
+    /// The line a statement starts on.
+    pub fn line(&self, stmt: StmtId) -> u32 {
+        self.locations[stmt.index()].line
+    }
+
+    pub fn location(&self, stmt: StmtId) -> StmtLocation {
+        self.locations[stmt.index()]
+    }
+
+    /// Add a statement at the top level of the program.
+    pub fn push_statement(&mut self, stmt: StmtId) {
+        self.statements.push(stmt);
+    }
+
+    pub fn statements(&self) -> &[StmtId] {
+        &self.statements
+    }
+
+    /// All the statements of the tree, nested ones included, in the order
+    /// they were added.
+    pub fn stmt_ids(&self) -> impl Iterator<Item = StmtId> + '_ {
+        (0..self.stmts.len()).map(|i| StmtId(i as u32))
+    }
+
+    /// Make `expr` the value of the program, evaluated after all the
+    /// statements.
+    pub fn set_result(&mut self, expr: ExprId) {
+        self.result = Some(expr);
+    }
+
+    pub fn result(&self) -> Option<ExprId> {
+        self.result
+    }
+
+    /// Attach the text of a doc comment to a function or class declaration.
+    pub fn set_doc(&mut self, stmt: StmtId, doc: Rc<str>) {
+        self.docs.insert(stmt, doc);
     }
 
-    pub fn unique_id(&self) -> UniqueId {
-        UniqueId(std::ptr::addr_of!(*self) as usize)
+    /// The doc comment of a function or class declaration, without the `///`.
+    pub fn doc(&self, stmt: StmtId) -> Option<&Rc<str>> {
+        self.docs.get(&stmt)
+    }
+
+    /// Let a function declaration close over just the variables it captures,
+    /// found by [`Resolver::take_captures`], instead of the whole environment
+    /// it's declared in.
+    ///
+    /// [`Resolver::take_captures`]: crate::prelude::Resolver::take_captures
+    pub fn set_captures(&mut self, stmt: StmtId, captures: Vec<Resolution>) {
+        self.captures.insert(stmt, captures.into());
+    }
+
+    /// Where the variables captured by a function declaration are, from the
+    /// environment it's declared in.
+    pub fn captures(&self, stmt: StmtId) -> Option<&[Resolution]> {
+        self.captures.get(&stmt).map(|captures| &**captures)
+    }
+
+    /// Remember the names of the local variables in scope where a global
+    /// variable is used, found by [`Resolver::take_scope_names`], to suggest
+    /// them if the global is undefined.
+    ///
+    /// [`Resolver::take_scope_names`]: crate::prelude::Resolver::take_scope_names
+    pub fn set_scope_names(&mut self, expr: ExprId, names: Vec<Rc<str>>) {
+        self.scope_names.insert(expr, names.into());
+    }
+
+    /// The names of the local variables in scope where a global variable is
+    /// used.
+    pub fn scope_names(&self, expr: ExprId) -> Option<&[Rc<str>]> {
+        self.scope_names.get(&expr).map(|names| &**names)
     }
 }
 
-#[derive(Debug)]
-pub enum Stmt {
-    Break { token: Token },
-    Return { keyword: Token, value: Option<Expr> },
-    Class { name: Token, methods: Vec<Stmt>, superclass: Option<Expr> },
-    Print { exprs: Vec<Expr> },
-    Expression { expr: Expr },
-    Var { name: Token, initializer: Option<Expr> },
-    Block { statements: Vec<Stmt> },
-    Function { name: Token, params: Vec<Token>, body: Vec<Rc<Stmt>> },
-    If { condition: Expr, then_branch: Box<Stmt>, else_branch: Option<Box<Stmt>> },
-    While { condition: Expr, body: Box<Stmt> },
+impl Index<ExprId> for Ast {
+    type Output = Expr;
+
+    fn index(&self, id: ExprId) -> &Expr {
+        &self.exprs[id.index()]
+    }
+}
+
+impl Index<StmtId> for Ast {
+    type Output = Stmt;
+
+    fn index(&self, id: StmtId) -> &Stmt {
+        &self.stmts[id.index()]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Binary {
+        left: ExprId,
+        operator: Token,
+        right: ExprId,
+    },
+    Call {
+        callee: ExprId,
+        paren: Token,
+        arguments: Vec<ExprId>,
+    },
+    Get {
+        object: ExprId,
+        name: Token,
+        cache: MethodCache,
+    },
+    Set {
+        object: ExprId,
+        name: Token,
+        value: ExprId,
+    },
+    Index {
+        object: ExprId,
+        bracket: Token,
+        index: ExprId,
+    },
+    IndexSet {
+        object: ExprId,
+        bracket: Token,
+        index: ExprId,
+        value: ExprId,
+    },
+    /// A list literal, e.g. `[1, 2, 3]`
+    List {
+        bracket: Token,
+        elements: Vec<ExprId>,
+    },
+    Super {
+        binding: Binding,
+        keyword: Token,
+        method: Token,
+    },
+    This {
+        binding: Binding,
+        keyword: Token,
+    },
+    Grouping {
+        expr: ExprId,
+    },
+    Literal {
+        value: Literal,
+    },
+    Unary {
+        operator: Token,
+        right: ExprId,
+    },
+    Variable {
+        binding: Binding,
+        name: Token,
+    },
+    Assignment {
+        binding: Binding,
+        name: Token,
+        value: ExprId,
+    },
+    Logical {
+        left: ExprId,
+        operator: Token,
+        right: ExprId,
+    },
+    Function {
+        keyword: Token,
+        declaration: StmtId,
+    },
+    Error,
 }
 
-impl AsRef<Stmt> for Stmt {
-    fn as_ref(&self) -> &Stmt {
-        self
+impl Expr {
+    pub fn int_literal(v: f64) -> Expr {
+        Expr::Literal { value: Literal::Number(v) }
     }
+
+    pub fn str_literal(s: &str) -> Expr {
+        Expr::Literal { value: Literal::String(s.into()) }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Break {
+        token: Token,
+    },
+    Return {
+        keyword: Token,
+        value: Option<ExprId>,
+    },
+    Class {
+        name: Token,
+        methods: Vec<StmtId>,
+        superclass: Option<ExprId>,
+    },
+    /// `extend Name { ... }`, which adds methods to an existing class
+    Extend {
+        keyword: Token,
+        class: ExprId,
+        methods: Vec<StmtId>,
+    },
+    Print {
+        exprs: Vec<ExprId>,
+    },
+    Expression {
+        expr: ExprId,
+    },
+    Var {
+        name: Token,
+        initializer: Option<ExprId>,
+    },
+    Block {
+        statements: Vec<StmtId>,
+    },
+    Function {
+        name: Token,
+        params: Vec<Token>,
+        body: Vec<StmtId>,
+    },
+    If {
+        condition: ExprId,
+        then_branch: StmtId,
+        else_branch: Option<StmtId>,
+    },
+    While {
+        keyword: Token,
+        condition: ExprId,
+        body: StmtId,
+        else_branch: Option<StmtId>,
+    },
+    /// `using (var name = initializer) body`, which calls `name.close()`
+    /// however the body ends
+    Using {
+        keyword: Token,
+        name: Token,
+        initializer: ExprId,
+        body: StmtId,
+        /// The `name.close()` call, made by the parser
+        close: ExprId,
+    },
+    Error,
 }
@@ -0,0 +1,562 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use serde_json::{Map, Value, json};
+
+use crate::prelude::*;
+
+/// Converts the AST to JSON and back, for tools that don't link this crate,
+/// e.g. a frontend with another syntax that runs its programs with
+/// [`Lox::run_ast`](crate::Lox::run_ast).
+///
+/// Nodes are objects whose `kind` is the name of their variant, e.g.
+/// `{"kind":"Unary","operator":{...},"right":{...}}`, with their children
+/// nested in them. Statements also have their `line` and `end_line`, and the
+/// resolution of the variables is left out: it is redone when the tree runs.
+pub struct AstJson;
+
+/// JSON that is not a syntax tree written by [`AstJson`].
+#[derive(Debug, PartialEq)]
+pub struct AstJsonError(String);
+
+impl Display for AstJsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid syntax tree: {}", self.0)
+    }
+}
+
+impl Error for AstJsonError {}
+
+/// The token types by the name they are written with.
+const TOKEN_TYPES: [TokenType; 44] = [
+    TokenType::LeftParen,
+    TokenType::RightParen,
+    TokenType::LeftBrace,
+    TokenType::RightBrace,
+    TokenType::LeftBracket,
+    TokenType::RightBracket,
+    TokenType::Comma,
+    TokenType::Dot,
+    TokenType::Minus,
+    TokenType::Plus,
+    TokenType::Semicolon,
+    TokenType::Slash,
+    TokenType::Star,
+    TokenType::Bang,
+    TokenType::BangEqual,
+    TokenType::Equal,
+    TokenType::EqualEqual,
+    TokenType::Greater,
+    TokenType::GreaterEqual,
+    TokenType::Less,
+    TokenType::LessEqual,
+    TokenType::Arrow,
+    TokenType::Identifier,
+    TokenType::StringLiteral,
+    TokenType::Number,
+    TokenType::And,
+    TokenType::Break,
+    TokenType::Class,
+    TokenType::Else,
+    TokenType::False,
+    TokenType::Fun,
+    TokenType::For,
+    TokenType::If,
+    TokenType::Nil,
+    TokenType::Or,
+    TokenType::Print,
+    TokenType::Return,
+    TokenType::Super,
+    TokenType::This,
+    TokenType::True,
+    TokenType::Var,
+    TokenType::While,
+    TokenType::Comment,
+    TokenType::EOF,
+];
+
+impl AstJson {
+    /// The program as `{"statements":[...]}`, with the `result` expression if
+    /// it has one.
+    pub fn program_to_json(ast: &Ast) -> Value {
+        let statements = ast.statements().iter().map(|&stmt| Self::stmt_to_json(ast, stmt));
+        let mut program = Map::new();
+        program.insert("statements".to_owned(), Value::Array(statements.collect()));
+        if let Some(result) = ast.result() {
+            program.insert("result".to_owned(), Self::expr_to_json(ast, result));
+        }
+        Value::Object(program)
+    }
+
+    pub fn expr_to_json(ast: &Ast, expr: ExprId) -> Value {
+        let to_json = |expr| Self::expr_to_json(ast, expr);
+        let (kind, fields) = match &ast[expr] {
+            Expr::Binary { left, operator, right } => (
+                "Binary",
+                json!({ "left": to_json(*left), "operator": token(operator), "right": to_json(*right) }),
+            ),
+            Expr::Logical { left, operator, right } => (
+                "Logical",
+                json!({ "left": to_json(*left), "operator": token(operator), "right": to_json(*right) }),
+            ),
+            Expr::Call { callee, paren, arguments } => (
+                "Call",
+                json!({
+                    "callee": to_json(*callee),
+                    "paren": token(paren),
+                    "arguments": arguments.iter().map(|&a| to_json(a)).collect::<Vec<_>>(),
+                }),
+            ),
+            Expr::Get { object, name, cache: _ } => {
+                ("Get", json!({ "object": to_json(*object), "name": token(name) }))
+            }
+            Expr::Set { object, name, value } => (
+                "Set",
+                json!({ "object": to_json(*object), "name": token(name), "value": to_json(*value) }),
+            ),
+            Expr::Index { object, bracket, index } => (
+                "Index",
+                json!({ "object": to_json(*object), "bracket": token(bracket), "index": to_json(*index) }),
+            ),
+            Expr::IndexSet { object, bracket, index, value } => (
+                "IndexSet",
+                json!({
+                    "object": to_json(*object),
+                    "bracket": token(bracket),
+                    "index": to_json(*index),
+                    "value": to_json(*value),
+                }),
+            ),
+            Expr::List { bracket, elements } => (
+                "List",
+                json!({
+                    "bracket": token(bracket),
+                    "elements": elements.iter().map(|&e| to_json(e)).collect::<Vec<_>>(),
+                }),
+            ),
+            Expr::Super { binding: _, keyword, method } => {
+                ("Super", json!({ "keyword": token(keyword), "method": token(method) }))
+            }
+            Expr::This { binding: _, keyword } => ("This", json!({ "keyword": token(keyword) })),
+            Expr::Grouping { expr } => ("Grouping", json!({ "expr": to_json(*expr) })),
+            Expr::Literal { value } => ("Literal", json!({ "value": literal(value) })),
+            Expr::Unary { operator, right } => {
+                ("Unary", json!({ "operator": token(operator), "right": to_json(*right) }))
+            }
+            Expr::Variable { binding: _, name } => ("Variable", json!({ "name": token(name) })),
+            Expr::Assignment { binding: _, name, value } => {
+                ("Assignment", json!({ "name": token(name), "value": to_json(*value) }))
+            }
+            Expr::Function { keyword, declaration } => (
+                "Function",
+                json!({
+                    "keyword": token(keyword),
+                    "declaration": Self::stmt_to_json(ast, *declaration),
+                }),
+            ),
+            Expr::Error => ("Error", json!({})),
+        };
+
+        node(kind, fields)
+    }
+
+    pub fn stmt_to_json(ast: &Ast, stmt: StmtId) -> Value {
+        let expr = |expr| Self::expr_to_json(ast, expr);
+        let optional =
+            |expr: &Option<ExprId>| expr.map_or(Value::Null, |e| Self::expr_to_json(ast, e));
+        let stmts = |stmts: &[StmtId]| -> Vec<Value> {
+            stmts.iter().map(|&s| Self::stmt_to_json(ast, s)).collect()
+        };
+
+        let (kind, fields) = match &ast[stmt] {
+            Stmt::Break { token: keyword } => ("Break", json!({ "token": token(keyword) })),
+            Stmt::Return { keyword, value } => {
+                ("Return", json!({ "keyword": token(keyword), "value": optional(value) }))
+            }
+            Stmt::Class { name, methods, superclass } => (
+                "Class",
+                json!({
+                    "name": token(name),
+                    "methods": stmts(methods),
+                    "superclass": optional(superclass),
+                }),
+            ),
+            Stmt::Extend { keyword, class, methods } => (
+                "Extend",
+                json!({
+                    "keyword": token(keyword),
+                    "class": expr(*class),
+                    "methods": stmts(methods),
+                }),
+            ),
+            Stmt::Print { exprs } => {
+                ("Print", json!({ "exprs": exprs.iter().map(|&e| expr(e)).collect::<Vec<_>>() }))
+            }
+            Stmt::Expression { expr: e } => ("Expression", json!({ "expr": expr(*e) })),
+            Stmt::Var { name, initializer } => {
+                ("Var", json!({ "name": token(name), "initializer": optional(initializer) }))
+            }
+            Stmt::Block { statements } => ("Block", json!({ "statements": stmts(statements) })),
+            Stmt::Function { name, params, body } => (
+                "Function",
+                json!({
+                    "name": token(name),
+                    "params": params.iter().map(token).collect::<Vec<_>>(),
+                    "body": stmts(body),
+                }),
+            ),
+            Stmt::If { condition, then_branch, else_branch } => (
+                "If",
+                json!({
+                    "condition": expr(*condition),
+                    "then_branch": Self::stmt_to_json(ast, *then_branch),
+                    "else_branch": else_branch.map_or(Value::Null, |s| Self::stmt_to_json(ast, s)),
+                }),
+            ),
+            Stmt::While { keyword, condition, body, else_branch } => (
+                "While",
+                json!({
+                    "keyword": token(keyword),
+                    "condition": expr(*condition),
+                    "body": Self::stmt_to_json(ast, *body),
+                    "else_branch": else_branch.map_or(Value::Null, |s| Self::stmt_to_json(ast, s)),
+                }),
+            ),
+            Stmt::Using { keyword, name, initializer, body, close } => (
+                "Using",
+                json!({
+                    "keyword": token(keyword),
+                    "name": token(name),
+                    "initializer": expr(*initializer),
+                    "body": Self::stmt_to_json(ast, *body),
+                    "close": expr(*close),
+                }),
+            ),
+            Stmt::Error => ("Error", json!({})),
+        };
+
+        let mut node = node(kind, fields);
+        let location = ast.location(stmt);
+        node["line"] = json!(location.line);
+        node["end_line"] = json!(location.end_line);
+        if location.synthetic {
+            node["synthetic"] = json!(true);
+        }
+        if let Some(doc) = ast.doc(stmt) {
+            node["doc"] = json!(&**doc);
+        }
+        node
+    }
+}
+
+impl AstJson {
+    /// Read a program written by [`AstJson::program_to_json`]. The tree isn't
+    /// resolved yet.
+    pub fn program_from_json(json: &Value) -> Result<Ast, AstJsonError> {
+        let mut ast = Ast::new();
+        for stmt in array(json, "statements")? {
+            let stmt = Self::stmt_from_json(&mut ast, stmt)?;
+            ast.push_statement(stmt);
+        }
+        if let Some(result) = json.get("result") {
+            let result = Self::expr_from_json(&mut ast, result)?;
+            ast.set_result(result);
+        }
+        Ok(ast)
+    }
+
+    /// Read an expression written by [`AstJson::expr_to_json`] into the tree.
+    pub fn expr_from_json(ast: &mut Ast, json: &Value) -> Result<ExprId, AstJsonError> {
+        let mut expr = |field| Self::expr_from_json(ast, field_of(json, field)?);
+        let expr = match kind(json)? {
+            "Binary" => Expr::Binary {
+                left: expr("left")?,
+                operator: token_field(json, "operator")?,
+                right: expr("right")?,
+            },
+            "Logical" => Expr::Logical {
+                left: expr("left")?,
+                operator: token_field(json, "operator")?,
+                right: expr("right")?,
+            },
+            "Call" => {
+                let callee = expr("callee")?;
+                let arguments = array(json, "arguments")?
+                    .iter()
+                    .map(|argument| Self::expr_from_json(ast, argument))
+                    .collect::<Result<_, _>>()?;
+                Expr::Call { callee, paren: token_field(json, "paren")?, arguments }
+            }
+            "Get" => Expr::Get {
+                object: expr("object")?,
+                name: token_field(json, "name")?,
+                cache: MethodCache::new(),
+            },
+            "Set" => Expr::Set {
+                object: expr("object")?,
+                name: token_field(json, "name")?,
+                value: expr("value")?,
+            },
+            "Index" => Expr::Index {
+                object: expr("object")?,
+                bracket: token_field(json, "bracket")?,
+                index: expr("index")?,
+            },
+            "IndexSet" => Expr::IndexSet {
+                object: expr("object")?,
+                bracket: token_field(json, "bracket")?,
+                index: expr("index")?,
+                value: expr("value")?,
+            },
+            "List" => {
+                let elements = array(json, "elements")?
+                    .iter()
+                    .map(|element| Self::expr_from_json(ast, element))
+                    .collect::<Result<_, _>>()?;
+                Expr::List { bracket: token_field(json, "bracket")?, elements }
+            }
+            "Super" => Expr::Super {
+                binding: Binding::new(),
+                keyword: token_field(json, "keyword")?,
+                method: token_field(json, "method")?,
+            },
+            "This" => {
+                Expr::This { binding: Binding::new(), keyword: token_field(json, "keyword")? }
+            }
+            "Grouping" => Expr::Grouping { expr: expr("expr")? },
+            "Literal" => Expr::Literal { value: literal_from_json(field_of(json, "value")?)? },
+            "Unary" => {
+                Expr::Unary { operator: token_field(json, "operator")?, right: expr("right")? }
+            }
+            "Variable" => {
+                Expr::Variable { binding: Binding::new(), name: token_field(json, "name")? }
+            }
+            "Assignment" => Expr::Assignment {
+                binding: Binding::new(),
+                name: token_field(json, "name")?,
+                value: expr("value")?,
+            },
+            "Function" => Expr::Function {
+                keyword: token_field(json, "keyword")?,
+                declaration: Self::stmt_from_json(ast, field_of(json, "declaration")?)?,
+            },
+            "Error" => Expr::Error,
+            kind => return Err(AstJsonError(format!("unknown expression kind '{kind}'"))),
+        };
+
+        Ok(ast.add_expr(expr))
+    }
+
+    /// Read a statement written by [`AstJson::stmt_to_json`] into the tree.
+    pub fn stmt_from_json(ast: &mut Ast, json: &Value) -> Result<StmtId, AstJsonError> {
+        let stmts = |ast: &mut Ast, field| -> Result<Vec<StmtId>, AstJsonError> {
+            array(json, field)?.iter().map(|stmt| Self::stmt_from_json(ast, stmt)).collect()
+        };
+        let optional = |ast: &mut Ast, field| match json.get(field) {
+            None | Some(Value::Null) => Ok(None),
+            Some(expr) => Self::expr_from_json(ast, expr).map(Some),
+        };
+
+        let stmt = match kind(json)? {
+            "Break" => Stmt::Break { token: token_field(json, "token")? },
+            "Return" => Stmt::Return {
+                keyword: token_field(json, "keyword")?,
+                value: optional(ast, "value")?,
+            },
+            "Class" => Stmt::Class {
+                name: token_field(json, "name")?,
+                methods: stmts(ast, "methods")?,
+                superclass: optional(ast, "superclass")?,
+            },
+            "Extend" => Stmt::Extend {
+                keyword: token_field(json, "keyword")?,
+                class: Self::expr_from_json(ast, field_of(json, "class")?)?,
+                methods: stmts(ast, "methods")?,
+            },
+            "Print" => {
+                let exprs = array(json, "exprs")?
+                    .iter()
+                    .map(|expr| Self::expr_from_json(ast, expr))
+                    .collect::<Result<_, _>>()?;
+                Stmt::Print { exprs }
+            }
+            "Expression" => {
+                Stmt::Expression { expr: Self::expr_from_json(ast, field_of(json, "expr")?)? }
+            }
+            "Var" => Stmt::Var {
+                name: token_field(json, "name")?,
+                initializer: optional(ast, "initializer")?,
+            },
+            "Block" => Stmt::Block { statements: stmts(ast, "statements")? },
+            "Function" => Stmt::Function {
+                name: token_field(json, "name")?,
+                params: array(json, "params")?
+                    .iter()
+                    .map(token_from_json)
+                    .collect::<Result<_, _>>()?,
+                body: stmts(ast, "body")?,
+            },
+            "If" => Stmt::If {
+                condition: Self::expr_from_json(ast, field_of(json, "condition")?)?,
+                then_branch: Self::stmt_from_json(ast, field_of(json, "then_branch")?)?,
+                else_branch: match json.get("else_branch") {
+                    None | Some(Value::Null) => None,
+                    Some(stmt) => Some(Self::stmt_from_json(ast, stmt)?),
+                },
+            },
+            "While" => Stmt::While {
+                keyword: token_field(json, "keyword")?,
+                condition: Self::expr_from_json(ast, field_of(json, "condition")?)?,
+                body: Self::stmt_from_json(ast, field_of(json, "body")?)?,
+                else_branch: match json.get("else_branch") {
+                    None | Some(Value::Null) => None,
+                    Some(stmt) => Some(Self::stmt_from_json(ast, stmt)?),
+                },
+            },
+            "Using" => Stmt::Using {
+                keyword: token_field(json, "keyword")?,
+                name: token_field(json, "name")?,
+                initializer: Self::expr_from_json(ast, field_of(json, "initializer")?)?,
+                body: Self::stmt_from_json(ast, field_of(json, "body")?)?,
+                close: Self::expr_from_json(ast, field_of(json, "close")?)?,
+            },
+            "Error" => Stmt::Error,
+            kind => return Err(AstJsonError(format!("unknown statement kind '{kind}'"))),
+        };
+
+        let line = number(json, "line")?;
+        let location = StmtLocation {
+            line,
+            end_line: json.get("end_line").map_or(Ok(line), |_| number(json, "end_line"))?,
+            synthetic: json.get("synthetic").and_then(Value::as_bool).unwrap_or(false),
+        };
+        let id = ast.add_stmt(stmt, location);
+        if let Some(doc) = json.get("doc").and_then(Value::as_str) {
+            ast.set_doc(id, doc.into());
+        }
+        Ok(id)
+    }
+}
+
+fn field_of<'a>(json: &'a Value, field: &str) -> Result<&'a Value, AstJsonError> {
+    json.get(field).ok_or_else(|| AstJsonError(format!("missing field '{field}'")))
+}
+
+fn kind(json: &Value) -> Result<&str, AstJsonError> {
+    field_of(json, "kind")?
+        .as_str()
+        .ok_or_else(|| AstJsonError("'kind' must be a string".to_owned()))
+}
+
+fn array<'a>(json: &'a Value, field: &str) -> Result<&'a Vec<Value>, AstJsonError> {
+    field_of(json, field)?
+        .as_array()
+        .ok_or_else(|| AstJsonError(format!("'{field}' must be an array")))
+}
+
+fn number(json: &Value, field: &str) -> Result<u32, AstJsonError> {
+    field_of(json, field)?
+        .as_u64()
+        .and_then(|n| u32::try_from(n).ok())
+        .ok_or_else(|| AstJsonError(format!("'{field}' must be a line or column number")))
+}
+
+fn token_field(json: &Value, field: &str) -> Result<Token, AstJsonError> {
+    token_from_json(field_of(json, field)?)
+}
+
+/// Read a token, whose column and span are optional.
+fn token_from_json(json: &Value) -> Result<Token, AstJsonError> {
+    let name = field_of(json, "type")?.as_str().unwrap_or_default();
+    let token_type = TOKEN_TYPES
+        .into_iter()
+        .find(|t| format!("{t:?}") == name)
+        .ok_or_else(|| AstJsonError(format!("unknown token type '{name}'")))?;
+    let lexeme = field_of(json, "lexeme")?
+        .as_str()
+        .ok_or_else(|| AstJsonError("'lexeme' must be a string".to_owned()))?;
+    let literal = json.get("literal").map(literal_from_json).transpose()?;
+
+    let column = json.get("column").map_or(Ok(0), |_| number(json, "column"))?;
+    let span = match json.get("span").and_then(Value::as_array).map(Vec::as_slice) {
+        Some([start, end]) => match (start.as_u64(), end.as_u64()) {
+            (Some(start), Some(end)) => Span::new(start as usize, end as usize),
+            _ => return Err(AstJsonError("'span' must be two offsets".to_owned())),
+        },
+        Some(_) => return Err(AstJsonError("'span' must be two offsets".to_owned())),
+        None => Span::default(),
+    };
+
+    Ok(Token::new(token_type, lexeme, literal, number(json, "line")?).with_position(column, span))
+}
+
+fn literal_from_json(json: &Value) -> Result<Literal, AstJsonError> {
+    match json {
+        Value::Null => Ok(Literal::Null),
+        Value::Bool(b) => Ok(Literal::Boolean(*b)),
+        Value::Number(n) => n
+            .as_f64()
+            .map(Literal::Number)
+            .ok_or_else(|| AstJsonError(format!("unsupported number {n}"))),
+        Value::String(s) => Ok(Literal::String(s.as_str().into())),
+        _ => Err(AstJsonError("a literal must be nil, a boolean, a number or a string".to_owned())),
+    }
+}
+
+/// The fields of a node with its kind.
+fn node(kind: &str, mut fields: Value) -> Value {
+    fields["kind"] = json!(kind);
+    fields
+}
+
+/// A token as `{"type":"Identifier","lexeme":"a","line":1,"column":5,
+/// "span":[4,5]}`, with its `literal` if it has one.
+fn token(token: &Token) -> Value {
+    let mut value = json!({
+        "type": format!("{:?}", token.token_type),
+        "lexeme": &*token.lexeme,
+        "line": token.line,
+        "column": token.column,
+        "span": [token.span.start, token.span.end],
+    });
+    if let Some(literal_value) = &token.literal {
+        value["literal"] = literal(literal_value);
+    }
+    value
+}
+
+fn literal(literal: &Literal) -> Value {
+    match literal {
+        Literal::Null => Value::Null,
+        Literal::Boolean(b) => json!(b),
+        Literal::Number(n) => json!(n),
+        Literal::String(s) => json!(&**s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_to_json() {
+        let tokens = Scanner::new("var a = -1;\nprint a;").scan_tokens().unwrap();
+        let (ast, errors) = Parser::new(tokens).parse();
+        assert!(errors.is_empty(), "failed to parse the source");
+
+        let json = AstJson::program_to_json(&ast);
+        let var = &json["statements"][0];
+        assert_eq!(var["kind"], "Var");
+        assert_eq!(var["line"], 1);
+        assert_eq!(var["name"]["lexeme"], "a");
+        assert_eq!(var["name"]["type"], "Identifier");
+        assert_eq!(var["initializer"]["kind"], "Unary");
+        assert_eq!(var["initializer"]["right"], json!({ "kind": "Literal", "value": 1.0 }));
+
+        let print = &json["statements"][1];
+        assert_eq!(print["kind"], "Print");
+        assert_eq!(print["line"], 2);
+        assert_eq!(print["exprs"][0]["kind"], "Variable");
+        assert!(print.get("synthetic").is_none());
+    }
+}
@@ -0,0 +1,218 @@
+//! Native functions whose implementation is async, e.g. fetching a URL or
+//! sleeping.
+//!
+//! A native defined by [`Lox::define_async_native`] returns a future. Programs
+//! run by [`Lox::run`] wait for it by blocking their thread. With the `sync`
+//! feature, [`Lox::run_async`] runs the program on a thread of its own instead,
+//! and the future it returns polls the futures of the natives: the program is
+//! suspended while they are pending, and the executor that polls the run is
+//! never blocked.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use crate::prelude::*;
+use crate::sync::{MaybeSync, Rc};
+
+/// What an async native returns: its result, or the message of the runtime
+/// error it fails with.
+pub type NativeFuture = Pin<Box<dyn Future<Output = Result<Object, String>>>>;
+
+/// An async native gets its arguments and returns a future.
+pub trait AsyncNativeFn: Fn(Vec<Object>) -> NativeFuture + MaybeSync {}
+impl<T: Fn(Vec<Object>) -> NativeFuture + MaybeSync> AsyncNativeFn for T {}
+
+pub(crate) struct AsyncNative {
+    pub name: String,
+    pub arity: usize,
+    pub function: Rc<dyn AsyncNativeFn>,
+    /// Where to send the calls when the program runs with [`Lox::run_async`]
+    #[cfg(feature = "sync")]
+    pub driver: Shared<Option<Driver>>,
+}
+
+impl std::fmt::Debug for AsyncNative {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AsyncNative").field("name", &self.name).finish_non_exhaustive()
+    }
+}
+
+impl Callable for AsyncNative {
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn call(
+        &self,
+        _interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        let arguments = arguments.to_vec();
+
+        #[cfg(feature = "sync")]
+        let driver = self.driver.borrow().clone();
+        #[cfg(feature = "sync")]
+        let result = match driver {
+            Some(driver) => driver.call(self.function.clone(), arguments),
+            None => block_on((self.function)(arguments)),
+        };
+        #[cfg(not(feature = "sync"))]
+        let result = block_on((self.function)(arguments));
+
+        result.map_err(|message| RuntimeError::native(DiagnosticCode::NativeFailed, message))
+    }
+}
+
+impl Display for AsyncNative {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+/// Wakes the thread that waits for a future.
+struct ThreadWaker(std::thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Poll the future on this thread until it's ready, sleeping while it's
+/// pending.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+#[cfg(feature = "sync")]
+pub use self::driven::AsyncRun;
+#[cfg(feature = "sync")]
+pub(crate) use self::driven::{Driver, spawn};
+
+#[cfg(feature = "sync")]
+mod driven {
+    use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::Lox;
+
+    /// The interpreter thread stops at each call of an async native, so it
+    /// needs room for deeply nested calls like the main thread.
+    const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+    enum Message {
+        /// Call an async native and send its result back
+        Call {
+            function: Rc<dyn AsyncNativeFn>,
+            arguments: Vec<Object>,
+            reply: Sender<Result<Object, String>>,
+        },
+        /// The program ended
+        Done(Box<Lox>, Result<(), anyhow::Error>),
+    }
+
+    /// Sends the calls of async natives from the interpreter thread to the
+    /// [`AsyncRun`] future.
+    #[derive(Clone)]
+    pub(crate) struct Driver {
+        messages: Sender<Message>,
+        /// Wakes the task polling the run
+        waker: Arc<Mutex<Option<Waker>>>,
+    }
+
+    impl Driver {
+        fn send(&self, message: Message) {
+            // The run can only be dropped before it's done by dropping its
+            // future, and then nobody is waiting for the program anyway
+            let _ = self.messages.send(message);
+            if let Some(waker) = self.waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+
+        /// Wait until the run polled the future of the native to completion.
+        pub fn call(
+            &self,
+            function: Rc<dyn AsyncNativeFn>,
+            arguments: Vec<Object>,
+        ) -> Result<Object, String> {
+            let (reply, result) = mpsc::channel();
+            self.send(Message::Call { function, arguments, reply });
+            result.recv().map_err(|_| "The program was cancelled.".to_owned())?
+        }
+    }
+
+    /// A program running on its own thread, made by [`Lox::run_async`]. It
+    /// resolves to the interpreter and the result of the run.
+    pub struct AsyncRun {
+        messages: Receiver<Message>,
+        waker: Arc<Mutex<Option<Waker>>>,
+        /// The native call being awaited, with where to send its result
+        pending: Option<(NativeFuture, Sender<Result<Object, String>>)>,
+    }
+
+    impl Future for AsyncRun {
+        type Output = (Lox, Result<(), anyhow::Error>);
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let run = self.get_mut();
+            // Set the waker before looking for messages, so none is missed
+            *run.waker.lock().unwrap() = Some(cx.waker().clone());
+
+            loop {
+                if let Some((future, reply)) = &mut run.pending {
+                    let Poll::Ready(result) = future.as_mut().poll(cx) else {
+                        return Poll::Pending;
+                    };
+                    let _ = reply.send(result);
+                    run.pending = None;
+                }
+
+                match run.messages.try_recv() {
+                    Ok(Message::Call { function, arguments, reply }) => {
+                        run.pending = Some((function(arguments), reply));
+                    }
+                    Ok(Message::Done(lox, result)) => return Poll::Ready((*lox, result)),
+                    Err(TryRecvError::Empty) => return Poll::Pending,
+                    Err(TryRecvError::Disconnected) => panic!("the interpreter thread panicked"),
+                }
+            }
+        }
+    }
+
+    /// Run the program of the interpreter on a new thread, sending the calls
+    /// of its async natives to the returned future.
+    pub(crate) fn spawn(mut lox: Lox, source: String) -> AsyncRun {
+        let (sender, messages) = mpsc::channel();
+        let waker = Arc::new(Mutex::new(None));
+        let driver = Driver { messages: sender, waker: waker.clone() };
+
+        *lox.async_driver.borrow_mut() = Some(driver.clone());
+        std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                let result = lox.run(&source);
+                *lox.async_driver.borrow_mut() = None;
+                driver.send(Message::Done(Box::new(lox), result));
+            })
+            .expect("failed to start the interpreter thread");
+
+        AsyncRun { messages, waker, pending: None }
+    }
+}
@@ -1,20 +1,765 @@
 use std::env;
+use std::io::{IsTerminal, Read};
+use std::path::{Path, PathBuf};
 
-use lox::Lox;
+use lox::prelude::{
+    AstJson, AstPrinter, BytecodeError, DapServer, Debugger, DiagnosticFormat, Equality, LintRule,
+    LoxError, Truthiness,
+};
+use lox::{Backend, Lox};
+
+static USAGE: &str = "Usage: {bin} [options] [script | - | -e <code>]
+       {bin} compile [options] <script | - | -e <code>> [-o <output>]
+       {bin} debug [options] <script>
+       {bin} dap
+       {bin} fmt [--check] <script | - | -e <code>>
+       {bin} lint [--allow=<rule>]... [--dead-globals] <script | - | -e <code>>
+       {bin} test [options] <directory | script>
+       {bin} bench [options] [--runs=<n>] [--save=<file>] [--baseline=<file>]
+             [--threshold=<percent>] <directory | script>
+
+Runs the script, the standard input (-) or the given code. Without any of them,
+starts an interactive prompt. Scripts compiled to bytecode (.loxc) run directly.
+
+The compile command writes the bytecode of the program to <output>, by default
+the script with a .loxc extension.
+
+The debug command stops the script before its first statement and reads
+debugger commands from the standard input. Type 'help' there for a list.
+
+The dap command debugs the scripts an editor launches, speaking the Debug Adapter
+Protocol on the standard input and output.
+
+The fmt command formats the script in place, or prints the formatted code of the
+standard input or the given code. With --check, it only fails if the code isn't
+formatted.
+
+The lint command prints the likely mistakes in the program and fails if there
+are any. Its rules are unused, shadow, unreachable, empty-block, mixed-equality
+and self-assign. A '// lox-ignore: <rule>, ...' comment turns rules off on its
+line and the next one, and --allow=<rule> turns a rule off everywhere. With
+--dead-globals, it also reports the globals the whole program never uses
+(rule dead-global).
+
+The test command runs the *_test.lox files of the directory, or the given script,
+and prints the result of each test(name, function) call they make. It fails if
+any test fails, e.g. when an assertEq(actual, expected) or assertTrue(value) in
+it doesn't hold.
+
+The bench command runs the .lox files of the directory, or the given script,
+<n> times each (5 by default) without printing their output, and prints the
+mean and the standard deviation of their running times. --save=<file> writes
+the results to a JSON baseline, and --baseline=<file> compares them with a saved
+one: the command fails if a script got slower than its baseline mean by more than
+the threshold (10% by default).
+
+Options:
+  --dump-tokens          Print the tokens of the program instead of running it
+  --dump-ast             Print the syntax tree of the program instead of running it
+  --emit-ast=<format>    Print the syntax tree as 'sexp' (like --dump-ast) or 'json'
+                         instead of running it
+  --diagnostics=<style>  How to print errors: 'plain', 'pretty' (the default on a
+                         terminal) or 'json'
+  --warnings-as-errors   Don't run programs that have warnings
+  --no-warnings          Don't print the warnings of the program
+  --fresh-loop-variables Give each iteration of a for loop its own loop variable,
+                         so closures made in the body don't share it
+  --top-level-return     Let a return at the top level end the script, with the
+                         number it returns as the exit code
+  --truthiness=<rule>    Which values are true in conditions: 'lox' (all but nil
+                         and false, the default) or 'strict' (only true)
+  --equality=<rule>      How == compares values of different types: 'strict'
+                         (never equal, the default) or 'coercing' (1 == \"1\")
+  --max-call-depth=<n>   Fail with a stack overflow error after <n> nested calls
+  --vm                   Run the program on the bytecode virtual machine
+  --profile              Print the calls and the time spent in each function when
+                         the program ends (not with --vm)
+  --coverage[=lcov]      Print how many times each line of the program ran when it
+                         ends, or with =lcov an LCOV tracefile (not with --vm)
+  --trace[=depth]        Print each statement with its line as it executes, and
+                         with =depth the depth of its environment (not with --vm)";
+
+/// The interpreter recurses on the native stack, so give it enough room for
+/// deeply nested calls, even in debug builds.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+/// Where the program to run comes from.
+enum Input {
+    Prompt,
+    File(String),
+    Stdin,
+    Eval(String),
+}
+
+/// What to do with the program.
+enum Mode {
+    Run,
+    DumpTokens,
+    DumpAst {
+        json: bool,
+    },
+    /// Write the bytecode of the program to the given file
+    Compile(Option<String>),
+    /// Run the program under the debugger
+    Debug,
+    /// Serve an editor over the Debug Adapter Protocol
+    Dap,
+    /// Format the program, or only check that it's formatted
+    Format {
+        check: bool,
+    },
+    /// Report likely mistakes, except the ones of the allowed rules, and
+    /// optionally the unused globals
+    Lint {
+        allow: Vec<LintRule>,
+        dead_globals: bool,
+    },
+    /// Run the tests of the `*_test.lox` files
+    Test,
+    /// Time the `.lox` files
+    Bench(Bench),
+}
+
+/// How to run the benchmarks and what to compare them with.
+struct Bench {
+    /// Number of times to run each script
+    runs: usize,
+    /// Where to write the results as a baseline
+    save: Option<String>,
+    /// The baseline to compare the results with
+    baseline: Option<String>,
+    /// How much slower than its baseline a script may get, in percent
+    threshold: f64,
+}
+
+struct Options {
+    input: Input,
+    mode: Mode,
+    diagnostic_format: DiagnosticFormat,
+    warnings_as_errors: bool,
+    /// Whether to print the warnings
+    warnings: bool,
+    fresh_loop_variables: bool,
+    top_level_return: bool,
+    truthiness: Truthiness,
+    equality: Equality,
+    max_call_depth: Option<usize>,
+    backend: Backend,
+    profile: bool,
+    coverage: Option<CoverageFormat>,
+    trace: bool,
+    trace_depth: bool,
+}
+
+/// How to print the lines that ran.
+#[derive(Clone, Copy)]
+enum CoverageFormat {
+    Report,
+    Lcov,
+}
 
 fn main() {
-    let mut args = env::args().into_iter().skip(1).collect::<Vec<_>>();
+    let interpreter = std::thread::Builder::new().stack_size(STACK_SIZE).spawn(run).unwrap();
+    if interpreter.join().is_err() {
+        std::process::exit(101);
+    }
+}
+
+fn run() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+
+    let options = match parse_args(args) {
+        Some(options) => options,
+        None => {
+            println!("{}", USAGE.replace("{bin}", env!("CARGO_BIN_NAME")));
+            std::process::exit(64);
+        }
+    };
 
-    if args.len() != 1 {
-        let bin_name = env!("CARGO_BIN_NAME");
-        println!("Usage: {} <script>", bin_name);
-        std::process::exit(64);
+    let mut lox = Lox::new().with_backend(options.backend);
+    lox.set_diagnostic_format(options.diagnostic_format);
+    lox.set_warnings_as_errors(options.warnings_as_errors);
+    lox.set_fresh_loop_variables(options.fresh_loop_variables);
+    lox.set_top_level_return(options.top_level_return);
+    lox.set_truthiness(options.truthiness);
+    lox.set_equality(options.equality);
+    if let Some(depth) = options.max_call_depth {
+        lox.set_max_call_depth(depth);
     }
+    lox.set_profiling(options.profile);
+    lox.set_coverage(options.coverage.is_some());
+    lox.set_trace(options.trace);
+    lox.set_trace_depth(options.trace_depth);
 
-    let mut lox = Lox::new();
-    let filename = args.pop().unwrap();
-    if let Err(e) = lox.run_file(filename.as_ref()) {
+    // Ctrl-C stops the running program. Pressing it again before the program
+    // notices, e.g. while waiting for input, exits.
+    let interrupt = lox.interrupt_handle();
+    let _ = ctrlc::set_handler(move || {
+        if interrupt.interrupt() {
+            std::process::exit(130);
+        }
+    });
+
+    let result = match (options.mode, options.input) {
+        (Mode::Run, Input::Prompt) => lox.run_prompt(),
+        (Mode::Run, Input::File(filename)) => lox.run_file(&filename),
+        // Scan the standard input as it comes, e.g. `echo 'print 1;' | lox -`
+        (Mode::Run, Input::Stdin) => lox.run_reader(std::io::stdin().lock()),
+        (Mode::Run, input) => read_source(input).and_then(|source| lox.run(&source)),
+        (Mode::DumpTokens, input) => {
+            read_source(input).and_then(|source| dump_tokens(&mut lox, &source))
+        }
+        (Mode::DumpAst { json }, input) => {
+            read_source(input).and_then(|source| dump_ast(&mut lox, &source, json))
+        }
+        (Mode::Debug, Input::File(filename)) => debug_file(&mut lox, &filename),
+        (Mode::Debug, _) => unreachable!("only scripts can be debugged"),
+        (Mode::Test, Input::File(path)) => {
+            run_tests(&path, options.backend, options.diagnostic_format)
+        }
+        (Mode::Test, _) => unreachable!("only files and directories can be tested"),
+        (Mode::Bench(bench), Input::File(path)) => run_benchmarks(&path, options.backend, &bench),
+        (Mode::Bench(_), _) => unreachable!("only files and directories can be benchmarked"),
+        (Mode::Format { check }, input) => format(&mut lox, input, check),
+        (Mode::Lint { allow, dead_globals }, input) => {
+            lint(&mut lox, input, &allow, dead_globals, options.diagnostic_format)
+        }
+        (Mode::Dap, _) => DapServer::new(std::io::stdin(), std::io::stdout()).run(),
+        (Mode::Compile(output), input) => {
+            let output = output.or_else(|| match &input {
+                Input::File(filename) => Some(
+                    std::path::Path::new(filename).with_extension("loxc").display().to_string(),
+                ),
+                _ => None,
+            });
+
+            match output {
+                Some(output) => read_source(input)
+                    .and_then(|source| lox.compile_to_bytecode(&source))
+                    .and_then(|bytes| Ok(std::fs::write(output, bytes)?)),
+                None => {
+                    println!("{}", USAGE.replace("{bin}", env!("CARGO_BIN_NAME")));
+                    std::process::exit(64);
+                }
+            }
+        }
+    };
+
+    if let Some(warnings) = lox.render_warnings().filter(|_| options.warnings) {
+        eprintln!("{warnings}");
+    }
+
+    if let Some(profile) = lox.profile() {
+        eprintln!("{profile}");
+    }
+
+    if let Some(coverage) = lox.coverage() {
+        match options.coverage {
+            Some(CoverageFormat::Lcov) => eprint!("{}", coverage.lcov()),
+            _ => eprintln!("{coverage}"),
+        }
+    }
+
+    if let Err(e) = result {
         eprintln!("{e}");
-        std::process::exit(1);
+        std::process::exit(exit_code(&e));
+    }
+    if let Some(code) = lox.exit_code() {
+        std::process::exit(code);
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Option<Options> {
+    let mut args = args.into_iter().peekable();
+    let mut mode = Mode::Run;
+    if args.next_if(|arg| arg == "compile").is_some() {
+        mode = Mode::Compile(None);
+    } else if args.next_if(|arg| arg == "fmt").is_some() {
+        mode = Mode::Format { check: false };
+    } else if args.next_if(|arg| arg == "lint").is_some() {
+        mode = Mode::Lint { allow: vec![], dead_globals: false };
+    } else if args.next_if(|arg| arg == "test").is_some() {
+        mode = Mode::Test;
+    } else if args.next_if(|arg| arg == "bench").is_some() {
+        mode = Mode::Bench(Bench { runs: 5, save: None, baseline: None, threshold: 10.0 });
+    } else if args.next_if(|arg| arg == "debug").is_some() {
+        mode = Mode::Debug;
+    } else if args.next_if(|arg| arg == "dap").is_some() {
+        // The editor gives the program and the options
+        if args.next().is_some() {
+            return None;
+        }
+        return Some(Options {
+            input: Input::Prompt,
+            mode: Mode::Dap,
+            diagnostic_format: DiagnosticFormat::Plain,
+            warnings_as_errors: false,
+            warnings: true,
+            fresh_loop_variables: false,
+            top_level_return: false,
+            truthiness: Truthiness::default(),
+            equality: Equality::default(),
+            max_call_depth: None,
+            backend: Backend::TreeWalker,
+            profile: false,
+            coverage: None,
+            trace: false,
+            trace_depth: false,
+        });
+    }
+
+    let mut input = None;
+    let mut warnings_as_errors = false;
+    let mut warnings = true;
+    let mut fresh_loop_variables = false;
+    let mut top_level_return = false;
+    let mut truthiness = Truthiness::default();
+    let mut equality = Equality::default();
+    let mut max_call_depth = None;
+    let mut backend = Backend::TreeWalker;
+    let mut profile = false;
+    let mut coverage = None;
+    let mut trace = false;
+    let mut trace_depth = false;
+    let mut diagnostic_format = if std::io::stderr().is_terminal() {
+        DiagnosticFormat::Pretty
+    } else {
+        DiagnosticFormat::Plain
+    };
+
+    while let Some(arg) = args.next() {
+        let next_input = match arg.as_str() {
+            "--dump-tokens" => {
+                mode = Mode::DumpTokens;
+                continue;
+            }
+            "--dump-ast" | "--emit-ast=sexp" => {
+                mode = Mode::DumpAst { json: false };
+                continue;
+            }
+            "--emit-ast=json" => {
+                mode = Mode::DumpAst { json: true };
+                continue;
+            }
+            "--diagnostics=plain" => {
+                diagnostic_format = DiagnosticFormat::Plain;
+                continue;
+            }
+            "--diagnostics=pretty" => {
+                diagnostic_format = DiagnosticFormat::Pretty;
+                continue;
+            }
+            "--diagnostics=json" => {
+                diagnostic_format = DiagnosticFormat::Json;
+                continue;
+            }
+            "--warnings-as-errors" => {
+                warnings_as_errors = true;
+                continue;
+            }
+            "--no-warnings" => {
+                warnings = false;
+                continue;
+            }
+            "--fresh-loop-variables" => {
+                fresh_loop_variables = true;
+                continue;
+            }
+            "--top-level-return" => {
+                top_level_return = true;
+                continue;
+            }
+            "--truthiness=lox" => {
+                truthiness = Truthiness::Lox;
+                continue;
+            }
+            "--truthiness=strict" => {
+                truthiness = Truthiness::Strict;
+                continue;
+            }
+            "--equality=strict" => {
+                equality = Equality::Strict;
+                continue;
+            }
+            "--equality=coercing" => {
+                equality = Equality::Coercing;
+                continue;
+            }
+            "--check" if matches!(mode, Mode::Format { .. }) => {
+                mode = Mode::Format { check: true };
+                continue;
+            }
+            arg if arg.starts_with("--allow=") && matches!(mode, Mode::Lint { .. }) => {
+                let rule = LintRule::from_name(&arg["--allow=".len()..])?;
+                if let Mode::Lint { allow, .. } = &mut mode {
+                    allow.push(rule);
+                }
+                continue;
+            }
+            "--dead-globals" if matches!(mode, Mode::Lint { .. }) => {
+                if let Mode::Lint { dead_globals, .. } = &mut mode {
+                    *dead_globals = true;
+                }
+                continue;
+            }
+            arg if arg.starts_with("--runs=") && matches!(mode, Mode::Bench(_)) => {
+                let runs = arg["--runs=".len()..].parse().ok().filter(|&runs| runs > 0)?;
+                if let Mode::Bench(bench) = &mut mode {
+                    bench.runs = runs;
+                }
+                continue;
+            }
+            arg if arg.starts_with("--save=") && matches!(mode, Mode::Bench(_)) => {
+                if let Mode::Bench(bench) = &mut mode {
+                    bench.save = Some(arg["--save=".len()..].to_owned());
+                }
+                continue;
+            }
+            arg if arg.starts_with("--baseline=") && matches!(mode, Mode::Bench(_)) => {
+                if let Mode::Bench(bench) = &mut mode {
+                    bench.baseline = Some(arg["--baseline=".len()..].to_owned());
+                }
+                continue;
+            }
+            arg if arg.starts_with("--threshold=") && matches!(mode, Mode::Bench(_)) => {
+                let threshold =
+                    arg["--threshold=".len()..].parse().ok().filter(|&t: &f64| t >= 0.0)?;
+                if let Mode::Bench(bench) = &mut mode {
+                    bench.threshold = threshold;
+                }
+                continue;
+            }
+            "-o" | "--output" if matches!(mode, Mode::Compile(_)) => {
+                mode = Mode::Compile(Some(args.next()?));
+                continue;
+            }
+            "--vm" => {
+                backend = Backend::Vm;
+                continue;
+            }
+            "--profile" => {
+                profile = true;
+                continue;
+            }
+            "--coverage" => {
+                coverage = Some(CoverageFormat::Report);
+                continue;
+            }
+            "--coverage=lcov" => {
+                coverage = Some(CoverageFormat::Lcov);
+                continue;
+            }
+            "--trace" => {
+                trace = true;
+                continue;
+            }
+            "--trace=depth" => {
+                trace = true;
+                trace_depth = true;
+                continue;
+            }
+            arg if arg.starts_with("--max-call-depth=") => {
+                max_call_depth = Some(arg["--max-call-depth=".len()..].parse().ok()?);
+                continue;
+            }
+            "-e" | "--eval" => Input::Eval(args.next()?),
+            "-" => Input::Stdin,
+            arg if arg.starts_with('-') => return None,
+            filename => Input::File(filename.to_owned()),
+        };
+
+        // Only one input is allowed
+        if input.replace(next_input).is_some() {
+            return None;
+        }
+    }
+
+    let input = input.unwrap_or(Input::Prompt);
+    if !matches!(mode, Mode::Run) && matches!(input, Input::Prompt) {
+        return None;
+    }
+    // The debugger reads its commands from the standard input
+    if matches!(mode, Mode::Debug | Mode::Test | Mode::Bench(_)) && !matches!(input, Input::File(_))
+    {
+        return None;
+    }
+    // Only the tree-walking interpreter records the calls and the lines, traces
+    // and debugs
+    if (profile || coverage.is_some() || trace || matches!(mode, Mode::Debug))
+        && backend == Backend::Vm
+    {
+        return None;
+    }
+
+    Some(Options {
+        input,
+        mode,
+        diagnostic_format,
+        warnings_as_errors,
+        warnings,
+        fresh_loop_variables,
+        top_level_return,
+        truthiness,
+        equality,
+        max_call_depth,
+        backend,
+        profile,
+        coverage,
+        trace,
+        trace_depth,
+    })
+}
+
+fn debug_file(lox: &mut Lox, filename: &str) -> Result<(), anyhow::Error> {
+    let source = std::fs::read_to_string(filename)?;
+    let commands = std::io::BufReader::new(std::io::stdin());
+    lox.set_debugger(Some(
+        Debugger::new(commands, std::io::stdout()).with_source(filename, &source),
+    ));
+    lox.run_file(filename)
+}
+
+fn read_source(input: Input) -> Result<String, anyhow::Error> {
+    let source = match input {
+        Input::File(filename) => std::fs::read_to_string(filename)?,
+        Input::Stdin => {
+            let mut source = String::new();
+            std::io::stdin().read_to_string(&mut source)?;
+            source
+        }
+        Input::Eval(source) => source,
+        Input::Prompt => unreachable!("the prompt has no source to read"),
+    };
+
+    Ok(source)
+}
+
+fn dump_tokens(lox: &mut Lox, source: &str) -> Result<(), anyhow::Error> {
+    for token in lox.tokenize(source)? {
+        let token_type = format!("{:?}", token.token_type);
+        let literal = token.literal.map(|l| format!("{l:?}")).unwrap_or_default();
+        let position = format!("{}:{}", token.line, token.column);
+        let line = format!("{position:<8} {token_type:<14} {:<12} {literal}", token.lexeme);
+        println!("{}", line.trim_end());
+    }
+
+    Ok(())
+}
+
+fn dump_ast(lox: &mut Lox, source: &str, json: bool) -> Result<(), anyhow::Error> {
+    let ast = lox.parse_source(source)?;
+    if json {
+        println!("{}", AstJson::program_to_json(&ast));
+    } else {
+        println!("{}", AstPrinter::program_to_string(&ast));
+    }
+    Ok(())
+}
+
+/// Format a script in place, or print the formatted code of other inputs.
+/// With `check`, fail if the code isn't formatted instead.
+fn format(lox: &mut Lox, input: Input, check: bool) -> Result<(), anyhow::Error> {
+    let filename = match &input {
+        Input::File(filename) => Some(filename.clone()),
+        _ => None,
+    };
+    let source = read_source(input)?;
+    let formatted = lox.format(&source)?;
+
+    match filename {
+        _ if check && formatted != source => {
+            let name = filename.as_deref().unwrap_or("The code");
+            anyhow::bail!("{name} is not formatted.")
+        }
+        _ if check => {}
+        Some(filename) if formatted != source => std::fs::write(filename, formatted)?,
+        Some(_) => {}
+        None => print!("{formatted}"),
+    }
+    Ok(())
+}
+
+/// Print the likely mistakes in the program, and fail if there are any.
+fn lint(
+    lox: &mut Lox,
+    input: Input,
+    allow: &[LintRule],
+    dead_globals: bool,
+    diagnostic_format: DiagnosticFormat,
+) -> Result<(), anyhow::Error> {
+    let filename = match &input {
+        Input::File(filename) => Some(filename.clone()),
+        _ => None,
+    };
+    let source = read_source(input)?;
+
+    let mut diagnostics = lox.lint(&source)?;
+    if dead_globals {
+        diagnostics.extend(lox.dead_globals(&source)?);
+        diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+    }
+
+    let mut count = 0;
+    for mut diagnostic in diagnostics {
+        if LintRule::from_code(diagnostic.code).is_some_and(|rule| allow.contains(&rule)) {
+            continue;
+        }
+        diagnostic.file = filename.clone();
+        println!("{}", diagnostic.render(diagnostic_format, &source));
+        count += 1;
+    }
+
+    match count {
+        0 => Ok(()),
+        1 => anyhow::bail!("Found 1 warning."),
+        _ => anyhow::bail!("Found {count} warnings."),
+    }
+}
+
+/// Run the test files, each on a new instance, and print the result of each
+/// test. Fails if a test fails, or a file fails outside of its tests.
+fn run_tests(
+    path: &str,
+    backend: Backend,
+    diagnostic_format: DiagnosticFormat,
+) -> Result<(), anyhow::Error> {
+    let mut files = vec![];
+    find_files(Path::new(path), "_test.lox", &mut files)?;
+    files.sort();
+
+    let (mut passed, mut failed) = (0, 0);
+    for file in files {
+        let mut lox = Lox::new().with_backend(backend);
+        lox.set_diagnostic_format(diagnostic_format);
+        let result = lox.run_file(&file.to_string_lossy());
+
+        for test in lox.take_test_results() {
+            match test.failure {
+                None => {
+                    passed += 1;
+                    println!("PASS {}: {}", file.display(), test.name);
+                }
+                Some(failure) => {
+                    failed += 1;
+                    println!("FAIL {}: {}\n  {failure}", file.display(), test.name);
+                }
+            }
+        }
+        if let Err(e) = result {
+            failed += 1;
+            println!("FAIL {}", file.display());
+            for line in e.to_string().lines() {
+                println!("  {line}");
+            }
+        }
+    }
+
+    println!("{passed} passed, {failed} failed.");
+    match failed {
+        0 => Ok(()),
+        1 => anyhow::bail!("1 test failed."),
+        _ => anyhow::bail!("{failed} tests failed."),
+    }
+}
+
+/// Time each script of the path and compare the times with the baseline, if
+/// there is one.
+fn run_benchmarks(path: &str, backend: Backend, bench: &Bench) -> Result<(), anyhow::Error> {
+    let root = Path::new(path);
+    let mut files = vec![];
+    find_files(root, ".lox", &mut files)?;
+    files.sort();
+
+    let baseline = match &bench.baseline {
+        Some(baseline) => {
+            let json = std::fs::read_to_string(baseline)?;
+            Some(serde_json::from_str::<serde_json::Value>(&json)?)
+        }
+        None => None,
+    };
+
+    let mut results = serde_json::Map::new();
+    let mut regressed = 0;
+    for file in files {
+        // Name the scripts relative to the directory, so a baseline can be
+        // compared with the same scripts elsewhere
+        let name = match file.strip_prefix(root) {
+            Ok(relative) if relative != Path::new("") => relative,
+            _ => Path::new(file.file_name().unwrap_or(file.as_os_str())),
+        };
+        let name = name.to_string_lossy().into_owned();
+
+        let mut times = vec![];
+        for _ in 0..bench.runs {
+            let mut lox = Lox::new().with_backend(backend);
+            lox.set_output(std::io::sink());
+            let start = std::time::Instant::now();
+            lox.run_file(&file.to_string_lossy())?;
+            times.push(start.elapsed().as_secs_f64() * 1000.0);
+        }
+
+        let mean = times.iter().sum::<f64>() / times.len() as f64;
+        let variance = times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / times.len() as f64;
+        let stddev = variance.sqrt();
+        let mut line = format!("{name:<30} {mean:>10.3} ms ± {stddev:>8.3} ms");
+
+        let previous = baseline.as_ref().and_then(|b| b[&name]["mean"].as_f64());
+        match previous {
+            Some(previous) => {
+                let change = (mean - previous) * 100.0 / previous;
+                line.push_str(&format!("  {change:>+7.1}%"));
+                if change > bench.threshold {
+                    regressed += 1;
+                    line.push_str("  REGRESSED");
+                }
+            }
+            None if baseline.is_some() => line.push_str("  (no baseline)"),
+            None => {}
+        }
+        println!("{line}");
+
+        results.insert(name, serde_json::json!({ "mean": mean, "stddev": stddev }));
+    }
+
+    if let Some(save) = &bench.save {
+        std::fs::write(save, serde_json::to_string_pretty(&results)? + "\n")?;
+    }
+
+    match regressed {
+        0 => Ok(()),
+        1 => anyhow::bail!("1 benchmark got slower than its baseline."),
+        _ => anyhow::bail!("{regressed} benchmarks got slower than their baseline."),
+    }
+}
+
+/// Collect the files of the directory and its subdirectories whose name ends
+/// with `suffix`. A file given directly is collected whatever its name.
+fn find_files(path: &Path, suffix: &str, files: &mut Vec<PathBuf>) -> Result<(), anyhow::Error> {
+    if !path.is_dir() {
+        files.push(path.to_owned());
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_files(&path, suffix, files)?;
+        } else if path.to_string_lossy().ends_with(suffix) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn exit_code(error: &anyhow::Error) -> i32 {
+    if let Some(e) = error.downcast_ref::<LoxError>() {
+        e.exit_code()
+    } else if error.is::<BytecodeError>() {
+        65
+    } else if error.is::<std::io::Error>() {
+        // Couldn't read the input file
+        66
+    } else {
+        1
     }
 }
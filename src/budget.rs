@@ -0,0 +1,69 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::diagnostic::DiagnosticCode;
+
+/// How many steps to run between two checks of the clock.
+const STEPS_PER_TIME_CHECK: u64 = 1024;
+
+/// Stops the program running on an interpreter from another thread, e.g. a
+/// Ctrl-C handler. The program fails with an "Interrupted." runtime error.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    /// Ask the running program to stop. Returns true if a previous interrupt
+    /// hasn't been handled yet, e.g. because no program is running.
+    pub fn interrupt(&self) -> bool {
+        self.0.swap(true, Ordering::Relaxed)
+    }
+
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// The steps and the time a run may use, shared by both backends.
+#[derive(Debug, Default)]
+pub(crate) struct Budget {
+    /// Number of steps taken in the current run
+    pub steps: u64,
+    pub max_steps: Option<u64>,
+    pub timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    next_time_check: u64,
+    pub interrupt: InterruptHandle,
+}
+
+impl Budget {
+    /// Start counting the steps and the time of a new run. An interrupt that
+    /// arrived while nothing was running is dropped.
+    pub fn reset(&mut self) {
+        self.interrupt.take();
+        self.steps = 0;
+        self.next_time_check = STEPS_PER_TIME_CHECK;
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    /// Fail if the run has used up its steps or its time, or was interrupted.
+    /// The clock is read only once every few steps, to keep the overhead low.
+    pub fn check(&mut self) -> Result<(), (DiagnosticCode, &'static str)> {
+        if self.interrupt.take() {
+            return Err((DiagnosticCode::Interrupted, "Interrupted."));
+        }
+
+        if matches!(self.max_steps, Some(max_steps) if self.steps > max_steps) {
+            return Err((DiagnosticCode::StepLimitExceeded, "Execution step limit exceeded."));
+        }
+
+        if self.steps >= self.next_time_check {
+            self.next_time_check = self.steps + STEPS_PER_TIME_CHECK;
+            if matches!(self.deadline, Some(deadline) if Instant::now() >= deadline) {
+                return Err((DiagnosticCode::TimeLimitExceeded, "Execution time limit exceeded."));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+//! Groups of natives that reach outside the program, which
+//! [`LoxBuilder::deny`](crate::LoxBuilder::deny) takes away from untrusted
+//! scripts.
+
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Reading and writing files. No native needs it yet.
+    Filesystem,
+    /// `httpGet` and `httpPost`, with the `net` feature
+    Network,
+    /// Reading environment variables. No native needs it yet.
+    Environment,
+    /// `clock`
+    Clock,
+    /// Starting or exiting processes. No native needs it yet.
+    Process,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 5] = [
+        Capability::Filesystem,
+        Capability::Network,
+        Capability::Environment,
+        Capability::Clock,
+        Capability::Process,
+    ];
+
+    /// The names of the natives that need this capability.
+    pub fn natives(self) -> &'static [&'static str] {
+        match self {
+            Self::Network => &["httpGet", "httpPost"],
+            Self::Clock => &["clock"],
+            Self::Filesystem | Self::Environment | Self::Process => &[],
+        }
+    }
+
+    /// The message of the runtime error of a native whose capability is
+    /// denied.
+    pub(crate) fn denied_message(self) -> String {
+        format!("This native function needs the {self} capability, which is disabled.")
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Filesystem => "filesystem",
+            Self::Network => "network",
+            Self::Environment => "environment",
+            Self::Clock => "clock",
+            Self::Process => "process",
+        };
+        write!(f, "{name}")
+    }
+}
@@ -1,15 +1,19 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt::Display;
-use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
+use crate::diagnostic::with_suggestion;
+use crate::gc::{self, Heap, Kind, Trace};
 use crate::prelude::*;
+use crate::sync::{Rc, RefCell, Weak};
 
 #[derive(Debug, Clone)]
 pub struct Class {
     name: String,
     methods: HashMap<String, Rc<LoxFunction>>,
     superclass: Option<Shared<Self>>,
+    /// The doc comment above the class declaration
+    doc: Option<Rc<str>>,
 }
 
 impl Class {
@@ -18,7 +22,36 @@ impl Class {
         methods: HashMap<String, Rc<LoxFunction>>,
         superclass: Option<Shared<Self>>,
     ) -> Self {
-        Self { name: name.as_ref().to_owned(), methods, superclass }
+        Self { name: name.as_ref().to_owned(), methods, superclass, doc: None }
+    }
+
+    pub fn with_doc(self, doc: Option<Rc<str>>) -> Self {
+        Self { doc, ..self }
+    }
+
+    pub fn doc(&self) -> Option<&Rc<str>> {
+        self.doc.as_ref()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn superclass(&self) -> Option<&Shared<Self>> {
+        self.superclass.as_ref()
+    }
+
+    /// The methods declared by this class itself, by name.
+    pub(crate) fn methods(&self) -> &HashMap<String, Rc<LoxFunction>> {
+        &self.methods
+    }
+
+    /// Add a method to the class, or replace the one with the same name, e.g.
+    /// for an `extend` statement. The instances of the class and its
+    /// subclasses see it right away.
+    pub(crate) fn define_method(&mut self, name: &str, method: Rc<LoxFunction>) {
+        self.methods.insert(name.to_owned(), method);
+        MethodCache::invalidate_all();
     }
 }
 
@@ -33,11 +66,13 @@ impl Class {
         class: Shared<Class>,
         arguments: &[Object],
         interpreter: &mut Interpreter,
-    ) -> Result<Shared<Instance>, RuntimeInterrupt> {
+    ) -> Result<Shared<Instance>, RuntimeError> {
         let instance = Rc::new(RefCell::new(Instance::new(class.clone())));
+        interpreter.heap.track(&instance);
 
         if let Some(initializer) = class.borrow().find_method("init") {
-            initializer.bind(Object::Instance(instance.clone())).call(interpreter, arguments)?;
+            let this = Object::Instance(instance.clone());
+            initializer.bind(this, &mut interpreter.heap).call(interpreter, arguments)?;
         }
 
         Ok(instance)
@@ -55,6 +90,15 @@ impl Class {
         None
     }
 
+    /// Names of the methods of this class and its superclasses.
+    pub fn method_names(&self) -> Vec<String> {
+        let mut names = self.methods.keys().cloned().collect::<Vec<_>>();
+        if let Some(superclass) = &self.superclass {
+            names.extend(superclass.borrow().method_names());
+        }
+        names
+    }
+
     pub fn arity(&self) -> usize {
         if let Some(initializer) = self.find_method("init") { initializer.arity() } else { 0 }
     }
@@ -71,23 +115,53 @@ impl Instance {
         Self { class, fields: HashMap::new() }
     }
 
-    pub fn get(&self, field: &Token, instance: &Object) -> Result<Object, RuntimeInterrupt> {
-        if let Some(object) = self.fields.get(&field.lexeme) {
+    /// The field or method of this instance with this name, looking the
+    /// method up through the cache of the property expression.
+    pub(crate) fn get(
+        &self,
+        field: &Token,
+        cache: &MethodCache,
+        instance: &Object,
+        heap: &mut Heap,
+    ) -> Result<Object, RuntimeError> {
+        if let Some(object) = self.fields.get(&*field.lexeme) {
             Ok(object.clone())
-        } else if let Some(function) = self.class.borrow().find_method(&field.lexeme) {
-            let function = function.bind(instance.clone());
-
-            Ok(Object::Callable(function))
+        } else if let Some(method) = cache.find_method(&self.class, &field.lexeme) {
+            Ok(Object::Callable(method.bind(instance.clone(), heap)))
         } else {
-            Err(RuntimeInterrupt::error(
-                field.line,
-                format!("Undefined property '{}'.", field.lexeme),
-            ))
+            Err(undefined_property(field, &self.property_names()))
         }
     }
 
+    /// The method of the class of this instance with this name, bound to
+    /// `instance`, which is this instance as an object.
+    pub(crate) fn bind_method(
+        &self,
+        name: &str,
+        instance: &Object,
+        heap: &mut Heap,
+    ) -> Option<Rc<LoxFunction>> {
+        let method = self.class.borrow().find_method(name)?;
+        Some(method.bind(instance.clone(), heap))
+    }
+
+    pub(crate) fn has_field(&self, name: &str) -> bool {
+        self.fields.contains_key(name)
+    }
+
+    /// Names of the fields and methods of this instance.
+    pub fn property_names(&self) -> Vec<String> {
+        let mut names = self.fields.keys().cloned().collect::<Vec<_>>();
+        names.extend(self.class.borrow().method_names());
+        names
+    }
+
     pub fn set(&mut self, field: &Token, value: Object) {
-        self.fields.insert(field.lexeme.clone(), value);
+        self.set_field(&field.lexeme, value);
+    }
+
+    pub(crate) fn set_field(&mut self, name: &str, value: Object) {
+        self.fields.insert(name.to_owned(), value);
     }
 
     pub fn unique_id(&self) -> UniqueId {
@@ -100,3 +174,127 @@ impl Display for Instance {
         write!(f, "{} instance", self.class.borrow())
     }
 }
+
+impl Trace for RefCell<Class> {
+    fn kind(&self) -> Kind {
+        Kind::Class
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        let Ok(class) = self.try_borrow() else { return };
+        class.methods.values().for_each(|method| visit(gc::address(method)));
+        if let Some(superclass) = &class.superclass {
+            visit(gc::address(superclass));
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut class) = self.try_borrow_mut() {
+            class.methods.clear();
+            class.superclass = None;
+            MethodCache::invalidate_all();
+        }
+    }
+}
+
+impl Trace for RefCell<Instance> {
+    fn kind(&self) -> Kind {
+        Kind::Instance
+    }
+
+    fn strings(&self) -> usize {
+        let Ok(instance) = self.try_borrow() else { return 0 };
+        instance.fields.values().filter(|value| matches!(value, Object::String(_))).count()
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        let Ok(instance) = self.try_borrow() else { return };
+        visit(gc::address(&instance.class));
+        instance.fields.values().for_each(|value| value.trace(visit));
+    }
+
+    fn clear(&self) {
+        if let Ok(mut instance) = self.try_borrow_mut() {
+            instance.fields.clear();
+        }
+    }
+}
+
+/// Changes whenever the methods of a class change, which makes all the
+/// entries of the method caches stale.
+static METHODS_VERSION: AtomicU64 = AtomicU64::new(0);
+
+/// The part of a property expression filled in by the interpreter: the method
+/// it found last, and in which class, so that the next access on an instance
+/// of the same class doesn't look the name up in each class of the chain.
+///
+/// Entries only hold weak references, so a cache doesn't keep classes or
+/// methods alive, and the address of a class can't be reused by another
+/// while an entry refers to it.
+#[derive(Debug, Default)]
+pub struct MethodCache {
+    entry: RefCell<Option<CachedMethod>>,
+}
+
+#[derive(Debug)]
+struct CachedMethod {
+    class: Weak<RefCell<Class>>,
+    version: u64,
+    method: Weak<LoxFunction>,
+}
+
+impl MethodCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The method of the class with this name, from the cache if it was the
+    /// last one found here for the same class.
+    pub(crate) fn find_method(&self, class: &Shared<Class>, name: &str) -> Option<Rc<LoxFunction>> {
+        let version = METHODS_VERSION.load(Ordering::Relaxed);
+        if let Some(entry) = &*self.entry.borrow() {
+            if entry.version == version && std::ptr::eq(entry.class.as_ptr(), Rc::as_ptr(class)) {
+                if let Some(method) = entry.method.upgrade() {
+                    return Some(method);
+                }
+            }
+        }
+
+        let method = class.borrow().find_method(name)?;
+        let class = Rc::downgrade(class);
+        *self.entry.borrow_mut() =
+            Some(CachedMethod { class, version, method: Rc::downgrade(&method) });
+        Some(method)
+    }
+
+    /// Make the entries of all the caches stale, after the methods of a class
+    /// changed.
+    pub(crate) fn invalidate_all() {
+        METHODS_VERSION.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Clone for MethodCache {
+    /// Copies of a tree start with empty caches.
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// The error for accessing a missing property, with a hint if one of the
+/// existing properties has a similar name.
+pub(crate) fn undefined_property(name: &Token, properties: &[String]) -> RuntimeError {
+    let message = with_suggestion(
+        format!("Undefined property '{}'.", name.lexeme),
+        &name.lexeme,
+        properties.iter().map(String::as_str),
+    );
+    RuntimeError::error(name, DiagnosticCode::UndefinedProperty, message)
+}
+
+/// The error of `super.name` when `name` is a field of the instance, which
+/// only `this` can get.
+pub(crate) fn field_through_super(name: &Token) -> RuntimeError {
+    let message = format!("Can't access field '{0}' through 'super'. Use 'this.{0}'.", name.lexeme);
+    RuntimeError::error(name, DiagnosticCode::UndefinedProperty, message)
+}
@@ -0,0 +1,710 @@
+use crate::prelude::{
+    Ast, DiagnosticCode, Expr, ExprId, Literal, Span, Stmt, StmtId, Token, TokenType,
+};
+use crate::sync::Rc;
+use crate::vm::chunk::{Op, Position};
+use crate::vm::value::{Function, UpvalueRef, Value};
+
+/// The number of constants a chunk can hold, like in clox, where the
+/// operand of a constant instruction is a single byte.
+const MAX_CONSTANTS: usize = 256;
+
+/// A limit of the bytecode that a program exceeds.
+#[derive(Debug)]
+pub struct CompileError {
+    pub line: u32,
+    /// 1-based column, or 0 if unknown.
+    pub column: u32,
+    pub span: Span,
+    /// The code the error is about, e.g. `at '"oops"'`.
+    pub location: String,
+    pub code: DiagnosticCode,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+/// A local variable of the function being compiled, which lives in a stack
+/// slot.
+struct Local {
+    name: Rc<str>,
+    /// The depth of the scope that declares the variable
+    depth: usize,
+    /// Whether a closure captures the variable, so it must be moved off the
+    /// stack when it goes out of scope
+    is_captured: bool,
+}
+
+/// A loop being compiled, for the `break` statements in it.
+struct Loop {
+    scope_depth: usize,
+    /// The number of `using` statements around the loop, whose values a
+    /// `break` doesn't close
+    usings: usize,
+    /// The jumps out of the loop, patched once its end is known
+    breaks: Vec<usize>,
+}
+
+/// The state of a function being compiled.
+struct FunctionState {
+    function: Function,
+    kind: FunctionKind,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+    /// The number of `using` statements being compiled, whose values a
+    /// `return` closes
+    usings: usize,
+}
+
+impl FunctionState {
+    fn new(name: Rc<str>, kind: FunctionKind) -> Self {
+        // Slot zero holds the function being called, or the receiver of a method
+        let receiver = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            FunctionKind::Script | FunctionKind::Function => "",
+        };
+        let slot_zero = Local { name: receiver.into(), depth: 0, is_captured: false };
+
+        Self {
+            function: Function { name, ..Default::default() },
+            kind,
+            locals: vec![slot_zero],
+            scope_depth: 0,
+            loops: vec![],
+            usings: 0,
+        }
+    }
+}
+
+/// Compiles a resolved syntax tree to bytecode for the [`Vm`](crate::vm::Vm).
+///
+/// The resolver has already reported the invalid programs, so compiling
+/// only fails if a function has too many constants for its chunk. Variables
+/// are resolved again here, because the VM keeps locals in stack slots and
+/// captured variables in upvalues, not in environments.
+pub struct Compiler<'a> {
+    ast: &'a Ast,
+    /// The functions being compiled, the innermost one last
+    functions: Vec<FunctionState>,
+    /// The position of the node being compiled
+    position: Position,
+    /// The line of the statement being compiled, for the literals, which
+    /// have no position of their own
+    line: u32,
+    errors: Vec<CompileError>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(ast: &'a Ast) -> Self {
+        Self { ast, functions: vec![], position: Position::default(), line: 0, errors: vec![] }
+    }
+
+    /// Compile each top-level statement of the program to its own script, so
+    /// the next statements can still run after one fails.
+    pub fn compile(ast: &Ast) -> Result<Vec<Rc<Function>>, Vec<CompileError>> {
+        let mut compiler = Compiler::new(ast);
+        let scripts = ast.statements().iter().map(|&stmt| {
+            compiler.functions.push(FunctionState::new("script".into(), FunctionKind::Script));
+            compiler.statement(stmt);
+            compiler.end_function()
+        });
+        let scripts = scripts.collect();
+
+        if compiler.errors.is_empty() { Ok(scripts) } else { Err(compiler.errors) }
+    }
+
+    /// Compile an expression to a script that returns its value.
+    pub fn compile_expression(ast: &Ast, expr: ExprId) -> Result<Rc<Function>, Vec<CompileError>> {
+        let mut compiler = Compiler::new(ast);
+        compiler.functions.push(FunctionState::new("script".into(), FunctionKind::Script));
+        compiler.expression(expr);
+        compiler.emit(Op::Return);
+        let script = Rc::new(compiler.functions.pop().expect("no function to compile").function);
+
+        if compiler.errors.is_empty() { Ok(script) } else { Err(compiler.errors) }
+    }
+}
+
+impl Compiler<'_> {
+    fn statement(&mut self, stmt: StmtId) {
+        let enclosing_line = std::mem::replace(&mut self.line, self.ast.line(stmt));
+        self.compile_statement(stmt);
+        self.line = enclosing_line;
+    }
+
+    fn compile_statement(&mut self, stmt: StmtId) {
+        let ast = self.ast;
+        match &ast[stmt] {
+            Stmt::Error => unreachable!("the resolver rejects syntax errors"),
+            Stmt::Expression { expr } => {
+                self.expression(*expr);
+                self.emit(Op::Pop);
+            }
+            Stmt::Print { exprs } => {
+                for &expr in exprs {
+                    self.expression(expr);
+                    self.emit(Op::Print);
+                }
+                self.emit(Op::PrintLine);
+            }
+            Stmt::Var { name, initializer } => {
+                self.set_position(name);
+                let global = self.declare_variable(name);
+                match *initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.emit(Op::Nil),
+                }
+                self.define_variable(global);
+            }
+            Stmt::Block { statements } => {
+                self.begin_scope();
+                for &stmt in statements {
+                    self.statement(stmt);
+                }
+                self.end_scope();
+            }
+            Stmt::Function { name, .. } => {
+                // The function is defined before its body, so it can call itself
+                self.set_position(name);
+                let global = self.declare_variable(name);
+                self.function(stmt, FunctionKind::Function);
+                self.define_variable(global);
+            }
+            Stmt::Class { name, methods, superclass } => {
+                self.class(name, methods, *superclass, self.ast.doc(stmt))
+            }
+            Stmt::Extend { keyword: _, class, methods } => {
+                self.expression(*class);
+                // The class is checked where it's named
+                if let Expr::Variable { name, .. } = &self.ast[*class] {
+                    self.set_position(name);
+                }
+                self.emit(Op::Extend);
+                self.methods(methods);
+                self.emit(Op::Pop);
+            }
+            Stmt::If { condition, then_branch, else_branch } => {
+                self.expression(*condition);
+                let then_jump = self.emit_jump(Op::JumpIfFalse(0));
+                self.emit(Op::Pop);
+                self.statement(*then_branch);
+
+                let else_jump = self.emit_jump(Op::Jump(0));
+                self.patch_jump(then_jump);
+                self.emit(Op::Pop);
+                if let Some(else_branch) = *else_branch {
+                    self.statement(else_branch);
+                }
+                self.patch_jump(else_jump);
+            }
+            Stmt::While { keyword, condition, body, else_branch } => {
+                let loop_start = self.current().function.chunk.code.len();
+                let scope_depth = self.current().scope_depth;
+                let usings = self.current().usings;
+                self.current().loops.push(Loop { scope_depth, usings, breaks: vec![] });
+
+                self.expression(*condition);
+                let exit_jump = self.emit_jump(Op::JumpIfFalse(0));
+                self.emit(Op::Pop);
+                self.statement(*body);
+                self.set_position(keyword);
+                self.emit(Op::Loop(loop_start));
+
+                self.patch_jump(exit_jump);
+                self.emit(Op::Pop);
+
+                // A `break` jumps over the `else` branch, where it belongs to
+                // the enclosing loop
+                let breaks = self.current().loops.pop().expect("no loop to end").breaks;
+                if let Some(else_branch) = *else_branch {
+                    self.statement(else_branch);
+                }
+                for jump in breaks {
+                    self.patch_jump(jump);
+                }
+            }
+            Stmt::Using { keyword, name, initializer, body, close: _ } => {
+                self.begin_scope();
+                self.set_position(name);
+                self.add_local(name.lexeme.clone());
+                self.expression(*initializer);
+                self.set_position(keyword);
+                self.emit(Op::Using);
+
+                self.current().usings += 1;
+                self.statement(*body);
+                self.current().usings -= 1;
+
+                self.set_position(keyword);
+                self.emit_close();
+                self.end_scope();
+            }
+            Stmt::Break { token } => {
+                self.set_position(token);
+                // The resolver doesn't reject a `break` outside of a loop, it does
+                // nothing here.
+                let Some(&Loop { scope_depth, usings, .. }) = self.current().loops.last() else {
+                    return;
+                };
+                for _ in usings..self.current().usings {
+                    self.emit_close();
+                }
+
+                // Leave the scopes of the loop body, without forgetting their
+                // variables, which are still in scope after the `break`. A
+                // closure compiled later in the body may capture them, so they
+                // are always closed.
+                let locals = self.current().locals.iter().rev();
+                let count = locals.take_while(|local| local.depth > scope_depth).count();
+                for _ in 0..count {
+                    self.emit(Op::CloseUpvalue);
+                }
+
+                let jump = self.emit_jump(Op::Jump(0));
+                self.current().loops.last_mut().expect("no loop to break").breaks.push(jump);
+            }
+            Stmt::Return { keyword, value } => {
+                self.set_position(keyword);
+                match *value {
+                    Some(value) => self.expression(value),
+                    None => self.emit_implicit_return_value(),
+                }
+                for _ in 0..self.current().usings {
+                    self.emit_close();
+                }
+                // The resolver only allows a `return` at the top level if it
+                // ends the script
+                if self.current().kind == FunctionKind::Script {
+                    self.emit(Op::Exit);
+                } else {
+                    self.emit(Op::Return);
+                }
+            }
+        }
+    }
+
+    fn class(
+        &mut self,
+        name: &Token,
+        methods: &[StmtId],
+        superclass: Option<ExprId>,
+        doc: Option<&Rc<str>>,
+    ) {
+        self.set_position(name);
+        let name_constant = self.identifier_constant(name);
+
+        // The class is defined once it's complete. Reserve the slot of a local
+        // class before the slot of `super`.
+        let global = self.declare_variable(name);
+        let slot = self.current().locals.len() - 1;
+        if global.is_none() {
+            self.emit(Op::Nil);
+        }
+
+        if let Some(superclass) = superclass {
+            self.begin_scope();
+            self.expression(superclass);
+            self.add_local("super".into());
+
+            // The superclass is checked where it's named
+            if let Expr::Variable { name, .. } = &self.ast[superclass] {
+                self.set_position(name);
+            }
+            self.emit(Op::Subclass(name_constant));
+        } else {
+            self.emit(Op::Class(name_constant));
+        }
+        if let Some(doc) = doc {
+            let constant =
+                self.make_constant(Value::String(doc.clone()), self.position, &name.lexeme);
+            self.emit(Op::ClassDoc(constant));
+        }
+
+        self.methods(methods);
+
+        match global {
+            Some(constant) => self.emit(Op::DefineGlobal(constant)),
+            None => {
+                self.emit(Op::SetLocal(slot));
+                self.emit(Op::Pop);
+            }
+        }
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+    }
+
+    /// Add the methods to the class on the top of the stack.
+    fn methods(&mut self, methods: &[StmtId]) {
+        for &method in methods {
+            let Stmt::Function { name, .. } = &self.ast[method] else {
+                panic!("Method is not encapsulated in Stmt::Function");
+            };
+            let kind = if &*name.lexeme == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+
+            self.set_position(name);
+            let constant = self.identifier_constant(name);
+            self.function(method, kind);
+            self.emit(Op::Method(constant));
+        }
+    }
+
+    /// Compile a function declaration and emit the closure for it.
+    fn function(&mut self, stmt: StmtId, kind: FunctionKind) {
+        let Stmt::Function { name, params, body } = &self.ast[stmt] else {
+            panic!("Function is not declared by a Stmt::Function");
+        };
+
+        self.functions.push(FunctionState::new(name.lexeme.clone(), kind));
+        self.current().function.arity = params.len();
+        self.current().function.doc = self.ast.doc(stmt).cloned();
+        self.begin_scope();
+        for param in params {
+            self.add_local(param.lexeme.clone());
+        }
+        for &stmt in body {
+            self.statement(stmt);
+        }
+
+        let function = self.end_function();
+        self.set_position(name);
+        let constant = self.make_constant(Value::Function(function), self.position, &name.lexeme);
+        self.emit(Op::Closure(constant));
+    }
+
+    fn end_function(&mut self) -> Rc<Function> {
+        self.emit_implicit_return_value();
+        self.emit(Op::Return);
+
+        let state = self.functions.pop().expect("no function to end");
+        Rc::new(state.function)
+    }
+
+    fn emit_implicit_return_value(&mut self) {
+        if self.current().kind == FunctionKind::Initializer {
+            self.emit(Op::GetLocal(0));
+        } else {
+            self.emit(Op::Nil);
+        }
+    }
+}
+
+impl Compiler<'_> {
+    fn expression(&mut self, expr: ExprId) {
+        let ast = self.ast;
+        match &ast[expr] {
+            Expr::Error => unreachable!("the resolver rejects syntax errors"),
+            Expr::Literal { value } => match value {
+                Literal::Null => self.emit(Op::Nil),
+                Literal::Boolean(true) => self.emit(Op::True),
+                Literal::Boolean(false) => self.emit(Op::False),
+                Literal::Number(n) => self.emit_constant(Value::Number(*n), &value.to_string()),
+                Literal::String(s) => {
+                    self.emit_constant(Value::String(s.clone()), &format!("\"{s}\""))
+                }
+            },
+            Expr::Grouping { expr } => self.expression(*expr),
+            Expr::Function { keyword: _, declaration } => {
+                self.function(*declaration, FunctionKind::Function)
+            }
+            Expr::Unary { operator, right } => {
+                self.expression(*right);
+                self.set_position(operator);
+                match operator.token_type {
+                    TokenType::Minus => self.emit(Op::Negate),
+                    _ => self.emit(Op::Not),
+                }
+            }
+            Expr::Binary { left, operator, right } => {
+                self.expression(*left);
+                self.expression(*right);
+                self.set_position(operator);
+                let op = match operator.token_type {
+                    TokenType::Plus => Op::Add,
+                    TokenType::Minus => Op::Subtract,
+                    TokenType::Star => Op::Multiply,
+                    TokenType::Slash => Op::Divide,
+                    TokenType::Greater => Op::Greater,
+                    TokenType::GreaterEqual => Op::GreaterEqual,
+                    TokenType::Less => Op::Less,
+                    TokenType::LessEqual => Op::LessEqual,
+                    TokenType::EqualEqual => Op::Equal,
+                    _ => Op::NotEqual,
+                };
+                self.emit(op);
+            }
+            Expr::Logical { left, operator, right } => {
+                self.expression(*left);
+                self.set_position(operator);
+                let end_jump = if operator.token_type == TokenType::Or {
+                    let else_jump = self.emit_jump(Op::JumpIfFalse(0));
+                    let end_jump = self.emit_jump(Op::Jump(0));
+                    self.patch_jump(else_jump);
+                    end_jump
+                } else {
+                    self.emit_jump(Op::JumpIfFalse(0))
+                };
+
+                self.emit(Op::Pop);
+                self.expression(*right);
+                self.patch_jump(end_jump);
+            }
+            Expr::Variable { binding: _, name } => self.get_variable(name),
+            Expr::This { binding: _, keyword } => self.get_variable(keyword),
+            Expr::Assignment { binding: _, name, value } => {
+                self.expression(*value);
+                self.set_variable(name);
+            }
+            Expr::Get { object, name, cache: _ } => {
+                self.expression(*object);
+                self.set_position(name);
+                let constant = self.identifier_constant(name);
+                self.emit(Op::GetProperty(constant));
+            }
+            Expr::Set { object, name, value } => {
+                self.expression(*object);
+                self.expression(*value);
+                self.set_position(name);
+                let constant = self.identifier_constant(name);
+                self.emit(Op::SetProperty(constant));
+            }
+            Expr::Index { object, bracket, index } => {
+                self.expression(*object);
+                self.expression(*index);
+                self.set_position(bracket);
+                self.emit(Op::GetIndex);
+            }
+            Expr::IndexSet { object, bracket, index, value } => {
+                self.expression(*object);
+                self.expression(*index);
+                self.expression(*value);
+                self.set_position(bracket);
+                self.emit(Op::SetIndex);
+            }
+            Expr::List { bracket, elements } => {
+                for &element in elements {
+                    self.expression(element);
+                }
+                self.set_position(bracket);
+                self.emit(Op::List(elements.len()));
+            }
+            Expr::Super { binding: _, keyword, method } => {
+                let this = Token { lexeme: "this".into(), ..keyword.clone() };
+                self.get_variable(&this);
+                self.get_variable(keyword);
+                self.set_position(method);
+                let constant = self.identifier_constant(method);
+                self.emit(Op::GetSuper(constant));
+            }
+            Expr::Call { callee, paren, arguments } => {
+                self.expression(*callee);
+                self.set_position(paren);
+                self.emit(Op::CheckCall(arguments.len()));
+                for &argument in arguments {
+                    self.expression(argument);
+                }
+                self.set_position(paren);
+                self.emit(Op::Call(arguments.len()));
+            }
+        }
+    }
+
+    fn get_variable(&mut self, name: &Token) {
+        self.set_position(name);
+        let op = if let Some(slot) = self.resolve_local(self.functions.len() - 1, name) {
+            Op::GetLocal(slot)
+        } else if let Some(index) = self.resolve_upvalue(self.functions.len() - 1, name) {
+            Op::GetUpvalue(index)
+        } else {
+            let constant = self.identifier_constant(name);
+            self.record_locals_in_scope();
+            Op::GetGlobal(constant)
+        };
+        self.emit(op);
+    }
+
+    fn set_variable(&mut self, name: &Token) {
+        self.set_position(name);
+        let op = if let Some(slot) = self.resolve_local(self.functions.len() - 1, name) {
+            Op::SetLocal(slot)
+        } else if let Some(index) = self.resolve_upvalue(self.functions.len() - 1, name) {
+            Op::SetUpvalue(index)
+        } else {
+            let constant = self.identifier_constant(name);
+            self.record_locals_in_scope();
+            Op::SetGlobal(constant)
+        };
+        self.emit(op);
+    }
+}
+
+impl Compiler<'_> {
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().expect("no function is being compiled")
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.current();
+        state.scope_depth -= 1;
+
+        let depth = state.scope_depth;
+        while let Some(local) = self.current().locals.pop_if(|local| local.depth > depth) {
+            self.emit(if local.is_captured { Op::CloseUpvalue } else { Op::Pop });
+        }
+    }
+
+    /// Declare a variable in the current scope. Returns the constant of its
+    /// name if it's a global variable.
+    fn declare_variable(&mut self, name: &Token) -> Option<usize> {
+        if self.current().scope_depth == 0 {
+            return Some(self.identifier_constant(name));
+        }
+
+        self.add_local(name.lexeme.clone());
+        None
+    }
+
+    /// Define the variable declared by [`Compiler::declare_variable`], with
+    /// the value on the top of the stack. A local variable is already in its
+    /// slot.
+    fn define_variable(&mut self, global: Option<usize>) {
+        if let Some(constant) = global {
+            self.emit(Op::DefineGlobal(constant));
+        }
+    }
+
+    fn add_local(&mut self, name: Rc<str>) {
+        let state = self.current();
+        let depth = state.scope_depth;
+        state.locals.push(Local { name, depth, is_captured: false });
+    }
+
+    fn resolve_local(&mut self, function: usize, name: &Token) -> Option<usize> {
+        let locals = &self.functions[function].locals;
+        locals.iter().rposition(|local| local.name == name.lexeme)
+    }
+
+    /// Find a variable of an enclosing function, and capture it in the
+    /// closures of all the functions in between.
+    fn resolve_upvalue(&mut self, function: usize, name: &Token) -> Option<usize> {
+        if function == 0 {
+            return None;
+        }
+
+        if let Some(slot) = self.resolve_local(function - 1, name) {
+            self.functions[function - 1].locals[slot].is_captured = true;
+            return Some(self.add_upvalue(function, UpvalueRef { is_local: true, index: slot }));
+        }
+
+        let index = self.resolve_upvalue(function - 1, name)?;
+        Some(self.add_upvalue(function, UpvalueRef { is_local: false, index }))
+    }
+
+    fn add_upvalue(&mut self, function: usize, upvalue: UpvalueRef) -> usize {
+        let upvalues = &mut self.functions[function].function.upvalues;
+        if let Some(index) = upvalues.iter().position(|u| *u == upvalue) {
+            return index;
+        }
+
+        upvalues.push(upvalue);
+        upvalues.len() - 1
+    }
+
+    /// Remember the local variables visible from the next instruction, which
+    /// reads or writes a global variable.
+    fn record_locals_in_scope(&mut self) {
+        let names = self
+            .functions
+            .iter()
+            .flat_map(|state| state.locals.iter())
+            .filter(|local| !local.name.is_empty())
+            .map(|local| local.name.clone())
+            .collect::<Rc<[_]>>();
+        if names.is_empty() {
+            return;
+        }
+
+        let chunk = &mut self.current().function.chunk;
+        chunk.locals_in_scope.insert(chunk.code.len(), names);
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> usize {
+        self.make_constant(Value::String(name.lexeme.clone()), name.into(), &name.lexeme)
+    }
+
+    /// Add a constant to the chunk of the current function. The position and
+    /// the source code of the constant are for the error if the chunk is
+    /// full, which is only reported once per chunk.
+    fn make_constant(&mut self, value: Value, position: Position, code: &str) -> usize {
+        if self.current().function.chunk.constants.len() == MAX_CONSTANTS {
+            self.errors.push(CompileError {
+                line: position.line,
+                column: position.column,
+                span: position.span,
+                location: format!("at '{code}'"),
+                code: DiagnosticCode::TooManyConstants,
+                message: "Too many constants in one chunk.".to_owned(),
+            });
+        }
+        self.current().function.chunk.add_constant(value)
+    }
+
+    /// Emit a literal, whose source `code` is on the line of the statement.
+    fn emit_constant(&mut self, value: Value, code: &str) {
+        let position = Position { line: self.line, ..Position::default() };
+        let constant = self.make_constant(value, position, code);
+        self.emit(Op::Constant(constant));
+    }
+
+    fn set_position(&mut self, token: &Token) {
+        self.position = token.into();
+    }
+
+    fn emit(&mut self, op: Op) {
+        let position = self.position;
+        self.current().function.chunk.write(op, position);
+    }
+
+    /// Emit a jump whose target is patched later.
+    /// Call `close()` on the value of the innermost `using` statement that
+    /// is still open.
+    fn emit_close(&mut self) {
+        self.emit(Op::Close);
+        let name = self.make_constant(Value::String("close".into()), self.position, "close");
+        self.emit(Op::GetProperty(name));
+        self.emit(Op::Call(0));
+        self.emit(Op::Pop);
+    }
+
+    fn emit_jump(&mut self, op: Op) -> usize {
+        let position = self.position;
+        self.current().function.chunk.write(op, position)
+    }
+
+    /// Make the jump at `offset` go to the next instruction.
+    fn patch_jump(&mut self, offset: usize) {
+        let chunk = &mut self.current().function.chunk;
+        let target = chunk.code.len();
+        chunk.code[offset] = match chunk.code[offset] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            op => panic!("{op:?} is not a jump"),
+        };
+    }
+}
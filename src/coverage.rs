@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::ops::ControlFlow;
+
+use crate::Shared;
+use crate::ast::{Ast, Stmt, StmtId};
+use crate::environment::Environment;
+use crate::hooks::InterpreterHooks;
+use crate::sync::Rc;
+
+/// The lines of each file that ran, as recorded by the interpreter with
+/// coverage on.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    /// The files that were run, in the order they were first run
+    pub files: Vec<FileCoverage>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileCoverage {
+    /// The name of the script, or `<script>` for code that isn't read from a
+    /// file
+    pub file: String,
+    /// The lines that have statements, in order
+    pub lines: Vec<LineCoverage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineCoverage {
+    pub line: u32,
+    /// Number of times the statements that start on the line ran
+    pub hits: u64,
+}
+
+impl FileCoverage {
+    /// Number of lines that ran at least once.
+    pub fn lines_hit(&self) -> usize {
+        self.lines.iter().filter(|line| line.hits > 0).count()
+    }
+}
+
+impl Coverage {
+    /// The coverage in the LCOV tracefile format, which most coverage tools
+    /// read.
+    pub fn lcov(&self) -> String {
+        let mut lcov = String::new();
+        for file in &self.files {
+            lcov.push_str(&format!("TN:\nSF:{}\n", file.file));
+            for line in &file.lines {
+                lcov.push_str(&format!("DA:{},{}\n", line.line, line.hits));
+            }
+            lcov.push_str(&format!(
+                "LF:{}\nLH:{}\nend_of_record\n",
+                file.lines.len(),
+                file.lines_hit()
+            ));
+        }
+        lcov
+    }
+}
+
+impl Display for Coverage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, file) in self.files.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let hit = file.lines_hit();
+            let percent = match file.lines.len() {
+                0 => 100.0,
+                total => hit as f64 * 100.0 / total as f64,
+            };
+            write!(f, "{}: {hit} of {} lines ran ({percent:.1}%)", file.file, file.lines.len())?;
+            write!(f, "\n{:>10}  line", "hits")?;
+            for line in &file.lines {
+                write!(f, "\n{:>10}  {}", line.hits, line.line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether running a statement counts as a hit of its line. Blocks and the
+/// statements the parser makes up only run the statements the code has.
+fn counts(ast: &Ast, stmt: StmtId) -> bool {
+    !ast.location(stmt).synthetic && !matches!(ast[stmt], Stmt::Block { .. })
+}
+
+/// Records the lines of the programs that run.
+#[derive(Debug, Default)]
+pub(crate) struct CoverageRecorder {
+    /// The hits of each line of each file
+    files: Vec<(String, BTreeMap<u32, u64>)>,
+    /// The file of each tree, by the address of the tree. Functions keep their
+    /// tree alive, so an address can't be reused while its code may run.
+    asts: HashMap<usize, usize>,
+}
+
+impl CoverageRecorder {
+    /// Start recording the lines of a program read from `file`. Its lines
+    /// with statements count as not run until they are.
+    pub fn add(&mut self, file: &str, ast: &Rc<Ast>) {
+        let index = match self.files.iter().position(|(name, _)| name == file) {
+            Some(index) => index,
+            None => {
+                self.files.push((file.to_owned(), BTreeMap::new()));
+                self.files.len() - 1
+            }
+        };
+        self.asts.insert(Rc::as_ptr(ast) as usize, index);
+
+        // Methods are never executed as statements, only their bodies are
+        let methods = ast
+            .stmt_ids()
+            .filter_map(|stmt| match &ast[stmt] {
+                Stmt::Class { methods, .. } | Stmt::Extend { methods, .. } => {
+                    Some(methods.iter().copied())
+                }
+                _ => None,
+            })
+            .flatten()
+            .collect::<HashSet<_>>();
+        let lines = &mut self.files[index].1;
+        for stmt in ast.stmt_ids() {
+            if !methods.contains(&stmt) && !matches!(ast[stmt], Stmt::Error) && counts(ast, stmt) {
+                lines.entry(ast.line(stmt)).or_default();
+            }
+        }
+    }
+
+    /// Count a statement of the tree as executed.
+    pub fn hit(&mut self, ast: &Rc<Ast>, stmt: StmtId) {
+        if !counts(ast, stmt) {
+            return;
+        }
+        if let Some(&index) = self.asts.get(&(Rc::as_ptr(ast) as usize)) {
+            *self.files[index].1.entry(ast.line(stmt)).or_default() += 1;
+        }
+    }
+
+    pub fn coverage(&self) -> Coverage {
+        let files = self
+            .files
+            .iter()
+            .map(|(file, lines)| FileCoverage {
+                file: file.clone(),
+                lines: lines.iter().map(|(&line, &hits)| LineCoverage { line, hits }).collect(),
+            })
+            .collect();
+
+        Coverage { files }
+    }
+}
+
+impl InterpreterHooks for CoverageRecorder {
+    fn on_statement(
+        &mut self,
+        ast: &Rc<Ast>,
+        stmt: StmtId,
+        _line: u32,
+        _environment: &Shared<Environment>,
+    ) -> ControlFlow<()> {
+        self.hit(ast, stmt);
+        ControlFlow::Continue(())
+    }
+}
@@ -0,0 +1,147 @@
+//! The debugger of the `lox debug` command, driven by text commands.
+
+use std::io::{BufRead, Write};
+
+use super::{Command, Frontend, State, StopReason};
+use crate::output::Writer;
+use crate::sync::MaybeSync;
+
+const HELP: &str = "Commands:
+  step, s                 Run until the next statement
+  next, n                 Run until the next statement of this function, stepping
+                          over calls
+  continue, c             Run until a breakpoint
+  break, b [file:]<line>  Stop at the first statement of a line
+  delete, d [file:]<line> Remove a breakpoint
+  breakpoints             List the breakpoints
+  backtrace, bt           List the calls being executed, the innermost first
+  vars                    List the variables visible from here, scope by scope
+  print, p <name>         Print the value of a variable
+  quit, q                 Stop the program
+  help, h                 Print this help
+
+An empty line repeats the previous command.";
+
+trait Input: BufRead + MaybeSync {}
+impl<T: BufRead + MaybeSync> Input for T {}
+
+/// Reads the commands from `input` and writes to `output`.
+pub(super) struct Console {
+    input: Box<dyn Input>,
+    output: Box<dyn Writer>,
+    last_command: String,
+}
+
+impl Console {
+    pub fn new(
+        input: impl BufRead + MaybeSync + 'static,
+        output: impl Write + MaybeSync + 'static,
+    ) -> Self {
+        Self { input: Box::new(input), output: Box::new(output), last_command: String::new() }
+    }
+
+    /// The line of a breakpoint given as `line` or `file:line`.
+    fn parse_line(state: &State, location: &str) -> Option<u32> {
+        let line = match location.rsplit_once(':') {
+            Some((file, line)) if state.file.as_deref() == Some(file) => line,
+            Some(_) => return None,
+            None => location,
+        };
+        line.parse().ok().filter(|&line| line > 0)
+    }
+
+    /// The variables of each environment visible from the current statement,
+    /// the innermost first.
+    fn scopes(state: &State) -> String {
+        let Some(environment) = state.frames.last().and_then(|f| f.environment.as_ref()) else {
+            return String::new();
+        };
+
+        let scopes = super::scopes(environment);
+        let scopes = scopes.iter().enumerate().map(|(i, env)| {
+            let title = match env.borrow().enclosing {
+                Some(_) => format!("scope {i}:"),
+                None => "globals:".to_owned(),
+            };
+            let variables = super::variables(env).into_iter();
+            let variables = variables.map(|(name, value)| format!("  {name} = {value}"));
+            std::iter::once(title).chain(variables).collect::<Vec<_>>().join("\n")
+        });
+        scopes.collect::<Vec<_>>().join("\n")
+    }
+
+    fn say(&mut self, message: impl AsRef<str>) {
+        let _ = writeln!(self.output, "{}", message.as_ref());
+    }
+}
+
+impl Frontend for Console {
+    /// Read and run commands until one of them resumes the program.
+    fn stopped(&mut self, _reason: StopReason, text: &str, state: &mut State) -> Command {
+        let line = state.frames.last().map_or(0, |frame| frame.line);
+        self.say(format!("Stopped at line {line}: {text}"));
+
+        loop {
+            let _ = write!(self.output, "(debug) ");
+            let _ = self.output.flush();
+
+            let mut line = String::new();
+            if !matches!(self.input.read_line(&mut line), Ok(n) if n > 0) {
+                // Without any more commands, let the program run to its end
+                state.breakpoints.clear();
+                return Command::Continue;
+            }
+
+            let mut command = line.trim().to_owned();
+            if command.is_empty() {
+                command = self.last_command.clone();
+            } else {
+                self.last_command = command.clone();
+            }
+
+            let (name, argument) = command.split_once(' ').unwrap_or((&command, ""));
+            match (name, argument.trim()) {
+                ("step" | "s", "") => return Command::Step,
+                ("next" | "n", "") => return Command::Next,
+                ("continue" | "c", "") => return Command::Continue,
+                ("quit" | "q", "") => return Command::Quit,
+                ("break" | "b", location) => match Self::parse_line(state, location) {
+                    Some(line) => {
+                        state.breakpoints.insert(line);
+                        self.say(format!("Breakpoint at line {line}."));
+                    }
+                    None => self.say(format!("Invalid location '{location}'.")),
+                },
+                ("delete" | "d", location) => match Self::parse_line(state, location) {
+                    Some(line) if state.breakpoints.remove(&line) => {
+                        self.say(format!("Removed the breakpoint at line {line}."))
+                    }
+                    Some(line) => self.say(format!("No breakpoint at line {line}.")),
+                    None => self.say(format!("Invalid location '{location}'.")),
+                },
+                ("breakpoints", "") => {
+                    let lines = state.breakpoints.iter().map(|line| format!("line {line}"));
+                    match lines.collect::<Vec<_>>() {
+                        lines if lines.is_empty() => self.say("No breakpoints."),
+                        lines => self.say(lines.join("\n")),
+                    }
+                }
+                ("backtrace" | "bt", "") => {
+                    let frames = state.frames.iter().rev().enumerate();
+                    let frames = frames.map(|(i, f)| format!("#{i} {} at line {}", f.name, f.line));
+                    self.say(frames.collect::<Vec<_>>().join("\n"));
+                }
+                ("vars", "") => self.say(Self::scopes(state)),
+                ("print" | "p", name) if !name.is_empty() => {
+                    let environment = state.frames.last().and_then(|f| f.environment.as_ref());
+                    match environment.and_then(|env| super::find(env, name)) {
+                        Some(value) => self.say(format!("{name} = {value}")),
+                        None => self.say(format!("Undefined variable '{name}'.")),
+                    }
+                }
+                ("help" | "h", "") => self.say(HELP),
+                _ => self.say(format!("Unknown command '{command}'. Type 'help' for a list.")),
+            }
+        }
+    }
+}
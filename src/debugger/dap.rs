@@ -0,0 +1,506 @@
+//! A Debug Adapter Protocol server, for editors like VS Code to debug scripts.
+//!
+//! Messages are JSON objects preceded by a `Content-Length` header. A thread
+//! reads the requests of the client, so that the ones sent while the program
+//! runs, like `pause`, are handled before the next statement. The others wait
+//! until the program stops.
+//!
+//! See <https://microsoft.github.io/debug-adapter-protocol/specification>.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, LineWriter, Read, Write};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use serde_json::{Value, json};
+
+use super::{Command, Debugger, Frontend, Resume, State, StopReason};
+use crate::error::LoxError;
+use crate::output::Writer;
+use crate::sync::{MaybeSync, Rc, RefCell};
+use crate::{Lox, Shared};
+
+/// Programs don't have threads, but the protocol needs one.
+const THREAD_ID: u64 = 1;
+
+/// Debugs the scripts an editor launches, speaking the protocol over `input`
+/// and `output`.
+pub struct DapServer {
+    connection: Shared<Connection>,
+    requests: Shared<Requests>,
+    /// The breakpoints set before the program is known, by file
+    breakpoints: HashMap<String, Vec<u32>>,
+}
+
+impl DapServer {
+    pub fn new(
+        input: impl Read + Send + 'static,
+        output: impl Write + MaybeSync + 'static,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || read_requests(input, sender));
+
+        let connection = Connection { output: Box::new(output), seq: 0, closed: false };
+        let requests = Requests { receiver: Mutex::new(receiver), pending: VecDeque::new() };
+        Self {
+            connection: Rc::new(RefCell::new(connection)),
+            requests: Rc::new(RefCell::new(requests)),
+            breakpoints: HashMap::new(),
+        }
+    }
+
+    /// Serve the client until it disconnects. Only one program is debugged.
+    pub fn run(mut self) -> Result<(), anyhow::Error> {
+        let Some((program, stop_on_entry)) = self.configure() else {
+            return Ok(());
+        };
+
+        let exit_code = match std::fs::read_to_string(&program) {
+            Ok(source) => self.debug(&program, &source, stop_on_entry),
+            Err(e) => {
+                self.output("stderr", &format!("Could not read '{program}': {e}\n"));
+                66
+            }
+        };
+        self.event("exited", json!({ "exitCode": exit_code }));
+        self.event("terminated", json!({}));
+
+        // Answer the client until it disconnects
+        while !self.connection.borrow().closed {
+            let Some(request) = self.requests.borrow_mut().next() else {
+                break;
+            };
+            let mut connection = self.connection.borrow_mut();
+            match command(&request) {
+                "disconnect" | "terminate" => {
+                    connection.respond(&request, json!({}));
+                    connection.closed = true;
+                }
+                "threads" => connection.respond(&request, json!({ "threads": [] })),
+                _ => connection.fail(&request, "The program has ended."),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle the requests until the client has launched a program and set
+    /// its breakpoints. Returns the program and whether to stop on its first
+    /// statement, or `None` if the client disconnects before.
+    fn configure(&mut self) -> Option<(String, bool)> {
+        let mut launch = None;
+        let mut configured = false;
+
+        while launch.is_none() || !configured {
+            let request = self.requests.borrow_mut().next()?;
+            let arguments = &request["arguments"];
+            let mut connection = self.connection.borrow_mut();
+
+            match command(&request) {
+                "initialize" => {
+                    let capabilities = json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsEvaluateForHovers": true,
+                        "supportsTerminateRequest": true,
+                    });
+                    connection.respond(&request, capabilities);
+                    connection.event("initialized", json!({}));
+                }
+                "launch" => match arguments["program"].as_str() {
+                    Some(program) => {
+                        let stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+                        launch = Some((program.to_owned(), stop_on_entry));
+                        connection.respond(&request, json!({}));
+                    }
+                    None => connection.fail(&request, "Missing the program to debug."),
+                },
+                "setBreakpoints" => {
+                    let (path, lines) = requested_breakpoints(arguments);
+                    let breakpoints =
+                        lines.iter().map(|line| json!({ "verified": true, "line": line }));
+                    connection.respond(
+                        &request,
+                        json!({ "breakpoints": breakpoints.collect::<Vec<_>>() }),
+                    );
+                    self.breakpoints.insert(path, lines);
+                }
+                "configurationDone" => {
+                    configured = true;
+                    connection.respond(&request, json!({}));
+                }
+                "threads" => connection.respond(&request, json!({ "threads": [] })),
+                "disconnect" | "terminate" => {
+                    connection.respond(&request, json!({}));
+                    return None;
+                }
+                other => connection.fail(&request, &format!("Unsupported request '{other}'.")),
+            }
+        }
+
+        launch
+    }
+
+    /// Run the program under the debugger, and return its exit code.
+    fn debug(&mut self, program: &str, source: &str, stop_on_entry: bool) -> i32 {
+        let breakpoints = self.breakpoints.iter().filter(|(path, _)| same_file(path, program));
+        let breakpoints =
+            breakpoints.flat_map(|(_, lines)| lines.iter().copied()).collect::<Vec<_>>();
+
+        let session = Session {
+            connection: self.connection.clone(),
+            requests: self.requests.clone(),
+            handles: Vec::new(),
+        };
+        let mut debugger = Debugger::with_frontend(session)
+            .with_source(program, source)
+            .with_breakpoints(breakpoints);
+        if stop_on_entry {
+            debugger.resume = Resume::Step;
+        }
+
+        let mut lox = Lox::new();
+        lox.set_output(LineWriter::new(ProgramOutput(self.connection.clone())));
+        lox.set_debugger(Some(debugger));
+        let result = lox.run_file(program);
+
+        if let Some(warnings) = lox.render_warnings() {
+            self.output("stderr", &format!("{warnings}\n"));
+        }
+        // Flush the output of the program before its errors
+        drop(lox);
+
+        match result {
+            Ok(()) => 0,
+            Err(e) => {
+                self.output("stderr", &format!("{e}\n"));
+                e.downcast_ref::<LoxError>().map_or(1, LoxError::exit_code)
+            }
+        }
+    }
+
+    fn event(&self, event: &str, body: Value) {
+        self.connection.borrow_mut().event(event, body);
+    }
+
+    fn output(&self, category: &str, output: &str) {
+        self.event("output", json!({ "category": category, "output": output }));
+    }
+}
+
+/// The connection with the client, shared by the server, the debugger and the
+/// output of the program.
+struct Connection {
+    output: Box<dyn Writer>,
+    /// Sequence number of the last message sent
+    seq: u64,
+    /// Whether the client has disconnected
+    closed: bool,
+}
+
+impl Connection {
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+
+        let message = message.to_string();
+        let _ = write!(self.output, "Content-Length: {}\r\n\r\n{message}", message.len());
+        let _ = self.output.flush();
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": true,
+            "command": request["command"],
+            "body": body,
+        }));
+    }
+
+    fn fail(&mut self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": false,
+            "command": request["command"],
+            "message": message,
+        }));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+}
+
+/// The requests of the client, in the order they were sent.
+struct Requests {
+    receiver: Mutex<Receiver<Value>>,
+    /// Requests received while the program ran, to handle when it stops
+    pending: VecDeque<Value>,
+}
+
+impl Requests {
+    /// Wait for the next request. Returns `None` once the client is gone.
+    fn next(&mut self) -> Option<Value> {
+        match self.pending.pop_front() {
+            Some(request) => Some(request),
+            None => self.receiver.lock().ok()?.recv().ok(),
+        }
+    }
+
+    /// The next request if there is one already, and no request is waiting
+    /// for the program to stop.
+    fn try_next(&mut self) -> Option<Value> {
+        if !self.pending.is_empty() {
+            return None;
+        }
+        self.receiver.lock().ok()?.try_recv().ok()
+    }
+}
+
+/// The front end of the debugger, which answers the client when the program
+/// stops.
+struct Session {
+    connection: Shared<Connection>,
+    requests: Shared<Requests>,
+    /// The environments the client can ask the variables of, by their
+    /// reference minus one. They are only valid until the program resumes.
+    handles: Vec<Shared<crate::environment::Environment>>,
+}
+
+impl Session {
+    /// Answer a request that can be handled at any time. Returns false if the
+    /// request needs the program to be stopped.
+    fn handle_anytime(&mut self, request: &Value, state: &mut State) -> bool {
+        let mut connection = self.connection.borrow_mut();
+        match command(request) {
+            "threads" => {
+                let threads = json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] });
+                connection.respond(request, threads);
+            }
+            "setBreakpoints" => {
+                let (path, lines) = requested_breakpoints(&request["arguments"]);
+                let verified = state.file.as_deref().is_some_and(|file| same_file(&path, file));
+                if verified {
+                    state.breakpoints = lines.iter().copied().collect();
+                }
+                let breakpoints =
+                    lines.iter().map(|line| json!({ "verified": verified, "line": line }));
+                connection
+                    .respond(request, json!({ "breakpoints": breakpoints.collect::<Vec<_>>() }));
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn stack_trace(&self, state: &State) -> Value {
+        let source = state.file.as_ref().map(|file| {
+            let name = std::path::Path::new(file).file_name().map(|n| n.to_string_lossy());
+            json!({ "name": name, "path": file })
+        });
+        let frames = state.frames.iter().enumerate().rev().map(|(id, frame)| {
+            json!({ "id": id, "name": frame.name, "line": frame.line, "column": 1, "source": source })
+        });
+        json!({ "stackFrames": frames.collect::<Vec<_>>(), "totalFrames": state.frames.len() })
+    }
+
+    fn scopes(&mut self, arguments: &Value, state: &State) -> Value {
+        let frame = arguments["frameId"].as_u64().and_then(|id| state.frames.get(id as usize));
+        let Some(environment) = frame.and_then(|frame| frame.environment.as_ref()) else {
+            return json!({ "scopes": [] });
+        };
+
+        let scopes = super::scopes(environment).into_iter().enumerate().map(|(i, env)| {
+            let name = match (i, env.borrow().enclosing.is_some()) {
+                (_, false) => "Globals",
+                (0, true) => "Locals",
+                (_, true) => "Enclosing",
+            };
+            self.handles.push(env);
+            json!({ "name": name, "variablesReference": self.handles.len(), "expensive": false })
+        });
+        json!({ "scopes": scopes.collect::<Vec<_>>() })
+    }
+
+    fn variables(&self, arguments: &Value) -> Value {
+        let reference = arguments["variablesReference"].as_u64().unwrap_or(0) as usize;
+        let Some(environment) = reference.checked_sub(1).and_then(|i| self.handles.get(i)) else {
+            return json!({ "variables": [] });
+        };
+
+        let variables = super::variables(environment).into_iter().map(|(name, value)| {
+            json!({ "name": name, "value": value.to_string(), "variablesReference": 0 })
+        });
+        json!({ "variables": variables.collect::<Vec<_>>() })
+    }
+
+    /// The value of a variable, the only expressions supported.
+    fn evaluate(&self, arguments: &Value, state: &State) -> Result<Value, String> {
+        let name = arguments["expression"].as_str().unwrap_or_default().trim();
+        let frame = match arguments["frameId"].as_u64() {
+            Some(id) => state.frames.get(id as usize),
+            None => state.frames.last(),
+        };
+        let environment = frame.and_then(|frame| frame.environment.as_ref());
+        match environment.and_then(|env| super::find(env, name)) {
+            Some(value) => Ok(json!({ "result": value.to_string(), "variablesReference": 0 })),
+            None => Err(format!("Undefined variable '{name}'.")),
+        }
+    }
+}
+
+impl Frontend for Session {
+    fn poll(&mut self, state: &mut State) -> Option<Command> {
+        loop {
+            let request = self.requests.borrow_mut().try_next()?;
+            match command(&request) {
+                "pause" => {
+                    self.connection.borrow_mut().respond(&request, json!({}));
+                    return Some(Command::Step);
+                }
+                "disconnect" | "terminate" => {
+                    let mut connection = self.connection.borrow_mut();
+                    connection.respond(&request, json!({}));
+                    connection.closed = true;
+                    return Some(Command::Quit);
+                }
+                _ if self.handle_anytime(&request, state) => {}
+                _ => {
+                    self.requests.borrow_mut().pending.push_back(request);
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: StopReason, _text: &str, state: &mut State) -> Command {
+        let reason = match reason {
+            StopReason::Entry => "entry",
+            StopReason::Step => "step",
+            StopReason::Breakpoint => "breakpoint",
+            StopReason::Pause => "pause",
+        };
+        let stopped = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        self.connection.borrow_mut().event("stopped", stopped);
+
+        loop {
+            // Nobody can resume the program once the client is gone
+            let Some(request) = self.requests.borrow_mut().next() else {
+                return Command::Quit;
+            };
+            if self.handle_anytime(&request, state) {
+                continue;
+            }
+
+            let arguments = &request["arguments"];
+            let (body, resume) = match command(&request) {
+                "continue" => (Ok(json!({ "allThreadsContinued": true })), Some(Command::Continue)),
+                "next" => (Ok(json!({})), Some(Command::Next)),
+                "stepIn" => (Ok(json!({})), Some(Command::Step)),
+                "stepOut" => (Ok(json!({})), Some(Command::StepOut)),
+                "disconnect" | "terminate" => {
+                    self.connection.borrow_mut().closed = true;
+                    (Ok(json!({})), Some(Command::Quit))
+                }
+                "pause" => (Ok(json!({})), None),
+                "stackTrace" => (Ok(self.stack_trace(state)), None),
+                "scopes" => (Ok(self.scopes(arguments, state)), None),
+                "variables" => (Ok(self.variables(arguments)), None),
+                "evaluate" => (self.evaluate(arguments, state), None),
+                other => (Err(format!("Unsupported request '{other}'.")), None),
+            };
+
+            let mut connection = self.connection.borrow_mut();
+            match body {
+                Ok(body) => connection.respond(&request, body),
+                Err(message) => connection.fail(&request, &message),
+            }
+            if let Some(resume) = resume {
+                self.handles.clear();
+                return resume;
+            }
+        }
+    }
+}
+
+/// Sends what the program prints to the client.
+struct ProgramOutput(Shared<Connection>);
+
+impl Write for ProgramOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let output = String::from_utf8_lossy(buf);
+        let body = json!({ "category": "stdout", "output": output });
+        self.0.borrow_mut().event("output", body);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Read the requests of the client and send them to the server, until the
+/// client closes the connection.
+fn read_requests(input: impl Read, sender: Sender<Value>) {
+    let mut input = BufReader::new(input);
+    loop {
+        // Headers, then an empty line
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            if !matches!(input.read_line(&mut line), Ok(n) if n > 0) {
+                return;
+            }
+            match line.trim_end() {
+                "" => break,
+                header => {
+                    if let Some(value) = header.strip_prefix("Content-Length:") {
+                        length = value.trim().parse().ok();
+                    }
+                }
+            }
+        }
+
+        let Some(length) = length else {
+            continue;
+        };
+        let mut body = vec![0; length];
+        if input.read_exact(&mut body).is_err() {
+            return;
+        }
+
+        // The server sends no requests, so the client has no responses to send
+        let Ok(message) = serde_json::from_slice::<Value>(&body) else {
+            continue;
+        };
+        if message["type"] == "request" && sender.send(message).is_err() {
+            return;
+        }
+    }
+}
+
+fn command(request: &Value) -> &str {
+    request["command"].as_str().unwrap_or_default()
+}
+
+/// The file and the lines of a `setBreakpoints` request.
+fn requested_breakpoints(arguments: &Value) -> (String, Vec<u32>) {
+    let path = arguments["source"]["path"].as_str().unwrap_or_default().to_owned();
+    let breakpoints = arguments["breakpoints"].as_array().into_iter().flatten();
+    let lines =
+        breakpoints.filter_map(|breakpoint| breakpoint["line"].as_u64()).map(|line| line as u32);
+    (path, lines.collect())
+}
+
+/// Whether two paths are the same file, as the client may not give the path
+/// the same way as the program was launched.
+fn same_file(a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
@@ -0,0 +1,262 @@
+//! An interactive debugger for the tree-walking interpreter.
+//!
+//! The interpreter asks the debugger before executing each statement whether
+//! to stop there. When it stops, a front end shows where, lets the user
+//! inspect the program and tells how to resume it: the [`console`] reads
+//! commands from a terminal, and the [`dap`] server speaks the Debug Adapter
+//! Protocol with an editor.
+
+mod console;
+mod dap;
+
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+use std::ops::ControlFlow;
+
+pub use self::dap::DapServer;
+use crate::ast::{Ast, Stmt, StmtId};
+use crate::environment::Environment;
+use crate::hooks::InterpreterHooks;
+use crate::object::Object;
+use crate::printer::AstPrinter;
+use crate::sync::{MaybeSync, Rc, RefCell};
+
+/// How the user wants to resume the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Command {
+    /// Stop at the next statement
+    Step,
+    /// Stop at the next statement of the current call or its callers
+    Next,
+    /// Stop at the next statement of the callers
+    StepOut,
+    /// Stop at the next breakpoint
+    Continue,
+    /// Stop the program
+    Quit,
+}
+
+/// Why the program stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StopReason {
+    Entry,
+    Step,
+    Breakpoint,
+    /// The front end asked to stop while the program was running
+    Pause,
+}
+
+/// Shows where the program stopped and reads how to resume it.
+pub(crate) trait Frontend: MaybeSync {
+    /// Called before each statement while the program runs, e.g. to handle
+    /// the requests of an editor. Returns [`Command::Step`] to stop before the
+    /// statement, or [`Command::Quit`] to stop the program.
+    fn poll(&mut self, _state: &mut State) -> Option<Command> {
+        None
+    }
+
+    /// The program stopped before a statement described by `text`, on the line
+    /// of the innermost frame.
+    fn stopped(&mut self, reason: StopReason, text: &str, state: &mut State) -> Command;
+}
+
+/// When to stop the program next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    /// At the next statement
+    Step,
+    /// At the next statement of a call at most this deep
+    Next(usize),
+    /// At a breakpoint
+    Continue,
+}
+
+/// A call being executed.
+#[derive(Debug)]
+pub(crate) struct Frame {
+    pub name: String,
+    /// The line of the statement being executed
+    pub line: u32,
+    /// The environment of the statement being executed
+    pub environment: Option<Rc<RefCell<Environment>>>,
+}
+
+/// What the front ends can inspect and change when the program stops.
+#[derive(Debug)]
+pub(crate) struct State {
+    /// The script being debugged
+    pub file: Option<String>,
+    /// The lines of the script, to show where the program stopped
+    pub source: Vec<String>,
+    pub breakpoints: BTreeSet<u32>,
+    /// The calls being executed, the outermost first
+    pub frames: Vec<Frame>,
+}
+
+/// Stops the program at breakpoints and lets the user inspect it.
+pub struct Debugger {
+    frontend: Box<dyn Frontend>,
+    state: State,
+    resume: Resume,
+    /// Whether the program stopped yet
+    started: bool,
+    /// The line of the previous statement, to stop only once per line
+    last_line: Option<u32>,
+}
+
+impl Debugger {
+    /// A debugger that stops at the first statement of the program, reading
+    /// the commands from `input` and writing to `output`.
+    pub fn new(
+        input: impl BufRead + MaybeSync + 'static,
+        output: impl Write + MaybeSync + 'static,
+    ) -> Self {
+        Self::with_frontend(console::Console::new(input, output))
+    }
+
+    pub(crate) fn with_frontend(frontend: impl Frontend + 'static) -> Self {
+        let script = Frame { name: "<script>".to_owned(), line: 0, environment: None };
+        Self {
+            frontend: Box::new(frontend),
+            state: State {
+                file: None,
+                source: Vec::new(),
+                breakpoints: BTreeSet::new(),
+                frames: vec![script],
+            },
+            resume: Resume::Step,
+            started: false,
+            last_line: None,
+        }
+    }
+
+    /// Show the lines of the script when stopping, and accept breakpoints in
+    /// the form `file:line`.
+    pub fn with_source(mut self, file: &str, source: &str) -> Self {
+        self.state.file = Some(file.to_owned());
+        self.state.source = source.lines().map(str::to_owned).collect();
+        self
+    }
+
+    /// Run until the first breakpoint instead of stopping at the first
+    /// statement.
+    pub fn with_breakpoints(mut self, lines: impl IntoIterator<Item = u32>) -> Self {
+        self.state.breakpoints = lines.into_iter().collect();
+        self.resume = Resume::Continue;
+        self
+    }
+
+    fn enter(&mut self, name: String) {
+        let line = self.state.frames.last().map_or(0, |frame| frame.line);
+        self.state.frames.push(Frame { name, line, environment: None });
+    }
+
+    fn exit(&mut self) {
+        self.state.frames.pop();
+    }
+
+    /// Called before executing a statement, with a description of it in case
+    /// the source isn't known. Breaks if the user quits.
+    fn before_stmt(
+        &mut self,
+        line: u32,
+        stmt: impl FnOnce() -> String,
+        environment: &Rc<RefCell<Environment>>,
+    ) -> ControlFlow<()> {
+        if let Some(frame) = self.state.frames.last_mut() {
+            frame.line = line;
+            frame.environment = Some(environment.clone());
+        }
+        let new_line = self.last_line.replace(line) != Some(line);
+
+        let reason = match (self.frontend.poll(&mut self.state), self.resume) {
+            (Some(Command::Quit), _) => return ControlFlow::Break(()),
+            (Some(_), _) => StopReason::Pause,
+            (None, Resume::Step) if !self.started => StopReason::Entry,
+            (None, Resume::Step) => StopReason::Step,
+            (None, Resume::Next(depth)) if self.state.frames.len() <= depth => StopReason::Step,
+            _ if new_line && self.state.breakpoints.contains(&line) => StopReason::Breakpoint,
+            _ => return ControlFlow::Continue(()),
+        };
+        self.started = true;
+
+        let text = match self.state.source.get(line as usize - 1) {
+            Some(text) => text.trim().to_owned(),
+            None => stmt(),
+        };
+        let depth = self.state.frames.len();
+        self.resume = match self.frontend.stopped(reason, &text, &mut self.state) {
+            Command::Step => Resume::Step,
+            Command::Next => Resume::Next(depth),
+            Command::StepOut => Resume::Next(depth - 1),
+            Command::Continue => Resume::Continue,
+            Command::Quit => return ControlFlow::Break(()),
+        };
+        ControlFlow::Continue(())
+    }
+}
+
+impl InterpreterHooks for Debugger {
+    fn on_statement(
+        &mut self,
+        ast: &Rc<Ast>,
+        stmt: StmtId,
+        line: u32,
+        environment: &Rc<RefCell<Environment>>,
+    ) -> ControlFlow<()> {
+        // Stop on the statements of a block rather than on the block itself
+        if matches!(ast[stmt], Stmt::Block { .. }) {
+            return ControlFlow::Continue(());
+        }
+
+        let printed = || {
+            let printed = AstPrinter::stmt_to_string(ast, stmt);
+            printed.lines().next().unwrap_or_default().to_owned()
+        };
+        self.before_stmt(line, printed, environment)
+    }
+
+    fn on_call(&mut self, name: &str, _line: Option<u32>, _depth: usize) {
+        self.enter(name.to_owned());
+    }
+
+    fn on_return(&mut self, _value: Option<&Object>) {
+        self.exit();
+    }
+}
+
+impl std::fmt::Debug for Debugger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Debugger")
+            .field("state", &self.state)
+            .field("resume", &self.resume)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The environments visible from this one, the innermost first.
+pub(crate) fn scopes(environment: &Rc<RefCell<Environment>>) -> Vec<Rc<RefCell<Environment>>> {
+    let mut scopes = vec![environment.clone()];
+    while let Some(enclosing) = scopes.last().and_then(|env| env.borrow().enclosing.clone()) {
+        scopes.push(enclosing);
+    }
+    scopes
+}
+
+/// The variables of an environment, without the native functions of the
+/// global one.
+pub(crate) fn variables(environment: &Rc<RefCell<Environment>>) -> Vec<(String, Object)> {
+    let environment = environment.borrow();
+    let global = environment.enclosing.is_none();
+    let mut variables = environment.bindings();
+    variables.retain(|(_, value)| {
+        !global || !matches!(value, Object::Callable(c) if c.line().is_none())
+    });
+    variables
+}
+
+/// The value of the variable with this name in the closest environment that
+/// defines it.
+pub(crate) fn find(environment: &Rc<RefCell<Environment>>, name: &str) -> Option<Object> {
+    scopes(environment).iter().find_map(|env| env.borrow().value(name))
+}
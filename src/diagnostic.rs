@@ -0,0 +1,576 @@
+use std::fmt::Display;
+
+use crate::token::Span;
+
+/// The phase of running a program that reported a diagnostic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Scan,
+    Parse,
+    Resolve,
+    Lint,
+    /// Compiling to bytecode, for the VM backend
+    Compile,
+    Runtime,
+}
+
+/// Whether a diagnostic stops the program from running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Stable identifiers of the diagnostics, so tools and documentation can refer
+/// to them regardless of the message text. Error codes start with `E` and
+/// warning codes with `W`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticCode {
+    /// E1001: A string literal is not closed before the end of the source.
+    UnterminatedString,
+    /// E1002: A character that is not part of the language.
+    UnexpectedCharacter,
+    /// E1003: The source couldn't be read, or is not valid UTF-8.
+    InvalidSource,
+    /// E2001: An expression was expected.
+    ExpectExpression,
+    /// E2002: A specific token, e.g. a `;`, was expected.
+    ExpectToken,
+    /// E2003: The left side of `=` is not a variable or a property.
+    InvalidAssignmentTarget,
+    /// E2004: A function has more than 255 parameters.
+    TooManyParameters,
+    /// E2005: A call has more than 255 arguments.
+    TooManyArguments,
+    /// E2006: A function declaration doesn't start with `fun`.
+    MissingFun,
+    /// E2007: A `for` loop variable is declared with a type, like in C.
+    TypedLoopVariable,
+    /// E2008: A keyword is called like a function, e.g. `class()`.
+    KeywordCall,
+    /// E2009: A binary operator has no left operand, e.g. `* 3`.
+    MissingLeftOperand,
+    /// E2010: Expressions or statements are nested deeper than the parser
+    /// allows.
+    TooDeeplyNested,
+    /// E3000: The syntax tree doesn't have the expected shape.
+    InvalidSyntaxTree,
+    /// E3001: A class names itself as its superclass.
+    InheritFromItself,
+    /// E3002: A `return` statement outside of any function.
+    ReturnFromTopLevel,
+    /// E3003: An initializer returns a value.
+    ReturnValueFromInitializer,
+    /// E3004: A local variable is declared twice in the same scope.
+    DuplicateVariable,
+    /// E3005: A local variable is read in its own initializer.
+    ReadInOwnInitializer,
+    /// E3006: `this` is used outside of a class.
+    ThisOutsideClass,
+    /// E3007: `super` is used outside of a class.
+    SuperOutsideClass,
+    /// E3008: `super` is used in a class that has no superclass.
+    SuperWithoutSuperclass,
+    /// E3009: A call to a local function has the wrong number of arguments.
+    StaticArityMismatch,
+    /// E3010: A function has two parameters with the same name.
+    DuplicateParameter,
+    /// E3011: A class declares two methods with the same name.
+    DuplicateMethod,
+    /// E3012: A function has more than 256 local variables in scope at once.
+    TooManyLocals,
+    /// E3013: A function captures more than 256 variables of the enclosing
+    /// functions.
+    TooManyUpvalues,
+    /// E4000: The runtime environments don't match what the resolver computed.
+    InvalidEnvironment,
+    /// E4001: A variable is read or assigned but never defined.
+    UndefinedVariable,
+    /// E4002: An instance has no field or method with the given name.
+    UndefinedProperty,
+    /// E4003: An operator got an operand of the wrong type.
+    InvalidOperand,
+    /// E4004: A property is accessed on something that isn't an instance.
+    NotAnInstance,
+    /// E4005: Something that isn't a function or a class is called.
+    NotCallable,
+    /// E4006: A call has the wrong number of arguments.
+    ArityMismatch,
+    /// E4007: A class inherits from something that isn't a class.
+    SuperclassNotClass,
+    /// E4008: Too many nested calls.
+    StackOverflow,
+    /// E4009: A run executed more statements than allowed.
+    StepLimitExceeded,
+    /// E4010: A run took longer than allowed.
+    TimeLimitExceeded,
+    /// E4011: The run was stopped from outside, e.g. with Ctrl-C.
+    Interrupted,
+    /// E4012: An assertion of a Lox test failed.
+    AssertionFailed,
+    /// E4013: A native function got an argument of the wrong type.
+    InvalidArgument,
+    /// E4014: A native function of the host reported a failure.
+    NativeFailed,
+    /// E4015: An HTTP request got no response.
+    RequestFailed,
+    /// E4016: A native function needs a capability that is denied.
+    CapabilityDenied,
+    /// E4017: A value that isn't `nil`, a boolean, a number or a string is
+    /// used as a key.
+    UnhashableKey,
+    /// E4018: A list is indexed with a fractional number or past its end.
+    IndexOutOfBounds,
+    /// E4019: A `return` at the top level returns something that isn't a
+    /// number or `nil`, so it can't be the exit code.
+    InvalidExitCode,
+    /// E4020: An extension adds methods to something that isn't a class.
+    NotAClass,
+    /// E5001: A function has too many constants for its bytecode chunk.
+    TooManyConstants,
+    /// W0001: A local variable is never read.
+    UnusedVariable,
+    /// W0002: A local function is never called.
+    UnusedFunction,
+    /// W0003: A local variable has the name of a variable of an enclosing
+    /// scope.
+    ShadowedVariable,
+    /// W0004: A statement comes after a `return` or a `break`.
+    UnreachableCode,
+    /// W0005: A block has no statements.
+    EmptyBlock,
+    /// W0006: Constants of different types are compared with `==` or `!=`.
+    MixedTypeEquality,
+    /// W0007: A variable or a property is assigned to itself.
+    SelfAssignment,
+    /// W0008: A global variable, function or class is never used.
+    UnusedGlobal,
+    /// W0009: The condition of an `if` is an assignment, probably meant to be
+    /// a comparison with `==`.
+    AssignmentInCondition,
+}
+
+impl DiagnosticCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UnterminatedString => "E1001",
+            Self::UnexpectedCharacter => "E1002",
+            Self::InvalidSource => "E1003",
+            Self::ExpectExpression => "E2001",
+            Self::ExpectToken => "E2002",
+            Self::InvalidAssignmentTarget => "E2003",
+            Self::TooManyParameters => "E2004",
+            Self::TooManyArguments => "E2005",
+            Self::MissingFun => "E2006",
+            Self::TypedLoopVariable => "E2007",
+            Self::KeywordCall => "E2008",
+            Self::MissingLeftOperand => "E2009",
+            Self::TooDeeplyNested => "E2010",
+            Self::InvalidSyntaxTree => "E3000",
+            Self::InheritFromItself => "E3001",
+            Self::ReturnFromTopLevel => "E3002",
+            Self::ReturnValueFromInitializer => "E3003",
+            Self::DuplicateVariable => "E3004",
+            Self::ReadInOwnInitializer => "E3005",
+            Self::ThisOutsideClass => "E3006",
+            Self::SuperOutsideClass => "E3007",
+            Self::SuperWithoutSuperclass => "E3008",
+            Self::StaticArityMismatch => "E3009",
+            Self::DuplicateParameter => "E3010",
+            Self::DuplicateMethod => "E3011",
+            Self::TooManyLocals => "E3012",
+            Self::TooManyUpvalues => "E3013",
+            Self::InvalidEnvironment => "E4000",
+            Self::UndefinedVariable => "E4001",
+            Self::UndefinedProperty => "E4002",
+            Self::InvalidOperand => "E4003",
+            Self::NotAnInstance => "E4004",
+            Self::NotCallable => "E4005",
+            Self::ArityMismatch => "E4006",
+            Self::SuperclassNotClass => "E4007",
+            Self::StackOverflow => "E4008",
+            Self::StepLimitExceeded => "E4009",
+            Self::TimeLimitExceeded => "E4010",
+            Self::Interrupted => "E4011",
+            Self::AssertionFailed => "E4012",
+            Self::InvalidArgument => "E4013",
+            Self::NativeFailed => "E4014",
+            Self::RequestFailed => "E4015",
+            Self::CapabilityDenied => "E4016",
+            Self::UnhashableKey => "E4017",
+            Self::IndexOutOfBounds => "E4018",
+            Self::InvalidExitCode => "E4019",
+            Self::NotAClass => "E4020",
+            Self::TooManyConstants => "E5001",
+            Self::UnusedVariable => "W0001",
+            Self::UnusedFunction => "W0002",
+            Self::ShadowedVariable => "W0003",
+            Self::UnreachableCode => "W0004",
+            Self::EmptyBlock => "W0005",
+            Self::MixedTypeEquality => "W0006",
+            Self::SelfAssignment => "W0007",
+            Self::UnusedGlobal => "W0008",
+            Self::AssignmentInCondition => "W0009",
+        }
+    }
+
+    /// The default severity of diagnostics with this code.
+    pub fn severity(&self) -> Severity {
+        if self.as_str().starts_with('W') { Severity::Warning } else { Severity::Error }
+    }
+}
+
+impl Display for DiagnosticCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// How diagnostics are rendered into text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiagnosticFormat {
+    /// One line per diagnostic, e.g. `[line 1] Error at ';': Expect
+    /// expression.`, like the reference implementation.
+    #[default]
+    Plain,
+    /// The plain line with the diagnostic code, followed by the offending
+    /// source line and a caret under the error location.
+    Pretty,
+    /// One JSON object per line, for editors and other tools.
+    Json,
+}
+
+/// An error or a warning about a Lox program, with its location in the source
+/// code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub phase: Phase,
+    pub code: DiagnosticCode,
+    pub severity: Severity,
+    /// The file the error is in, if the source came from a file.
+    pub file: Option<String>,
+    pub line: u32,
+    /// 1-based column, or 0 if unknown.
+    pub column: u32,
+    pub span: Span,
+    /// Where the error is relative to the tokens, e.g. `at 'foo'` or `at end`.
+    pub location: Option<String>,
+    pub message: String,
+    /// More details, e.g. what the parser expected instead.
+    pub note: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(phase: Phase, code: DiagnosticCode, line: u32, message: impl AsRef<str>) -> Self {
+        Self {
+            phase,
+            code,
+            severity: code.severity(),
+            file: None,
+            line,
+            column: 0,
+            span: Span::default(),
+            location: None,
+            message: message.as_ref().to_owned(),
+            note: None,
+        }
+    }
+
+    pub fn with_position(self, column: u32, span: Span) -> Self {
+        Self { column, span, ..self }
+    }
+
+    pub fn with_location(self, location: impl AsRef<str>) -> Self {
+        Self { location: Some(location.as_ref().to_owned()), ..self }
+    }
+
+    pub fn with_note(self, note: Option<String>) -> Self {
+        Self { note, ..self }
+    }
+
+    pub fn render(&self, format: DiagnosticFormat, source: &str) -> String {
+        match format {
+            DiagnosticFormat::Plain => self.to_string(),
+            DiagnosticFormat::Pretty => {
+                let mut text = self.header(Some(self.code));
+                if let Some(snippet) = self.snippet(source) {
+                    text = format!("{text}\n{snippet}");
+                }
+                if let Some(note) = &self.note {
+                    let gutter = " ".repeat(self.line.to_string().len());
+                    text = format!("{text}\n{gutter} = note: {note}");
+                }
+                text
+            }
+            DiagnosticFormat::Json => self.to_json(),
+        }
+    }
+
+    /// Render the diagnostic as a single-line JSON object, e.g.
+    /// `{"file":null,"line":1,"column":7,"code":"E2001","severity":"error",
+    /// "phase":"parse","location":"at ';'","message":"Expect expression."}`.
+    /// The note is only there if there is one.
+    fn to_json(&self) -> String {
+        let optional = |value: &Option<String>| match value {
+            Some(s) => json_string(s),
+            None => "null".to_owned(),
+        };
+
+        let phase = match self.phase {
+            Phase::Scan => "scan",
+            Phase::Parse => "parse",
+            Phase::Resolve => "resolve",
+            Phase::Lint => "lint",
+            Phase::Compile => "compile",
+            Phase::Runtime => "runtime",
+        };
+
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+
+        let note = match &self.note {
+            Some(note) => format!(r#","note":{}"#, json_string(note)),
+            None => String::new(),
+        };
+
+        format!(
+            r#"{{"file":{},"line":{},"column":{},"code":"{}","severity":"{severity}","phase":"{phase}","location":{},"message":{}{note}}}"#,
+            optional(&self.file),
+            self.line,
+            self.column,
+            self.code,
+            optional(&self.location),
+            json_string(&self.message),
+        )
+    }
+
+    /// Render the source line of the diagnostic with carets under its span:
+    ///
+    /// ```text
+    ///   |
+    /// 3 | print a +;
+    ///   |          ^
+    /// ```
+    fn snippet(&self, source: &str) -> Option<String> {
+        if self.line == 0 || self.column == 0 {
+            return None;
+        }
+
+        let text = source.lines().nth(self.line as usize - 1)?;
+
+        // The span may belong to another source, e.g. a function defined in an earlier
+        // REPL input.
+        let length = source
+            .get(self.span.start..self.span.end)
+            .map(|s| s.chars().take_while(|&c| c != '\n').count())
+            .unwrap_or(0)
+            .max(1);
+
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let padding = " ".repeat(self.column as usize - 1);
+        let carets = "^".repeat(length);
+
+        Some(format!("{gutter} |\n{number} | {text}\n{gutter} | {padding}{carets}"))
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.header(None))
+    }
+}
+
+impl Diagnostic {
+    /// The one-line description of the diagnostic, e.g. `[line 1] Error at
+    /// ';': Expect expression.`, optionally with the code after the severity.
+    fn header(&self, code: Option<DiagnosticCode>) -> String {
+        let line = self.line;
+        let message = &self.message;
+
+        let mut kind = match self.severity {
+            Severity::Error => "Error".to_owned(),
+            Severity::Warning => "Warning".to_owned(),
+        };
+        if let Some(code) = code {
+            kind = format!("{kind}[{code}]");
+        }
+
+        match (self.phase, &self.location) {
+            // Some errors aren't tied to any line
+            _ if line == 0 => message.to_owned(),
+            // Runtime errors are reported without the severity, like in the reference
+            // implementation.
+            (Phase::Runtime, _) if code.is_none() => format!("[line {line}] {message}"),
+            (_, Some(location)) => format!("[line {line}] {kind} {location}: {message}"),
+            (_, None) => format!("[line {line}] {kind}: {message}"),
+        }
+    }
+}
+
+/// Quote and escape a string for JSON.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+
+    result.push('"');
+    result
+}
+
+/// Append a "Did you mean" hint to the message if one of the candidates is
+/// close enough to the misspelled name.
+pub(crate) fn with_suggestion<'a>(
+    message: String,
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> String {
+    match suggest(name, candidates) {
+        Some(suggestion) => format!("{message} Did you mean '{suggestion}'?"),
+        None => message,
+    }
+}
+
+/// Find the candidate closest to `name`, allowing about one typo per three
+/// characters. Ties are broken alphabetically, so the result doesn't depend on
+/// the order of the candidates.
+fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggest_close_names() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(suggest("prnt", ["print", "point", "clock"]), Some("print"));
+        assert_eq!(suggest("clok", ["clock", "cloak"]), Some("cloak"));
+        assert_eq!(suggest("a", ["b", "abc"]), Some("b"));
+        assert_eq!(suggest("notDefined", ["clock"]), None);
+    }
+
+    #[test]
+    fn render_plain() {
+        let diagnostic = Diagnostic::new(
+            Phase::Parse,
+            DiagnosticCode::ExpectExpression,
+            2,
+            "Expect expression.",
+        )
+        .with_position(9, Span::new(18, 19))
+        .with_location("at ';'");
+
+        assert_eq!(diagnostic.to_string(), "[line 2] Error at ';': Expect expression.");
+        assert_eq!(
+            Diagnostic::new(
+                Phase::Runtime,
+                DiagnosticCode::InvalidOperand,
+                3,
+                "Operands must be numbers."
+            )
+            .to_string(),
+            "[line 3] Operands must be numbers."
+        );
+    }
+
+    #[test]
+    fn render_pretty() {
+        let source = "var a = 1;\nprint a +;\n";
+        let diagnostic = Diagnostic::new(
+            Phase::Parse,
+            DiagnosticCode::ExpectExpression,
+            2,
+            "Expect expression.",
+        )
+        .with_position(10, Span::new(20, 21))
+        .with_location("at ';'");
+
+        let expected =
+            "[line 2] Error[E2001] at ';': Expect expression.\n  |\n2 | print a +;\n  |          ^";
+        assert_eq!(diagnostic.render(DiagnosticFormat::Pretty, source), expected);
+    }
+
+    #[test]
+    fn render_warning() {
+        let diagnostic =
+            Diagnostic::new(Phase::Resolve, DiagnosticCode::UnusedVariable, 4, "Unused.")
+                .with_location("at 'a'");
+
+        assert_eq!(diagnostic.severity, Severity::Warning);
+        assert_eq!(diagnostic.to_string(), "[line 4] Warning at 'a': Unused.");
+        assert_eq!(
+            diagnostic.render(DiagnosticFormat::Pretty, ""),
+            "[line 4] Warning[W0001] at 'a': Unused."
+        );
+    }
+
+    #[test]
+    fn render_json() {
+        let mut diagnostic = Diagnostic::new(
+            Phase::Runtime,
+            DiagnosticCode::UndefinedVariable,
+            1,
+            "Undefined variable 'a\"b'.",
+        )
+        .with_position(7, Span::new(6, 7));
+        diagnostic.file = Some("dir\\test.lox".to_owned());
+
+        let expected = r#"{"file":"dir\\test.lox","line":1,"column":7,"code":"E4001","severity":"error","phase":"runtime","location":null,"message":"Undefined variable 'a\"b'."}"#;
+        assert_eq!(diagnostic.render(DiagnosticFormat::Json, ""), expected);
+    }
+
+    #[test]
+    fn render_pretty_underlines_the_span() {
+        let source = "print undefined_name;";
+        let diagnostic = Diagnostic::new(
+            Phase::Runtime,
+            DiagnosticCode::UndefinedVariable,
+            1,
+            "Undefined variable 'undefined_name'.",
+        )
+        .with_position(7, Span::new(6, 20));
+
+        let rendered = diagnostic.render(DiagnosticFormat::Pretty, source);
+        assert_eq!(rendered.lines().last(), Some("  |       ^^^^^^^^^^^^^^"));
+    }
+}
@@ -1,15 +1,138 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::rc::Rc;
 
-use super::RuntimeInterrupt;
+use super::RuntimeError;
+use crate::ast::Resolution;
+use crate::diagnostic::{DiagnosticCode, with_suggestion};
+use crate::gc::{self, Heap, Kind, Trace};
 use crate::object::Object;
+use crate::sync::{Rc, RefCell};
 use crate::token::Token;
 
+/// The name of a variable to get or assign: a token of the program, or a plain
+/// string, e.g. for the variables the interpreter defines itself like `this`.
+/// Errors about a token are located at it, the others have no location, like
+/// the errors of native functions.
+pub trait VariableName {
+    fn as_str(&self) -> &str;
+
+    fn error(&self, code: DiagnosticCode, msg: String) -> RuntimeError;
+}
+
+impl VariableName for Token {
+    fn as_str(&self) -> &str {
+        &self.lexeme
+    }
+
+    fn error(&self, code: DiagnosticCode, msg: String) -> RuntimeError {
+        RuntimeError::error(self, code, msg)
+    }
+}
+
+impl VariableName for str {
+    fn as_str(&self) -> &str {
+        self
+    }
+
+    fn error(&self, code: DiagnosticCode, msg: String) -> RuntimeError {
+        RuntimeError::native(code, msg)
+    }
+}
+
+/// A local variable captured by a closure. The environment that defines it and
+/// the ones the closures keep share the cell, so they all see its assignments.
+#[derive(Debug)]
+pub(crate) struct Upvalue(RefCell<Object>);
+
+/// A local variable: its value, or the cell it moved to once captured.
+#[derive(Debug, Clone)]
+enum Slot {
+    Value(Object),
+    Captured(Rc<Upvalue>),
+}
+
+impl Slot {
+    fn get(&self) -> Object {
+        match self {
+            Self::Value(value) => value.clone(),
+            Self::Captured(upvalue) => upvalue.0.borrow().clone(),
+        }
+    }
+
+    fn set(&mut self, value: Object) {
+        match self {
+            Self::Value(slot) => *slot = value,
+            Self::Captured(upvalue) => *upvalue.0.borrow_mut() = value,
+        }
+    }
+
+    /// The cell of the variable, which it moves to if it's not captured yet.
+    fn capture(&mut self, heap: &mut Heap) -> Rc<Upvalue> {
+        if let Self::Value(value) = self {
+            let upvalue = Rc::new(Upvalue(RefCell::new(std::mem::replace(value, Object::Null))));
+            heap.track(&upvalue);
+            *self = Self::Captured(upvalue);
+        }
+        match self {
+            Self::Captured(upvalue) => upvalue.clone(),
+            Self::Value(_) => unreachable!("the variable was just captured"),
+        }
+    }
+}
+
+/// The global variables, in the order they are first defined. Variable
+/// expressions remember the index of their global, see
+/// [`Environment::global_at`], so that they don't hash its name each time.
+#[derive(Debug, Default)]
+struct Globals {
+    indices: HashMap<Rc<str>, usize>,
+    /// The variables with their names, `None` once removed
+    values: Vec<(Rc<str>, Option<Object>)>,
+}
+
+impl Globals {
+    fn get(&self, name: &str) -> Option<&Object> {
+        self.indices.get(name).and_then(|index| self.values[*index].1.as_ref())
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut Object> {
+        self.indices.get(name).and_then(|index| self.values[*index].1.as_mut())
+    }
+
+    /// Define the variable, in the same place if it's already defined.
+    fn insert(&mut self, name: Rc<str>, value: Object) {
+        match self.indices.get(&name) {
+            Some(index) => self.values[*index].1 = Some(value),
+            None => {
+                self.indices.insert(name.clone(), self.values.len());
+                self.values.push((name, Some(value)));
+            }
+        }
+    }
+
+    /// Undefine the variable. Its place isn't reused, so the expressions
+    /// that remembered it find it empty.
+    fn remove(&mut self, name: &str) {
+        if let Some(index) = self.indices.remove(name) {
+            self.values[index].1 = None;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&Rc<str>, &Object)> {
+        self.values.iter().filter_map(|(name, value)| Some((name, value.as_ref()?)))
+    }
+}
+
+/// The variables of a scope.
+///
+/// The global environment, the only one without an enclosing environment,
+/// keeps its variables by name. Local environments keep them in the order they
+/// are defined, which is the slot order the resolver computed for them.
 #[derive(Debug, Default)]
 pub struct Environment {
     pub enclosing: Option<Rc<RefCell<Environment>>>,
-    values: HashMap<String, Object>,
+    values: Globals,
+    /// Local variables, with their names for error messages
+    slots: Vec<(Rc<str>, Slot)>,
 }
 
 impl Environment {
@@ -25,71 +148,275 @@ impl Environment {
         Rc::new(RefCell::new(self))
     }
 
-    pub fn define(&mut self, name: &str, value: Object) {
-        self.values.insert(name.to_owned(), value);
+    /// The variables defined directly in this environment with their values,
+    /// in the order they are defined.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Object)> {
+        let globals = self.values.iter().map(|(name, value)| (&**name, value.clone()));
+        let locals = self.slots.iter().map(|(name, slot)| (&**name, slot.get()));
+        // The slots left out of a capture have no name
+        globals.chain(locals.filter(|(name, _)| !name.is_empty()))
     }
 
-    pub fn assign(&mut self, name: &Token, value: Object) -> Result<(), RuntimeInterrupt> {
-        if !self.values.contains_key(&name.lexeme) {
-            // Ask one level above if possible
-            if let Some(ref e) = self.enclosing {
-                return e.borrow_mut().assign(name, value);
-            }
+    /// Names of the variables defined directly in this environment.
+    pub fn names(&self) -> Vec<String> {
+        self.iter().map(|(name, _)| name.to_owned()).collect()
+    }
 
-            return Err(RuntimeInterrupt::error(
-                name.line,
-                format!("Undefined variable '{}'.", name.lexeme),
-            ));
+    /// The variables defined directly in this environment with their values,
+    /// globals by name and locals in the order they are defined.
+    pub fn bindings(&self) -> Vec<(String, Object)> {
+        let mut bindings = self.iter().collect::<Vec<_>>();
+        if self.enclosing.is_none() {
+            bindings.sort_by_key(|(name, _)| *name);
         }
+        bindings.into_iter().map(|(name, value)| (name.to_owned(), value)).collect()
+    }
 
-        self.values.insert(name.lexeme.clone(), value);
-        Ok(())
+    /// Whether a variable with this name is defined directly in this
+    /// environment.
+    pub fn contains(&self, name: &str) -> bool {
+        self.value(name).is_some()
     }
 
-    pub fn assign_at(
+    /// The value of the variable with this name defined directly in this
+    /// environment. See [`Environment::lookup`] to look in the enclosing ones
+    /// too.
+    pub fn value(&self, name: &str) -> Option<Object> {
+        match self.values.get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.slots.iter().find(|(slot, _)| &**slot == name).map(|(_, slot)| slot.get()),
+        }
+    }
+
+    /// Names of the variables visible from this environment, including the
+    /// enclosing ones.
+    pub fn visible_names(&self) -> Vec<String> {
+        let mut names = self.names();
+        if let Some(enclosing) = &self.enclosing {
+            names.extend(enclosing.borrow().visible_names());
+        }
+        names
+    }
+
+    /// Drop the variables and the enclosing environment, keeping the memory
+    /// of the slots to reuse this environment for another scope.
+    pub(crate) fn reset(&mut self) {
+        self.enclosing = None;
+        self.slots.clear();
+    }
+
+    /// Define a variable. In a local environment, it takes the next slot.
+    pub fn define(&mut self, name: impl Into<Rc<str>>, value: Object) {
+        if self.enclosing.is_none() {
+            self.values.insert(name.into(), value);
+        } else {
+            self.slots.push((name.into(), Slot::Value(value)));
+        }
+    }
+
+    /// Give its value to the variable this environment defined last, e.g. a
+    /// function defined before it's created so that it can capture itself.
+    pub(crate) fn initialize(&mut self, name: &str, value: Object) {
+        match self.slots.last_mut() {
+            Some((_, slot)) if self.enclosing.is_some() => slot.set(value),
+            _ => self.define(name, value),
+        }
+    }
+
+    /// Define a variable in the environment `distance` levels above this one,
+    /// 0 being this one.
+    pub fn define_at(
         &mut self,
         distance: usize,
-        name: &Token,
+        name: &str,
         value: Object,
-    ) -> Result<(), RuntimeInterrupt> {
+    ) -> Result<(), RuntimeError> {
         if distance == 0 {
-            return self.assign(name, value);
+            self.define(name, value);
+            return Ok(());
         }
 
-        match self.ancestor(distance) {
-            None => Err(RuntimeInterrupt::error(
-                name.line,
-                format!("No enclosing environment at {distance} for '{}'.", name.lexeme),
-            )),
-            Some(ancestor) => ancestor.borrow_mut().assign(name, value),
-        }
+        let ancestor = self.ancestor(distance).ok_or_else(|| no_environment(distance, name))?;
+        ancestor.borrow_mut().define(name, value);
+        Ok(())
+    }
+
+    /// Assign to a global variable.
+    pub fn assign(
+        &mut self,
+        name: &(impl VariableName + ?Sized),
+        value: Object,
+    ) -> Result<(), RuntimeError> {
+        if self.assign_existing(name, value) { Ok(()) } else { Err(self.undefined_variable(name)) }
     }
 
-    pub fn get(&self, name: &Token) -> Result<Object, RuntimeInterrupt> {
-        let value = self.values.get(&name.lexeme).map(|lit| lit.to_owned());
+    /// Assign to the variable in the closest environment that defines it by
+    /// name. Returns false if there is no such environment.
+    fn assign_existing(&mut self, name: &(impl VariableName + ?Sized), value: Object) -> bool {
+        if let Some(slot) = self.values.get_mut(name.as_str()) {
+            *slot = value;
+            return true;
+        }
+
         // Ask one level above if possible
-        if value.is_none() && self.enclosing.is_some() {
-            let rc = self.enclosing.as_ref().unwrap();
-            return rc.borrow_mut().get(name);
+        match &self.enclosing {
+            Some(e) => e.borrow_mut().assign_existing(name, value),
+            None => false,
         }
+    }
 
-        value.ok_or_else(|| {
-            RuntimeInterrupt::error(name.line, format!("Undefined variable '{}'.", name.lexeme))
-        })
+    /// Assign to a local variable found by the resolver.
+    pub fn assign_at(
+        &mut self,
+        resolution: Resolution,
+        name: &(impl VariableName + ?Sized),
+        value: Object,
+    ) -> Result<(), RuntimeError> {
+        if resolution.depth == 0 {
+            self.slot_mut(resolution.slot, name)?.set(value);
+            return Ok(());
+        }
+
+        let ancestor = self
+            .ancestor(resolution.depth)
+            .ok_or_else(|| no_environment(resolution.depth, name))?;
+        let mut ancestor = ancestor.borrow_mut();
+        ancestor.slot_mut(resolution.slot, name)?.set(value);
+        Ok(())
     }
 
-    pub fn get_at(&self, distance: usize, name: &Token) -> Result<Object, RuntimeInterrupt> {
-        if distance == 0 {
-            return self.get(name);
+    /// Get a global variable.
+    pub fn get(&self, name: &(impl VariableName + ?Sized)) -> Result<Object, RuntimeError> {
+        self.lookup(name.as_str()).ok_or_else(|| self.undefined_variable(name))
+    }
+
+    /// Where the global variable with this name is, to get or assign it with
+    /// [`Environment::global_at`] and [`Environment::assign_global_at`].
+    pub(crate) fn global_index(&self, name: &str) -> Option<usize> {
+        self.values.indices.get(name).copied()
+    }
+
+    /// The global variable at this index, if it's still the variable with
+    /// this name, which is checked without hashing the name.
+    pub(crate) fn global_at(&self, index: usize, name: &str) -> Option<Object> {
+        match self.values.values.get(index) {
+            Some((global, Some(value))) if **global == *name => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// Assign to the global variable at this index, if it's still the
+    /// variable with this name. Returns false otherwise.
+    pub(crate) fn assign_global_at(&mut self, index: usize, name: &str, value: Object) -> bool {
+        match self.values.values.get_mut(index) {
+            Some((global, Some(slot))) if **global == *name => {
+                *slot = value;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove a global variable, e.g. one that was only defined temporarily.
+    pub(crate) fn remove(&mut self, name: &str) {
+        self.values.remove(name);
+    }
+
+    /// Get a variable by name, from this environment or an enclosing one.
+    pub fn lookup(&self, name: &str) -> Option<Object> {
+        match (self.values.get(name), &self.enclosing) {
+            (Some(value), _) => Some(value.clone()),
+            // Ask one level above if possible
+            (None, Some(enclosing)) => enclosing.borrow().lookup(name),
+            (None, None) => None,
+        }
+    }
+
+    /// The error for using an undefined variable, with a hint if a variable
+    /// visible from here has a similar name.
+    pub fn undefined_variable(&self, name: &(impl VariableName + ?Sized)) -> RuntimeError {
+        let names = self.visible_names();
+        let message = with_suggestion(
+            format!("Undefined variable '{}'.", name.as_str()),
+            name.as_str(),
+            names.iter().map(String::as_str),
+        );
+        name.error(DiagnosticCode::UndefinedVariable, message)
+    }
+
+    /// Get a local variable found by the resolver.
+    pub fn get_at(
+        &self,
+        resolution: Resolution,
+        name: &(impl VariableName + ?Sized),
+    ) -> Result<Object, RuntimeError> {
+        if resolution.depth == 0 {
+            return self.slot(resolution.slot, name).map(Slot::get);
+        }
+
+        let ancestor = self
+            .ancestor(resolution.depth)
+            .ok_or_else(|| no_environment(resolution.depth, name))?;
+        let ancestor = ancestor.borrow();
+        ancestor.slot(resolution.slot, name).map(Slot::get)
+    }
+
+    fn slot(
+        &self,
+        slot: usize,
+        name: &(impl VariableName + ?Sized),
+    ) -> Result<&Slot, RuntimeError> {
+        self.slots.get(slot).map(|(_, slot)| slot).ok_or_else(|| no_slot(slot, name))
+    }
+
+    fn slot_mut(
+        &mut self,
+        slot: usize,
+        name: &(impl VariableName + ?Sized),
+    ) -> Result<&mut Slot, RuntimeError> {
+        self.slots.get_mut(slot).map(|(_, slot)| slot).ok_or_else(|| no_slot(slot, name))
+    }
+
+    /// What a function declared in `environment` closes over if it only
+    /// needs the variables at `captures`: copies of the environments down to
+    /// the deepest of them, enclosed by the global environment. The copies
+    /// share the [`Upvalue`] of the captured variables and leave the other
+    /// slots `nil`, so the resolutions in the body of the function still
+    /// hold, while the other variables can be freed.
+    pub(crate) fn capture(
+        environment: &Rc<RefCell<Environment>>,
+        captures: &[Resolution],
+        heap: &mut Heap,
+    ) -> Rc<RefCell<Environment>> {
+        let levels = captures.iter().map(|capture| capture.depth + 1).max().unwrap_or(0);
+        let mut originals = Vec::with_capacity(levels);
+        let mut env = environment.clone();
+        loop {
+            let enclosing = env.borrow().enclosing.clone();
+            let Some(enclosing) = enclosing else { break };
+            if originals.len() < levels {
+                originals.push(env);
+            }
+            env = enclosing;
         }
 
-        match self.ancestor(distance) {
-            None => Err(RuntimeInterrupt::error(
-                name.line,
-                format!("No enclosing environment at {distance} for '{}'.", name.lexeme),
-            )),
-            Some(ancestor) => ancestor.borrow().get(name),
+        // `env` is the global environment now
+        for (depth, original) in originals.iter().enumerate().rev() {
+            let mut original = original.borrow_mut();
+            let mut copy = Environment::new().with_enclosing(env);
+            for capture in captures.iter().filter(|capture| capture.depth == depth) {
+                let Some((name, slot)) = original.slots.get_mut(capture.slot) else {
+                    continue;
+                };
+                if copy.slots.len() <= capture.slot {
+                    copy.slots.resize(capture.slot + 1, ("".into(), Slot::Value(Object::Null)));
+                }
+                copy.slots[capture.slot] = (name.clone(), Slot::Captured(slot.capture(heap)));
+            }
+            env = copy.as_shared();
+            heap.track(&env);
         }
+        env
     }
 
     fn ancestor(&self, distance: usize) -> Option<Rc<RefCell<Environment>>> {
@@ -103,3 +430,77 @@ impl Environment {
         Some(env)
     }
 }
+
+impl Trace for RefCell<Environment> {
+    fn kind(&self) -> Kind {
+        Kind::Environment
+    }
+
+    fn strings(&self) -> usize {
+        let Ok(env) = self.try_borrow() else { return 0 };
+        let locals = env.slots.iter().filter_map(|(_, slot)| match slot {
+            Slot::Value(value) => Some(value),
+            Slot::Captured(_) => None,
+        });
+        env.values
+            .iter()
+            .map(|(_, value)| value)
+            .chain(locals)
+            .filter(|value| matches!(value, Object::String(_)))
+            .count()
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        let Ok(env) = self.try_borrow() else { return };
+        if let Some(enclosing) = &env.enclosing {
+            visit(gc::address(enclosing));
+        }
+        env.values.iter().for_each(|(_, value)| value.trace(visit));
+        env.slots.iter().for_each(|(_, slot)| match slot {
+            Slot::Value(value) => value.trace(visit),
+            Slot::Captured(upvalue) => visit(gc::address(upvalue)),
+        });
+    }
+
+    fn clear(&self) {
+        if let Ok(mut env) = self.try_borrow_mut() {
+            *env = Environment::default();
+        }
+    }
+}
+
+impl Trace for Upvalue {
+    fn kind(&self) -> Kind {
+        Kind::Upvalue
+    }
+
+    fn strings(&self) -> usize {
+        matches!(self.0.try_borrow().as_deref(), Ok(Object::String(_))) as usize
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Ok(value) = self.0.try_borrow() {
+            value.trace(visit);
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut value) = self.0.try_borrow_mut() {
+            *value = Object::Null;
+        }
+    }
+}
+
+fn no_environment(distance: usize, name: &(impl VariableName + ?Sized)) -> RuntimeError {
+    name.error(
+        DiagnosticCode::InvalidEnvironment,
+        format!("No enclosing environment at {distance} for '{}'.", name.as_str()),
+    )
+}
+
+fn no_slot(slot: usize, name: &(impl VariableName + ?Sized)) -> RuntimeError {
+    name.error(
+        DiagnosticCode::InvalidEnvironment,
+        format!("No variable at slot {slot} for '{}'.", name.as_str()),
+    )
+}
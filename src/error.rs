@@ -1,34 +1,173 @@
+use std::error::Error;
 use std::fmt::Display;
+use std::sync::Arc;
 
-use crate::object::Object;
+use crate::diagnostic::{Diagnostic, DiagnosticCode};
+use crate::token::{Span, Token};
 
+/// An error that stops the execution of a Lox program, possibly because of an
+/// error of the host, e.g. of a native function that failed to read a file.
+///
+/// `break` and `return` aren't errors, see
+/// [`ControlFlow`](crate::prelude::ControlFlow).
 #[derive(Debug, PartialEq)]
-pub enum RuntimeInterrupt {
-    /// A runtime error has occured.
-    Error { line: u32, msg: String },
-    /// A break statement has been reached.
-    Break { line: u32 },
-    /// A return statement has been reached.
-    Return { line: u32, value: Object },
+pub struct RuntimeError {
+    pub line: u32,
+    pub column: u32,
+    pub span: Span,
+    pub code: DiagnosticCode,
+    pub message: String,
+    pub cause: Option<ErrorCause>,
 }
 
-impl RuntimeInterrupt {
-    pub fn error(line: u32, msg: impl AsRef<str>) -> Self {
-        Self::Error { line, msg: msg.as_ref().to_owned() }
+impl RuntimeError {
+    /// Create a runtime error located at the given token.
+    pub fn error(token: &Token, code: DiagnosticCode, message: impl AsRef<str>) -> Self {
+        Self {
+            line: token.line,
+            column: token.column,
+            span: token.span,
+            code,
+            message: message.as_ref().to_owned(),
+            cause: None,
+        }
+    }
+
+    /// Create a runtime error of a native function, which doesn't know where
+    /// it's called from. The interpreter locates it at the call.
+    pub fn native(code: DiagnosticCode, message: impl AsRef<str>) -> Self {
+        Self {
+            line: 0,
+            column: 0,
+            span: Span::default(),
+            code,
+            message: message.as_ref().to_owned(),
+            cause: None,
+        }
+    }
+
+    /// Create a runtime error of a native function for an error of the host,
+    /// with the same message. The error is kept as the
+    /// [`source`](Error::source) of the runtime error.
+    pub fn from_error(code: DiagnosticCode, error: impl Error + Send + Sync + 'static) -> Self {
+        Self::native(code, error.to_string()).with_cause(error)
+    }
+
+    /// Keep the error of the host that caused this runtime error.
+    pub fn with_cause(self, cause: impl Into<ErrorCause>) -> Self {
+        Self { cause: Some(cause.into()), ..self }
+    }
+
+    /// Locate the error of a native function at the given token.
+    pub(crate) fn located_at(self, token: &Token) -> Self {
+        if self.line != 0 {
+            return self;
+        }
+        Self { line: token.line, column: token.column, span: token.span, ..self }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.message)
+    }
+}
+
+impl Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.cause.as_ref().map(ErrorCause::as_error)
+    }
+}
+
+/// An error of the host that caused a runtime error, e.g. the I/O error of a
+/// native function. It can be shared between threads, so that the errors
+/// holding it can become an [`anyhow::Error`].
+///
+/// Causes are compared by their messages.
+#[derive(Clone)]
+pub struct ErrorCause(Arc<dyn Error + Send + Sync>);
+
+impl ErrorCause {
+    pub fn as_error(&self) -> &(dyn Error + 'static) {
+        &*self.0
+    }
+}
+
+impl<E: Error + Send + Sync + 'static> From<E> for ErrorCause {
+    fn from(error: E) -> Self {
+        Self(Arc::new(error))
+    }
+}
+
+impl std::fmt::Debug for ErrorCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&*self.0, f)
     }
 }
 
-impl Display for RuntimeInterrupt {
+impl Display for ErrorCause {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&*self.0, f)
+    }
+}
+
+impl PartialEq for ErrorCause {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+/// An error that stopped a Lox program, classified by the phase that failed.
+///
+/// It displays as the rendered diagnostics, one per line.
+#[derive(Debug, PartialEq)]
+pub enum LoxError {
+    /// Scanning, parsing or resolving the source failed. Nothing was executed.
+    Compile(Report),
+    /// The program failed while it was running.
+    Runtime(Report),
+}
+
+/// The diagnostics of a failed run, along with their rendered text.
+#[derive(Debug, PartialEq)]
+pub struct Report {
+    pub diagnostics: Vec<Diagnostic>,
+    pub text: String,
+    /// The error of the host that caused the first of the errors that have
+    /// one, e.g. the I/O error of a native function
+    pub cause: Option<ErrorCause>,
+}
+
+impl LoxError {
+    /// The process exit code for this error, following the `sysexits.h`
+    /// convention used by the reference implementations.
+    pub fn exit_code(&self) -> i32 {
         match self {
-            RuntimeInterrupt::Error { line, msg } => {
-                write!(f, "[line {line}] {msg}")
-            }
-            RuntimeInterrupt::Break { line } => {
-                write!(f, "[line {line}] Unexpected break statement")
-            }
-            RuntimeInterrupt::Return { line, .. } => {
-                write!(f, "[line {line}] Unexpected return statement")
+            LoxError::Compile(_) => 65,
+            LoxError::Runtime(_) => 70,
+        }
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match self {
+            LoxError::Compile(report) | LoxError::Runtime(report) => &report.diagnostics,
+        }
+    }
+}
+
+impl Display for LoxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoxError::Compile(report) | LoxError::Runtime(report) => write!(f, "{}", report.text),
+        }
+    }
+}
+
+impl Error for LoxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoxError::Compile(report) | LoxError::Runtime(report) => {
+                report.cause.as_ref().map(ErrorCause::as_error)
             }
         }
     }
@@ -0,0 +1,267 @@
+//! A C API to embed the interpreter in applications written in other
+//! languages, enabled by the `lox-ffi` feature. `include/lox.h` declares it.
+//!
+//! An interpreter is an opaque handle made by [`lox_new`] and freed by
+//! [`lox_free`]. Programs run on the tree-walking interpreter, and print to the
+//! standard output.
+
+use std::ffi::{CStr, CString, c_char, c_int, c_void};
+use std::fmt::Display;
+
+use crate::Lox;
+use crate::prelude::*;
+use crate::sync::Rc;
+
+/// The interpreter behind a handle, with the message of its last error.
+pub struct LoxHandle {
+    lox: Lox,
+    error: Option<CString>,
+}
+
+/// The type of a [`LoxValue`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoxValueType {
+    Nil = 0,
+    Bool = 1,
+    Number = 2,
+    String = 3,
+}
+
+/// A value passed to and returned by native functions. Only the field of its
+/// type is meaningful. Strings are NUL-terminated UTF-8.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct LoxValue {
+    pub value_type: LoxValueType,
+    pub boolean: bool,
+    pub number: f64,
+    pub string: *const c_char,
+}
+
+/// A native function of the host. It gets the data it was registered with and
+/// the arguments, writes its result and returns 0, or returns another number
+/// to fail with a runtime error.
+pub type LoxNativeFn = extern "C" fn(
+    data: *mut c_void,
+    arguments: *const LoxValue,
+    count: usize,
+    result: *mut LoxValue,
+) -> c_int;
+
+#[derive(Debug)]
+struct Native {
+    name: String,
+    arity: usize,
+    function: LoxNativeFn,
+    data: *mut c_void,
+}
+
+// SAFETY: the host promises that the data can be used from the thread that
+// runs the interpreter, which is all `Lox` being `Send` allows.
+unsafe impl Send for Native {}
+unsafe impl Sync for Native {}
+
+impl Callable for Native {
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    fn call(
+        &self,
+        _interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        // Keep the strings alive until the native function returns
+        let strings = arguments
+            .iter()
+            .map(|argument| match argument {
+                Object::String(s) => CString::new(&**s).ok(),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let values = arguments.iter().zip(&strings).map(|(argument, string)| match argument {
+            Object::Null => LoxValue::nil(),
+            Object::Boolean(b) => {
+                LoxValue { value_type: LoxValueType::Bool, boolean: *b, ..LoxValue::nil() }
+            }
+            Object::Number(n) => {
+                LoxValue { value_type: LoxValueType::Number, number: *n, ..LoxValue::nil() }
+            }
+            Object::String(_) => LoxValue {
+                value_type: LoxValueType::String,
+                string: string.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+                ..LoxValue::nil()
+            },
+            // Functions, classes and instances have no C equivalent
+            _ => LoxValue::nil(),
+        });
+        let values = values.collect::<Vec<_>>();
+
+        let mut result = LoxValue::nil();
+        let status = (self.function)(self.data, values.as_ptr(), values.len(), &mut result);
+        if status != 0 {
+            return Err(RuntimeError::native(
+                DiagnosticCode::NativeFailed,
+                format!("Native function '{}' failed with status {status}.", self.name),
+            ));
+        }
+
+        // SAFETY: the native function wrote a valid value
+        Ok(unsafe { result.to_object() })
+    }
+}
+
+impl Display for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+impl LoxValue {
+    fn nil() -> Self {
+        Self {
+            value_type: LoxValueType::Nil,
+            boolean: false,
+            number: 0.0,
+            string: std::ptr::null(),
+        }
+    }
+
+    /// SAFETY: the string of a string value must be null or NUL-terminated.
+    unsafe fn to_object(self) -> Object {
+        match self.value_type {
+            LoxValueType::Nil => Object::Null,
+            LoxValueType::Bool => Object::Boolean(self.boolean),
+            LoxValueType::Number => Object::Number(self.number),
+            LoxValueType::String if self.string.is_null() => Object::Null,
+            LoxValueType::String => {
+                Object::String(unsafe { CStr::from_ptr(self.string) }.to_string_lossy().into())
+            }
+        }
+    }
+}
+
+/// Make an interpreter. Free it with [`lox_free`].
+#[unsafe(no_mangle)]
+pub extern "C" fn lox_new() -> *mut LoxHandle {
+    let mut lox = Lox::new();
+    lox.set_diagnostic_format(DiagnosticFormat::Plain);
+    Box::into_raw(Box::new(LoxHandle { lox, error: None }))
+}
+
+/// Free an interpreter made by [`lox_new`]. Does nothing if it is null.
+///
+/// # Safety
+///
+/// The handle must come from [`lox_new`] and not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_free(lox: *mut LoxHandle) {
+    if !lox.is_null() {
+        drop(unsafe { Box::from_raw(lox) });
+    }
+}
+
+/// Run the source code, keeping the globals of the previous runs. Returns 0 on
+/// success, 65 if the code doesn't compile and 70 if it fails while running,
+/// like the exit codes of the `lox` command. [`lox_last_error`] then describes
+/// the errors. Returns -1 if the handle or the source is null.
+///
+/// # Safety
+///
+/// The handle must come from [`lox_new`], and the source must be a
+/// NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_run(lox: *mut LoxHandle, source: *const c_char) -> c_int {
+    if lox.is_null() || source.is_null() {
+        return -1;
+    }
+    let handle = unsafe { &mut *lox };
+    let source = unsafe { CStr::from_ptr(source) }.to_string_lossy();
+
+    match handle.lox.run(&source) {
+        Ok(()) => {
+            handle.error = None;
+            0
+        }
+        Err(e) => {
+            let code = e.downcast_ref::<LoxError>().map_or(1, LoxError::exit_code);
+            handle.error = CString::new(e.to_string().replace('\0', "")).ok();
+            code
+        }
+    }
+}
+
+/// The errors of the last failed run, or null if it succeeded or the handle is
+/// null. The string belongs to the interpreter and is valid until its next
+/// run.
+///
+/// # Safety
+///
+/// The handle must come from [`lox_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_last_error(lox: *const LoxHandle) -> *const c_char {
+    if lox.is_null() {
+        return std::ptr::null();
+    }
+    let handle = unsafe { &*lox };
+    handle.error.as_ref().map_or(std::ptr::null(), |error| error.as_ptr())
+}
+
+/// Write the value of a global variable that holds a number to `out`, and
+/// return 0. Returns -1 if there is no such global or it isn't a number, or if
+/// a pointer is null.
+///
+/// # Safety
+///
+/// The handle must come from [`lox_new`], the name must be a NUL-terminated
+/// string and `out` must point to a double.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_get_number(
+    lox: *const LoxHandle,
+    name: *const c_char,
+    out: *mut f64,
+) -> c_int {
+    if lox.is_null() || name.is_null() || out.is_null() {
+        return -1;
+    }
+    let handle = unsafe { &*lox };
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+
+    match handle.lox.interpreter.globals.borrow().lookup(&name) {
+        Some(Object::Number(n)) => {
+            unsafe { *out = n };
+            0
+        }
+        _ => -1,
+    }
+}
+
+/// Define a global native function that takes `arity` arguments and calls
+/// `function` with `data`. Returns 0, or -1 if the handle or the name is null
+/// or the name isn't valid UTF-8.
+///
+/// # Safety
+///
+/// The handle must come from [`lox_new`] and the name must be a NUL-terminated
+/// string. `data` must stay valid as long as the interpreter.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_register_native(
+    lox: *mut LoxHandle,
+    name: *const c_char,
+    arity: usize,
+    function: LoxNativeFn,
+    data: *mut c_void,
+) -> c_int {
+    if lox.is_null() || name.is_null() {
+        return -1;
+    }
+    let handle = unsafe { &mut *lox };
+    let Ok(name) = unsafe { CStr::from_ptr(name) }.to_str() else {
+        return -1;
+    };
+
+    let native = Native { name: name.to_owned(), arity, function, data };
+    handle.lox.interpreter.globals.borrow_mut().define(name, Object::Callable(Rc::new(native)));
+    0
+}
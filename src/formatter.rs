@@ -0,0 +1,390 @@
+use crate::prelude::*;
+
+const INDENT: &str = "  ";
+
+/// Turns the AST back into canonically formatted source code.
+///
+/// Comments aren't part of the AST, so they are put back by line: the ones
+/// before a statement stay above it, and a comment on the last line of a
+/// statement stays at the end of that line. Single blank lines between
+/// statements are kept.
+pub struct Formatter<'a> {
+    ast: &'a Ast,
+    comments: &'a [Token],
+    /// Index of the next comment to print
+    next_comment: usize,
+    out: String,
+    indent: usize,
+    /// Whether nothing was written on the current line yet
+    at_line_start: bool,
+    /// The source line of the last statement or comment printed
+    last_line: u32,
+}
+
+impl<'a> Formatter<'a> {
+    /// Format a program with the comments of its source code, as the tokens
+    /// of [`Scanner::with_comment_tokens`].
+    pub fn format(ast: &'a Ast, comments: &'a [Token]) -> String {
+        let mut formatter = Self {
+            ast,
+            comments,
+            next_comment: 0,
+            out: String::new(),
+            indent: 0,
+            at_line_start: true,
+            last_line: 0,
+        };
+        formatter.statements(ast.statements(), u32::MAX);
+        formatter.out
+    }
+
+    /// Print statements on their own lines, then the comments before
+    /// `end_line`, e.g. the closing brace of a block.
+    fn statements(&mut self, statements: &[StmtId], end_line: u32) {
+        let mut first = true;
+        for &stmt in statements {
+            let location = self.ast.location(stmt);
+            self.comments_before(location.line, &mut first);
+            self.separate(location.line, &mut first);
+
+            // Comments inside a simple statement can only go above it
+            if !is_compound(&self.ast[stmt]) {
+                while let Some(comment) = self.comments.get(self.next_comment) {
+                    if comment.line >= location.end_line {
+                        break;
+                    }
+                    self.write(&comment.lexeme);
+                    self.newline();
+                    self.next_comment += 1;
+                }
+            }
+            self.stmt(stmt);
+            self.end_line(location.end_line, end_line);
+        }
+
+        self.comments_before(end_line, &mut first);
+    }
+
+    /// End the line of a statement that ends on `line`, with the comment at
+    /// the end of that line if there is one. A comment on the line of the
+    /// closing brace of the block belongs to the statement of the block.
+    fn end_line(&mut self, line: u32, block_end_line: u32) {
+        if let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.line == line && line != block_end_line {
+                self.write(" ");
+                self.write(&comment.lexeme);
+                self.next_comment += 1;
+            }
+        }
+        self.newline();
+        self.last_line = line;
+    }
+
+    fn comments_before(&mut self, line: u32, first: &mut bool) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.line >= line {
+                break;
+            }
+            self.separate(comment.line, first);
+            self.write(&comment.lexeme);
+            self.newline();
+            self.last_line = comment.line;
+            self.next_comment += 1;
+        }
+    }
+
+    /// Keep one blank line before something on `line` if there was at least
+    /// one in the source, except at the start of a block.
+    fn separate(&mut self, line: u32, first: &mut bool) {
+        if !std::mem::take(first) && line > self.last_line + 1 {
+            self.newline();
+        }
+    }
+
+    fn stmt(&mut self, stmt: StmtId) {
+        let ast = self.ast;
+        let location = ast.location(stmt);
+        match &ast[stmt] {
+            Stmt::Expression { expr } => {
+                let expr = self.expr(*expr);
+                self.write(&format!("{expr};"));
+            }
+            Stmt::Print { exprs } => {
+                let exprs = exprs.iter().map(|&expr| self.expr(expr)).collect::<Vec<_>>();
+                self.write(&format!("print {};", exprs.join(", ")));
+            }
+            Stmt::Var { name, initializer: Some(initializer) } => {
+                let initializer = self.expr(*initializer);
+                self.write(&format!("var {} = {initializer};", name.lexeme));
+            }
+            Stmt::Var { name, initializer: None } => self.write(&format!("var {};", name.lexeme)),
+            Stmt::Return { keyword: _, value: Some(value) } => {
+                let value = self.expr(*value);
+                self.write(&format!("return {value};"));
+            }
+            Stmt::Return { keyword: _, value: None } => self.write("return;"),
+            Stmt::Break { token: _ } => self.write("break;"),
+            Stmt::Error => self.write("<error>"),
+            // A `for` loop with an initializer
+            Stmt::Block { statements } if location.synthetic => {
+                self.for_loop(Some(statements[0]), statements[1]);
+            }
+            Stmt::Block { statements } => self.block(statements, location.end_line),
+            Stmt::If { condition, then_branch, else_branch } => {
+                let condition = self.expr(*condition);
+                self.write(&format!("if ({condition}) "));
+                self.stmt(*then_branch);
+
+                if let Some(else_branch) = else_branch {
+                    self.else_branch(*then_branch, *else_branch);
+                }
+            }
+            Stmt::While { keyword, .. } if &*keyword.lexeme == "for" => self.for_loop(None, stmt),
+            Stmt::While { keyword: _, condition, body, else_branch } => {
+                let condition = self.expr(*condition);
+                self.write(&format!("while ({condition}) "));
+                self.stmt(*body);
+                if let Some(else_branch) = else_branch {
+                    self.else_branch(*body, *else_branch);
+                }
+            }
+            Stmt::Function { .. } => {
+                self.write("fun ");
+                self.function(stmt);
+            }
+            Stmt::Class { name, methods, superclass } => {
+                self.write(&format!("class {}", name.lexeme));
+                if let Some(superclass) = superclass {
+                    let superclass = self.expr(*superclass);
+                    self.write(&format!(" < {superclass}"));
+                }
+                self.write(" ");
+                self.methods(methods, location.end_line);
+            }
+            Stmt::Extend { keyword: _, class, methods } => {
+                let class = self.expr(*class);
+                self.write(&format!("extend {class} "));
+                self.methods(methods, location.end_line);
+            }
+            Stmt::Using { keyword: _, name, initializer, body, close: _ } => {
+                let initializer = self.expr(*initializer);
+                self.write(&format!("using (var {} = {initializer}) ", name.lexeme));
+                self.stmt(*body);
+            }
+        }
+    }
+
+    /// The body of a class or an extension.
+    fn methods(&mut self, methods: &[StmtId], end_line: u32) {
+        if methods.is_empty() && !self.has_comment_before(end_line) {
+            self.write("{}");
+            return;
+        }
+
+        self.write("{");
+        self.newline();
+        self.indent += 1;
+        let mut first = true;
+        for &method in methods {
+            let method_location = self.ast.location(method);
+            self.comments_before(method_location.line, &mut first);
+            self.separate(method_location.line, &mut first);
+            self.function(method);
+            self.end_line(method_location.end_line, end_line);
+        }
+        self.comments_before(end_line, &mut first);
+        self.indent -= 1;
+        self.write("}");
+    }
+
+    /// A function or a method, without the `fun` keyword.
+    fn function(&mut self, stmt: StmtId) {
+        let Stmt::Function { name, params, body } = &self.ast[stmt] else {
+            unreachable!("not a function");
+        };
+        let params = params.iter().map(|param| &*param.lexeme).collect::<Vec<_>>();
+        self.write(&format!("{}({}) ", name.lexeme, params.join(", ")));
+        self.block(body, self.ast.location(stmt).end_line);
+    }
+
+    /// A loop written as `for`, which the parser turned into a `while` loop,
+    /// in a block with the initializer if there is one.
+    fn for_loop(&mut self, initializer: Option<StmtId>, while_loop: StmtId) {
+        let ast = self.ast;
+        let Stmt::While { keyword: _, condition, body, else_branch } = &ast[while_loop] else {
+            unreachable!("not a loop");
+        };
+
+        // The increment is at the end of a block with the body
+        let (body, increment) = match &ast[*body] {
+            Stmt::Block { statements } if ast.location(*body).synthetic => {
+                let Stmt::Expression { expr } = &ast[statements[1]] else {
+                    unreachable!("not an increment");
+                };
+                (statements[0], Some(*expr))
+            }
+            _ => (*body, None),
+        };
+
+        self.write("for (");
+        match initializer {
+            Some(initializer) => self.stmt(initializer),
+            None => self.write(";"),
+        }
+        // A missing condition is always true
+        if !matches!(ast[*condition], Expr::Literal { value: Literal::Boolean(true) }) {
+            let condition = self.expr(*condition);
+            self.write(&format!(" {condition}"));
+        }
+        self.write(";");
+        if let Some(increment) = increment {
+            let increment = self.expr(increment);
+            self.write(&format!(" {increment}"));
+        }
+        self.write(") ");
+        self.stmt(body);
+        if let Some(else_branch) = else_branch {
+            self.else_branch(body, *else_branch);
+        }
+    }
+
+    /// The `else` of an `if` or a loop, on the line of the closing brace if
+    /// the statement before it is a block.
+    fn else_branch(&mut self, before: StmtId, else_branch: StmtId) {
+        if self.is_block(before) {
+            self.write(" else ");
+        } else {
+            self.newline();
+            self.write("else ");
+        }
+        self.stmt(else_branch);
+    }
+
+    fn block(&mut self, statements: &[StmtId], end_line: u32) {
+        if statements.is_empty() && !self.has_comment_before(end_line) {
+            self.write("{}");
+            return;
+        }
+
+        self.write("{");
+        self.newline();
+        self.indent += 1;
+        self.statements(statements, end_line);
+        self.indent -= 1;
+        self.write("}");
+    }
+
+    fn is_block(&self, stmt: StmtId) -> bool {
+        matches!(self.ast[stmt], Stmt::Block { .. }) && !self.ast.location(stmt).synthetic
+    }
+
+    fn has_comment_before(&self, line: u32) -> bool {
+        self.comments.get(self.next_comment).is_some_and(|comment| comment.line < line)
+    }
+
+    fn expr(&self, expr: ExprId) -> String {
+        let print = |expr| self.expr(expr);
+        match &self.ast[expr] {
+            Expr::Binary { left, operator, right } | Expr::Logical { left, operator, right } => {
+                format!("{} {} {}", print(*left), operator.lexeme, print(*right))
+            }
+            Expr::Grouping { expr } => format!("({})", print(*expr)),
+            Expr::Literal { value: Literal::String(s) } => format!("\"{s}\""),
+            Expr::Literal { value } => value.to_string(),
+            Expr::Unary { operator, right } => format!("{}{}", operator.lexeme, print(*right)),
+            Expr::Variable { binding: _, name } => name.lexeme.to_string(),
+            Expr::Assignment { binding: _, name, value } => {
+                format!("{} = {}", name.lexeme, print(*value))
+            }
+            Expr::Call { callee, paren: _, arguments } => {
+                let arguments = arguments.iter().map(|&argument| print(argument));
+                format!("{}({})", print(*callee), arguments.collect::<Vec<_>>().join(", "))
+            }
+            Expr::This { binding: _, keyword: _ } => "this".to_owned(),
+            Expr::Error => "<error>".to_owned(),
+            Expr::Get { object, name, cache: _ } => format!("{}.{}", print(*object), name.lexeme),
+            Expr::Set { object, name, value } => {
+                format!("{}.{} = {}", print(*object), name.lexeme, print(*value))
+            }
+            Expr::Index { object, bracket: _, index } => {
+                format!("{}[{}]", print(*object), print(*index))
+            }
+            Expr::IndexSet { object, bracket: _, index, value } => {
+                format!("{}[{}] = {}", print(*object), print(*index), print(*value))
+            }
+            Expr::List { bracket: _, elements } => {
+                let elements = elements.iter().map(|&element| print(element));
+                format!("[{}]", elements.collect::<Vec<_>>().join(", "))
+            }
+            Expr::Super { binding: _, keyword: _, method } => format!("super.{}", method.lexeme),
+            Expr::Function { keyword, declaration } => {
+                let Stmt::Function { name: _, params, body } = &self.ast[*declaration] else {
+                    unreachable!("not a function");
+                };
+                let params = params.iter().map(|param| &*param.lexeme).collect::<Vec<_>>();
+                let params = params.join(", ");
+                match &body[..] {
+                    // The `return` the parser made up for an expression body
+                    [stmt] if keyword.token_type == TokenType::Arrow => match &self.ast[*stmt] {
+                        Stmt::Return { keyword: arrow, value: Some(value) }
+                            if arrow.token_type == TokenType::Arrow =>
+                        {
+                            format!("({params}) -> {}", print(*value))
+                        }
+                        _ => format!("({params}) -> {}", self.function_body(*declaration)),
+                    },
+                    _ if keyword.token_type == TokenType::Arrow => {
+                        format!("({params}) -> {}", self.function_body(*declaration))
+                    }
+                    _ => format!("fun ({params}) {}", self.function_body(*declaration)),
+                }
+            }
+        }
+    }
+
+    /// The block of an anonymous function, indented like the statement it's
+    /// in. The comments inside it go above that statement.
+    fn function_body(&self, declaration: StmtId) -> String {
+        let Stmt::Function { name: _, params: _, body } = &self.ast[declaration] else {
+            unreachable!("not a function");
+        };
+        let mut formatter = Formatter {
+            ast: self.ast,
+            comments: &[],
+            next_comment: 0,
+            out: String::new(),
+            indent: self.indent,
+            at_line_start: false,
+            last_line: 0,
+        };
+        formatter.block(body, self.ast.location(declaration).end_line);
+        formatter.out
+    }
+
+    fn write(&mut self, text: &str) {
+        if std::mem::take(&mut self.at_line_start) {
+            self.out.push_str(&INDENT.repeat(self.indent));
+        }
+        self.out.push_str(text);
+    }
+
+    fn newline(&mut self) {
+        self.out.push('\n');
+        self.at_line_start = true;
+    }
+}
+
+/// Whether a statement contains other statements, which take care of the
+/// comments inside it.
+fn is_compound(stmt: &Stmt) -> bool {
+    matches!(
+        stmt,
+        Stmt::Block { .. }
+            | Stmt::If { .. }
+            | Stmt::While { .. }
+            | Stmt::Function { .. }
+            | Stmt::Class { .. }
+            | Stmt::Extend { .. }
+            | Stmt::Using { .. }
+    )
+}
@@ -1,101 +1,162 @@
 use std::fmt::{Debug, Display};
-use std::rc::Rc;
 
+use crate::gc::{self, Heap, Kind, Trace};
 use crate::prelude::*;
+use crate::sync::{MaybeSync, Rc};
 
-pub trait Callable: Debug + Display {
+pub trait Callable: Debug + Display + MaybeSync {
     fn arity(&self) -> usize;
+    /// The name the function is declared with. Natives have none, except
+    /// the async ones.
+    fn name(&self) -> Option<&str> {
+        None
+    }
+    /// The line the function is declared on, for functions declared in Lox.
+    fn line(&self) -> Option<u32> {
+        None
+    }
+    /// The doc comment above the declaration, for functions declared in Lox.
+    fn doc(&self) -> Option<&Rc<str>> {
+        None
+    }
+    /// The function itself, for functions declared in Lox.
+    fn as_lox_function(&self) -> Option<&LoxFunction> {
+        None
+    }
     fn call(
         &self,
         interpret: &mut Interpreter,
         arguments: &[Object],
-    ) -> Result<Object, RuntimeInterrupt>;
+    ) -> Result<Object, RuntimeError>;
 }
 
 #[derive(Debug, Clone)]
 pub struct LoxFunction {
-    name: Token,
-    params: Vec<Token>,
-    body: Vec<Rc<Stmt>>,
+    /// The tree the function is declared in, shared by all the bound copies of
+    /// a method
+    ast: Rc<Ast>,
+    declaration: StmtId,
     closure: Shared<Environment>,
     is_initializer: bool,
 }
 
 impl LoxFunction {
     pub fn new(
-        name: Token,
-        params: Vec<Token>,
-        body: &[Rc<Stmt>],
+        ast: Rc<Ast>,
+        declaration: StmtId,
         closure: Shared<Environment>,
         is_initializer: bool,
     ) -> Self {
-        Self { name, params, body: body.to_vec(), closure, is_initializer }
+        Self { ast, declaration, closure, is_initializer }
     }
 
-    pub fn bind(&self, this: Object) -> Rc<LoxFunction> {
+    /// The tree and the statement that declare the function, and the
+    /// environment it closes over.
+    pub(crate) fn definition(&self) -> (&Rc<Ast>, StmtId, &Shared<Environment>) {
+        (&self.ast, self.declaration, &self.closure)
+    }
+
+    /// The name, parameters and body of the function.
+    fn declaration(&self) -> (&Token, &[Token], &[StmtId]) {
+        match &self.ast[self.declaration] {
+            Stmt::Function { name, params, body } => (name, params, body),
+            _ => panic!("Function is not declared by a Stmt::Function"),
+        }
+    }
+
+    pub(crate) fn bind(&self, this: Object, heap: &mut Heap) -> Rc<LoxFunction> {
         let env = Environment::new().with_enclosing(self.closure.clone()).as_shared();
         env.borrow_mut().define("this", this);
+        heap.track(&env);
 
-        Rc::new(LoxFunction::new(
-            self.name.clone(),
-            self.params.clone(),
-            &self.body,
-            env,
-            self.is_initializer,
-        ))
+        let function = Rc::new(LoxFunction { closure: env, ..self.clone() });
+        heap.track(&function);
+        function
     }
 
-    fn new_env_for_call(&self, arguments: &[Object]) -> Shared<Environment> {
-        let mut environment = Environment::new().with_enclosing(self.closure.clone());
+    fn new_env_for_call(
+        &self,
+        arguments: &[Object],
+        interpret: &mut Interpreter,
+    ) -> Shared<Environment> {
+        let environment = interpret.new_environment(self.closure.clone());
 
         // Put all arguments in this new environment
-        //let mut env_borrow = environment.borrow_mut();
-        for (arg, param) in arguments.iter().zip(&self.params) {
-            environment.define(param.lexeme.as_str(), arg.clone());
+        let mut env_borrow = environment.borrow_mut();
+        let (_, params, _) = self.declaration();
+        for (arg, param) in arguments.iter().zip(params) {
+            env_borrow.define(param.lexeme.clone(), arg.clone());
         }
 
-        environment.as_shared()
+        drop(env_borrow);
+        environment
     }
 }
 
 impl Callable for LoxFunction {
     fn arity(&self) -> usize {
-        self.params.len()
+        self.declaration().1.len()
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some(&self.declaration().0.lexeme)
+    }
+
+    fn line(&self) -> Option<u32> {
+        Some(self.declaration().0.line)
+    }
+
+    fn doc(&self) -> Option<&Rc<str>> {
+        self.ast.doc(self.declaration)
+    }
+
+    fn as_lox_function(&self) -> Option<&LoxFunction> {
+        Some(self)
     }
 
     fn call(
         &self,
         interpret: &mut Interpreter,
         arguments: &[Object],
-    ) -> Result<Object, RuntimeInterrupt> {
+    ) -> Result<Object, RuntimeError> {
         // Every call needs a new environment (i.e. "stack"). If we keep one stack for
         // all calls, subsequent calls will override each others' parameters.
-        let environment = self.new_env_for_call(arguments);
+        let environment = self.new_env_for_call(arguments, interpret);
 
-        let res = interpret.execute_block(&self.body, environment);
+        let (_, _, body) = self.declaration();
+        let flow = interpret.execute_block(&self.ast, body, environment.clone());
+        interpret.recycle_environment(environment);
+        let flow = flow?;
 
-        // If this function is an initializer and we didn't get an error, return "this"
-        // as the return value.
-        if self.is_initializer
-            && (matches!(res, Ok(_)) || matches!(res, Err(RuntimeInterrupt::Return { .. })))
-        {
-            let token = Token::new(TokenType::This, "this", None, u32::MAX);
-            return self.closure.borrow().get_at(0, &token);
+        // An initializer returns "this", even from a `return;`
+        if self.is_initializer {
+            // `this` is the only variable of the closure of a bound method
+            return self.closure.borrow().get_at(Resolution { depth: 0, slot: 0 }, "this");
         }
 
-        // If a 'Return' runtime exception is generated, this means the block had a
-        // return statement. We should extract the value from it and return it.
-        // Otherwise, return Object::Null or the runtime error.
-        if let Err(RuntimeInterrupt::Return { value, .. }) = res {
-            Ok(value)
-        } else {
-            res.map(|_| Object::Null)
+        match flow {
+            ControlFlow::Return(value) => Ok(value),
+            ControlFlow::Break | ControlFlow::Continue => Ok(Object::Null),
         }
     }
 }
 
+impl Trace for LoxFunction {
+    fn kind(&self) -> Kind {
+        Kind::Function
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        visit(gc::address(&self.closure));
+    }
+
+    /// A function can't be changed, but it can only be part of a cycle through
+    /// the environment it captures, which is cleared instead.
+    fn clear(&self) {}
+}
+
 impl Display for LoxFunction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "<fn {}>", self.name.lexeme)
+        write!(f, "<fn {}>", self.declaration().0.lexeme)
     }
 }
@@ -0,0 +1,200 @@
+//! A cycle collector for the objects of both backends.
+//!
+//! Objects are reference counted, so they are freed as soon as nothing refers
+//! to them, except when they refer to each other: a closure stored in the
+//! environment it captures, or an instance holding one of its bound methods.
+//! The [`Heap`] keeps track of the objects that can be part of such cycles
+//! and frees the cycles nothing else refers to.
+//!
+//! A collection doesn't need to know the roots. An object is alive if it has
+//! more references than the tracked objects hold to it: the other ones come
+//! from the interpreter, e.g. its stack or its global variables. Everything
+//! reachable from the alive objects is alive too, and the rest is garbage.
+
+use std::collections::HashMap;
+
+use crate::sync::{MaybeSync, Rc, Weak};
+
+/// The kinds of tracked objects, for the statistics of the heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Environment,
+    /// A variable captured by a closure
+    Upvalue,
+    Instance,
+    List,
+    Class,
+    Function,
+}
+
+/// An object that can refer to other tracked objects.
+pub(crate) trait Trace: MaybeSync {
+    fn kind(&self) -> Kind;
+
+    /// Number of strings this object holds directly, e.g. in its variables.
+    fn strings(&self) -> usize {
+        0
+    }
+
+    /// Call `visit` with the address of every object this one holds a
+    /// reference to, once per reference.
+    ///
+    /// An object that is being modified can skip this, which keeps the
+    /// objects it refers to alive.
+    fn trace(&self, visit: &mut dyn FnMut(*const ()));
+
+    /// Drop the references to other objects, to break a cycle of garbage.
+    fn clear(&self);
+}
+
+/// The address of the object an `Rc` points to, which identifies it in the
+/// heap whatever the type of the pointer is.
+pub(crate) fn address<T: ?Sized>(object: &Rc<T>) -> *const () {
+    Rc::as_ptr(object).cast()
+}
+
+/// Statistics about the objects of an interpreter and its garbage collector.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Tracked objects that are still alive, of all the kinds below
+    pub objects: usize,
+    pub environments: usize,
+    /// Variables captured by closures
+    pub upvalues: usize,
+    pub instances: usize,
+    pub lists: usize,
+    pub classes: usize,
+    /// Functions, closures and bound methods
+    pub functions: usize,
+    /// Strings stored in variables, fields and lists
+    pub strings: usize,
+    /// Number of collections so far
+    pub collections: usize,
+    /// Number of objects freed by the collections
+    pub freed: usize,
+}
+
+impl HeapStats {
+    /// The statistics by name, as returned to programs by `memoryStats()`.
+    pub fn fields(&self) -> [(&'static str, usize); 10] {
+        [
+            ("objects", self.objects),
+            ("environments", self.environments),
+            ("upvalues", self.upvalues),
+            ("instances", self.instances),
+            ("lists", self.lists),
+            ("classes", self.classes),
+            ("functions", self.functions),
+            ("strings", self.strings),
+            ("collections", self.collections),
+            ("freed", self.freed),
+        ]
+    }
+}
+
+/// Collect automatically once this many objects are tracked.
+const INITIAL_THRESHOLD: usize = 10_000;
+
+/// The objects that can be part of a reference cycle.
+#[derive(Debug)]
+pub(crate) struct Heap {
+    objects: Vec<Weak<dyn Trace>>,
+    /// Collect when `objects` grows this large
+    threshold: usize,
+    collections: usize,
+    freed: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self { objects: Vec::new(), threshold: INITIAL_THRESHOLD, collections: 0, freed: 0 }
+    }
+}
+
+impl Heap {
+    /// Keep track of a new object, collecting the garbage if the heap has
+    /// grown enough since the last collection.
+    pub fn track<T: Trace + 'static>(&mut self, object: &Rc<T>) {
+        let object: Rc<dyn Trace> = object.clone();
+        self.objects.push(Rc::downgrade(&object));
+
+        if self.objects.len() >= self.threshold {
+            self.collect();
+        }
+    }
+
+    /// Free the objects that are only referred to by garbage, and return
+    /// their number.
+    pub fn collect(&mut self) -> usize {
+        // Holding the objects keeps them from being freed while they are
+        // inspected
+        let mut index = HashMap::new();
+        let objects = std::mem::take(&mut self.objects)
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|object| index.insert(address(object), index.len()).is_none())
+            .collect::<Vec<_>>();
+
+        // The references that don't come from tracked objects, without the one
+        // taken above
+        let mut external = objects.iter().map(|o| Rc::strong_count(o) - 1).collect::<Vec<_>>();
+        for object in &objects {
+            object.trace(&mut |child| {
+                if let Some(&i) = index.get(&child) {
+                    external[i] -= 1;
+                }
+            });
+        }
+
+        // Everything reachable from an object referred to from outside is alive
+        let mut alive = vec![false; objects.len()];
+        let mut pending = (0..objects.len()).filter(|&i| external[i] > 0).collect::<Vec<_>>();
+        while let Some(i) = pending.pop() {
+            if std::mem::replace(&mut alive[i], true) {
+                continue;
+            }
+            objects[i].trace(&mut |child| {
+                if let Some(&child) = index.get(&child) {
+                    pending.push(child);
+                }
+            });
+        }
+
+        // The garbage is freed once `objects` is dropped, after its cycles
+        // have been broken
+        let mut freed = 0;
+        for (object, alive) in objects.iter().zip(&alive) {
+            if *alive {
+                self.objects.push(Rc::downgrade(object));
+            } else {
+                object.clear();
+                freed += 1;
+            }
+        }
+
+        self.threshold = INITIAL_THRESHOLD.max(self.objects.len() * 2);
+        self.collections += 1;
+        self.freed += freed;
+        freed
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let mut stats =
+            HeapStats { collections: self.collections, freed: self.freed, ..Default::default() };
+
+        for object in self.objects.iter().filter_map(Weak::upgrade) {
+            stats.objects += 1;
+            stats.strings += object.strings();
+            match object.kind() {
+                Kind::Environment => stats.environments += 1,
+                Kind::Upvalue => stats.upvalues += 1,
+                Kind::Instance => stats.instances += 1,
+                Kind::List => stats.lists += 1,
+                Kind::Class => stats.classes += 1,
+                Kind::Function => stats.functions += 1,
+            }
+        }
+
+        stats
+    }
+}
@@ -0,0 +1,131 @@
+//! Callbacks that let hosts watch the tree-walking interpreter run. The
+//! profiler, the tracer, the debugger and coverage are built on them too.
+
+use std::ops::ControlFlow;
+
+use crate::coverage::CoverageRecorder;
+use crate::debugger::Debugger;
+use crate::prelude::*;
+use crate::profile::Profiler;
+use crate::sync::{MaybeSync, Rc};
+
+/// Callbacks of the tree-walking interpreter, registered with
+/// [`Lox::add_hooks`](crate::Lox::add_hooks). They all do nothing by default.
+pub trait InterpreterHooks: MaybeSync {
+    /// Before a statement of the tree runs, in the environment it runs in.
+    /// Breaking interrupts the program with an "Interrupted." error.
+    fn on_statement(
+        &mut self,
+        _ast: &Rc<Ast>,
+        _stmt: StmtId,
+        _line: u32,
+        _environment: &Shared<Environment>,
+    ) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+
+    /// Before a function or a class is called, `depth` calls deep. `line` is
+    /// where the function is declared, if it's declared in Lox.
+    fn on_call(&mut self, _name: &str, _line: Option<u32>, _depth: usize) {}
+
+    /// After a call, with what it returned, or `None` if it failed.
+    fn on_return(&mut self, _value: Option<&Object>) {}
+
+    /// When a program fails with a runtime error.
+    fn on_error(&mut self, _error: &RuntimeError) {}
+}
+
+/// Logs each statement to stderr with its line before it runs.
+#[derive(Debug, Default)]
+pub(crate) struct Tracer {
+    pub enabled: bool,
+    /// Add the depth of the environment to the logged statements
+    pub depth: bool,
+}
+
+impl InterpreterHooks for Tracer {
+    fn on_statement(
+        &mut self,
+        ast: &Rc<Ast>,
+        stmt: StmtId,
+        line: u32,
+        environment: &Shared<Environment>,
+    ) -> ControlFlow<()> {
+        // Nested statements are traced when they are executed, so only the
+        // first line is printed
+        let printed = AstPrinter::stmt_to_string(ast, stmt);
+        let printed = printed.lines().next().unwrap_or_default();
+        if self.depth {
+            eprintln!("[line {line}, depth {}] {printed}", depth(environment));
+        } else {
+            eprintln!("[line {line}] {printed}");
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Number of environments enclosing this one.
+fn depth(environment: &Shared<Environment>) -> usize {
+    let mut depth = 0;
+    let mut env = environment.borrow().enclosing.clone();
+    while let Some(enclosing) = env {
+        depth += 1;
+        env = enclosing.borrow().enclosing.clone();
+    }
+    depth
+}
+
+/// The hooks of an interpreter: its own tools, then the ones of the host.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pub coverage: Option<CoverageRecorder>,
+    pub tracer: Tracer,
+    pub debugger: Option<Debugger>,
+    pub profiler: Option<Profiler>,
+    pub host: Vec<Box<dyn InterpreterHooks>>,
+}
+
+impl Hooks {
+    /// Whether no hook is on, so the interpreter can skip calling them.
+    pub fn is_empty(&self) -> bool {
+        self.coverage.is_none()
+            && !self.tracer.enabled
+            && self.debugger.is_none()
+            && self.profiler.is_none()
+            && self.host.is_empty()
+    }
+
+    fn each(&mut self) -> impl Iterator<Item = &mut dyn InterpreterHooks> {
+        let tools: [Option<&mut dyn InterpreterHooks>; 4] = [
+            self.coverage.as_mut().map(|h| h as _),
+            self.tracer.enabled.then_some(&mut self.tracer as _),
+            self.debugger.as_mut().map(|h| h as _),
+            self.profiler.as_mut().map(|h| h as _),
+        ];
+        tools.into_iter().flatten().chain(self.host.iter_mut().map(|h| h.as_mut() as _))
+    }
+}
+
+impl InterpreterHooks for Hooks {
+    fn on_statement(
+        &mut self,
+        ast: &Rc<Ast>,
+        stmt: StmtId,
+        line: u32,
+        environment: &Shared<Environment>,
+    ) -> ControlFlow<()> {
+        self.each().try_for_each(|hooks| hooks.on_statement(ast, stmt, line, environment))
+    }
+
+    fn on_call(&mut self, name: &str, line: Option<u32>, depth: usize) {
+        self.each().for_each(|hooks| hooks.on_call(name, line, depth));
+    }
+
+    fn on_return(&mut self, value: Option<&Object>) {
+        self.each().for_each(|hooks| hooks.on_return(value));
+    }
+
+    fn on_error(&mut self, error: &RuntimeError) {
+        self.each().for_each(|hooks| hooks.on_error(error));
+    }
+}
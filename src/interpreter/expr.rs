@@ -1,51 +1,95 @@
 use super::InterpreterResult;
+use crate::diagnostic::with_suggestion;
+use crate::list;
 use crate::prelude::*;
+use crate::sync::Rc;
 
 impl Interpreter {
-    pub fn evaluate_expr(&mut self, expr: &Expr) -> InterpreterResult {
-        match expr {
+    pub fn evaluate_expr(&mut self, ast: &Rc<Ast>, expr: ExprId) -> InterpreterResult {
+        match &ast[expr] {
+            Expr::Error => unreachable!("the resolver rejects syntax errors"),
             Expr::Literal { value } => Ok(value.clone().into()),
-            Expr::Grouping { expr: inner } => self.evaluate_expr(inner.as_ref()),
-            Expr::Unary { operator, right } => self.evaluate_unary(operator, right),
-            Expr::Binary { left, operator, right } => self.evaluate_binary(left, operator, right),
-            Expr::Variable { name } => self.lookup_variable(name, expr),
-            Expr::Assignment { name, value } => {
-                let value = self.evaluate_expr(value.as_ref())?;
-
-                if let Some(&distance) = self.locals.get(&expr.unique_id()) {
-                    self.environment.borrow_mut().assign_at(distance, name, value.clone())?;
+            Expr::Grouping { expr: inner } => self.evaluate_expr(ast, *inner),
+            Expr::Unary { operator, right } => self.evaluate_unary(ast, operator, *right),
+            Expr::Binary { left, operator, right } => {
+                self.evaluate_binary(ast, *left, operator, *right)
+            }
+            Expr::Variable { binding, name } => self.lookup_variable(ast, expr, name, binding),
+            Expr::Assignment { binding, name, value } => {
+                let value = self.evaluate_expr(ast, *value)?;
+
+                if let Some(resolution) = binding.resolution() {
+                    self.environment.borrow_mut().assign_at(resolution, name, value.clone())?;
                 } else {
-                    self.globals.borrow_mut().assign(name, value.clone())?;
+                    self.assign_global(ast, expr, name, binding, value.clone())?;
                 }
 
                 Ok(value)
             }
-            Expr::Get { object, name } => {
-                let object = self.evaluate_expr(object)?;
+            Expr::Get { object, name, cache } => {
+                let object = self.evaluate_expr(ast, *object)?;
                 if let Object::Instance(ref instance) = object {
-                    instance.borrow().get(name, &object)
+                    instance.borrow().get(name, cache, &object, &mut self.heap)
+                } else if let Object::Set(_)
+                | Object::List(_)
+                | Object::StringBuilder(_)
+                | Object::WeakRef(_) = object
+                {
+                    crate::native::method(&object, name)
                 } else {
-                    Err(RuntimeInterrupt::error(name.line, "Only instances have properties."))
+                    Err(RuntimeError::error(
+                        name,
+                        DiagnosticCode::NotAnInstance,
+                        "Only instances have properties.",
+                    ))
                 }
             }
             Expr::Set { object, name, value } => {
-                let object = self.evaluate_expr(object)?;
-                let value = self.evaluate_expr(value)?;
+                let object = self.evaluate_expr(ast, *object)?;
+                let value = self.evaluate_expr(ast, *value)?;
 
                 if let Object::Instance(instance) = object {
                     instance.borrow_mut().set(name, value.clone());
                     Ok(value)
                 } else {
-                    Err(RuntimeInterrupt::error(name.line, "Only instances have fields."))
+                    Err(RuntimeError::error(
+                        name,
+                        DiagnosticCode::NotAnInstance,
+                        "Only instances have fields.",
+                    ))
                 }
             }
-            Expr::Super { keyword, method: method_name } => {
-                self.evaluate_super(expr, keyword, method_name)
+            Expr::Index { object, bracket, index } => {
+                let object = self.evaluate_expr(ast, *object)?;
+                let index = self.evaluate_expr(ast, *index)?;
+
+                let (list, position) = list_position(bracket, &object, &index)?;
+                let value = list.borrow()[position].clone();
+                Ok(value)
             }
+            Expr::IndexSet { object, bracket, index, value } => {
+                let object = self.evaluate_expr(ast, *object)?;
+                let index = self.evaluate_expr(ast, *index)?;
+                let value = self.evaluate_expr(ast, *value)?;
 
-            Expr::This { keyword } => self.lookup_variable(keyword, expr),
+                let (list, position) = list_position(bracket, &object, &index)?;
+                list.borrow_mut()[position] = value.clone();
+                Ok(value)
+            }
+            Expr::List { bracket: _, elements } => {
+                let mut values = vec![];
+                for &element in elements {
+                    values.push(self.evaluate_expr(ast, element)?);
+                }
+                Ok(crate::native::new_list(self, values))
+            }
+            Expr::Super { binding, keyword, method: method_name } => {
+                self.evaluate_super(binding, keyword, method_name)
+            }
+
+            Expr::This { binding, keyword } => self.lookup_variable(ast, expr, keyword, binding),
             Expr::Logical { left, operator, right } => {
-                let left_val = self.evaluate_expr(left)?;
+                let left_val = self.evaluate_expr(ast, *left)?;
 
                 if operator.token_type == TokenType::Or {
                     if self.is_truthy(&left_val) {
@@ -58,25 +102,35 @@ impl Interpreter {
                     }
                 }
 
-                self.evaluate_expr(right)
+                self.evaluate_expr(ast, *right)
             }
             Expr::Call { callee, paren, arguments } => {
-                self.evaluate_call(callee, paren.line, arguments)
+                self.evaluate_call(ast, *callee, paren, arguments)
+            }
+            Expr::Function { keyword: _, declaration } => {
+                Ok(Object::Callable(self.declare_function(ast, *declaration)))
             }
         }
     }
 
-    pub(super) fn is_truthy(&self, value: &Object) -> bool {
-        !matches!(value, Object::Null | Object::Boolean(false))
+    pub(crate) fn is_truthy(&self, value: &Object) -> bool {
+        value.is_true(self.truthiness)
     }
 
-    fn evaluate_call(&mut self, callee: &Expr, line: u32, arguments: &[Expr]) -> InterpreterResult {
-        let callee = self.evaluate_expr(callee)?;
+    fn evaluate_call(
+        &mut self,
+        ast: &Rc<Ast>,
+        callee: ExprId,
+        paren: &Token,
+        arguments: &[ExprId],
+    ) -> InterpreterResult {
+        let callee = self.evaluate_expr(ast, callee)?;
         match callee {
             Object::Callable(callable) => {
                 if callable.arity() != arguments.len() {
-                    return Err(RuntimeInterrupt::error(
-                        line,
+                    return Err(RuntimeError::error(
+                        paren,
+                        DiagnosticCode::ArityMismatch,
                         format!(
                             "Expected {} arguments but got {}.",
                             callable.arity(),
@@ -86,70 +140,148 @@ impl Interpreter {
                 }
                 // Evaluate all arguments
                 let mut args = vec![];
-                for arg in arguments {
-                    args.push(self.evaluate_expr(arg)?);
+                for &arg in arguments {
+                    args.push(self.evaluate_expr(ast, arg)?);
                 }
 
-                callable.call(self, args.as_ref())
+                let function = || (callable.to_string(), callable.line());
+                self.hook_call(function, |interpreter| {
+                    interpreter.nested_call(paren, |interpreter| callable.call(interpreter, &args))
+                })
+                .map_err(|e| e.located_at(paren))
             }
             Object::Class(class) => {
                 let arity = class.borrow().arity();
                 if arity != arguments.len() {
-                    return Err(RuntimeInterrupt::error(
-                        line,
+                    return Err(RuntimeError::error(
+                        paren,
+                        DiagnosticCode::ArityMismatch,
                         format!("Expected {arity} arguments but got {}.", arguments.len()),
                     ));
                 }
 
                 // Evaluate all arguments
                 let mut args = vec![];
-                for arg in arguments {
-                    args.push(self.evaluate_expr(arg)?);
+                for &arg in arguments {
+                    args.push(self.evaluate_expr(ast, arg)?);
                 }
 
-                Class::construct(class, args.as_ref(), self).map(Object::Instance)
+                let function = || (class.borrow().to_string(), None);
+                self.hook_call(function, |interpreter| {
+                    interpreter.nested_call(paren, |interpreter| {
+                        Class::construct(class.clone(), &args, interpreter).map(Object::Instance)
+                    })
+                })
             }
-            _ => Err(RuntimeInterrupt::error(line, "Can only call functions and classes.")),
+            _ => Err(RuntimeError::error(
+                paren,
+                DiagnosticCode::NotCallable,
+                "Can only call functions and classes.",
+            )),
         }
     }
 
+    /// Run a call one level deeper, failing with a "Stack overflow." error
+    /// instead of overflowing the native stack.
+    fn nested_call<T>(
+        &mut self,
+        paren: &Token,
+        call: impl FnOnce(&mut Self) -> Result<T, RuntimeError>,
+    ) -> Result<T, RuntimeError> {
+        if self.call_depth >= self.max_call_depth {
+            return Err(RuntimeError::error(
+                paren,
+                DiagnosticCode::StackOverflow,
+                "Stack overflow.",
+            ));
+        }
+
+        self.check_budget(paren)?;
+
+        self.call_depth += 1;
+        let result = call(self);
+        self.call_depth -= 1;
+
+        result
+    }
+
+    /// Run a call made by the host one level deep, like the calls of the
+    /// program, so that a `return` in it isn't taken for a `return` at the
+    /// top level.
+    #[cfg(feature = "pyo3")]
+    pub(crate) fn host_call<T>(&mut self, call: impl FnOnce(&mut Self) -> T) -> T {
+        self.call_depth += 1;
+        let result = call(self);
+        self.call_depth -= 1;
+        result
+    }
+
+    /// Call the hooks around a call, if there are any.
+    fn hook_call(
+        &mut self,
+        function: impl FnOnce() -> (String, Option<u32>),
+        call: impl FnOnce(&mut Self) -> InterpreterResult,
+    ) -> InterpreterResult {
+        if self.hooks.is_empty() {
+            return call(self);
+        }
+
+        let (name, line) = function();
+        self.hooks.on_call(&name, line, self.call_depth);
+        let result = call(self);
+        self.hooks.on_return(result.as_ref().ok());
+
+        result
+    }
+
     fn evaluate_super(
         &mut self,
-        expr: &Expr,
+        binding: &Binding,
         keyword: &Token,
         method_name: &Token,
     ) -> InterpreterResult {
-        let distance = *self.locals.get(&expr.unique_id()).expect("Cannot find distance");
+        let resolution = binding.resolution().expect("Cannot find distance");
 
-        let superclass = self.environment.borrow().get_at(distance, keyword)?;
+        let superclass = self.environment.borrow().get_at(resolution, keyword)?;
         let superclass = match superclass {
             Object::Class(c) => c,
             _ => panic!("Superclass is not wrapped in Object::Class"),
         };
 
-        let this = Token::new(TokenType::Identifier, "this", None, u32::MAX);
-        let instance = self.environment.borrow().get_at(distance - 1, &this)?;
+        // `this` is the only variable in the environment right below `super`
+        let this_resolution = Resolution { depth: resolution.depth - 1, slot: 0 };
+        let instance = self.environment.borrow().get_at(this_resolution, "this");
+        let instance = instance.map_err(|e| e.located_at(keyword))?;
 
         let method = superclass.borrow().find_method(&method_name.lexeme);
 
-        if let Some(method) = method {
-            Ok(Object::Callable(method.bind(instance)))
-        } else {
-            Err(RuntimeInterrupt::error(
-                method_name.line,
-                format!("Undefined property '{}'.", method_name.lexeme),
-            ))
+        match (method, &instance) {
+            (Some(method), _) => Ok(Object::Callable(method.bind(instance, &mut self.heap))),
+            // Fields belong to the instance, not to a class
+            (None, Object::Instance(i)) if i.borrow().has_field(&method_name.lexeme) => {
+                Err(field_through_super(method_name))
+            }
+            (None, _) => Err(undefined_property(method_name, &superclass.borrow().method_names())),
         }
     }
 
-    fn evaluate_unary(&mut self, operator: &Token, right: &Expr) -> InterpreterResult {
-        let value = self.evaluate_expr(right)?;
+    fn evaluate_unary(
+        &mut self,
+        ast: &Rc<Ast>,
+        operator: &Token,
+        right: ExprId,
+    ) -> InterpreterResult {
+        let value = self.evaluate_expr(ast, right)?;
         match operator.token_type {
             TokenType::Minus => {
                 if let Object::Number(n) = value {
                     Ok(Object::Number(-n))
                 } else {
-                    Err(RuntimeInterrupt::error(operator.line, "Operand must be a number."))
+                    Err(RuntimeError::error(
+                        operator,
+                        DiagnosticCode::InvalidOperand,
+                        "Operand must be a number.",
+                    ))
                 }
             }
             TokenType::Bang => Ok(Object::Boolean(!self.is_truthy(&value))),
@@ -161,22 +293,24 @@ impl Interpreter {
 
     fn evaluate_binary(
         &mut self,
-        left: &Expr,
+        ast: &Rc<Ast>,
+        left: ExprId,
         operator: &Token,
-        right: &Expr,
+        right: ExprId,
     ) -> InterpreterResult {
-        let left_value = self.evaluate_expr(left)?;
-        let right_value = self.evaluate_expr(right)?;
+        let left_value = self.evaluate_expr(ast, left)?;
+        let right_value = self.evaluate_expr(ast, right)?;
 
         match operator.token_type {
             TokenType::Plus => {
                 if let (Some(l), Some(r)) = (left_value.number(), right_value.number()) {
                     Ok(Object::Number(l + r))
                 } else if let (Some(l), Some(r)) = (left_value.string(), right_value.string()) {
-                    Ok(Object::String(format!("{l}{r}")))
+                    Ok(Object::String(format!("{l}{r}").into()))
                 } else {
-                    Err(RuntimeInterrupt::error(
-                        operator.line,
+                    Err(RuntimeError::error(
+                        operator,
+                        DiagnosticCode::InvalidOperand,
                         "Operands must be two numbers or two strings.",
                     ))
                 }
@@ -203,8 +337,12 @@ impl Interpreter {
                 .check_number_operands(operator, &left_value, &right_value)
                 .map(|(l, r)| Object::Boolean(l <= r)),
 
-            TokenType::EqualEqual => Ok(Object::Boolean(left_value == right_value)),
-            TokenType::BangEqual => Ok(Object::Boolean(left_value != right_value)),
+            TokenType::EqualEqual => {
+                Ok(Object::Boolean(left_value.equals(&right_value, self.equality)))
+            }
+            TokenType::BangEqual => {
+                Ok(Object::Boolean(!left_value.equals(&right_value, self.equality)))
+            }
 
             // Unreachable code
             _ => Ok(Object::Null),
@@ -216,19 +354,129 @@ impl Interpreter {
         operator: &Token,
         left: &Object,
         right: &Object,
-    ) -> Result<(f64, f64), RuntimeInterrupt> {
+    ) -> Result<(f64, f64), RuntimeError> {
         if let (Some(l), Some(r)) = (left.number(), right.number()) {
             Ok((l, r))
         } else {
-            Err(RuntimeInterrupt::error(operator.line, "Operands must be numbers."))
+            Err(RuntimeError::error(
+                operator,
+                DiagnosticCode::InvalidOperand,
+                "Operands must be numbers.",
+            ))
         }
     }
 
-    fn lookup_variable(&self, name: &Token, expr: &Expr) -> Result<Object, RuntimeInterrupt> {
-        if let Some(&distance) = self.locals.get(&expr.unique_id()) {
-            self.environment.borrow().get_at(distance, name)
+    fn lookup_variable(
+        &self,
+        ast: &Ast,
+        expr: ExprId,
+        name: &Token,
+        binding: &Binding,
+    ) -> Result<Object, RuntimeError> {
+        if let Some(resolution) = binding.resolution() {
+            self.environment.borrow().get_at(resolution, name)
         } else {
-            self.globals.borrow().get(name)
+            self.lookup_global(ast, expr, name, binding)
+        }
+    }
+
+    /// Get a global variable, at the index the binding remembers if it's
+    /// still there.
+    fn lookup_global(
+        &self,
+        ast: &Ast,
+        expr: ExprId,
+        name: &Token,
+        binding: &Binding,
+    ) -> Result<Object, RuntimeError> {
+        let globals = self.globals.borrow();
+        if let Some(value) =
+            binding.global().and_then(|index| globals.global_at(index, &name.lexeme))
+        {
+            return Ok(value);
+        }
+
+        match globals.global_index(&name.lexeme) {
+            Some(index) => {
+                binding.remember_global(index);
+                Ok(globals.global_at(index, &name.lexeme).expect("the global is defined"))
+            }
+            None => {
+                drop(globals);
+                Err(self.undefined_variable(ast, expr, name))
+            }
         }
     }
+
+    /// Assign to a global variable, at the index the binding remembers if
+    /// it's still there.
+    fn assign_global(
+        &self,
+        ast: &Ast,
+        expr: ExprId,
+        name: &Token,
+        binding: &Binding,
+        value: Object,
+    ) -> Result<(), RuntimeError> {
+        let mut globals = self.globals.borrow_mut();
+        if let Some(index) = binding.global() {
+            if globals.assign_global_at(index, &name.lexeme, value.clone()) {
+                return Ok(());
+            }
+        }
+
+        match globals.global_index(&name.lexeme) {
+            Some(index) if globals.assign_global_at(index, &name.lexeme, value) => {
+                binding.remember_global(index);
+                Ok(())
+            }
+            _ => {
+                drop(globals);
+                Err(self.undefined_variable(ast, expr, name))
+            }
+        }
+    }
+
+    /// The error for a global variable that isn't defined, which suggests the
+    /// local variables in scope too. The resolver knows their names, since a
+    /// closure only keeps the variables it captures.
+    fn undefined_variable(&self, ast: &Ast, expr: ExprId, name: &Token) -> RuntimeError {
+        let Some(locals) = ast.scope_names(expr) else {
+            return self.environment.borrow().undefined_variable(name);
+        };
+
+        let globals = self.globals.borrow().names();
+        let names = globals.iter().map(String::as_str).chain(locals.iter().map(|name| &**name));
+        let message =
+            with_suggestion(format!("Undefined variable '{}'.", name.lexeme), &name.lexeme, names);
+        RuntimeError::error(name, DiagnosticCode::UndefinedVariable, message)
+    }
+}
+
+/// The list an index expression reads or writes, and the position of the
+/// index in it.
+fn list_position(
+    bracket: &Token,
+    object: &Object,
+    index: &Object,
+) -> Result<(Shared<Vec<Object>>, usize), RuntimeError> {
+    let Object::List(list) = object else {
+        return Err(RuntimeError::error(
+            bracket,
+            DiagnosticCode::InvalidOperand,
+            list::NOT_INDEXABLE,
+        ));
+    };
+    let Object::Number(index) = index else {
+        return Err(RuntimeError::error(
+            bracket,
+            DiagnosticCode::InvalidOperand,
+            list::NOT_AN_INDEX,
+        ));
+    };
+
+    let position = list::position(*index, list.borrow().len()).map_err(|message| {
+        RuntimeError::error(bracket, DiagnosticCode::IndexOutOfBounds, message)
+    })?;
+    Ok((list.clone(), position))
 }
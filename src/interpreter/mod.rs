@@ -1,22 +1,110 @@
 mod expr;
 mod stmt;
 
-use std::collections::HashMap;
+use std::time::Duration;
 
+use crate::budget::Budget;
+use crate::coverage::{Coverage, CoverageRecorder};
+use crate::debugger::Debugger;
+use crate::gc::{Heap, HeapStats};
+use crate::hooks::Hooks;
+use crate::output::Output;
 use crate::prelude::*;
+use crate::profile::{Profile, Profiler};
+use crate::sync::{MaybeSync, Rc};
 
-type InterpreterResult = Result<Object, RuntimeInterrupt>;
+type InterpreterResult = Result<Object, RuntimeError>;
 
+/// How the execution of a statement ended, when it didn't fail.
+#[derive(Debug, PartialEq)]
+pub enum ControlFlow {
+    /// A `break` statement was reached: the enclosing loop ends.
+    Break,
+    /// The statement completed: execution goes on with the next one.
+    Continue,
+    /// A `return` statement was reached: the enclosing function returns
+    /// the value.
+    Return(Object),
+}
+
+/// The default limit of nested calls, low enough to not overflow the stack of
+/// the main thread.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+/// The most environments kept for reuse, so that a deep recursion doesn't
+/// hold on to its memory once it returned.
+const ENVIRONMENT_POOL_SIZE: usize = 64;
+
+#[derive(Debug)]
 pub struct InterpreterError {
     pub line: u32,
+    pub column: u32,
+    pub span: Span,
+    pub code: DiagnosticCode,
     pub message: String,
+    /// The error of the host that caused this one, see
+    /// [`RuntimeError::from_error`]
+    pub cause: Option<ErrorCause>,
+}
+
+impl InterpreterError {
+    /// Keep the error of the host that caused this error.
+    pub fn with_cause(self, cause: impl Into<ErrorCause>) -> Self {
+        Self { cause: Some(cause.into()), ..self }
+    }
+}
+
+impl From<crate::compiler::CompileError> for InterpreterError {
+    fn from(e: crate::compiler::CompileError) -> Self {
+        Self {
+            line: e.line,
+            column: e.column,
+            span: e.span,
+            code: e.code,
+            message: e.message,
+            cause: None,
+        }
+    }
+}
+
+impl std::fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for InterpreterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.cause.as_ref().map(ErrorCause::as_error)
+    }
 }
 
 pub struct Interpreter {
     pub globals: Shared<Environment>,
     environment: Shared<Environment>,
-    locals: HashMap<UniqueId, usize>, // unique id -> depth
     errors: Vec<InterpreterError>,
+    /// Number of calls currently being executed
+    call_depth: usize,
+    max_call_depth: usize,
+    truthiness: Truthiness,
+    equality: Equality,
+    /// Counts the statements executed in the current run
+    budget: Budget,
+    pub(crate) heap: Heap,
+    /// Empty environments of calls and blocks that ended, to reuse instead of
+    /// allocating new ones
+    environment_pool: Vec<Shared<Environment>>,
+    /// The statements left to execute, see [`Interpreter::execute`]
+    work: Vec<stmt::Work>,
+    /// The profiler, coverage, tracer and debugger when they are on, and the
+    /// hooks of the host
+    hooks: Hooks,
+    /// Where `print` writes
+    output: Output,
+    /// The results of the `test` calls, not taken yet
+    pub(crate) test_results: Vec<TestResult>,
+    /// The exit code a `return` at the top level ended the last run with
+    exit_code: Option<i32>,
 }
 
 impl Interpreter {
@@ -25,7 +113,248 @@ impl Interpreter {
         let environment = globals.clone();
 
         globals.borrow_mut().define("clock", Object::Callable(crate::native::clock()));
+        globals.borrow_mut().define("gcCollect", Object::Callable(crate::native::gc_collect()));
+        globals.borrow_mut().define("memoryStats", Object::Callable(crate::native::memory_stats()));
+        globals.borrow_mut().define("doc", Object::Callable(crate::native::doc()));
+        globals.borrow_mut().define("test", Object::Callable(crate::native::test()));
+        globals.borrow_mut().define("assertEq", Object::Callable(crate::native::assert_eq()));
+        globals.borrow_mut().define("assertTrue", Object::Callable(crate::native::assert_true()));
+        globals.borrow_mut().define("bindMethod", Object::Callable(crate::native::bind_method()));
+        globals.borrow_mut().define("arity", Object::Callable(crate::native::arity()));
+        globals.borrow_mut().define("name", Object::Callable(crate::native::name()));
+        globals.borrow_mut().define("memoize", Object::Callable(crate::native::memoize()));
+        globals.borrow_mut().define("Set", Object::Callable(crate::native::new_set()));
+        globals.borrow_mut().define("List", Object::Callable(crate::native::list()));
+        globals
+            .borrow_mut()
+            .define("StringBuilder", Object::Callable(crate::native::string_builder()));
+        globals.borrow_mut().define("WeakRef", Object::Callable(crate::native::weak_ref()));
+        globals.borrow_mut().define("split", Object::Callable(crate::native::split()));
+        globals.borrow_mut().define("join", Object::Callable(crate::native::join()));
+        #[cfg(feature = "net")]
+        {
+            globals.borrow_mut().define("httpGet", Object::Callable(crate::native::http_get()));
+            globals.borrow_mut().define("httpPost", Object::Callable(crate::native::http_post()));
+        }
+
+        let mut heap = Heap::default();
+        heap.track(&globals);
+
+        Self {
+            globals,
+            environment,
+            errors: Vec::new(),
+            call_depth: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            truthiness: Truthiness::default(),
+            equality: Equality::default(),
+            budget: Budget::default(),
+            heap,
+            environment_pool: Vec::new(),
+            work: Vec::new(),
+            hooks: Hooks::default(),
+            output: Output::default(),
+            test_results: Vec::new(),
+            exit_code: None,
+        }
+    }
+
+    /// Take the exit code of the last run, if a `return` at the top level
+    /// ended it, see [`Resolver::with_top_level_return`].
+    pub fn take_exit_code(&mut self) -> Option<i32> {
+        self.exit_code.take()
+    }
+
+    /// Set the number of nested calls after which a program fails with a
+    /// "Stack overflow." error.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Choose which values are true in conditions.
+    pub fn set_truthiness(&mut self, truthiness: Truthiness) {
+        self.truthiness = truthiness;
+    }
+
+    /// Choose how `==` compares values of different types.
+    pub fn set_equality(&mut self, equality: Equality) {
+        self.equality = equality;
+    }
+
+    /// Replace the natives that need the capability with ones that fail with
+    /// a runtime error.
+    pub(crate) fn deny(&mut self, capability: Capability) {
+        let mut globals = self.globals.borrow_mut();
+        for &name in capability.natives() {
+            if let Some(Object::Callable(native)) = globals.lookup(name) {
+                let denied = crate::native::denied(capability, native.arity());
+                globals.define(name, Object::Callable(denied));
+            }
+        }
+    }
+}
+
+impl Interpreter {
+    /// Fail a run with a runtime error after executing this many statements.
+    /// `None`, the default, means there is no limit.
+    pub fn set_max_steps(&mut self, steps: Option<u64>) {
+        self.budget.max_steps = steps;
+    }
+
+    /// Fail a run with a runtime error if it takes longer than this. `None`,
+    /// the default, means there is no limit.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.budget.timeout = timeout;
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.budget.interrupt.clone()
+    }
+
+    /// Free the objects that are only kept alive by reference cycles, and
+    /// return their number. This also happens automatically as the program
+    /// allocates.
+    pub fn collect_garbage(&mut self) -> usize {
+        self.heap.collect()
+    }
+
+    /// Record the number of calls and the time spent in each function. Turning
+    /// it on discards what was recorded before.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.hooks.profiler = enabled.then(Profiler::default);
+    }
+
+    /// What was recorded since profiling was turned on, if it is.
+    pub fn profile(&self) -> Option<Profile> {
+        self.hooks.profiler.as_ref().map(Profiler::profile)
+    }
+
+    /// Record which lines of the programs run. Turning it on discards what was
+    /// recorded before.
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.hooks.coverage = enabled.then(CoverageRecorder::default);
+    }
+
+    /// Count the lines of a program as read from `file` when it runs, if
+    /// coverage is on. Only the lines of the programs given here are recorded.
+    pub fn cover(&mut self, file: &str, ast: &Rc<Ast>) {
+        if let Some(coverage) = &mut self.hooks.coverage {
+            coverage.add(file, ast);
+        }
+    }
+
+    /// What was recorded since coverage was turned on, if it is.
+    pub fn coverage(&self) -> Option<Coverage> {
+        self.hooks.coverage.as_ref().map(CoverageRecorder::coverage)
+    }
+
+    /// Log each statement to stderr with its line before executing it, e.g.
+    /// `[line 3] (print a)`.
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.hooks.tracer.enabled = enabled;
+    }
+
+    /// Add the depth of the current environment to the traced statements, the
+    /// global environment being at depth 0, e.g. `[line 3, depth 1] (print a)`.
+    pub fn set_trace_depth(&mut self, enabled: bool) {
+        self.hooks.tracer.depth = enabled;
+    }
+
+    /// Write what programs print to this writer instead of the standard
+    /// output.
+    pub fn set_output(&mut self, writer: impl std::io::Write + MaybeSync + 'static) {
+        self.output = Output::new(writer);
+    }
+
+    /// Print to this output from now on, and return the previous one.
+    pub(crate) fn replace_output(&mut self, output: Output) -> Output {
+        std::mem::replace(&mut self.output, output)
+    }
+
+    /// Let a debugger stop the program before its statements, or run it
+    /// without stopping if `None`.
+    pub fn set_debugger(&mut self, debugger: Option<Debugger>) {
+        self.hooks.debugger = debugger;
+    }
+
+    /// Call these hooks as the programs run, after the ones registered
+    /// before.
+    pub fn add_hooks(&mut self, hooks: impl InterpreterHooks + 'static) {
+        self.hooks.host.push(Box::new(hooks));
+    }
+
+    /// Count the objects alive in the heap, e.g. to find leaks.
+    pub fn heap_stats(&self) -> HeapStats {
+        let mut stats = self.heap.stats();
+        // The environments waiting to be reused are empty
+        stats.environments -= self.environment_pool.len();
+        stats.objects -= self.environment_pool.len();
+        stats
+    }
+
+    /// A new local environment enclosed by this one, reusing an environment
+    /// of the pool if there is one.
+    pub(crate) fn new_environment(
+        &mut self,
+        enclosing: Shared<Environment>,
+    ) -> Shared<Environment> {
+        match self.environment_pool.pop() {
+            Some(environment) => {
+                environment.borrow_mut().enclosing = Some(enclosing);
+                environment
+            }
+            None => {
+                let environment = Environment::new().with_enclosing(enclosing).as_shared();
+                self.heap.track(&environment);
+                environment
+            }
+        }
+    }
+
+    /// Put the environment of a call or block that ended in the pool, unless
+    /// something still refers to it, e.g. a debugger frame.
+    pub(crate) fn recycle_environment(&mut self, environment: Shared<Environment>) {
+        if Rc::strong_count(&environment) == 1
+            && self.environment_pool.len() < ENVIRONMENT_POOL_SIZE
+        {
+            environment.borrow_mut().reset();
+            self.environment_pool.push(environment);
+        }
+    }
+
+    /// The results of the tests run by the `test` native function since the
+    /// last call.
+    pub fn take_test_results(&mut self) -> Vec<TestResult> {
+        std::mem::take(&mut self.test_results)
+    }
+
+    /// Call the hooks before a statement. The program is interrupted if one
+    /// of them breaks, e.g. when the user quits the debugger.
+    fn hook_stmt(&mut self, ast: &Rc<Ast>, stmt: StmtId) -> Result<(), RuntimeError> {
+        if self.hooks.is_empty() {
+            return Ok(());
+        }
+
+        let line = ast.line(stmt);
+        match self.hooks.on_statement(ast, stmt, line, &self.environment) {
+            std::ops::ControlFlow::Continue(()) => Ok(()),
+            std::ops::ControlFlow::Break(()) => Err(RuntimeError {
+                line,
+                ..RuntimeError::native(DiagnosticCode::Interrupted, "Interrupted.")
+            }),
+        }
+    }
+
+    /// Start counting the steps and the time of a new run.
+    pub(crate) fn reset_budget(&mut self) {
+        self.budget.reset();
+    }
 
-        Self { globals, environment, locals: HashMap::new(), errors: Vec::new() }
+    /// Fail if the run has used up its steps or its time, or was interrupted.
+    ///
+    /// Only loops and calls can make a program run for long, so the budget is
+    /// checked there instead of on every statement.
+    fn check_budget(&mut self, token: &Token) -> Result<(), RuntimeError> {
+        self.budget.check().map_err(|(code, message)| RuntimeError::error(token, code, message))
     }
 }
@@ -1,128 +1,247 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::rc::Rc;
 
 use crate::prelude::*;
+use crate::sync::{Rc, RefCell};
+
+/// What is left to do in the statements being executed, the innermost last.
+pub(super) enum Work {
+    /// Execute this statement
+    Stmt(StmtId),
+    /// Execute the body of this `while` statement as long as its condition
+    /// holds
+    Loop(StmtId),
+    /// Go back to the environment enclosing a block
+    Leave { previous: Shared<Environment> },
+    /// Evaluate the `close()` call of a `using` statement, even if its body
+    /// ends with a `break`, a `return` or an error
+    Close(ExprId),
+}
 
 impl Interpreter {
-    pub fn interpret(&mut self, statements: &[Stmt]) -> Result<(), Vec<InterpreterError>> {
-        for stmt in statements {
-            if let Err(e) = self.execute(stmt) {
-                self.runtime_error(e);
+    pub fn interpret(&mut self, ast: &Rc<Ast>) -> Result<(), Vec<InterpreterError>> {
+        self.reset_budget();
+        self.exit_code = None;
+        for &stmt in ast.statements() {
+            match self.execute(ast, stmt) {
+                // A `return` at the top level ends the script
+                Ok(ControlFlow::Return(Object::Number(code))) => {
+                    self.exit_code = Some(code as i32);
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // An interrupted program doesn't run its remaining statements
+                    let interrupted = e.code == DiagnosticCode::Interrupted;
+                    self.runtime_error(e);
+                    if interrupted {
+                        break;
+                    }
+                }
             }
         }
 
         if !self.errors.is_empty() { Err(std::mem::take(&mut self.errors)) } else { Ok(()) }
     }
 
-    pub fn execute_block<I, R>(
+    /// Evaluate an expression of a resolved tree on its own, e.g. the
+    /// [`Ast::result`] of a program.
+    pub fn evaluate(
+        &mut self,
+        ast: &Rc<Ast>,
+        expr: ExprId,
+    ) -> Result<Object, Vec<InterpreterError>> {
+        self.reset_budget();
+        self.evaluate_expr(ast, expr).map_err(|e| {
+            self.runtime_error(e);
+            std::mem::take(&mut self.errors)
+        })
+    }
+
+    pub fn execute_block(
         &mut self,
-        statements: I,
+        ast: &Rc<Ast>,
+        statements: &[StmtId],
         environment: Rc<RefCell<Environment>>,
-    ) -> Result<(), RuntimeInterrupt>
-    where
-        I: IntoIterator<Item = R>,
-        R: AsRef<Stmt>,
-    {
-        let prev_env = self.environment.clone();
-        self.environment = environment;
-
-        for s in statements {
-            let result = self.execute(s.as_ref());
-            if matches!(result, Err(_)) {
-                self.environment = prev_env;
-                return result;
+    ) -> Result<ControlFlow, RuntimeError> {
+        let prev_env = std::mem::replace(&mut self.environment, environment);
+        let base = self.work.len();
+        self.work.extend(statements.iter().rev().map(|&stmt| Work::Stmt(stmt)));
+        let result = self.run(ast, base);
+        self.environment = prev_env;
+        result
+    }
+
+    pub fn execute(&mut self, ast: &Rc<Ast>, stmt: StmtId) -> Result<ControlFlow, RuntimeError> {
+        let base = self.work.len();
+        self.work.push(Work::Stmt(stmt));
+        self.run(ast, base)
+    }
+
+    /// Do the work above `base` on the work stack until there is none left.
+    /// The statements nested in blocks, `if` and `while` statements are
+    /// pushed on the work stack instead of being executed recursively, so
+    /// that a deeply nested program can't overflow the native stack. Only
+    /// calls recurse, up to the maximum call depth, each with its own part
+    /// of the work stack.
+    fn run(&mut self, ast: &Rc<Ast>, base: usize) -> Result<ControlFlow, RuntimeError> {
+        while self.work.len() > base {
+            let flow = match self.work.pop().expect("the work stack is not empty") {
+                Work::Stmt(stmt) => self.step(ast, stmt),
+                Work::Loop(stmt) => self.iterate(ast, stmt).map(|()| ControlFlow::Continue),
+                Work::Leave { previous } => {
+                    self.leave_block(previous);
+                    Ok(ControlFlow::Continue)
+                }
+                Work::Close(close) => self.evaluate_expr(ast, close).map(|_| ControlFlow::Continue),
+            };
+
+            if let Ok(ControlFlow::Continue) = flow {
+                continue;
+            }
+            if let Some(flow) = self.skip(ast, base, flow) {
+                return flow;
             }
         }
+        Ok(ControlFlow::Continue)
+    }
 
-        self.environment = prev_env;
-        Ok(())
+    /// Drop the work above `base` that a `break`, `return` or error skips,
+    /// leaving the blocks and closing the values of the `using` statements on
+    /// the way. A `break` only skips the work up to the loop it breaks out
+    /// of: returns `None` if that loop was found, and else how the work ends.
+    fn skip(
+        &mut self,
+        ast: &Rc<Ast>,
+        base: usize,
+        mut flow: Result<ControlFlow, RuntimeError>,
+    ) -> Option<Result<ControlFlow, RuntimeError>> {
+        while self.work.len() > base {
+            match self.work.pop().expect("the work stack is not empty") {
+                Work::Leave { previous } => self.leave_block(previous),
+                Work::Loop(_) if matches!(flow, Ok(ControlFlow::Break)) => return None,
+                Work::Close(close) => {
+                    // A failing `close()` replaces a `break` or a `return`,
+                    // but the first error is the one reported
+                    if let (Err(e), Ok(_)) = (self.evaluate_expr(ast, close), &flow) {
+                        flow = Err(e);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(flow)
     }
 
-    pub fn resolve(&mut self, input: &Expr, depth: usize) {
-        self.locals.insert(input.unique_id(), depth);
+    /// Go back to the environment that encloses the block that ended.
+    fn leave_block(&mut self, previous: Shared<Environment>) {
+        let block = std::mem::replace(&mut self.environment, previous);
+        self.recycle_environment(block);
     }
 
-    pub fn execute(&mut self, stmt: &Stmt) -> Result<(), RuntimeInterrupt> {
-        match stmt {
+    /// Execute a statement, or push its nested statements on the work stack.
+    fn step(&mut self, ast: &Rc<Ast>, stmt: StmtId) -> Result<ControlFlow, RuntimeError> {
+        self.budget.steps += 1;
+        self.hook_stmt(ast, stmt)?;
+
+        match &ast[stmt] {
+            Stmt::Error => unreachable!("the resolver rejects syntax errors"),
             Stmt::Expression { expr } => {
-                self.evaluate_expr(expr)?;
+                self.evaluate_expr(ast, *expr)?;
             }
             Stmt::Class { name, methods, superclass } => {
-                self.handle_class_stmt(name, methods, superclass)?
-            }
-            Stmt::Function { name, params, body } => {
-                // self.environment is the current active environment when function
-                // is being declared, NOT when it's being called!
-                // In other words, this is the enclosing environment in which the function is
-                // declarad. For inner functions, it refers to their parent function's
-                // environment.
-                let env = self.environment.clone();
-                let function = LoxFunction::new(name.clone(), params.to_vec(), body, env, false);
-                self.environment
-                    .borrow_mut()
-                    .define(&name.lexeme, Object::Callable(Rc::new(function)));
-            }
-            Stmt::Break { token } => return Err(RuntimeInterrupt::Break { line: token.line }),
+                self.handle_class_stmt(ast, name, methods, *superclass, ast.doc(stmt).cloned())?
+            }
+            Stmt::Extend { keyword: _, class, methods } => {
+                self.handle_extend_stmt(ast, *class, methods)?
+            }
+            Stmt::Function { name, .. } => {
+                // Defined before it's created, so a local function can capture
+                // itself to call itself
+                self.environment.borrow_mut().define(name.lexeme.clone(), Object::Null);
+                let function = self.declare_function(ast, stmt);
+                self.environment.borrow_mut().initialize(&name.lexeme, Object::Callable(function));
+            }
+            Stmt::Break { .. } => return Ok(ControlFlow::Break),
             Stmt::Return { keyword, value } => {
-                let value =
-                    if let Some(expr) = value { self.evaluate_expr(expr)? } else { Object::Null };
+                let value = if let Some(expr) = *value {
+                    self.evaluate_expr(ast, expr)?
+                } else {
+                    Object::Null
+                };
 
-                return Err(RuntimeInterrupt::Return { line: keyword.line, value });
+                if self.call_depth == 0 {
+                    return Self::exit(keyword, value);
+                }
+                return Ok(ControlFlow::Return(value));
             }
             Stmt::Print { exprs } => {
-                for expr in exprs {
-                    let value = self.evaluate_expr(expr)?;
-                    print!("{value}");
+                for &expr in exprs {
+                    let value = self.evaluate_expr(ast, expr)?;
+                    self.output.print(format_args!("{value}"));
                 }
 
-                println!();
+                self.output.print(format_args!("\n"));
             }
             Stmt::Var { name, initializer } => {
-                let value = if let Some(expr) = initializer {
-                    self.evaluate_expr(expr)?
+                let value = if let Some(expr) = *initializer {
+                    self.evaluate_expr(ast, expr)?
                 } else {
                     Object::Null
                 };
 
-                self.environment.borrow_mut().define(&name.lexeme, value);
+                self.environment.borrow_mut().define(name.lexeme.clone(), value);
             }
             Stmt::Block { statements } => {
                 // Create a new environment for executing the block
-                let new_env =
-                    Environment::new().with_enclosing(self.environment.clone()).as_shared();
-
-                self.execute_block(statements, new_env)?;
+                let new_env = self.new_environment(self.environment.clone());
+                let previous = std::mem::replace(&mut self.environment, new_env);
+                self.work.push(Work::Leave { previous });
+                self.work.extend(statements.iter().rev().map(|&stmt| Work::Stmt(stmt)));
             }
             Stmt::If { condition, then_branch, else_branch } => {
-                let condition_result = self.evaluate_expr(condition)?;
+                let condition_result = self.evaluate_expr(ast, *condition)?;
 
                 if self.is_truthy(&condition_result) {
-                    self.execute(then_branch.as_ref())?;
-                } else if let Some(stmt) = else_branch {
-                    self.execute(stmt.as_ref())?;
+                    self.work.push(Work::Stmt(*then_branch));
+                } else if let Some(stmt) = *else_branch {
+                    self.work.push(Work::Stmt(stmt));
                 }
             }
-            Stmt::While { condition, body } => self.handle_while_stmt(condition, body)?,
+            Stmt::While { .. } => self.work.push(Work::Loop(stmt)),
+            Stmt::Using { keyword: _, name, initializer, body, close } => {
+                let value = self.evaluate_expr(ast, *initializer)?;
+
+                // The variable is only in scope in the body, which is closed
+                // before leaving it
+                let new_env = self.new_environment(self.environment.clone());
+                new_env.borrow_mut().define(name.lexeme.clone(), value);
+                let previous = std::mem::replace(&mut self.environment, new_env);
+                self.work.push(Work::Leave { previous });
+                self.work.push(Work::Close(*close));
+                self.work.push(Work::Stmt(*body));
+            }
         };
-        Ok(())
+        Ok(ControlFlow::Continue)
     }
 
     pub fn handle_class_stmt(
         &mut self,
+        ast: &Rc<Ast>,
         name: &Token,
-        methods: &Vec<Stmt>,
-        superclass: &Option<Expr>,
-    ) -> Result<(), RuntimeInterrupt> {
+        methods: &[StmtId],
+        superclass: Option<ExprId>,
+        doc: Option<Rc<str>>,
+    ) -> Result<(), RuntimeError> {
         // TODO: this looks really ugly!!
         let superclass = if let Some(s) = superclass {
-            let obj = self.evaluate_expr(s)?;
+            let obj = self.evaluate_expr(ast, s)?;
             match obj {
                 Object::Class(c) => Some(c),
                 _ => {
-                    if let Expr::Variable { name: super_name } = s {
-                        return Err(RuntimeInterrupt::error(
-                            super_name.line,
+                    if let Expr::Variable { binding: _, name: super_name } = &ast[s] {
+                        return Err(RuntimeError::error(
+                            super_name,
+                            DiagnosticCode::SuperclassNotClass,
                             "Superclass must be a class.",
                         ));
                     } else {
@@ -134,78 +253,132 @@ impl Interpreter {
             None
         };
 
-        self.environment.borrow_mut().define(&name.lexeme, Object::Null);
-
         if let Some(ref superclass) = superclass {
             self.environment =
                 Environment::new().with_enclosing(self.environment.clone()).as_shared();
+            self.heap.track(&self.environment);
 
             self.environment.borrow_mut().define("super", Object::Class(superclass.clone()));
         }
 
         // Create method functions
         let mut method_funcs = HashMap::new();
-        for method in methods {
-            if let Stmt::Function { name, params, body } = method {
-                let is_initializer = name.lexeme == "init";
-
-                method_funcs.insert(
-                    name.lexeme.clone(),
-                    Rc::new(LoxFunction::new(
-                        name.clone(),
-                        params.to_vec(),
-                        body,
-                        self.environment.clone(),
-                        is_initializer,
-                    )),
-                );
+        for &method in methods {
+            if let Stmt::Function { name, .. } = &ast[method] {
+                let is_initializer = &*name.lexeme == "init";
+
+                let function = Rc::new(LoxFunction::new(
+                    ast.clone(),
+                    method,
+                    self.environment.clone(),
+                    is_initializer,
+                ));
+                self.heap.track(&function);
+                method_funcs.insert(name.lexeme.to_string(), function);
             } else {
                 panic!("Method is not encapsulated in Stmt::Function");
             }
         }
 
-        let class =
-            Rc::new(RefCell::new(Class::new(&name.lexeme, method_funcs, superclass.clone())));
+        let class = Class::new(&name.lexeme, method_funcs, superclass.clone()).with_doc(doc);
+        let class = Rc::new(RefCell::new(class));
+        self.heap.track(&class);
 
         if superclass.is_some() {
             let enclosing = self.environment.borrow().enclosing.clone().unwrap();
             self.environment = enclosing;
         }
 
-        self.environment.borrow_mut().assign(name, Object::Class(class))
+        // Methods only look the class up when they are called, so it's enough to
+        // define it once it's complete.
+        self.environment.borrow_mut().define(name.lexeme.clone(), Object::Class(class));
+        Ok(())
     }
 
-    pub fn handle_while_stmt(
+    /// Add the methods of an `extend` statement to the class it names. They
+    /// close over the environment of the statement, like the methods of a
+    /// class without a superclass.
+    fn handle_extend_stmt(
         &mut self,
-        condition: &Expr,
-        body: &Stmt,
-    ) -> Result<(), RuntimeInterrupt> {
-        loop {
-            let value = &self.evaluate_expr(condition)?;
-            if !self.is_truthy(value) {
-                break;
-            }
+        ast: &Rc<Ast>,
+        class: ExprId,
+        methods: &[StmtId],
+    ) -> Result<(), RuntimeError> {
+        let Object::Class(class_object) = self.evaluate_expr(ast, class)? else {
+            let Expr::Variable { binding: _, name } = &ast[class] else {
+                panic!("Extended class is not enclosed in a Expr::Variable!");
+            };
+            return Err(RuntimeError::error(
+                name,
+                DiagnosticCode::NotAClass,
+                "Can only extend a class.",
+            ));
+        };
 
-            // We will catch 'Break' runtime errors. That error means that we hit a `break`
-            // statement. Any other error will be propagated up.
-            let result = self.execute(body);
+        for &method in methods {
+            let Stmt::Function { name, .. } = &ast[method] else {
+                panic!("Method is not encapsulated in Stmt::Function");
+            };
+            let is_initializer = &*name.lexeme == "init";
+            let function =
+                LoxFunction::new(ast.clone(), method, self.environment.clone(), is_initializer);
+            let function = Rc::new(function);
+            self.heap.track(&function);
+            class_object.borrow_mut().define_method(&name.lexeme, function);
+        }
+        Ok(())
+    }
 
-            if matches!(result, Err(RuntimeInterrupt::Break { .. })) {
-                break;
-            }
+    /// Create the function declared by the statement, e.g. a `fun` or a lambda.
+    /// It closes over the environment it's declared in, not the one it's
+    /// called in, or over just the variables it captures if the resolver
+    /// found them, see [`Environment::capture`].
+    pub(crate) fn declare_function(&mut self, ast: &Rc<Ast>, stmt: StmtId) -> Rc<LoxFunction> {
+        let closure = match ast.captures(stmt) {
+            Some(captures) => Environment::capture(&self.environment, captures, &mut self.heap),
+            None => self.environment.clone(),
+        };
+        let function = Rc::new(LoxFunction::new(ast.clone(), stmt, closure, false));
+        self.heap.track(&function);
+        function
+    }
 
-            result?;
-        }
+    /// Start another iteration of a `while` loop if its condition still
+    /// holds, or run its `else` branch if it doesn't.
+    fn iterate(&mut self, ast: &Rc<Ast>, stmt: StmtId) -> Result<(), RuntimeError> {
+        let Stmt::While { keyword, condition, body, else_branch } = &ast[stmt] else {
+            unreachable!("only `while` statements loop")
+        };
+        self.check_budget(keyword)?;
 
+        let value = self.evaluate_expr(ast, *condition)?;
+        if self.is_truthy(&value) {
+            self.work.push(Work::Loop(stmt));
+            self.work.push(Work::Stmt(*body));
+        } else if let Some(else_branch) = *else_branch {
+            // A `break` drops the loop before it gets here
+            self.work.push(Work::Stmt(else_branch));
+        }
         Ok(())
     }
 
-    fn runtime_error(&mut self, e: RuntimeInterrupt) {
-        match e {
-            RuntimeInterrupt::Error { line, msg } => {
-                self.errors.push(InterpreterError { line, message: msg })
-            }
-            _ => panic!("Invalid runtime interrupt in this context"),
+    /// End the script with the value of a `return` at the top level, which
+    /// is truncated to an exit code. Returning nothing exits with 0.
+    fn exit(keyword: &Token, value: Object) -> Result<ControlFlow, RuntimeError> {
+        match value {
+            Object::Number(code) => Ok(ControlFlow::Return(Object::Number(code))),
+            Object::Null => Ok(ControlFlow::Return(Object::Number(0.0))),
+            _ => Err(RuntimeError::error(
+                keyword,
+                DiagnosticCode::InvalidExitCode,
+                "Can only return a number from top-level code.",
+            )),
         }
     }
+
+    fn runtime_error(&mut self, e: RuntimeError) {
+        self.hooks.on_error(&e);
+        let RuntimeError { line, column, span, code, message, cause } = e;
+        self.errors.push(InterpreterError { line, column, span, code, message, cause });
+    }
 }
@@ -0,0 +1,50 @@
+//! Keys of the hash maps of Lox values, like the results of a memoized
+//! function by its arguments.
+//!
+//! Only `nil`, booleans, numbers and strings, which `==` compares by value, can
+//! be keys; using another value as a key is a runtime error. Numbers are
+//! compared by their bits, once the two zeros and all the NaNs are made the
+//! same: `0` and `-0` are the same key, like they are equal, and although
+//! `nan != nan`, a NaN finds what was stored with another NaN.
+
+use std::fmt::Display;
+
+use crate::sync::Rc;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Nil,
+    Boolean(bool),
+    /// The bits of the number, see [`Key::number`]
+    Number(u64),
+    String(Rc<str>),
+}
+
+impl Key {
+    pub fn number(n: f64) -> Self {
+        let n = if n.is_nan() {
+            f64::NAN
+        } else if n == 0.0 {
+            0.0
+        } else {
+            n
+        };
+        Self::Number(n.to_bits())
+    }
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Nil => write!(f, "nil"),
+            Self::Boolean(b) => write!(f, "{b}"),
+            Self::Number(bits) => write!(f, "{}", f64::from_bits(*bits)),
+            Self::String(s) => write!(f, "{s}"),
+        }
+    }
+}
+
+/// The message of the error for using a value that can't be a key.
+pub(crate) fn unhashable(value: &impl Display) -> String {
+    format!("Can't use '{value}' as a key. Only nil, booleans, numbers and strings can be keys.")
+}
@@ -1,113 +1,912 @@
 #![allow(clippy::new_without_default)]
-#![allow(clippy::vtable_address_comparisons)]
+#![allow(ambiguous_wide_pointer_comparisons)]
 
 mod ast;
+mod ast_json;
+mod async_native;
+mod budget;
+mod capability;
 mod class;
+mod compiler;
+mod coverage;
+mod debugger;
+mod diagnostic;
 mod environment;
 mod error;
+#[cfg(feature = "lox-ffi")]
+pub mod ffi;
+mod formatter;
 mod func;
+mod gc;
+mod hooks;
 mod interpreter;
+mod key;
+mod linter;
+mod list;
 mod native;
+#[cfg(feature = "net")]
+mod net;
 mod object;
+mod output;
 mod parser;
 mod printer;
+mod profile;
+#[cfg(feature = "pyo3")]
+mod python;
+mod repl;
 mod resolver;
 mod scanner;
+mod semantics;
+mod set;
+mod snapshot;
+mod string_builder;
+pub mod sync;
+mod testing;
 mod token;
+mod vm;
+mod weak_ref;
 
+/// The public API of the crate, besides [`Lox`].
+///
+/// [`Interpreter`] is the tree-walking backend and [`Vm`] the bytecode one. A
+/// runtime error unwinds the tree-walker as a [`RuntimeError`], while `break`
+/// and `return` end statements with a [`ControlFlow`](prelude::ControlFlow).
+/// Both backends report runtime errors as an
+/// [`InterpreterError`](prelude::InterpreterError). [`Lox`] turns all the
+/// errors of a run into a [`LoxError`].
 pub mod prelude {
+    pub use crate::Shared;
     pub use crate::ast::*;
+    pub use crate::ast_json::AstJson;
+    #[cfg(feature = "sync")]
+    pub use crate::async_native::AsyncRun;
+    pub use crate::async_native::{AsyncNativeFn, NativeFuture};
+    pub use crate::budget::InterruptHandle;
+    pub use crate::capability::Capability;
     pub use crate::class::*;
-    pub use crate::environment::Environment;
+    pub use crate::coverage::{Coverage, FileCoverage, LineCoverage};
+    pub use crate::debugger::{DapServer, Debugger};
+    pub use crate::diagnostic::*;
+    pub use crate::environment::{Environment, VariableName};
     pub use crate::error::*;
+    pub use crate::formatter::Formatter;
     pub use crate::func::*;
+    pub use crate::gc::HeapStats;
+    pub use crate::hooks::InterpreterHooks;
     pub use crate::interpreter::*;
+    pub use crate::key::Key;
+    pub use crate::linter::{LintRule, Linter};
     pub use crate::object::*;
     pub use crate::parser::*;
+    pub use crate::printer::AstPrinter;
+    pub use crate::profile::{FunctionProfile, Profile};
     pub use crate::resolver::Resolver;
     pub use crate::scanner::*;
+    pub use crate::semantics::{Equality, Truthiness};
+    pub use crate::set::LoxSet;
+    pub use crate::snapshot::SnapshotError;
+    pub use crate::testing::TestResult;
     pub use crate::token::*;
-    pub use crate::Shared;
+    pub use crate::vm::serialize::BytecodeError;
+    pub use crate::vm::{Value, Vm};
 }
 
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::collections::HashSet;
+use std::io::Read;
+
+use prelude::{
+    Diagnostic, DiagnosticFormat, Interpreter, LoxError, Parser, Phase, Report, Resolver,
+    RuntimeError, Severity, TokenType, Vm,
+};
 
-use prelude::{Interpreter, Parser, Resolver, RuntimeInterrupt, TokenType};
+use crate::output::Output;
+use crate::prelude::Capability;
+use crate::sync::{Rc, RefCell};
 
 pub type Shared<T> = Rc<RefCell<T>>;
 
+/// What runs the programs of a [`Lox`] instance.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Walk the syntax tree. This is the reference implementation.
+    #[default]
+    TreeWalker,
+    /// Compile the syntax tree to bytecode and run it on a virtual machine.
+    Vm,
+}
+
+/// What a run printed, made by [`Lox::run_capture`].
+#[derive(Debug)]
+pub struct RunOutput {
+    /// What the program printed
+    pub stdout: String,
+    /// The warnings and errors, rendered like the `lox` command prints them
+    pub stderr: String,
+    pub result: Result<(), anyhow::Error>,
+}
+
+/// Builds a [`Lox`] instance, choosing its backend and the capabilities of its
+/// natives. All capabilities are allowed by default, like with [`Lox::new`].
+///
+/// ```
+/// use lox::Lox;
+/// use lox::prelude::Capability;
+///
+/// let mut lox = Lox::builder().sandboxed().allow(Capability::Clock).build();
+/// lox.run("print clock() > 0;").unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LoxBuilder {
+    backend: Backend,
+    denied: HashSet<Capability>,
+}
+
+impl LoxBuilder {
+    /// Run the programs with the given backend.
+    pub fn backend(self, backend: Backend) -> Self {
+        Self { backend, ..self }
+    }
+
+    /// Deny a capability. Its natives stay defined, but fail with a runtime
+    /// error when they are called.
+    pub fn deny(mut self, capability: Capability) -> Self {
+        self.denied.insert(capability);
+        self
+    }
+
+    /// Allow a capability denied before, e.g. by [`LoxBuilder::sandboxed`].
+    pub fn allow(mut self, capability: Capability) -> Self {
+        self.denied.remove(&capability);
+        self
+    }
+
+    /// Deny all the capabilities, so untrusted scripts can only compute.
+    pub fn sandboxed(mut self) -> Self {
+        self.denied.extend(Capability::ALL);
+        self
+    }
+
+    pub fn build(self) -> Lox {
+        let mut lox = Lox::new().with_backend(self.backend);
+        for capability in self.denied {
+            lox.interpreter.deny(capability);
+            lox.vm.deny(capability);
+        }
+        lox
+    }
+}
+
+/// The entry point for running Lox code.
+///
+/// A `Lox` instance owns a single interpreter. Global variables, functions and
+/// classes defined by one call to [`Lox::run`] (or [`Lox::run_file`]) stay
+/// visible to the following calls on the same instance, which is what a REPL or
+/// an embedding host usually wants. Create a new instance to start from a clean
+/// state.
 pub struct Lox {
-    error_messages: Vec<String>,
+    backend: Backend,
+    interpreter: Interpreter,
+    vm: Vm,
+    diagnostics: Vec<Diagnostic>,
+    diagnostic_format: DiagnosticFormat,
+    /// Warnings of the last compiled source, not rendered yet
+    warnings: Vec<Diagnostic>,
+    warnings_as_errors: bool,
+    /// Whether each iteration of a `for` loop gets its own loop variable
+    fresh_loop_variables: bool,
+    /// Whether a `return` at the top level ends the script
+    top_level_return: bool,
+    /// The exit code a `return` at the top level ended the last run with
+    exit_code: Option<i32>,
+    /// The source code of the current run, used to render diagnostics
+    source: String,
+    /// The file being run, if any
+    file: Option<String>,
+    /// Where the async natives send their calls during [`Lox::run_async`]
+    #[cfg(feature = "sync")]
+    async_driver: Shared<Option<async_native::Driver>>,
 }
 
 impl Lox {
     pub fn new() -> Self {
-        Self { error_messages: Vec::new() }
+        Self {
+            backend: Backend::default(),
+            interpreter: Interpreter::new(),
+            vm: Vm::new(),
+            diagnostics: Vec::new(),
+            diagnostic_format: DiagnosticFormat::default(),
+            warnings: Vec::new(),
+            warnings_as_errors: false,
+            fresh_loop_variables: false,
+            top_level_return: false,
+            exit_code: None,
+            source: String::new(),
+            file: None,
+            #[cfg(feature = "sync")]
+            async_driver: Shared::default(),
+        }
     }
 
-    pub fn run_file(&mut self, filename: &str) -> Result<(), anyhow::Error> {
-        let content = std::fs::read_to_string(filename)?;
+    /// Start building an instance whose natives can be restricted, see
+    /// [`LoxBuilder`].
+    pub fn builder() -> LoxBuilder {
+        LoxBuilder::default()
+    }
 
-        let tokens = self.scan(content)?;
-        let statements = self.parse(tokens)?;
+    /// Run the programs with the given backend instead of the tree-walking
+    /// interpreter.
+    pub fn with_backend(self, backend: Backend) -> Self {
+        Self { backend, ..self }
+    }
 
-        let mut interpreter = Interpreter::new();
-        let mut resolver = Resolver::new(&mut interpreter);
-        if let Err(errors) = resolver.resolve(&statements) {
-            for e in errors {
-                self.error_messages.push(format!("{e}"));
-            }
-            return Err(self.aggregate_errors());
+    /// Set how the diagnostics are rendered in the errors returned by this
+    /// instance. The default is [`DiagnosticFormat::Plain`].
+    pub fn set_diagnostic_format(&mut self, format: DiagnosticFormat) {
+        self.diagnostic_format = format;
+    }
+
+    /// Treat warnings as errors: a program with warnings fails to compile
+    /// and is not executed. Off by default.
+    pub fn set_warnings_as_errors(&mut self, enabled: bool) {
+        self.warnings_as_errors = enabled;
+    }
+
+    /// Give each iteration of a `for` loop its own copy of the loop variable,
+    /// so closures made in the loop body capture different variables. Off by
+    /// default, like in the reference implementation, where all the
+    /// iterations share the variable.
+    pub fn set_fresh_loop_variables(&mut self, enabled: bool) {
+        self.fresh_loop_variables = enabled;
+    }
+
+    /// Let a `return` at the top level end the script, instead of being an
+    /// error. The number it returns, truncated, is the exit code of the run,
+    /// see [`Lox::exit_code`]. Off by default.
+    pub fn set_top_level_return(&mut self, enabled: bool) {
+        self.top_level_return = enabled;
+    }
+
+    /// The exit code of the last run, if a `return` at the top level ended
+    /// it. Returning nothing, or `nil`, exits with 0.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Take the warnings found in the last compiled source, e.g. by
+    /// [`Lox::run`]. Warnings don't stop a program from running.
+    pub fn take_warnings(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Take the warnings of the last compiled source, rendered in the current
+    /// diagnostic format, one per line. Returns `None` if there are none.
+    pub fn render_warnings(&mut self) -> Option<String> {
+        let warnings = self.take_warnings();
+        if warnings.is_empty() {
+            return None;
         }
 
-        if let Err(errors) = interpreter.interpret(&statements) {
-            for e in errors {
-                self.error_messages.push(format!("[line {}] {}", e.line, e.message));
+        let text = warnings
+            .iter()
+            .map(|d| d.render(self.diagnostic_format, &self.source))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Some(text)
+    }
+
+    /// Set the number of nested calls after which a program fails with a
+    /// "Stack overflow." error. The default is
+    /// [`DEFAULT_MAX_CALL_DEPTH`](prelude::DEFAULT_MAX_CALL_DEPTH).
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.interpreter.set_max_call_depth(depth);
+        self.vm.set_max_call_depth(depth);
+    }
+
+    /// Choose which values are true in conditions and for `!`, `and` and
+    /// `or`. The default is [`Truthiness::Lox`](prelude::Truthiness::Lox),
+    /// where everything but `nil` and `false` is true.
+    pub fn set_truthiness(&mut self, truthiness: prelude::Truthiness) {
+        self.interpreter.set_truthiness(truthiness);
+        self.vm.set_truthiness(truthiness);
+    }
+
+    /// Choose how `==` and `!=` compare values of different types. The default
+    /// is [`Equality::Strict`](prelude::Equality::Strict), where they are
+    /// never equal.
+    pub fn set_equality(&mut self, equality: prelude::Equality) {
+        self.interpreter.set_equality(equality);
+        self.vm.set_equality(equality);
+    }
+
+    /// Fail a run after executing this many statements (instructions on the
+    /// VM backend), e.g. to stop untrusted scripts. `None`, the default, means
+    /// there is no limit.
+    pub fn set_max_steps(&mut self, steps: Option<u64>) {
+        self.interpreter.set_max_steps(steps);
+        self.vm.set_max_steps(steps);
+    }
+
+    /// Fail a run if it takes longer than this. `None`, the default, means
+    /// there is no limit.
+    pub fn set_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        self.interpreter.set_timeout(timeout);
+        self.vm.set_timeout(timeout);
+    }
+
+    /// A handle to stop the running program from another thread, e.g. a
+    /// Ctrl-C handler.
+    pub fn interrupt_handle(&self) -> prelude::InterruptHandle {
+        match self.backend {
+            Backend::TreeWalker => self.interpreter.interrupt_handle(),
+            Backend::Vm => self.vm.interrupt_handle(),
+        }
+    }
+
+    /// Record the number of calls and the time spent in each function, see
+    /// [`Lox::profile`]. Only the tree-walking interpreter can be profiled.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.interpreter.set_profiling(enabled);
+    }
+
+    /// Record which lines of the programs run, see [`Lox::coverage`]. Only the
+    /// tree-walking interpreter records coverage.
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.interpreter.set_coverage(enabled);
+    }
+
+    /// Log each statement to stderr with its line before executing it. Only the
+    /// tree-walking interpreter can be traced.
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.interpreter.set_trace(enabled);
+    }
+
+    /// Add the depth of the environment to the traced statements.
+    pub fn set_trace_depth(&mut self, enabled: bool) {
+        self.interpreter.set_trace_depth(enabled);
+    }
+
+    /// Write what programs print to this writer instead of the standard
+    /// output.
+    pub fn set_output(&mut self, writer: impl std::io::Write + sync::MaybeSync + 'static) {
+        match self.backend {
+            Backend::TreeWalker => self.interpreter.set_output(writer),
+            Backend::Vm => self.vm.set_output(writer),
+        }
+    }
+
+    /// Let a debugger stop the program before its statements, or run it
+    /// without stopping if `None`. Only the tree-walking interpreter can be
+    /// debugged.
+    pub fn set_debugger(&mut self, debugger: Option<prelude::Debugger>) {
+        self.interpreter.set_debugger(debugger);
+    }
+
+    /// Call these hooks as the programs run, e.g. to instrument them. Only the
+    /// tree-walking interpreter calls hooks.
+    pub fn add_hooks(&mut self, hooks: impl prelude::InterpreterHooks + 'static) {
+        self.interpreter.add_hooks(hooks);
+    }
+
+    /// The calls recorded since profiling was turned on, if it is.
+    pub fn profile(&self) -> Option<prelude::Profile> {
+        match self.backend {
+            Backend::TreeWalker => self.interpreter.profile(),
+            Backend::Vm => None,
+        }
+    }
+
+    /// The lines of each file that ran since coverage was turned on, if it is.
+    /// Code that isn't read from a file is reported as `<script>`.
+    pub fn coverage(&self) -> Option<prelude::Coverage> {
+        match self.backend {
+            Backend::TreeWalker => self.interpreter.coverage(),
+            Backend::Vm => None,
+        }
+    }
+
+    /// Save the globals as JSON, to restore them later with [`Lox::restore`],
+    /// e.g. in another session. Only the globals of the tree-walking
+    /// interpreter can be saved, see [`Interpreter::snapshot`].
+    pub fn snapshot(&self) -> Option<String> {
+        match self.backend {
+            Backend::TreeWalker => Some(self.interpreter.snapshot()),
+            Backend::Vm => None,
+        }
+    }
+
+    /// Define the globals saved by [`Lox::snapshot`].
+    pub fn restore(&mut self, snapshot: &str) -> Result<(), anyhow::Error> {
+        match self.backend {
+            Backend::TreeWalker => Ok(self.interpreter.restore(snapshot)?),
+            Backend::Vm => {
+                anyhow::bail!("Only the tree-walking interpreter can restore snapshots.")
             }
-            return Err(self.aggregate_errors());
         }
+    }
 
-        Ok(())
+    /// Free the objects of the active backend that are only kept alive by
+    /// reference cycles, and return their number. Programs can do the same by
+    /// calling `gcCollect()`.
+    pub fn collect_garbage(&mut self) -> usize {
+        match self.backend {
+            Backend::TreeWalker => self.interpreter.collect_garbage(),
+            Backend::Vm => self.vm.collect_garbage(),
+        }
     }
 
-    fn parse(&mut self, tokens: Vec<prelude::Token>) -> Result<Vec<prelude::Stmt>, anyhow::Error> {
-        let mut parser = Parser::new(tokens);
-        parser.parse().or_else(|errors| {
-            self.add_parse_errors(errors);
-            return Err(self.aggregate_errors());
+    pub fn heap_stats(&self) -> prelude::HeapStats {
+        match self.backend {
+            Backend::TreeWalker => self.interpreter.heap_stats(),
+            Backend::Vm => self.vm.heap_stats(),
+        }
+    }
+
+    /// Run a script, or a program compiled by [`Lox::compile_to_bytecode`].
+    pub fn run_file(&mut self, filename: &str) -> Result<(), anyhow::Error> {
+        let content = std::fs::read(filename)?;
+        if vm::serialize::is_bytecode(&content) {
+            return self.run_bytecode(&content);
+        }
+
+        let content = String::from_utf8(content)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        self.file = Some(filename.to_owned());
+        let result = self.run(&content);
+        self.file = None;
+
+        result
+    }
+
+    /// Resolve and execute a tree that was built without the parser, e.g. read
+    /// by [`AstJson::program_from_json`](prelude::AstJson::program_from_json).
+    /// The trees of other frontends run like the ones of Lox source code, but
+    /// errors don't show a snippet of the source.
+    pub fn run_ast(&mut self, ast: prelude::Ast) -> Result<(), anyhow::Error> {
+        self.source.clear();
+        let ast = self.resolve(ast, vec![])?;
+        self.execute(&ast)
+    }
+
+    /// Compile the source code to the `.loxc` bytecode format, without
+    /// running it.
+    pub fn compile_to_bytecode(&mut self, source: &str) -> Result<Vec<u8>, anyhow::Error> {
+        let ast = self.compile(source, false)?;
+        let scripts = self.compile_to_scripts(&ast)?;
+        Ok(vm::serialize::write_program(&scripts))
+    }
+
+    /// Run a program compiled by [`Lox::compile_to_bytecode`]. Bytecode always
+    /// runs on the VM, whatever the backend of this instance is.
+    ///
+    /// The source code isn't part of the bytecode, so runtime errors don't
+    /// show a snippet of it.
+    pub fn run_bytecode(&mut self, bytes: &[u8]) -> Result<(), anyhow::Error> {
+        let scripts = vm::serialize::read_program(bytes)?;
+
+        self.source.clear();
+        let result = self.vm.run_program(scripts);
+        self.exit_code = self.vm.take_exit_code();
+        self.report_runtime_errors(result)
+    }
+
+    /// Define a global native function whose implementation is async, see
+    /// [`Lox::run_async`]. The future it returns fails the program with a
+    /// runtime error if it resolves to an error. Only the tree-walking
+    /// interpreter has async natives.
+    pub fn define_async_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl prelude::AsyncNativeFn + 'static,
+    ) {
+        let native = async_native::AsyncNative {
+            name: name.to_owned(),
+            arity,
+            function: Rc::new(function),
+            #[cfg(feature = "sync")]
+            driver: self.async_driver.clone(),
+        };
+        self.interpreter
+            .globals
+            .borrow_mut()
+            .define(name, prelude::Object::Callable(Rc::new(native)));
+    }
+
+    /// Run the source code like [`Lox::run`], but on a thread of its own, and
+    /// return a future that polls the futures of the async natives it calls.
+    /// The program waits for them without blocking the thread polling the
+    /// run, which ends with this instance and the result of the program.
+    ///
+    /// [`Lox::run`] blocks its thread while an async native is pending
+    /// instead.
+    #[cfg(feature = "sync")]
+    pub fn run_async(self, source: impl Into<String>) -> prelude::AsyncRun {
+        async_native::spawn(self, source.into())
+    }
+
+    /// Scan, parse, resolve and execute the given source code.
+    ///
+    /// All errors found during a run are aggregated into the returned error. If
+    /// scanning, parsing or resolving fails, nothing is executed. Definitions
+    /// made before a runtime error are kept.
+    pub fn run(&mut self, source: &str) -> Result<(), anyhow::Error> {
+        let ast = self.compile(source, false)?;
+        self.execute(&ast)
+    }
+
+    /// Run the source code like [`Lox::run`], scanning it while it is read,
+    /// e.g. from the standard input. Failing to read it, or invalid UTF-8, is
+    /// reported as a scanner error.
+    pub fn run_reader(&mut self, reader: impl Read) -> Result<(), anyhow::Error> {
+        let mut reader = SourceReader { reader, read: Vec::new() };
+        let tokens = scanner::Scanner::from_reader(&mut reader).with_comment_tokens().scan_tokens();
+
+        // The diagnostics show the lines of the source
+        let tokens = self.scanned(tokens, String::from_utf8_lossy(&reader.read).into_owned())?;
+        let ast = self.compile_tokens(tokens, false)?;
+        self.execute(&ast)
+    }
+
+    /// Run the source code like [`Lox::run`], collecting what it prints
+    /// instead of writing it to the output, and its warnings and errors.
+    pub fn run_capture(&mut self, source: &str) -> RunOutput {
+        let stdout = output::Capture::default();
+        let previous = match self.backend {
+            Backend::TreeWalker => self.interpreter.replace_output(Output::new(stdout.clone())),
+            Backend::Vm => self.vm.replace_output(Output::new(stdout.clone())),
+        };
+        let result = self.run(source);
+        match self.backend {
+            Backend::TreeWalker => self.interpreter.replace_output(previous),
+            Backend::Vm => self.vm.replace_output(previous),
+        };
+
+        let mut stderr = String::new();
+        if let Some(warnings) = self.render_warnings() {
+            stderr.push_str(&format!("{warnings}\n"));
+        }
+        if let Err(e) = &result {
+            stderr.push_str(&format!("{e}\n"));
+        }
+        RunOutput { stdout: stdout.contents(), stderr, result }
+    }
+
+    /// Run the source code like [`Lox::run`], and return the value of the
+    /// expression statement it ends with, if any. The `;` after that expression
+    /// is optional, e.g. `1 + 2` evaluates to `3`.
+    pub fn eval(&mut self, source: &str) -> Result<Option<String>, anyhow::Error> {
+        let ast = self.compile(source, true)?;
+        self.execute(&ast)?;
+
+        // A script that ended with a `return` has no result
+        let Some(expr) = ast.result().filter(|_| self.exit_code.is_none()) else {
+            return Ok(None);
+        };
+        let value = match self.backend {
+            Backend::TreeWalker => self.interpreter.evaluate(&ast, expr).map(|v| v.to_string()),
+            Backend::Vm => self.vm.evaluate(&ast, expr).map(|v| v.to_string()).map_err(|e| vec![e]),
+        };
+        match value {
+            Ok(value) => Ok(Some(value)),
+            Err(errors) => self.report_runtime_errors(Err(errors)).map(|_| None),
+        }
+    }
+
+    /// The results of the tests run by the `test` native function since the
+    /// last call, e.g. by a `*_test.lox` file.
+    pub fn take_test_results(&mut self) -> Vec<prelude::TestResult> {
+        match self.backend {
+            Backend::TreeWalker => self.interpreter.take_test_results(),
+            Backend::Vm => self.vm.take_test_results(),
+        }
+    }
+
+    /// The doc comment of a global function or class, i.e. the `///` comment
+    /// lines right above its declaration.
+    pub fn doc(&self, name: &str) -> Option<String> {
+        let doc = match self.backend {
+            Backend::TreeWalker => self.interpreter.globals.borrow().lookup(name)?.doc(),
+            Backend::Vm => self.vm.global(name)?.doc(),
+        };
+        doc.map(|doc| doc.to_string())
+    }
+
+    /// Scan the source code and return its tokens, without running it.
+    pub fn tokenize(&mut self, source: &str) -> Result<Vec<prelude::Token>, anyhow::Error> {
+        self.scan(source)
+    }
+
+    /// Format the source code canonically, keeping its comments, see
+    /// [`prelude::Formatter`]. Fails if the code doesn't parse.
+    pub fn format(&mut self, source: &str) -> Result<String, anyhow::Error> {
+        let mut scanner = scanner::Scanner::new(source).with_comment_tokens();
+        let tokens = self.scan_with_scanner(&mut scanner, source)?;
+        let comments = comment_tokens(&tokens);
+        let (ast, _) = self.parse(Parser::new(tokens))?;
+
+        // The scanner skips the shebang line, so put it back
+        let shebang = source.lines().next().filter(|line| line.starts_with("#!"));
+        let formatted = prelude::Formatter::format(&ast, &comments);
+        Ok(match shebang {
+            Some(shebang) => format!("{shebang}\n{formatted}"),
+            None => formatted,
         })
     }
 
-    fn add_parse_errors(&mut self, errors: Vec<prelude::ParserError>) {
-        for e in errors {
-            if e.token.token_type == TokenType::EOF {
-                self.add_error(e.token.line, "at end", &e.message);
-            } else {
-                self.add_error(e.token.line, &format!("at '{}'", e.token.lexeme), &e.message);
+    /// Check the source code for likely mistakes, see [`prelude::Linter`],
+    /// without running it. Fails if the code doesn't compile.
+    pub fn lint(&mut self, source: &str) -> Result<Vec<Diagnostic>, anyhow::Error> {
+        self.analyze(source, |ast, source, comments| prelude::Linter::lint(ast, source, comments))
+    }
+
+    /// Find the globals that the program in the source code never uses, see
+    /// [`prelude::Linter::dead_globals`]. Fails if the code doesn't compile.
+    pub fn dead_globals(&mut self, source: &str) -> Result<Vec<Diagnostic>, anyhow::Error> {
+        self.analyze(source, prelude::Linter::dead_globals)
+    }
+
+    /// Resolve the source code and run an analysis on it, with the comments
+    /// of the source.
+    fn analyze(
+        &mut self,
+        source: &str,
+        analysis: impl FnOnce(&prelude::Ast, &str, &[prelude::Token]) -> Vec<Diagnostic>,
+    ) -> Result<Vec<Diagnostic>, anyhow::Error> {
+        let mut scanner = scanner::Scanner::new(source).with_comment_tokens();
+        let tokens = self.scan_with_scanner(&mut scanner, source)?;
+        let comments = comment_tokens(&tokens);
+        let (ast, _) = self.parse(Parser::new(tokens))?;
+
+        let mut resolver = Resolver::new().with_top_level_return(self.top_level_return);
+        if let Err(errors) = resolver.resolve(&ast) {
+            self.diagnostics.extend(errors.into_iter().map(resolver_diagnostic));
+            return Err(self.aggregate_errors(LoxError::Compile));
+        }
+
+        let mut diagnostics = analysis(&ast, source, &comments);
+        for diagnostic in diagnostics.iter_mut() {
+            diagnostic.file = self.file.clone();
+        }
+        Ok(diagnostics)
+    }
+
+    /// Scan and parse the source code and return its syntax tree, without
+    /// running it.
+    pub fn parse_source(&mut self, source: &str) -> Result<prelude::Ast, anyhow::Error> {
+        let tokens = self.scan(source)?;
+        let (ast, _) = self.parse(Parser::new(tokens))?;
+        Ok(ast)
+    }
+
+    /// Scan, parse and resolve the source code, without executing it. With
+    /// `trailing_expression`, an expression at the end is kept as the result of
+    /// the program, see [`Parser::with_trailing_expression`].
+    fn compile(
+        &mut self,
+        source: &str,
+        trailing_expression: bool,
+    ) -> Result<Rc<prelude::Ast>, anyhow::Error> {
+        // The comment tokens are needed to find the doc comments
+        let mut scanner = scanner::Scanner::new(source).with_comment_tokens();
+        let tokens = self.scan_with_scanner(&mut scanner, source)?;
+        self.compile_tokens(tokens, trailing_expression)
+    }
+
+    /// Parse and resolve the tokens of the source code, like
+    /// [`Lox::compile`].
+    fn compile_tokens(
+        &mut self,
+        tokens: Vec<prelude::Token>,
+        trailing_expression: bool,
+    ) -> Result<Rc<prelude::Ast>, anyhow::Error> {
+        let parser = Parser::new(tokens)
+            .with_fresh_loop_variables(self.fresh_loop_variables)
+            .with_trailing_expression(trailing_expression);
+        let (ast, warnings) = self.parse(parser)?;
+        self.resolve(ast, warnings)
+    }
+
+    /// Resolve a parsed tree, and report its errors and warnings with the ones
+    /// of the parser.
+    fn resolve(
+        &mut self,
+        mut ast: prelude::Ast,
+        mut warnings: Vec<Diagnostic>,
+    ) -> Result<Rc<prelude::Ast>, anyhow::Error> {
+        let mut resolver = Resolver::new().with_top_level_return(self.top_level_return);
+        let result = resolver.resolve(&ast);
+        for (stmt, captures) in resolver.take_captures() {
+            ast.set_captures(stmt, captures);
+        }
+        for (expr, names) in resolver.take_scope_names() {
+            ast.set_scope_names(expr, names);
+        }
+
+        warnings.extend(resolver.take_warnings().into_iter().map(resolver_diagnostic));
+        // Warnings would only get in the way of fixing the errors
+        if result.is_err() {
+            warnings.clear();
+        }
+        if self.warnings_as_errors {
+            for warning in &mut warnings {
+                warning.severity = Severity::Error;
             }
+            self.diagnostics.append(&mut warnings);
+        }
+        for diagnostic in warnings.iter_mut() {
+            diagnostic.file = self.file.clone();
+        }
+        self.warnings = warnings;
+
+        if let Err(errors) = result {
+            self.diagnostics.extend(errors.into_iter().map(resolver_diagnostic));
         }
+        if !self.diagnostics.is_empty() {
+            return Err(self.aggregate_errors(LoxError::Compile));
+        }
+
+        Ok(Rc::new(ast))
+    }
+
+    fn execute(&mut self, ast: &Rc<prelude::Ast>) -> Result<(), anyhow::Error> {
+        self.exit_code = None;
+        let result = match self.backend {
+            Backend::TreeWalker => {
+                let file = self.file.as_deref().unwrap_or("<script>");
+                self.interpreter.cover(file, ast);
+                self.interpreter.interpret(ast)
+            }
+            Backend::Vm => {
+                let scripts = self.compile_to_scripts(ast)?;
+                self.vm.run_program(scripts)
+            }
+        };
+        self.exit_code = match self.backend {
+            Backend::TreeWalker => self.interpreter.take_exit_code(),
+            Backend::Vm => self.vm.take_exit_code(),
+        };
+        self.report_runtime_errors(result)
     }
 
-    fn scan(&mut self, content: String) -> Result<Vec<prelude::Token>, anyhow::Error> {
-        let mut scanner = scanner::Scanner::new(&content);
-        scanner.scan_tokens().or_else(|errors| {
-            errors.iter().for_each(|e| self.add_error(e.line, "", &e.message));
-            return Err(self.aggregate_errors());
+    /// Compile a resolved program to bytecode, for the VM.
+    fn compile_to_scripts(
+        &mut self,
+        ast: &prelude::Ast,
+    ) -> Result<Vec<Rc<vm::value::Function>>, anyhow::Error> {
+        compiler::Compiler::compile(ast).map_err(|errors| {
+            for e in errors {
+                let diagnostic = Diagnostic::new(Phase::Compile, e.code, e.line, &e.message)
+                    .with_position(e.column, e.span)
+                    .with_location(e.location);
+                self.diagnostics.push(diagnostic);
+            }
+            self.aggregate_errors(LoxError::Compile)
         })
     }
 
-    fn aggregate_errors(&mut self) -> anyhow::Error {
-        let res = anyhow::anyhow!(self.error_messages.join("\n"));
-        self.error_messages.clear();
-        res
+    fn report_runtime_errors(
+        &mut self,
+        result: Result<(), Vec<prelude::InterpreterError>>,
+    ) -> Result<(), anyhow::Error> {
+        if let Err(errors) = result {
+            let cause = errors.iter().find_map(|e| e.cause.clone());
+            for e in errors {
+                let diagnostic = Diagnostic::new(Phase::Runtime, e.code, e.line, &e.message)
+                    .with_position(e.column, e.span);
+                self.diagnostics.push(diagnostic);
+            }
+            return Err(
+                self.aggregate_errors(|report| LoxError::Runtime(Report { cause, ..report }))
+            );
+        }
+
+        Ok(())
     }
 
-    fn add_error(&mut self, line: u32, location: &str, message: &str) {
-        if location.is_empty() {
-            self.error_messages.push(format!("[line {line}] Error: {message}"));
-        } else {
-            self.error_messages.push(format!("[line {line}] Error {location}: {message}"));
+    /// Parse the tokens and return the tree with the warnings of the parser.
+    fn parse(
+        &mut self,
+        mut parser: Parser,
+    ) -> Result<(prelude::Ast, Vec<Diagnostic>), anyhow::Error> {
+        let (ast, errors) = parser.parse();
+        let (warnings, errors): (Vec<_>, Vec<_>) =
+            errors.into_iter().partition(prelude::ParserError::is_warning);
+        if !errors.is_empty() {
+            self.diagnostics.extend(errors.into_iter().map(parser_diagnostic));
+            return Err(self.aggregate_errors(LoxError::Compile));
         }
+        Ok((ast, warnings.into_iter().map(parser_diagnostic).collect()))
+    }
+
+    fn scan(&mut self, content: &str) -> Result<Vec<prelude::Token>, anyhow::Error> {
+        self.scan_with_scanner(&mut scanner::Scanner::new(content), content)
+    }
+
+    fn scan_with_scanner(
+        &mut self,
+        scanner: &mut scanner::Scanner,
+        content: &str,
+    ) -> Result<Vec<prelude::Token>, anyhow::Error> {
+        let tokens = scanner.scan_tokens();
+        self.scanned(tokens, content.to_owned())
+    }
+
+    /// Report the errors of a scanner, if any, and keep the source code they
+    /// were found in.
+    fn scanned(
+        &mut self,
+        tokens: Result<Vec<prelude::Token>, Vec<prelude::ScannerError>>,
+        source: String,
+    ) -> Result<Vec<prelude::Token>, anyhow::Error> {
+        self.source = source;
+
+        tokens.map_err(|errors| {
+            for e in errors {
+                // Scanner errors point at a single character, so there is no span
+                let diagnostic = Diagnostic::new(Phase::Scan, e.code, e.line, &e.message)
+                    .with_position(e.column, prelude::Span::default());
+                self.diagnostics.push(diagnostic);
+            }
+            self.aggregate_errors(LoxError::Compile)
+        })
+    }
+
+    fn aggregate_errors(&mut self, kind: impl FnOnce(Report) -> LoxError) -> anyhow::Error {
+        let mut diagnostics = std::mem::take(&mut self.diagnostics);
+        for diagnostic in diagnostics.iter_mut() {
+            diagnostic.file = self.file.clone();
+        }
+
+        let text = diagnostics
+            .iter()
+            .map(|d| d.render(self.diagnostic_format, &self.source))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        kind(Report { diagnostics, text, cause: None }).into()
+    }
+}
+
+fn parser_diagnostic(e: prelude::ParserError) -> Diagnostic {
+    let location = if e.token.token_type == TokenType::EOF {
+        "at end".to_owned()
+    } else {
+        format!("at '{}'", e.token.lexeme)
+    };
+
+    Diagnostic::new(Phase::Parse, e.code, e.token.line, &e.message)
+        .with_position(e.token.column, e.token.span)
+        .with_location(location)
+        .with_note(e.expectation())
+}
+
+fn resolver_diagnostic(e: resolver::ResolverError) -> Diagnostic {
+    match e.token {
+        Some(token) => Diagnostic::new(Phase::Resolve, e.code, token.line, &e.msg)
+            .with_position(token.column, token.span)
+            .with_location(format!("at '{}'", token.lexeme)),
+        None => Diagnostic::new(Phase::Resolve, e.code, 0, &e.msg),
+    }
+}
+
+/// The comment tokens among the tokens of a scanner that keeps them, for the
+/// formatter and the linter.
+fn comment_tokens(tokens: &[prelude::Token]) -> Vec<prelude::Token> {
+    tokens.iter().filter(|token| token.token_type == TokenType::Comment).cloned().collect()
+}
+
+/// Keeps the bytes read from a reader, so the diagnostics can show the source
+/// code that was scanned as it was read.
+struct SourceReader<R> {
+    reader: R,
+    read: Vec<u8>,
+}
+
+impl<R: Read> Read for SourceReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.read.extend_from_slice(&buf[..n]);
+        Ok(n)
     }
 }
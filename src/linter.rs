@@ -0,0 +1,574 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use crate::prelude::*;
+
+/// The checks of the [`Linter`]. Each one can be turned off for a line with a
+/// `// lox-ignore: <rule>` comment on that line or the line before, e.g.
+/// `// lox-ignore: unused, shadow`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintRule {
+    /// Local variables that are never read and local functions that are
+    /// never called
+    Unused,
+    /// Local variables with the name of a variable of an enclosing scope
+    Shadow,
+    /// Statements after a `return` or a `break` in the same block
+    Unreachable,
+    /// Blocks without any statement
+    EmptyBlock,
+    /// `==` and `!=` between constants of different types
+    MixedEquality,
+    /// Variables and properties assigned to themselves
+    SelfAssign,
+    /// Globals the program never uses, only checked by
+    /// [`Linter::dead_globals`]
+    DeadGlobal,
+}
+
+impl LintRule {
+    pub const ALL: [LintRule; 7] = [
+        Self::Unused,
+        Self::Shadow,
+        Self::Unreachable,
+        Self::EmptyBlock,
+        Self::MixedEquality,
+        Self::SelfAssign,
+        Self::DeadGlobal,
+    ];
+
+    /// The name of the rule in `lox-ignore` comments.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Unused => "unused",
+            Self::Shadow => "shadow",
+            Self::Unreachable => "unreachable",
+            Self::EmptyBlock => "empty-block",
+            Self::MixedEquality => "mixed-equality",
+            Self::SelfAssign => "self-assign",
+            Self::DeadGlobal => "dead-global",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|rule| rule.name() == name)
+    }
+
+    /// The rule that reports diagnostics with the given code, if any.
+    pub fn from_code(code: DiagnosticCode) -> Option<Self> {
+        match code {
+            DiagnosticCode::UnusedVariable | DiagnosticCode::UnusedFunction => Some(Self::Unused),
+            DiagnosticCode::ShadowedVariable => Some(Self::Shadow),
+            DiagnosticCode::UnreachableCode => Some(Self::Unreachable),
+            DiagnosticCode::EmptyBlock => Some(Self::EmptyBlock),
+            DiagnosticCode::MixedTypeEquality => Some(Self::MixedEquality),
+            DiagnosticCode::SelfAssignment => Some(Self::SelfAssign),
+            DiagnosticCode::UnusedGlobal => Some(Self::DeadGlobal),
+            _ => None,
+        }
+    }
+}
+
+impl Display for LintRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A name declared in a scope.
+struct Declaration {
+    name: Token,
+    /// Functions are reported as unused functions, classes are never reported
+    kind: DeclarationKind,
+    used: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DeclarationKind {
+    Variable,
+    Function,
+    /// Parameters, classes, and other names that are fine to leave unused
+    Other,
+}
+
+/// Finds code that runs but is likely a mistake, like variables that are
+/// never read. The program must be resolved without errors first.
+pub struct Linter<'a> {
+    ast: &'a Ast,
+    /// The global scope first, then the local scopes
+    scopes: Vec<Vec<Declaration>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Linter<'a> {
+    /// Check a program and return its warnings, ordered by line, except the
+    /// ones turned off by the `lox-ignore` comments of its source, given as
+    /// the tokens of [`Scanner::with_comment_tokens`].
+    pub fn lint(ast: &'a Ast, source: &str, comments: &[Token]) -> Vec<Diagnostic> {
+        let mut linter = Self { ast, scopes: vec![vec![]], diagnostics: vec![] };
+        linter.statements(ast.statements(), false);
+
+        let mut diagnostics = linter.diagnostics;
+        diagnostics.retain(|diagnostic| !is_ignored(diagnostic, source, comments));
+        diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+        diagnostics
+    }
+
+    /// Find the global variables, functions and classes that the whole
+    /// program never uses, except the ones turned off by the `lox-ignore`
+    /// comments of its source.
+    ///
+    /// A global is used if the top-level code reads it, or if a function or
+    /// a class that is used does. So functions that only call each other are
+    /// reported too.
+    pub fn dead_globals(ast: &Ast, source: &str, comments: &[Token]) -> Vec<Diagnostic> {
+        let mut declarations = vec![];
+        // The globals read by the code that runs, and by each global
+        let mut roots = vec![];
+        let mut uses: HashMap<&str, Vec<&str>> = HashMap::new();
+
+        for &stmt in ast.statements() {
+            match &ast[stmt] {
+                Stmt::Var { name, initializer } => {
+                    declarations.push(name);
+                    if let Some(initializer) = initializer {
+                        expr_globals(ast, *initializer, &mut roots);
+                    }
+                }
+                Stmt::Function { name, .. } => {
+                    declarations.push(name);
+                    stmt_globals(ast, stmt, uses.entry(&name.lexeme).or_default());
+                }
+                Stmt::Class { name, methods, superclass } => {
+                    declarations.push(name);
+                    // The superclass is read when the class is declared
+                    if let Some(superclass) = superclass {
+                        expr_globals(ast, *superclass, &mut roots);
+                    }
+                    for &method in methods {
+                        stmt_globals(ast, method, uses.entry(&name.lexeme).or_default());
+                    }
+                }
+                _ => stmt_globals(ast, stmt, &mut roots),
+            }
+        }
+
+        let mut used = HashSet::new();
+        while let Some(name) = roots.pop() {
+            if used.insert(name) {
+                roots.extend(uses.get(name).into_iter().flatten());
+            }
+        }
+
+        let mut diagnostics = declarations
+            .into_iter()
+            .filter(|name| !used.contains(&*name.lexeme))
+            .map(|name| {
+                Diagnostic::new(
+                    Phase::Lint,
+                    DiagnosticCode::UnusedGlobal,
+                    name.line,
+                    "Global is never used.",
+                )
+                .with_position(name.column, name.span)
+                .with_location(format!("at '{}'", name.lexeme))
+            })
+            .collect::<Vec<_>>();
+        diagnostics.retain(|diagnostic| !is_ignored(diagnostic, source, comments));
+        diagnostics
+    }
+
+    /// The statements of a block, in the current scope. The blocks the parser
+    /// makes up for `for` loops are `synthetic`.
+    fn statements(&mut self, statements: &[StmtId], synthetic: bool) {
+        let mut exited = false;
+        for &stmt in statements {
+            // Only the first of the unreachable statements is reported
+            if std::mem::take(&mut exited) && !synthetic {
+                let line = self.ast.line(stmt);
+                self.warn_at_line(line, DiagnosticCode::UnreachableCode, "Unreachable code.");
+            }
+            self.stmt(stmt);
+            exited = matches!(self.ast[stmt], Stmt::Return { .. } | Stmt::Break { .. });
+        }
+    }
+
+    fn stmt(&mut self, stmt: StmtId) {
+        let ast = self.ast;
+        match &ast[stmt] {
+            Stmt::Block { statements } => {
+                let location = ast.location(stmt);
+                if statements.is_empty() {
+                    self.warn_at_line(location.line, DiagnosticCode::EmptyBlock, "Empty block.");
+                }
+                self.begin_scope();
+                self.statements(statements, location.synthetic);
+                self.end_scope();
+            }
+            Stmt::Var { name, initializer } => {
+                if let Some(initializer) = initializer {
+                    self.expr(*initializer);
+                }
+                self.declare(name, DeclarationKind::Variable);
+            }
+            Stmt::Function { name, .. } => {
+                // Declared first, so the function can call itself
+                self.declare(name, DeclarationKind::Function);
+                self.function(stmt);
+            }
+            Stmt::Class { name, methods, superclass } => {
+                self.declare(name, DeclarationKind::Other);
+                if let Some(superclass) = superclass {
+                    self.expr(*superclass);
+                }
+                for &method in methods {
+                    self.function(method);
+                }
+            }
+            Stmt::Extend { keyword: _, class, methods } => {
+                self.expr(*class);
+                for &method in methods {
+                    self.function(method);
+                }
+            }
+            Stmt::Expression { expr } => self.expr(*expr),
+            Stmt::Print { exprs } => {
+                for &expr in exprs {
+                    self.expr(expr);
+                }
+            }
+            Stmt::Return { keyword: _, value } => {
+                if let Some(value) = value {
+                    self.expr(*value);
+                }
+            }
+            Stmt::If { condition, then_branch, else_branch } => {
+                self.expr(*condition);
+                self.stmt(*then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.stmt(*else_branch);
+                }
+            }
+            Stmt::While { keyword: _, condition, body, else_branch } => {
+                self.expr(*condition);
+                self.stmt(*body);
+                if let Some(else_branch) = else_branch {
+                    self.stmt(*else_branch);
+                }
+            }
+            Stmt::Using { keyword: _, name, initializer, body, close } => {
+                self.expr(*initializer);
+                self.begin_scope();
+                self.declare(name, DeclarationKind::Variable);
+                self.stmt(*body);
+                self.expr(*close);
+                self.end_scope();
+            }
+            Stmt::Break { token: _ } | Stmt::Error => {}
+        }
+    }
+
+    fn function(&mut self, stmt: StmtId) {
+        let Stmt::Function { name: _, params, body } = &self.ast[stmt] else {
+            unreachable!("not a function");
+        };
+
+        self.begin_scope();
+        for param in params {
+            self.declare(param, DeclarationKind::Other);
+        }
+        self.statements(body, false);
+        self.end_scope();
+    }
+
+    fn expr(&mut self, expr: ExprId) {
+        let ast = self.ast;
+        match &ast[expr] {
+            Expr::Binary { left, operator, right } => {
+                if matches!(operator.token_type, TokenType::EqualEqual | TokenType::BangEqual)
+                    && self.has_mixed_constants(*left, *right)
+                {
+                    self.warn(
+                        operator,
+                        DiagnosticCode::MixedTypeEquality,
+                        "Values of different types are never equal.",
+                    );
+                }
+                self.expr(*left);
+                self.expr(*right);
+            }
+            Expr::Logical { left, operator: _, right } => {
+                self.expr(*left);
+                self.expr(*right);
+            }
+            Expr::Grouping { expr } | Expr::Unary { operator: _, right: expr } => self.expr(*expr),
+            Expr::Variable { binding: _, name } => self.use_variable(name),
+            Expr::Assignment { binding: _, name, value } => {
+                if matches!(&ast[*value], Expr::Variable { name: other, .. } if other.lexeme == name.lexeme)
+                {
+                    self.warn(
+                        name,
+                        DiagnosticCode::SelfAssignment,
+                        "Variable is assigned to itself.",
+                    );
+                }
+                self.expr(*value);
+            }
+            Expr::Call { callee, paren: _, arguments } => {
+                self.expr(*callee);
+                for &argument in arguments {
+                    self.expr(argument);
+                }
+            }
+            Expr::Get { object, name: _, cache: _ } => self.expr(*object),
+            Expr::Index { object, bracket: _, index } => {
+                self.expr(*object);
+                self.expr(*index);
+            }
+            Expr::IndexSet { object, bracket: _, index, value } => {
+                self.expr(*object);
+                self.expr(*index);
+                self.expr(*value);
+            }
+            Expr::List { bracket: _, elements } => {
+                for &element in elements {
+                    self.expr(element);
+                }
+            }
+            Expr::Set { object, name, value } => {
+                if let Expr::Get { object: other_object, name: other_name, cache: _ } = &ast[*value]
+                {
+                    if other_name.lexeme == name.lexeme && self.same_object(*object, *other_object)
+                    {
+                        self.warn(
+                            name,
+                            DiagnosticCode::SelfAssignment,
+                            "Property is assigned to itself.",
+                        );
+                    }
+                }
+                self.expr(*object);
+                self.expr(*value);
+            }
+            Expr::Function { keyword: _, declaration } => self.function(*declaration),
+            Expr::Literal { value: _ } | Expr::This { .. } | Expr::Super { .. } | Expr::Error => {}
+        }
+    }
+
+    /// Whether both operands are constants, but of different types.
+    fn has_mixed_constants(&self, left: ExprId, right: ExprId) -> bool {
+        match (self.constant(left), self.constant(right)) {
+            (Some(left), Some(right)) => {
+                std::mem::discriminant(left) != std::mem::discriminant(right)
+            }
+            _ => false,
+        }
+    }
+
+    fn constant(&self, expr: ExprId) -> Option<&'a Literal> {
+        match &self.ast[expr] {
+            Expr::Literal { value } => Some(value),
+            Expr::Grouping { expr } => self.constant(*expr),
+            _ => None,
+        }
+    }
+
+    /// Whether two expressions are the same variable or `this`.
+    fn same_object(&self, a: ExprId, b: ExprId) -> bool {
+        match (&self.ast[a], &self.ast[b]) {
+            (Expr::Variable { name: a, .. }, Expr::Variable { name: b, .. }) => {
+                a.lexeme == b.lexeme
+            }
+            (Expr::This { .. }, Expr::This { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Linter<'_> {
+    fn begin_scope(&mut self) {
+        self.scopes.push(vec![]);
+    }
+
+    /// Report the unused declarations of the innermost scope and leave it.
+    fn end_scope(&mut self) {
+        let scope = self.scopes.pop().expect("no scope to end");
+        for declaration in scope.into_iter().filter(|declaration| !declaration.used) {
+            match declaration.kind {
+                DeclarationKind::Variable => self.warn(
+                    &declaration.name,
+                    DiagnosticCode::UnusedVariable,
+                    "Local variable is never read.",
+                ),
+                DeclarationKind::Function => self.warn(
+                    &declaration.name,
+                    DiagnosticCode::UnusedFunction,
+                    "Local function is never used.",
+                ),
+                DeclarationKind::Other => {}
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &Token, kind: DeclarationKind) {
+        let (scope, enclosing) = self.scopes.split_last_mut().expect("no scope to declare in");
+        let is_global = enclosing.is_empty();
+
+        let shadows = enclosing
+            .iter()
+            .any(|scope| scope.iter().any(|other| other.name.lexeme == name.lexeme));
+        // Globals can be used anywhere, even before they are declared
+        scope.push(Declaration { name: name.clone(), kind, used: is_global });
+
+        if shadows && kind != DeclarationKind::Other {
+            self.warn(
+                name,
+                DiagnosticCode::ShadowedVariable,
+                "Shadows a variable of an enclosing scope.",
+            );
+        }
+    }
+
+    /// Mark the innermost declaration with the name as used.
+    fn use_variable(&mut self, name: &Token) {
+        let declaration = self
+            .scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.iter_mut().rev().find(|d| d.name.lexeme == name.lexeme));
+        if let Some(declaration) = declaration {
+            declaration.used = true;
+        }
+    }
+
+    fn warn(&mut self, token: &Token, code: DiagnosticCode, msg: &str) {
+        let diagnostic = Diagnostic::new(Phase::Lint, code, token.line, msg)
+            .with_position(token.column, token.span)
+            .with_location(format!("at '{}'", token.lexeme));
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// Report a problem with a statement, which has no token of its own.
+    fn warn_at_line(&mut self, line: u32, code: DiagnosticCode, msg: &str) {
+        self.diagnostics.push(Diagnostic::new(Phase::Lint, code, line, msg));
+    }
+}
+
+/// Collect the names of the globals a statement reads, in the resolved tree.
+fn stmt_globals<'a>(ast: &'a Ast, stmt: StmtId, globals: &mut Vec<&'a str>) {
+    match &ast[stmt] {
+        Stmt::Expression { expr }
+        | Stmt::Var { name: _, initializer: Some(expr) }
+        | Stmt::Return { keyword: _, value: Some(expr) } => expr_globals(ast, *expr, globals),
+        Stmt::Print { exprs } => {
+            for &expr in exprs {
+                expr_globals(ast, expr, globals);
+            }
+        }
+        Stmt::Block { statements } | Stmt::Function { body: statements, .. } => {
+            for &stmt in statements {
+                stmt_globals(ast, stmt, globals);
+            }
+        }
+        Stmt::Class { name: _, methods, superclass } => {
+            if let Some(superclass) = superclass {
+                expr_globals(ast, *superclass, globals);
+            }
+            for &method in methods {
+                stmt_globals(ast, method, globals);
+            }
+        }
+        Stmt::Extend { keyword: _, class, methods } => {
+            expr_globals(ast, *class, globals);
+            for &method in methods {
+                stmt_globals(ast, method, globals);
+            }
+        }
+        Stmt::If { condition, then_branch, else_branch } => {
+            expr_globals(ast, *condition, globals);
+            stmt_globals(ast, *then_branch, globals);
+            if let Some(else_branch) = else_branch {
+                stmt_globals(ast, *else_branch, globals);
+            }
+        }
+        Stmt::While { keyword: _, condition, body, else_branch } => {
+            expr_globals(ast, *condition, globals);
+            stmt_globals(ast, *body, globals);
+            if let Some(else_branch) = else_branch {
+                stmt_globals(ast, *else_branch, globals);
+            }
+        }
+        Stmt::Using { keyword: _, name: _, initializer, body, close: _ } => {
+            expr_globals(ast, *initializer, globals);
+            stmt_globals(ast, *body, globals);
+        }
+        Stmt::Var { name: _, initializer: None }
+        | Stmt::Return { keyword: _, value: None }
+        | Stmt::Break { token: _ }
+        | Stmt::Error => {}
+    }
+}
+
+fn expr_globals<'a>(ast: &'a Ast, expr: ExprId, globals: &mut Vec<&'a str>) {
+    let mut visit = |expr| expr_globals(ast, expr, globals);
+    match &ast[expr] {
+        // Variables the resolver didn't bind to a local are globals
+        Expr::Variable { binding, name } => {
+            if binding.resolution().is_none() {
+                globals.push(&name.lexeme);
+            }
+        }
+        Expr::Binary { left, operator: _, right } | Expr::Logical { left, operator: _, right } => {
+            visit(*left);
+            visit(*right);
+        }
+        Expr::Call { callee, paren: _, arguments } => {
+            visit(*callee);
+            arguments.iter().for_each(|&argument| visit(argument));
+        }
+        Expr::Set { object, name: _, value } | Expr::Index { object, bracket: _, index: value } => {
+            visit(*object);
+            visit(*value);
+        }
+        Expr::IndexSet { object, bracket: _, index, value } => {
+            visit(*object);
+            visit(*index);
+            visit(*value);
+        }
+        Expr::List { bracket: _, elements } => elements.iter().for_each(|&element| visit(element)),
+        Expr::Get { object: expr, name: _, cache: _ }
+        | Expr::Grouping { expr }
+        | Expr::Unary { operator: _, right: expr }
+        | Expr::Assignment { binding: _, name: _, value: expr } => visit(*expr),
+        Expr::Function { keyword: _, declaration } => stmt_globals(ast, *declaration, globals),
+        Expr::Literal { value: _ } | Expr::This { .. } | Expr::Super { .. } | Expr::Error => {}
+    }
+}
+
+/// Whether a `lox-ignore` comment on the line of the diagnostic, or alone on
+/// the line before, turns off its rule.
+fn is_ignored(diagnostic: &Diagnostic, source: &str, comments: &[Token]) -> bool {
+    let Some(rule) = LintRule::from_code(diagnostic.code) else {
+        return false;
+    };
+
+    comments
+        .iter()
+        .filter(|comment| {
+            comment.line == diagnostic.line
+                || comment.line + 1 == diagnostic.line && is_alone(comment, source)
+        })
+        .filter_map(|comment| {
+            let text = comment.lexeme.trim_start_matches('/').trim();
+            text.strip_prefix("lox-ignore:")
+        })
+        .flat_map(|rules| rules.split(','))
+        .any(|name| LintRule::from_name(name.trim()) == Some(rule))
+}
+
+/// Whether nothing comes before the comment on its line.
+fn is_alone(comment: &Token, source: &str) -> bool {
+    let before = &source[..comment.span.start];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    before[line_start..].trim().is_empty()
+}
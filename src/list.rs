@@ -0,0 +1,85 @@
+//! Lists of values, made by the `List()` native or returned by
+//! `split(string, separator)`.
+//!
+//! Lists have methods, like instances, but no fields:
+//!
+//! - `push(value)` adds a value at the end and `pop()` removes and returns the
+//!   last one.
+//! - `get(index)` and `set(index, value)` read and replace the value at an
+//!   index, from 0. Negative indexes count from the end.
+//! - `length()` is the number of values.
+//! - `forEach(function)` calls the function with each value.
+//!
+//! `join(list, separator)` makes a string of a list of strings, and
+//! `join(split(s, separator), separator)` is `s` again.
+
+use std::fmt::Display;
+
+use crate::sync::RefCell;
+
+/// The methods of lists, with their number of arguments.
+const METHODS: [(&str, usize); 6] =
+    [("push", 1), ("pop", 0), ("get", 1), ("set", 2), ("length", 0), ("forEach", 1)];
+
+/// The number of arguments of the method with this name, if lists have one.
+pub(crate) fn arity(method: &str) -> Option<usize> {
+    METHODS.iter().find(|(name, _)| *name == method).map(|(_, arity)| *arity)
+}
+
+/// The names of the methods, for the suggestions of the errors.
+pub(crate) fn method_names() -> Vec<String> {
+    METHODS.iter().map(|(name, _)| name.to_string()).collect()
+}
+
+/// The message of the error for calling `pop` on an empty list.
+pub(crate) const EMPTY: &str = "Can't pop from an empty list.";
+/// The message of the error for indexing something that isn't a list.
+pub(crate) const NOT_INDEXABLE: &str = "Only lists can be indexed.";
+/// The message of the error for an index that isn't a number.
+pub(crate) const NOT_AN_INDEX: &str = "The index must be a number.";
+/// The messages of the errors of `split` and `join`.
+pub(crate) const NOT_A_STRING: &str = "The first argument must be a string.";
+pub(crate) const NOT_A_LIST: &str = "The first argument must be a list.";
+pub(crate) const INVALID_SEPARATOR: &str = "The separator must be a string.";
+pub(crate) const NOT_ALL_STRINGS: &str = "Only lists of strings can be joined.";
+
+/// The position in a list of `length` values of `index`, which counts from
+/// the end if it's negative, or the message of the error if there's none.
+pub(crate) fn position(index: f64, length: usize) -> Result<usize, String> {
+    if index.fract() != 0.0 {
+        return Err(format!("The index must be an integer, not {index}."));
+    }
+    let position = if index < 0.0 { index + length as f64 } else { index };
+    if position < 0.0 || position >= length as f64 {
+        return Err(format!("Index {index} is out of bounds for a list of length {length}."));
+    }
+    Ok(position as usize)
+}
+
+/// The parts of a string around a separator. An empty separator splits the
+/// string into its characters.
+pub(crate) fn split(string: &str, separator: &str) -> Vec<String> {
+    if separator.is_empty() {
+        string.chars().map(String::from).collect()
+    } else {
+        string.split(separator).map(str::to_owned).collect()
+    }
+}
+
+/// How lists print, given how their values print. A list that contains
+/// itself prints as `[...]` inside itself, instead of recursing forever.
+pub(crate) fn display<T: Display>(list: &RefCell<Vec<T>>) -> String {
+    let address = list as *const RefCell<Vec<T>> as usize;
+    if PRINTING.with(|printing| printing.borrow().contains(&address)) {
+        return "[...]".to_owned();
+    }
+    PRINTING.with(|printing| printing.borrow_mut().push(address));
+    let values: Vec<String> = list.borrow().iter().map(T::to_string).collect();
+    PRINTING.with(|printing| printing.borrow_mut().retain(|a| *a != address));
+    format!("[{}]", values.join(", "))
+}
+
+thread_local! {
+    /// The addresses of the lists being printed by this thread.
+    static PRINTING: std::cell::RefCell<Vec<usize>> = const { std::cell::RefCell::new(Vec::new()) };
+}
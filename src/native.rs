@@ -1,9 +1,15 @@
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use super::*;
+use crate::class::undefined_property;
+use crate::gc::{Kind, Trace};
 use crate::object::Object;
-use crate::prelude::Callable;
+use crate::prelude::{Callable, Capability, Class, DiagnosticCode, Instance, Key, LoxSet, Token};
+use crate::sync::{RefCell, Weak};
+use crate::testing::{self, TestResult};
+use crate::{Shared, list, set, string_builder, weak_ref};
 
 #[derive(Debug)]
 struct Clock;
@@ -17,7 +23,7 @@ impl Callable for Clock {
         &self,
         _interpret: &mut Interpreter,
         _arguments: &[Object],
-    ) -> Result<Object, RuntimeInterrupt> {
+    ) -> Result<Object, RuntimeError> {
         let start = SystemTime::now();
         let since_epoch = start.duration_since(UNIX_EPOCH).expect("Time went backward");
 
@@ -34,3 +40,1009 @@ impl Display for Clock {
 pub fn clock() -> Rc<dyn Callable> {
     Rc::new(Clock)
 }
+
+/// Run the garbage collector and return the number of objects it freed.
+#[derive(Debug)]
+struct GcCollect;
+
+impl Callable for GcCollect {
+    fn arity(&self) -> usize {
+        0
+    }
+
+    fn call(
+        &self,
+        interpret: &mut Interpreter,
+        _arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        Ok(Object::Number(interpret.collect_garbage() as f64))
+    }
+}
+
+impl Display for GcCollect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn gc_collect() -> Rc<dyn Callable> {
+    Rc::new(GcCollect)
+}
+
+/// Return the statistics of the heap, as the fields of a `MemoryStats`
+/// instance.
+#[derive(Debug)]
+struct MemoryStats;
+
+impl Callable for MemoryStats {
+    fn arity(&self) -> usize {
+        0
+    }
+
+    fn call(
+        &self,
+        interpret: &mut Interpreter,
+        _arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        let stats = interpret.heap_stats();
+
+        let class = Rc::new(RefCell::new(Class::new("MemoryStats", HashMap::new(), None)));
+        interpret.heap.track(&class);
+
+        let instance = Rc::new(RefCell::new(Instance::new(class)));
+        for (name, value) in stats.fields() {
+            instance.borrow_mut().set_field(name, Object::Number(value as f64));
+        }
+        interpret.heap.track(&instance);
+
+        Ok(Object::Instance(instance))
+    }
+}
+
+impl Display for MemoryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn memory_stats() -> Rc<dyn Callable> {
+    Rc::new(MemoryStats)
+}
+
+/// Return the doc comment of a function, method or class, or `nil` if it has
+/// none.
+#[derive(Debug)]
+struct Doc;
+
+impl Callable for Doc {
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn call(
+        &self,
+        _interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        Ok(arguments[0].doc().map_or(Object::Null, |doc| Object::String(doc.clone())))
+    }
+}
+
+impl Display for Doc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn doc() -> Rc<dyn Callable> {
+    Rc::new(Doc)
+}
+
+/// Run a function as a named test, recording whether it fails, and return
+/// whether it passed.
+#[derive(Debug)]
+struct Test;
+
+impl Callable for Test {
+    fn arity(&self) -> usize {
+        2
+    }
+
+    fn call(
+        &self,
+        interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        let Object::String(name) = &arguments[0] else {
+            return Err(RuntimeError::native(
+                DiagnosticCode::InvalidArgument,
+                testing::INVALID_TEST_NAME,
+            ));
+        };
+        let function = match &arguments[1] {
+            Object::Callable(function) if function.arity() == 0 => function,
+            _ => {
+                return Err(RuntimeError::native(
+                    DiagnosticCode::InvalidArgument,
+                    testing::INVALID_TEST_FUNCTION,
+                ));
+            }
+        };
+
+        let failure = match function.call(interpret, &[]) {
+            Ok(_) => None,
+            Err(e) if testing::stops_run(e.code) => {
+                return Err(e);
+            }
+            Err(e) => Some(e.to_string()),
+        };
+
+        let passed = failure.is_none();
+        interpret.test_results.push(TestResult { name: name.to_string(), failure });
+        Ok(Object::Boolean(passed))
+    }
+}
+
+impl Display for Test {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn test() -> Rc<dyn Callable> {
+    Rc::new(Test)
+}
+
+/// Fail unless the two values are equal.
+#[derive(Debug)]
+struct AssertEq;
+
+impl Callable for AssertEq {
+    fn arity(&self) -> usize {
+        2
+    }
+
+    fn call(
+        &self,
+        _interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        let (actual, expected) = (&arguments[0], &arguments[1]);
+        if actual != expected {
+            return Err(RuntimeError::native(
+                DiagnosticCode::AssertionFailed,
+                testing::assert_eq_message(actual, expected),
+            ));
+        }
+        Ok(Object::Null)
+    }
+}
+
+impl Display for AssertEq {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn assert_eq() -> Rc<dyn Callable> {
+    Rc::new(AssertEq)
+}
+
+/// Fail unless the value is truthy.
+#[derive(Debug)]
+struct AssertTrue;
+
+impl Callable for AssertTrue {
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn call(
+        &self,
+        interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        if !interpret.is_truthy(&arguments[0]) {
+            return Err(RuntimeError::native(
+                DiagnosticCode::AssertionFailed,
+                testing::assert_true_message(&arguments[0]),
+            ));
+        }
+        Ok(Object::Null)
+    }
+}
+
+impl Display for AssertTrue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn assert_true() -> Rc<dyn Callable> {
+    Rc::new(AssertTrue)
+}
+
+/// The message of the error for passing something that can't be called to
+/// `arity` or `name`.
+pub(crate) const NOT_CALLABLE: &str = "The argument must be a function or a class.";
+/// The messages of the errors of `bindMethod`.
+pub(crate) const NOT_AN_INSTANCE: &str = "Only instances have methods.";
+pub(crate) const INVALID_METHOD_NAME: &str = "The name of a method must be a string.";
+
+pub(crate) fn undefined_method(name: &str) -> String {
+    format!("Undefined method '{name}'.")
+}
+
+/// Return a method of an instance, bound to the instance.
+#[derive(Debug)]
+struct BindMethod;
+
+impl Callable for BindMethod {
+    fn arity(&self) -> usize {
+        2
+    }
+
+    fn call(
+        &self,
+        interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        let Object::Instance(instance) = &arguments[0] else {
+            return Err(RuntimeError::native(DiagnosticCode::NotAnInstance, NOT_AN_INSTANCE));
+        };
+        let Object::String(name) = &arguments[1] else {
+            return Err(RuntimeError::native(DiagnosticCode::InvalidArgument, INVALID_METHOD_NAME));
+        };
+
+        match instance.borrow().bind_method(name, &arguments[0], &mut interpret.heap) {
+            Some(method) => Ok(Object::Callable(method)),
+            None => {
+                Err(RuntimeError::native(DiagnosticCode::UndefinedProperty, undefined_method(name)))
+            }
+        }
+    }
+}
+
+impl Display for BindMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn bind_method() -> Rc<dyn Callable> {
+    Rc::new(BindMethod)
+}
+
+/// Return the number of arguments a function or a class takes.
+#[derive(Debug)]
+struct Arity;
+
+impl Callable for Arity {
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn call(
+        &self,
+        _interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        match arguments[0].arity() {
+            Some(arity) => Ok(Object::Number(arity as f64)),
+            None => Err(RuntimeError::native(DiagnosticCode::InvalidArgument, NOT_CALLABLE)),
+        }
+    }
+}
+
+impl Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn arity() -> Rc<dyn Callable> {
+    Rc::new(Arity)
+}
+
+/// Return the name of a function or a class, or `nil` for natives.
+#[derive(Debug)]
+struct Name;
+
+impl Callable for Name {
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn call(
+        &self,
+        _interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        if arguments[0].arity().is_none() {
+            return Err(RuntimeError::native(DiagnosticCode::InvalidArgument, NOT_CALLABLE));
+        }
+        Ok(arguments[0].name().map_or(Object::Null, |name| Object::String(name.into())))
+    }
+}
+
+impl Display for Name {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn name() -> Rc<dyn Callable> {
+    Rc::new(Name)
+}
+
+/// Return a function that calls the given one once per list of arguments, and
+/// returns the result of that call again after that.
+#[derive(Debug)]
+struct Memoize;
+
+impl Callable for Memoize {
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn call(
+        &self,
+        interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        if arguments[0].arity().is_none() {
+            return Err(RuntimeError::native(DiagnosticCode::InvalidArgument, NOT_CALLABLE));
+        }
+
+        let memoized =
+            Rc::new(Memoized { function: arguments[0].clone(), cache: RefCell::default() });
+        interpret.heap.track(&memoized);
+        Ok(Object::Callable(memoized))
+    }
+}
+
+impl Display for Memoize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn memoize() -> Rc<dyn Callable> {
+    Rc::new(Memoize)
+}
+
+/// Call a function or a class, whose arity has been checked.
+fn call(
+    interpret: &mut Interpreter,
+    function: &Object,
+    arguments: &[Object],
+) -> Result<Object, RuntimeError> {
+    match function {
+        Object::Callable(callable) => callable.call(interpret, arguments),
+        Object::Class(class) => {
+            Class::construct(class.clone(), arguments, interpret).map(Object::Instance)
+        }
+        _ => unreachable!("only functions and classes can be called"),
+    }
+}
+
+/// The value as the key of a hash map, or an error if it can't be one.
+pub(crate) fn key(value: &Object) -> Result<Key, RuntimeError> {
+    value.key().ok_or_else(|| {
+        RuntimeError::native(DiagnosticCode::UnhashableKey, crate::key::unhashable(value))
+    })
+}
+
+/// A function or a class wrapped by `memoize`, with the results of its calls.
+#[derive(Debug)]
+struct Memoized {
+    function: Object,
+    /// The results of the calls, by their arguments
+    cache: RefCell<HashMap<Vec<Key>, Object>>,
+}
+
+impl Callable for Memoized {
+    fn arity(&self) -> usize {
+        self.function.arity().unwrap_or_default()
+    }
+
+    fn name(&self) -> Option<&str> {
+        match &self.function {
+            Object::Callable(callable) => callable.name(),
+            _ => None,
+        }
+    }
+
+    fn call(
+        &self,
+        interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        let key = arguments.iter().map(key).collect::<Result<Vec<_>, _>>()?;
+        if let Some(result) = self.cache.borrow().get(&key) {
+            return Ok(result.clone());
+        }
+
+        let result = call(interpret, &self.function, arguments)?;
+        self.cache.borrow_mut().insert(key, result.clone());
+        Ok(result)
+    }
+}
+
+impl Display for Memoized {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.function)
+    }
+}
+
+impl Trace for Memoized {
+    fn kind(&self) -> Kind {
+        Kind::Function
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        self.function.trace(visit);
+        if let Ok(cache) = self.cache.try_borrow() {
+            cache.values().for_each(|result| result.trace(visit));
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut cache) = self.cache.try_borrow_mut() {
+            cache.clear();
+        }
+    }
+}
+
+/// Return a new empty set.
+#[derive(Debug)]
+struct NewSet;
+
+impl Callable for NewSet {
+    fn arity(&self) -> usize {
+        0
+    }
+
+    fn call(
+        &self,
+        _interpret: &mut Interpreter,
+        _arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        Ok(Object::Set(Rc::new(RefCell::new(LoxSet::new()))))
+    }
+}
+
+impl Display for NewSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn new_set() -> Rc<dyn Callable> {
+    Rc::new(NewSet)
+}
+
+/// Return a new empty list.
+#[derive(Debug)]
+struct NewList;
+
+impl Callable for NewList {
+    fn arity(&self) -> usize {
+        0
+    }
+
+    fn call(
+        &self,
+        interpret: &mut Interpreter,
+        _arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        Ok(new_list(interpret, vec![]))
+    }
+}
+
+impl Display for NewList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn list() -> Rc<dyn Callable> {
+    Rc::new(NewList)
+}
+
+pub(crate) fn new_list(interpret: &mut Interpreter, values: Vec<Object>) -> Object {
+    let list = Rc::new(RefCell::new(values));
+    interpret.heap.track(&list);
+    Object::List(list)
+}
+
+/// Return a new empty string builder.
+#[derive(Debug)]
+struct NewStringBuilder;
+
+impl Callable for NewStringBuilder {
+    fn arity(&self) -> usize {
+        0
+    }
+
+    fn call(
+        &self,
+        _interpret: &mut Interpreter,
+        _arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        Ok(Object::StringBuilder(Rc::new(RefCell::new(String::new()))))
+    }
+}
+
+impl Display for NewStringBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn string_builder() -> Rc<dyn Callable> {
+    Rc::new(NewStringBuilder)
+}
+
+pub(crate) const NOT_WEAKLY_REFERABLE: &str = "Can only make weak references to instances.";
+
+/// Return a weak reference to an instance.
+#[derive(Debug)]
+struct NewWeakRef;
+
+impl Callable for NewWeakRef {
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn call(
+        &self,
+        _interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        let Object::Instance(instance) = &arguments[0] else {
+            return Err(RuntimeError::native(DiagnosticCode::NotAnInstance, NOT_WEAKLY_REFERABLE));
+        };
+        Ok(Object::WeakRef(Rc::downgrade(instance)))
+    }
+}
+
+impl Display for NewWeakRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn weak_ref() -> Rc<dyn Callable> {
+    Rc::new(NewWeakRef)
+}
+
+/// Return the parts of a string around a separator, as a list.
+#[derive(Debug)]
+struct Split;
+
+impl Callable for Split {
+    fn arity(&self) -> usize {
+        2
+    }
+
+    fn call(
+        &self,
+        interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        let Object::String(string) = &arguments[0] else {
+            return Err(RuntimeError::native(DiagnosticCode::InvalidArgument, list::NOT_A_STRING));
+        };
+        let Object::String(separator) = &arguments[1] else {
+            return Err(RuntimeError::native(
+                DiagnosticCode::InvalidArgument,
+                list::INVALID_SEPARATOR,
+            ));
+        };
+
+        let parts = list::split(string, separator)
+            .into_iter()
+            .map(|part| Object::String(part.into()))
+            .collect();
+        Ok(new_list(interpret, parts))
+    }
+}
+
+impl Display for Split {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn split() -> Rc<dyn Callable> {
+    Rc::new(Split)
+}
+
+/// Return the strings of a list, with a separator between them.
+#[derive(Debug)]
+struct Join;
+
+impl Callable for Join {
+    fn arity(&self) -> usize {
+        2
+    }
+
+    fn call(
+        &self,
+        _interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        let Object::List(values) = &arguments[0] else {
+            return Err(RuntimeError::native(DiagnosticCode::InvalidArgument, list::NOT_A_LIST));
+        };
+        let Object::String(separator) = &arguments[1] else {
+            return Err(RuntimeError::native(
+                DiagnosticCode::InvalidArgument,
+                list::INVALID_SEPARATOR,
+            ));
+        };
+
+        let values = values.borrow();
+        let strings = values.iter().map(|value| match value {
+            Object::String(s) => Ok(&**s),
+            _ => Err(RuntimeError::native(DiagnosticCode::InvalidArgument, list::NOT_ALL_STRINGS)),
+        });
+        Ok(Object::String(strings.collect::<Result<Vec<_>, _>>()?.join(separator).into()))
+    }
+}
+
+impl Display for Join {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn join() -> Rc<dyn Callable> {
+    Rc::new(Join)
+}
+
+/// The method of a set or a list with this name, bound to it.
+pub(crate) fn method(receiver: &Object, name: &Token) -> Result<Object, RuntimeError> {
+    let names = match receiver {
+        Object::Set(_) if LoxSet::arity(&name.lexeme).is_some() => None,
+        Object::Set(_) => Some(LoxSet::method_names()),
+        Object::List(_) if list::arity(&name.lexeme).is_some() => None,
+        Object::List(_) => Some(list::method_names()),
+        Object::StringBuilder(_) if string_builder::arity(&name.lexeme).is_some() => None,
+        Object::StringBuilder(_) => Some(string_builder::method_names()),
+        Object::WeakRef(_) if weak_ref::arity(&name.lexeme).is_some() => None,
+        Object::WeakRef(_) => Some(weak_ref::method_names()),
+        _ => unreachable!("only sets, lists, builders and weak references have native methods"),
+    };
+    if let Some(names) = names {
+        return Err(undefined_property(name, &names));
+    }
+
+    let method = NativeMethod { receiver: receiver.clone(), name: name.lexeme.clone() };
+    Ok(Object::Callable(Rc::new(method)))
+}
+
+/// A method of a set, a list, a builder or a weak reference, see
+/// [`crate::set`], [`crate::list`], [`crate::string_builder`] and
+/// [`crate::weak_ref`].
+#[derive(Debug)]
+struct NativeMethod {
+    receiver: Object,
+    name: Rc<str>,
+}
+
+impl Callable for NativeMethod {
+    fn arity(&self) -> usize {
+        let arity = match &self.receiver {
+            Object::Set(_) => LoxSet::arity(&self.name),
+            Object::List(_) => list::arity(&self.name),
+            Object::StringBuilder(_) => string_builder::arity(&self.name),
+            Object::WeakRef(_) => weak_ref::arity(&self.name),
+            _ => None,
+        };
+        arity.expect("the receiver has this method")
+    }
+
+    fn call(
+        &self,
+        interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        match &self.receiver {
+            Object::Set(set) => set_method(interpret, set, &self.name, arguments),
+            Object::List(list) => list_method(interpret, list, &self.name, arguments),
+            Object::StringBuilder(builder) => {
+                Ok(string_builder_method(builder, &self.name, arguments))
+            }
+            Object::WeakRef(weak) => Ok(weak_ref_method(weak, &self.name)),
+            _ => unreachable!("only sets, lists, builders and weak references have native methods"),
+        }
+    }
+}
+
+impl Display for NativeMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+/// The message of the error for passing something to the `forEach` method of
+/// a set or a list that can't be called with a value.
+pub(crate) const INVALID_CALLBACK: &str = "The argument must be a function of one argument.";
+
+/// Fail unless the argument of `forEach` can be called with a value.
+fn check_callback(callback: &Object) -> Result<(), RuntimeError> {
+    if callback.arity() != Some(1) {
+        return Err(RuntimeError::native(DiagnosticCode::InvalidArgument, INVALID_CALLBACK));
+    }
+    Ok(())
+}
+
+fn set_method(
+    interpret: &mut Interpreter,
+    set: &Shared<LoxSet>,
+    name: &str,
+    arguments: &[Object],
+) -> Result<Object, RuntimeError> {
+    let other = |argument: &Object| match argument {
+        Object::Set(other) => Ok(other.clone()),
+        _ => Err(RuntimeError::native(DiagnosticCode::InvalidArgument, set::NOT_A_SET)),
+    };
+
+    let result = match name {
+        // The key is made before borrowing the set, whose error message
+        // prints the argument, which may be the set itself
+        "add" => {
+            let key = key(&arguments[0])?;
+            set.borrow_mut().add(key);
+            Object::Null
+        }
+        "has" => {
+            let key = key(&arguments[0])?;
+            Object::Boolean(set.borrow().has(&key))
+        }
+        "remove" => {
+            let key = key(&arguments[0])?;
+            Object::Boolean(set.borrow_mut().remove(&key))
+        }
+        "union" => {
+            let union = set.borrow().union(&other(&arguments[0])?.borrow());
+            Object::Set(Rc::new(RefCell::new(union)))
+        }
+        "intersect" => {
+            let intersection = set.borrow().intersect(&other(&arguments[0])?.borrow());
+            Object::Set(Rc::new(RefCell::new(intersection)))
+        }
+        "size" => Object::Number(set.borrow().len() as f64),
+        "forEach" => {
+            check_callback(&arguments[0])?;
+            // The function may change the set
+            let values = set.borrow().iter().cloned().collect::<Vec<_>>();
+            for value in values {
+                call(interpret, &arguments[0], &[Object::from(value)])?;
+            }
+            Object::Null
+        }
+        _ => unreachable!("sets have no method '{name}'"),
+    };
+    Ok(result)
+}
+
+fn list_method(
+    interpret: &mut Interpreter,
+    values: &Shared<Vec<Object>>,
+    name: &str,
+    arguments: &[Object],
+) -> Result<Object, RuntimeError> {
+    let position = |index: &Object| {
+        let Object::Number(index) = index else {
+            return Err(RuntimeError::native(DiagnosticCode::InvalidArgument, list::NOT_AN_INDEX));
+        };
+        list::position(*index, values.borrow().len())
+            .map_err(|message| RuntimeError::native(DiagnosticCode::InvalidArgument, message))
+    };
+
+    let result = match name {
+        "push" => {
+            values.borrow_mut().push(arguments[0].clone());
+            Object::Null
+        }
+        "pop" => values
+            .borrow_mut()
+            .pop()
+            .ok_or_else(|| RuntimeError::native(DiagnosticCode::InvalidArgument, list::EMPTY))?,
+        "get" => values.borrow()[position(&arguments[0])?].clone(),
+        "set" => {
+            let position = position(&arguments[0])?;
+            values.borrow_mut()[position] = arguments[1].clone();
+            arguments[1].clone()
+        }
+        "length" => Object::Number(values.borrow().len() as f64),
+        "forEach" => {
+            check_callback(&arguments[0])?;
+            // The function may change the list
+            let values = values.borrow().clone();
+            for value in values {
+                call(interpret, &arguments[0], &[value])?;
+            }
+            Object::Null
+        }
+        _ => unreachable!("lists have no method '{name}'"),
+    };
+    Ok(result)
+}
+
+fn string_builder_method(builder: &Shared<String>, name: &str, arguments: &[Object]) -> Object {
+    match name {
+        "append" => {
+            match &arguments[0] {
+                Object::String(s) => builder.borrow_mut().push_str(s),
+                // The value may be the builder itself
+                value => {
+                    let text = value.to_string();
+                    builder.borrow_mut().push_str(&text);
+                }
+            }
+            Object::StringBuilder(builder.clone())
+        }
+        "length" => Object::Number(builder.borrow().chars().count() as f64),
+        "toString" => Object::String(builder.borrow().as_str().into()),
+        _ => unreachable!("builders have no method '{name}'"),
+    }
+}
+
+fn weak_ref_method(weak: &Weak<RefCell<Instance>>, name: &str) -> Object {
+    match name {
+        "get" => weak.upgrade().map_or(Object::Null, Object::Instance),
+        _ => unreachable!("weak references have no method '{name}'"),
+    }
+}
+
+/// A native whose capability is denied, failing whenever it's called.
+#[derive(Debug)]
+struct Denied {
+    capability: Capability,
+    arity: usize,
+}
+
+impl Callable for Denied {
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    fn call(
+        &self,
+        _interpret: &mut Interpreter,
+        _arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        Err(RuntimeError::native(
+            DiagnosticCode::CapabilityDenied,
+            self.capability.denied_message(),
+        ))
+    }
+}
+
+impl Display for Denied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+pub fn denied(capability: Capability, arity: usize) -> Rc<dyn Callable> {
+    Rc::new(Denied { capability, arity })
+}
+
+/// Send a GET request and return the response, see [`crate::net`].
+#[cfg(feature = "net")]
+#[derive(Debug)]
+struct HttpGet;
+
+#[cfg(feature = "net")]
+impl Callable for HttpGet {
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn call(
+        &self,
+        interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        let Object::String(url) = &arguments[0] else {
+            return Err(RuntimeError::native(
+                DiagnosticCode::InvalidArgument,
+                crate::net::not_a_string("The URL"),
+            ));
+        };
+        http_response(interpret, crate::net::get(url))
+    }
+}
+
+#[cfg(feature = "net")]
+impl Display for HttpGet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+#[cfg(feature = "net")]
+pub fn http_get() -> Rc<dyn Callable> {
+    Rc::new(HttpGet)
+}
+
+/// Send a POST request with the body and return the response.
+#[cfg(feature = "net")]
+#[derive(Debug)]
+struct HttpPost;
+
+#[cfg(feature = "net")]
+impl Callable for HttpPost {
+    fn arity(&self) -> usize {
+        2
+    }
+
+    fn call(
+        &self,
+        interpret: &mut Interpreter,
+        arguments: &[Object],
+    ) -> Result<Object, RuntimeError> {
+        let (Object::String(url), Object::String(body)) = (&arguments[0], &arguments[1]) else {
+            let what =
+                if matches!(arguments[0], Object::String(_)) { "The body" } else { "The URL" };
+            return Err(RuntimeError::native(
+                DiagnosticCode::InvalidArgument,
+                crate::net::not_a_string(what),
+            ));
+        };
+        http_response(interpret, crate::net::post(url, body))
+    }
+}
+
+#[cfg(feature = "net")]
+impl Display for HttpPost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+#[cfg(feature = "net")]
+pub fn http_post() -> Rc<dyn Callable> {
+    Rc::new(HttpPost)
+}
+
+/// Turn the response into a `Response` instance.
+#[cfg(feature = "net")]
+fn http_response(
+    interpret: &mut Interpreter,
+    response: Result<crate::net::Response, crate::net::RequestError>,
+) -> Result<Object, RuntimeError> {
+    let response = response.map_err(|e| {
+        RuntimeError::native(DiagnosticCode::RequestFailed, e.message).with_cause(e.cause)
+    })?;
+
+    let headers =
+        response.headers.into_iter().map(|(name, value)| (name, Object::String(value.into())));
+    let headers = new_instance(interpret, "Headers", headers.collect());
+    let fields = vec![
+        ("status".to_owned(), Object::Number(response.status as f64)),
+        ("headers".to_owned(), headers),
+        ("body".to_owned(), Object::String(response.body.into())),
+    ];
+    Ok(new_instance(interpret, "Response", fields))
+}
+
+/// Make an instance of a new class with the fields.
+#[cfg(feature = "net")]
+fn new_instance(interpret: &mut Interpreter, class: &str, fields: Vec<(String, Object)>) -> Object {
+    let class = Rc::new(RefCell::new(Class::new(class, HashMap::new(), None)));
+    interpret.heap.track(&class);
+
+    let instance = Rc::new(RefCell::new(Instance::new(class)));
+    for (name, value) in fields {
+        instance.borrow_mut().set_field(&name, value);
+    }
+    interpret.heap.track(&instance);
+
+    Object::Instance(instance)
+}
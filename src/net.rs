@@ -0,0 +1,64 @@
+//! HTTP requests for the `httpGet(url)` and `httpPost(url, body)` natives,
+//! enabled by the `net` feature.
+//!
+//! Both natives return a `Response` instance with the `status` code, the
+//! `headers` and the `body` of the response. Headers are the fields of a
+//! `Headers` instance, named in lowercase with dashes replaced by underscores,
+//! e.g. `response.headers.content_type`. Responses with an error status are
+//! returned too, only failing to get a response at all is a runtime error.
+
+use crate::error::ErrorCause;
+
+/// A response, before it becomes a `Response` instance of either backend.
+pub(crate) struct Response {
+    pub status: u16,
+    /// The names of the fields of the `Headers` instance, with their values
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// Why there is no response: the message of the runtime error, and the error
+/// of the HTTP client that causes it.
+pub(crate) struct RequestError {
+    pub message: String,
+    pub cause: ErrorCause,
+}
+
+pub(crate) fn get(url: &str) -> Result<Response, RequestError> {
+    response(ureq::get(url).call())
+}
+
+pub(crate) fn post(url: &str, body: &str) -> Result<Response, RequestError> {
+    response(ureq::post(url).send_string(body))
+}
+
+fn response(response: Result<ureq::Response, ureq::Error>) -> Result<Response, RequestError> {
+    let response = match response {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(ureq::Error::Transport(e)) => {
+            let message = format!("Request failed: {e}.");
+            return Err(RequestError { message, cause: e.into() });
+        }
+    };
+
+    let status = response.status();
+    let headers = response
+        .headers_names()
+        .into_iter()
+        .map(|name| {
+            let value = response.header(&name).unwrap_or_default().to_owned();
+            (name.to_lowercase().replace('-', "_"), value)
+        })
+        .collect();
+    let body = response.into_string().map_err(|e| RequestError {
+        message: format!("Failed to read the response: {e}."),
+        cause: e.into(),
+    })?;
+
+    Ok(Response { status, headers, body })
+}
+
+/// The message of the error for an argument that isn't a string.
+pub(crate) fn not_a_string(what: &str) -> String {
+    format!("{what} must be a string.")
+}
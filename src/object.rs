@@ -1,17 +1,25 @@
 use std::fmt::Display;
-use std::rc::Rc;
 
+use crate::gc::{self, Kind, Trace};
+use crate::list;
 use crate::prelude::*;
+use crate::semantics::{self, Equality, Truthiness};
+use crate::sync::{Rc, RefCell, Weak};
 
 #[derive(Debug, Clone)]
 pub enum Object {
     Null,
     Boolean(bool),
     Number(f64),
-    String(String),
+    String(Rc<str>),
     Callable(Rc<dyn Callable>),
     Class(Shared<Class>),
     Instance(Shared<Instance>),
+    Set(Shared<LoxSet>),
+    List(Shared<Vec<Object>>),
+    StringBuilder(Shared<String>),
+    /// A weak reference to an instance, see [`crate::weak_ref`]
+    WeakRef(Weak<RefCell<Instance>>),
 }
 
 impl PartialEq for Object {
@@ -28,6 +36,12 @@ impl PartialEq for Object {
             (Self::Instance(left), Self::Instance(right)) => {
                 std::ptr::eq(left.as_ref(), right.as_ref())
             }
+            (Self::Set(left), Self::Set(right)) => std::ptr::eq(left.as_ref(), right.as_ref()),
+            (Self::List(left), Self::List(right)) => std::ptr::eq(left.as_ref(), right.as_ref()),
+            (Self::StringBuilder(left), Self::StringBuilder(right)) => {
+                std::ptr::eq(left.as_ref(), right.as_ref())
+            }
+            (Self::WeakRef(left), Self::WeakRef(right)) => Weak::ptr_eq(left, right),
             _ => false,
         }
     }
@@ -46,7 +60,101 @@ impl From<Literal> for Object {
     }
 }
 
+impl From<Key> for Object {
+    fn from(key: Key) -> Self {
+        match key {
+            Key::Nil => Self::Null,
+            Key::Boolean(b) => Self::Boolean(b),
+            Key::Number(bits) => Self::Number(f64::from_bits(bits)),
+            Key::String(s) => Self::String(s),
+        }
+    }
+}
+
 impl Object {
+    /// Whether the value is true in a condition, following the truthiness
+    /// rule.
+    pub(crate) fn is_true(&self, truthiness: Truthiness) -> bool {
+        match truthiness {
+            Truthiness::Lox => !matches!(self, Self::Null | Self::Boolean(false)),
+            Truthiness::Strict => matches!(self, Self::Boolean(true)),
+        }
+    }
+
+    /// Whether `==` is true for the two values, following the equality rule.
+    pub(crate) fn equals(&self, other: &Self, equality: Equality) -> bool {
+        match (equality, self, other) {
+            (Equality::Coercing, Self::Number(n), Self::String(s))
+            | (Equality::Coercing, Self::String(s), Self::Number(n)) => {
+                semantics::number_of(s) == Some(*n)
+            }
+            (Equality::Coercing, Self::Number(n), Self::Boolean(b))
+            | (Equality::Coercing, Self::Boolean(b), Self::Number(n)) => {
+                semantics::number_of_boolean(*b) == *n
+            }
+            _ => self == other,
+        }
+    }
+
+    /// Report the tracked object this value refers to, if any.
+    pub(crate) fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        match self {
+            Self::Callable(callable) => visit(gc::address(callable)),
+            Self::Class(class) => visit(gc::address(class)),
+            Self::Instance(instance) => visit(gc::address(instance)),
+            Self::List(list) => visit(gc::address(list)),
+            // Sets only hold plain values, and builders only text
+            Self::Null
+            | Self::Boolean(_)
+            | Self::Number(_)
+            | Self::String(_)
+            | Self::Set(_)
+            | Self::StringBuilder(_)
+            // Not reporting the instance lets the collector free it
+            | Self::WeakRef(_) => {}
+        }
+    }
+
+    /// The doc comment of a function, method or class.
+    pub fn doc(&self) -> Option<Rc<str>> {
+        match self {
+            Self::Callable(callable) => callable.doc().cloned(),
+            Self::Class(class) => class.borrow().doc().cloned(),
+            _ => None,
+        }
+    }
+
+    /// The number of arguments of a function or a class, or `None` if this
+    /// can't be called.
+    pub fn arity(&self) -> Option<usize> {
+        match self {
+            Self::Callable(callable) => Some(callable.arity()),
+            Self::Class(class) => Some(class.borrow().arity()),
+            _ => None,
+        }
+    }
+
+    /// The name of a function or a class, or `None` for natives and what
+    /// can't be called.
+    pub fn name(&self) -> Option<String> {
+        match self {
+            Self::Callable(callable) => callable.name().map(str::to_owned),
+            Self::Class(class) => Some(class.borrow().name().to_owned()),
+            _ => None,
+        }
+    }
+
+    /// This value as the key of a hash map, or `None` if it can't be one.
+    pub fn key(&self) -> Option<Key> {
+        match self {
+            Self::Null => Some(Key::Nil),
+            Self::Boolean(b) => Some(Key::Boolean(*b)),
+            Self::Number(n) => Some(Key::number(*n)),
+            Self::String(s) => Some(Key::String(s.clone())),
+            _ => None,
+        }
+    }
+
     pub fn number(&self) -> Option<f64> {
         match self {
             Self::Number(n) => Some(*n),
@@ -61,7 +169,7 @@ impl Object {
     }
     pub fn string(&self) -> Option<String> {
         match self {
-            Self::String(s) => Some(s.clone()),
+            Self::String(s) => Some(s.to_string()),
             _ => None,
         }
     }
@@ -79,6 +187,33 @@ impl Display for Object {
             Self::Callable(c) => write!(f, "{c}"),
             Self::Class(c) => write!(f, "{}", c.borrow()),
             Self::Instance(i) => write!(f, "{}", i.borrow()),
+            Self::Set(s) => write!(f, "{}", s.borrow()),
+            Self::List(l) => write!(f, "{}", list::display(l)),
+            Self::StringBuilder(b) => write!(f, "{}", b.borrow()),
+            Self::WeakRef(_) => write!(f, "<weak ref>"),
+        }
+    }
+}
+
+impl Trace for RefCell<Vec<Object>> {
+    fn kind(&self) -> Kind {
+        Kind::List
+    }
+
+    fn strings(&self) -> usize {
+        let Ok(values) = self.try_borrow() else { return 0 };
+        values.iter().filter(|value| matches!(value, Object::String(_))).count()
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Ok(values) = self.try_borrow() {
+            values.iter().for_each(|value| value.trace(visit));
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut values) = self.try_borrow_mut() {
+            values.clear();
         }
     }
 }
@@ -0,0 +1,58 @@
+use std::io::Write;
+
+use crate::Shared;
+use crate::sync::MaybeSync;
+
+/// A writer that can be stored in an interpreter.
+pub(crate) trait Writer: Write + MaybeSync {}
+impl<T: Write + MaybeSync> Writer for T {}
+
+/// Where a program prints: the standard output, unless the embedder gave
+/// another writer.
+#[derive(Default)]
+pub(crate) struct Output(Option<Box<dyn Writer>>);
+
+impl Output {
+    pub fn new(writer: impl Write + MaybeSync + 'static) -> Self {
+        Self(Some(Box::new(writer)))
+    }
+
+    pub fn print(&mut self, args: std::fmt::Arguments) {
+        match &mut self.0 {
+            // Like `print!`, which fails only if the standard output is gone
+            Some(writer) => writer.write_fmt(args).expect("failed to write the output"),
+            None => print!("{args}"),
+        }
+    }
+}
+
+/// Collects what a program prints, for
+/// [`Lox::run_capture`](crate::Lox::run_capture).
+#[derive(Clone, Default)]
+pub(crate) struct Capture(Shared<Vec<u8>>);
+
+impl Capture {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("Output(<writer>)"),
+            None => f.write_str("Output(<stdout>)"),
+        }
+    }
+}
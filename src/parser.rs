@@ -1,59 +1,251 @@
-use std::rc::Rc;
+use std::collections::HashMap;
 
 use crate::prelude::*;
+use crate::sync::Rc;
+
+/// How deeply expressions and statements can be nested by default. The parser,
+/// the resolver and the evaluation of expressions recurse on the native stack;
+/// the tree-walking interpreter executes nested statements without recursing.
+pub const DEFAULT_MAX_DEPTH: usize = 256;
 
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    /// The doc comments, by the index of the token after them
+    docs: HashMap<usize, Rc<str>>,
     errors: Vec<ParserError>,
+    /// The token types that were tried at the current token
+    expected: Vec<TokenType>,
+    /// The tree being built
+    ast: Ast,
+    /// Whether each iteration of a `for` loop gets its own copy of the loop
+    /// variable
+    fresh_loop_variables: bool,
+    /// Whether a trailing expression statement is the result of the program
+    trailing_expression: bool,
+    /// Whether the current statement ends the `then` branch of an `if`, so an
+    /// `else` after it belongs to the `if` and not to a loop
+    in_then_branch: bool,
+    /// How deeply the current token is nested
+    depth: usize,
+    max_depth: usize,
+    /// Whether the nesting went too deep, so the rest of the source was
+    /// skipped
+    too_deep: bool,
 }
 
 #[derive(Debug)]
 pub struct ParserError {
     pub message: String,
     pub token: Token,
+    pub code: DiagnosticCode,
+    /// The token types that would have been accepted instead of `token`
+    pub expected: Vec<TokenType>,
+}
+
+impl ParserError {
+    /// Whether the parser ran out of tokens, i.e. the source ended in the
+    /// middle of a declaration or statement.
+    pub fn is_at_end(&self) -> bool {
+        self.token.token_type == TokenType::EOF
+    }
+
+    /// Whether this is a warning, which doesn't stop the program from running.
+    pub fn is_warning(&self) -> bool {
+        self.code.severity() == Severity::Warning
+    }
+
+    /// Describe what the parser would have accepted instead of the token, e.g.
+    /// `Expected ';' or an operator, found 'var'.`
+    pub fn expectation(&self) -> Option<String> {
+        const OPERATORS: &[TokenType] = &[
+            TokenType::Dot,
+            TokenType::Minus,
+            TokenType::Plus,
+            TokenType::Slash,
+            TokenType::Star,
+            TokenType::BangEqual,
+            TokenType::Equal,
+            TokenType::EqualEqual,
+            TokenType::Greater,
+            TokenType::GreaterEqual,
+            TokenType::Less,
+            TokenType::LessEqual,
+            TokenType::And,
+            TokenType::Or,
+        ];
+        const EXPRESSION_STARTS: &[TokenType] = &[
+            TokenType::Bang,
+            TokenType::Minus,
+            TokenType::Fun,
+            TokenType::False,
+            TokenType::True,
+            TokenType::Super,
+            TokenType::Nil,
+            TokenType::Number,
+            TokenType::StringLiteral,
+            TokenType::This,
+            TokenType::Identifier,
+            TokenType::LeftParen,
+            TokenType::LeftBracket,
+        ];
+
+        if self.expected.is_empty() {
+            return None;
+        }
+
+        // Numbers are only tried at the start of an expression, and `+` only
+        // after an operand
+        let expression = self.expected.contains(&TokenType::Number);
+        let operator = !expression && self.expected.contains(&TokenType::Plus);
+        let mut choices: Vec<String> = self
+            .expected
+            .iter()
+            .filter(|tt| !(expression && EXPRESSION_STARTS.contains(tt)))
+            .filter(|tt| !(operator && OPERATORS.contains(tt)))
+            .map(TokenType::to_string)
+            .collect();
+        if expression {
+            choices.push("an expression".to_owned());
+        }
+        if operator {
+            choices.push("an operator".to_owned());
+        }
+
+        let choices = match choices.split_last() {
+            Some((last, [])) => last.to_owned(),
+            Some((last, rest)) => format!("{} or {last}", rest.join(", ")),
+            None => return None,
+        };
+        let found = match self.token.token_type {
+            TokenType::EOF => "the end".to_owned(),
+            _ => format!("'{}'", self.token.lexeme),
+        };
+        Some(format!("Expected {choices}, found {found}."))
+    }
 }
 
 impl Parser {
+    /// Create a parser for the tokens of a program. Comment tokens are
+    /// skipped, except that `///` comments right above a function, method or
+    /// class are kept as its documentation, see [`Ast::doc`].
+    ///
+    /// Any tokens can be parsed without panicking, e.g. the output of a fuzzer.
+    /// If they don't end with an EOF token, one is added.
     pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, current: 0, errors: Vec::new() }
+        let (mut tokens, docs) = doc_comments(tokens);
+        if tokens.last().is_none_or(|token| token.token_type != TokenType::EOF) {
+            let line = tokens.last().map_or(1, |token| token.line);
+            tokens.push(Token::new(TokenType::EOF, "", None, line));
+        }
+        Self {
+            tokens,
+            current: 0,
+            docs,
+            errors: Vec::new(),
+            expected: Vec::new(),
+            ast: Ast::new(),
+            fresh_loop_variables: false,
+            trailing_expression: false,
+            in_then_branch: false,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            too_deep: false,
+        }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<ParserError>> {
-        let mut statements = vec![];
+    /// Keep an expression statement at the end of the program apart, as the
+    /// [`Ast::result`] of the program, so its value can be shown like in a
+    /// REPL. Its `;` is optional, e.g. `1 + 2` is a whole program.
+    pub fn with_trailing_expression(self, enabled: bool) -> Self {
+        Self { trailing_expression: enabled, ..self }
+    }
+
+    /// Limit how deeply expressions and statements can be nested, e.g. with
+    /// parentheses or blocks. Deeper code is an error instead of a stack
+    /// overflow.
+    pub fn with_max_depth(self, max_depth: usize) -> Self {
+        Self { max_depth, ..self }
+    }
+
+    /// Give each iteration of a `for` loop its own copy of the variable
+    /// declared in the initializer, so the closures made in different
+    /// iterations don't share it. Off by default, like in the reference
+    /// implementation.
+    pub fn with_fresh_loop_variables(self, enabled: bool) -> Self {
+        Self { fresh_loop_variables: enabled, ..self }
+    }
+
+    /// Parse the whole program. The tree is returned even if there are
+    /// errors: each declaration that failed to parse is replaced by a
+    /// [`Stmt::Error`] and each invalid expression by an [`Expr::Error`], so
+    /// tools can still work on the rest of the program. Warnings are returned
+    /// with the errors, see [`ParserError::is_warning`].
+    pub fn parse(&mut self) -> (Ast, Vec<ParserError>) {
         while !self.is_at_end() {
-            // We won't stop if the return value is None. Error reporter
-            // keeps track of any error that happens and Lox won't go to next stages.
-            // Also, we have the `synchronize` mechanism which helps us to get out of
-            // errornous state in statements and move to the next one.
-            if let Some(s) = self.declaration() {
-                statements.push(s);
+            // The `synchronize` mechanism helps us to get out of errornous
+            // state in statements and move to the next one.
+            let s = self.declaration();
+            if let Stmt::Expression { expr } = self.ast[s] {
+                if self.trailing_expression && self.is_at_end() {
+                    self.ast.set_result(expr);
+                    break;
+                }
             }
+            self.ast.push_statement(s);
         }
 
-        if !self.errors.is_empty() { Err(std::mem::take(&mut self.errors)) } else { Ok(statements) }
+        (std::mem::take(&mut self.ast), std::mem::take(&mut self.errors))
     }
 
-    fn declaration(&mut self) -> Option<Stmt> {
-        let result = if self.match_tt(&[TokenType::Var]) {
+    fn declaration(&mut self) -> StmtId {
+        let line = self.peek().line;
+        let start = self.current;
+
+        // Other statements can start with a keyword and a parenthesis
+        let declaration = matches!(self.peek().token_type, TokenType::Class | TokenType::Var);
+        let result = if declaration && self.check_next(&TokenType::LeftParen) {
+            self.keyword_call()
+        } else if self.peek().token_type == TokenType::Fun && self.check_next(&TokenType::LeftParen)
+        {
+            // An anonymous function
+            self.statement()
+        } else if self.match_tt(&[TokenType::Var]) {
             self.var_declaration()
         } else if self.match_tt(&[TokenType::Class]) {
             self.class()
+        } else if self.check_extend() {
+            self.advance();
+            self.extension()
         } else if self.match_tt(&[TokenType::Fun]) {
             self.function("function")
         } else {
             self.statement()
         };
 
-        if result.is_none() {
-            self.synchronize();
-            return None;
+        match result {
+            Some(stmt) => {
+                if matches!(self.ast[stmt], Stmt::Function { .. } | Stmt::Class { .. }) {
+                    self.attach_doc(start, stmt);
+                }
+                stmt
+            }
+            None => {
+                self.synchronize();
+                self.stmt(Stmt::Error, line)
+            }
         }
+    }
 
-        result
+    /// Attach the doc comment above the token at `start` to a declaration.
+    fn attach_doc(&mut self, start: usize, stmt: StmtId) {
+        if let Some(doc) = self.docs.remove(&start) {
+            self.ast.set_doc(stmt, doc);
+        }
     }
 
-    fn var_declaration(&mut self) -> Option<Stmt> {
+    fn var_declaration(&mut self) -> Option<StmtId> {
+        let line = self.previous().line;
         let name = self.consume(TokenType::Identifier, "Expect variable name.")?;
 
         let initializer =
@@ -61,57 +253,157 @@ impl Parser {
 
         self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.")?;
 
-        Some(Stmt::Var { name, initializer })
+        Some(self.stmt(Stmt::Var { name, initializer }, line))
     }
 
-    fn class(&mut self) -> Option<Stmt> {
+    fn class(&mut self) -> Option<StmtId> {
+        let line = self.previous().line;
         let name = self.consume(TokenType::Identifier, "Expect class name")?;
         let superclass = if self.match_tt(&[TokenType::Less]) {
             let super_class = self.consume(TokenType::Identifier, "Expect superclass name.")?;
-            Some(Expr::Variable { name: super_class })
+            Some(self.expr(Expr::Variable { binding: Binding::new(), name: super_class }))
         } else {
             None
         };
 
         self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
+        let methods = self.methods()?;
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
+
+        Some(self.stmt(Stmt::Class { name, methods, superclass }, line))
+    }
+
+    /// Whether the next tokens start an extension, `extend Name {`. `extend`
+    /// is only a keyword there, so it can still name variables.
+    fn check_extend(&self) -> bool {
+        let token = self.peek();
+        token.token_type == TokenType::Identifier
+            && &*token.lexeme == "extend"
+            && self.check_next(&TokenType::Identifier)
+    }
+
+    fn extension(&mut self) -> Option<StmtId> {
+        let keyword = self.previous().clone();
+        let name = self.consume(TokenType::Identifier, "Expect class name.")?;
+        let class = self.expr(Expr::Variable { binding: Binding::new(), name });
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before extension body.")?;
+        let methods = self.methods()?;
+        self.consume(TokenType::RightBrace, "Expect '}' after extension body.")?;
+
+        let line = keyword.line;
+        Some(self.stmt(Stmt::Extend { keyword, class, methods }, line))
+    }
+
+    /// The methods of a class body, up to its closing brace.
+    fn methods(&mut self) -> Option<Vec<StmtId>> {
         let mut methods = vec![];
         while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
-            methods.push(self.function("method")?);
+            let start = self.current;
+            let method = self.function("method")?;
+            self.attach_doc(start, method);
+            methods.push(method);
         }
-
-        self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
-
-        Some(Stmt::Class { name, methods, superclass })
+        Some(methods)
     }
 
-    fn function(&mut self, kind: &str) -> Option<Stmt> {
+    fn function(&mut self, kind: &str) -> Option<StmtId> {
         let name = self.consume(TokenType::Identifier, format!("Expect {kind} name.").as_str())?;
 
         self.consume(TokenType::LeftParen, format!("Expect '(' after {kind} name.").as_str())?;
+        let parameters = self.parameters()?;
+        self.consume(TokenType::LeftBrace, format!("Expect '{{' before {kind} body.").as_str())?;
+
+        let body = self.nested(Self::block)?;
 
+        let line = name.line;
+        Some(self.stmt(Stmt::Function { name, params: parameters, body }, line))
+    }
+
+    /// The parameters of a function, after the `(`, and the `)`.
+    fn parameters(&mut self) -> Option<Vec<Token>> {
         let mut parameters = vec![];
         if !self.check(&TokenType::RightParen) {
             loop {
                 if parameters.len() >= 255 {
-                    self.error(self.peek().clone(), "Can't have more than 255 parameters.");
+                    self.error(
+                        self.peek().clone(),
+                        DiagnosticCode::TooManyParameters,
+                        "Can't have more than 255 parameters.",
+                    );
                 }
 
                 parameters.push(self.consume(TokenType::Identifier, "Expect parameter name.")?);
-                if !self.match_tt(&[TokenType::Comma]) {
+                if !self.next_item(TokenType::RightParen) {
                     break;
                 }
             }
         }
 
         self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
-        self.consume(TokenType::LeftBrace, format!("Expect '{{' before {kind} body.").as_str())?;
+        Some(parameters)
+    }
+
+    /// An anonymous function after its `fun`, e.g. `fun (a) { return a; }`.
+    fn anonymous_function(&mut self) -> Option<ExprId> {
+        let keyword = self.previous().clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'fun'.")?;
+        let params = self.parameters()?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.")?;
+        let body = self.nested(Self::block)?;
+
+        Some(self.function_expr(keyword, params, body))
+    }
+
+    /// An arrow function, e.g. `(a, b) -> a + b` or `(a) -> { return a; }`.
+    /// An expression body is returned by a `return` statement at the `->`.
+    fn arrow_function(&mut self) -> Option<ExprId> {
+        self.consume(TokenType::LeftParen, "Expect '(' before parameters.")?;
+        let params = self.parameters()?;
+        let keyword = self.consume(TokenType::Arrow, "Expect '->' after parameters.")?;
+
+        let body = if self.match_tt(&[TokenType::LeftBrace]) {
+            self.nested(Self::block)?
+        } else {
+            let line = keyword.line;
+            let value = self.expression()?;
+            vec![self.stmt(Stmt::Return { keyword: keyword.clone(), value: Some(value) }, line)]
+        };
 
-        let body = self.block()?.into_iter().map(Rc::new).collect::<Vec<_>>();
+        Some(self.function_expr(keyword, params, body))
+    }
 
-        Some(Stmt::Function { name, params: parameters, body })
+    /// Declare an anonymous function. It is named `lambda`, and its
+    /// declaration is synthetic since it never runs as a statement.
+    fn function_expr(&mut self, keyword: Token, params: Vec<Token>, body: Vec<StmtId>) -> ExprId {
+        let line = keyword.line;
+        let name = Token::new(TokenType::Identifier, "lambda", None, line);
+        let declaration = self.synthetic_stmt(Stmt::Function { name, params, body }, line);
+        self.expr(Expr::Function { keyword, declaration })
     }
 
-    fn statement(&mut self) -> Option<Stmt> {
+    /// Whether the next tokens are the parameters of an arrow function rather
+    /// than a grouping, i.e. identifiers in parentheses followed by `->`.
+    fn is_arrow_function(&self) -> bool {
+        let mut tokens = self.tokens[self.current..].iter().map(|token| token.token_type);
+        if tokens.next() != Some(TokenType::LeftParen) {
+            return false;
+        }
+        loop {
+            match tokens.next() {
+                Some(TokenType::Identifier) => match tokens.next() {
+                    Some(TokenType::Comma) => {}
+                    Some(TokenType::RightParen) => break,
+                    _ => return false,
+                },
+                Some(TokenType::RightParen) => break,
+                _ => return false,
+            }
+        }
+        tokens.next() == Some(TokenType::Arrow)
+    }
+
+    fn statement(&mut self) -> Option<StmtId> {
         if self.match_tt(&[TokenType::If]) {
             self.if_statement()
         } else if self.match_tt(&[TokenType::While]) {
@@ -125,57 +417,137 @@ impl Parser {
         } else if self.match_tt(&[TokenType::Break]) {
             self.break_statement()
         } else if self.match_tt(&[TokenType::LeftBrace]) {
-            Some(Stmt::Block { statements: self.block()? })
+            let line = self.previous().line;
+            let statements = self.nested(Self::block)?;
+            Some(self.stmt(Stmt::Block { statements }, line))
+        } else if self.check_using() {
+            self.advance();
+            self.using_statement()
         } else {
             self.expression_statement()
         }
     }
 
-    fn if_statement(&mut self) -> Option<Stmt> {
+    fn if_statement(&mut self) -> Option<StmtId> {
+        let line = self.previous().line;
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
+        let start = self.peek().clone();
         let condition = self.expression()?;
         self.consume(TokenType::RightParen, "Expect ')' after if condition.")?;
 
-        let then_branch = Box::new(self.statement()?);
+        // An assignment in parentheses is taken as intended
+        if matches!(
+            self.ast[condition],
+            Expr::Assignment { .. } | Expr::Set { .. } | Expr::IndexSet { .. }
+        ) {
+            self.error(
+                start,
+                DiagnosticCode::AssignmentInCondition,
+                "Assignment in a condition. Use '==' to compare.",
+            );
+        }
+
+        let in_then_branch = std::mem::replace(&mut self.in_then_branch, true);
+        let then_branch = self.nested(Self::statement);
+        self.in_then_branch = in_then_branch;
+        let then_branch = then_branch?;
         let else_branch = if self.match_tt(&[TokenType::Else]) {
-            Some(Box::new(self.statement()?))
+            Some(self.nested(Self::statement)?)
         } else {
             None
         };
 
-        Some(Stmt::If { condition, then_branch, else_branch })
+        Some(self.stmt(Stmt::If { condition, then_branch, else_branch }, line))
     }
 
-    fn return_statement(&mut self) -> Option<Stmt> {
-        let keyword = self.previous();
+    fn return_statement(&mut self) -> Option<StmtId> {
+        let keyword = self.previous().clone();
         let value = if self.check(&TokenType::Semicolon) { None } else { Some(self.expression()?) };
 
         self.consume(TokenType::Semicolon, "Expect ';' after 'return'.")?;
-        Some(Stmt::Return { keyword, value })
+        let line = keyword.line;
+        Some(self.stmt(Stmt::Return { keyword, value }, line))
     }
 
-    fn while_statement(&mut self) -> Option<Stmt> {
+    fn while_statement(&mut self) -> Option<StmtId> {
+        let keyword = self.previous().clone();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
         let condition = self.expression()?;
         self.consume(TokenType::RightParen, "Expect ')' after while condition.")?;
 
-        let body = Box::new(self.statement()?);
-        Some(Stmt::While { condition, body })
+        let body = self.nested(Self::statement)?;
+        let else_branch = self.loop_else()?;
+        let line = keyword.line;
+        Some(self.stmt(Stmt::While { keyword, condition, body, else_branch }, line))
     }
 
-    fn for_statement(&mut self) -> Option<Stmt> {
+    /// The `else` branch of a loop, which runs when the loop ends without a
+    /// `break`. In the `then` branch of an `if`, an `else` after the loop
+    /// belongs to the `if`, like in `if (a) while (b) f(); else g();`, so the
+    /// loop needs braces around it to have one.
+    fn loop_else(&mut self) -> Option<Option<StmtId>> {
+        if self.in_then_branch || !self.match_tt(&[TokenType::Else]) {
+            return Some(None);
+        }
+        Some(Some(self.nested(Self::statement)?))
+    }
+
+    /// Whether the next tokens start a `using (var` statement. `using` is
+    /// only a keyword there, so a function named `using` can still be called.
+    fn check_using(&self) -> bool {
+        let token = self.peek();
+        token.token_type == TokenType::Identifier
+            && &*token.lexeme == "using"
+            && self.check_next(&TokenType::LeftParen)
+            && self.tokens.get(self.current + 2).is_some_and(|t| t.token_type == TokenType::Var)
+    }
+
+    fn using_statement(&mut self) -> Option<StmtId> {
+        let keyword = self.previous().clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'using'.")?;
+        self.consume(TokenType::Var, "Expect 'var' after '('.")?;
+        let name = self.consume(TokenType::Identifier, "Expect variable name.")?;
+        self.consume(TokenType::Equal, "Expect '=' after 'using' variable.")?;
+        let initializer = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after 'using' variable.")?;
+
+        let body = self.nested(Self::statement)?;
+
+        // The call that closes the value, at the `using` keyword
+        let object = self.expr(Expr::Variable { binding: Binding::new(), name: name.clone() });
+        let method = Token { lexeme: "close".into(), ..keyword.clone() };
+        let callee = self.expr(Expr::Get { object, name: method, cache: MethodCache::new() });
+        let paren =
+            Token { token_type: TokenType::RightParen, lexeme: ")".into(), ..keyword.clone() };
+        let close = self.expr(Expr::Call { callee, paren, arguments: vec![] });
+
+        let line = keyword.line;
+        Some(self.stmt(Stmt::Using { keyword, name, initializer, body, close }, line))
+    }
+
+    fn for_statement(&mut self) -> Option<StmtId> {
+        let keyword = self.previous().clone();
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
 
         let initializer = if self.match_tt(&[TokenType::Semicolon]) {
             None
         } else if self.match_tt(&[TokenType::Var]) {
             Some(self.var_declaration()?)
+        } else if self.check(&TokenType::Identifier) && self.check_next(&TokenType::Identifier) {
+            // Parse the rest as if the type were `var`
+            let type_name = self.advance().clone();
+            self.error(
+                type_name,
+                DiagnosticCode::TypedLoopVariable,
+                "Expect 'var' before loop variable. Lox has no type names.",
+            );
+            Some(self.var_declaration()?)
         } else {
             Some(self.expression_statement()?)
         };
 
         let condition = if self.check(&TokenType::Semicolon) {
-            Expr::Literal { value: Literal::Boolean(true) }
+            self.expr(Expr::Literal { value: Literal::Boolean(true) })
         } else {
             self.expression()?
         };
@@ -185,23 +557,70 @@ impl Parser {
             if self.check(&TokenType::RightParen) { None } else { Some(self.expression()?) };
         self.consume(TokenType::RightParen, "Expect ')' after 'for' clauses.")?;
 
-        let mut body = self.statement()?;
+        let mut body = self.nested(Self::statement)?;
+        let else_branch = self.loop_else()?;
 
         // Now reconstruct all those parts as a For statement
+        let line = keyword.line;
+        if let Some(Stmt::Var { name, .. }) = initializer.map(|stmt| &self.ast[stmt]) {
+            if self.fresh_loop_variables {
+                body = self.fresh_loop_variable(name.clone(), body, line);
+            }
+        }
         if let Some(increment) = increment {
-            body = Stmt::Block { statements: vec![body, Stmt::Expression { expr: increment }] };
+            let increment = self.synthetic_stmt(Stmt::Expression { expr: increment }, line);
+            body = self.synthetic_stmt(Stmt::Block { statements: vec![body, increment] }, line);
         }
 
-        body = Stmt::While { condition, body: Box::new(body) };
+        body = self.stmt(Stmt::While { keyword, condition, body, else_branch }, line);
 
         if let Some(initializer) = initializer {
-            body = Stmt::Block { statements: vec![initializer, body] };
+            body = self.synthetic_stmt(Stmt::Block { statements: vec![initializer, body] }, line);
         }
 
         Some(body)
     }
 
-    fn print_statement(&mut self) -> Option<Stmt> {
+    /// Run the body of a loop with a copy of the loop variable, which is
+    /// written back after the body, so the condition and the increment see the
+    /// changes:
+    ///
+    /// ```text
+    /// { var ' i' = i; { var i = ' i'; body; ' i' = i; } i = ' i'; }
+    /// ```
+    ///
+    /// The name of the intermediate variable can't be written in Lox code.
+    fn fresh_loop_variable(&mut self, name: Token, body: StmtId, line: u32) -> StmtId {
+        let hidden = Token { lexeme: format!(" {}", name.lexeme).into(), ..name.clone() };
+        let variable = |parser: &mut Self, name: &Token| {
+            parser.expr(Expr::Variable { binding: Binding::new(), name: name.clone() })
+        };
+        let assignment = |parser: &mut Self, name: &Token, value: &Token| {
+            let value = variable(parser, value);
+            let expr = parser.expr(Expr::Assignment {
+                binding: Binding::new(),
+                name: name.clone(),
+                value,
+            });
+            parser.synthetic_stmt(Stmt::Expression { expr }, line)
+        };
+        let declaration = |parser: &mut Self, name: &Token, value: &Token| {
+            let initializer = Some(variable(parser, value));
+            parser.synthetic_stmt(Stmt::Var { name: name.clone(), initializer }, line)
+        };
+
+        let copy = declaration(self, &name, &hidden);
+        let write_back = assignment(self, &hidden, &name);
+        let iteration =
+            self.synthetic_stmt(Stmt::Block { statements: vec![copy, body, write_back] }, line);
+
+        let save = declaration(self, &hidden, &name);
+        let restore = assignment(self, &name, &hidden);
+        self.synthetic_stmt(Stmt::Block { statements: vec![save, iteration, restore] }, line)
+    }
+
+    fn print_statement(&mut self) -> Option<StmtId> {
+        let line = self.previous().line;
         let mut exprs = vec![];
         exprs.push(self.expression()?);
         while self.match_tt(&[TokenType::Comma]) {
@@ -209,91 +628,146 @@ impl Parser {
         }
 
         self.consume(TokenType::Semicolon, "Expect ';' after the print statement.")?;
-        Some(Stmt::Print { exprs })
+        Some(self.stmt(Stmt::Print { exprs }, line))
     }
 
-    fn break_statement(&mut self) -> Option<Stmt> {
-        let token = self.previous();
+    fn break_statement(&mut self) -> Option<StmtId> {
+        let token = self.previous().clone();
         self.consume(TokenType::Semicolon, "Expect ';' after 'break'.")?;
-        Some(Stmt::Break { token })
+        let line = token.line;
+        Some(self.stmt(Stmt::Break { token }, line))
     }
 
-    fn block(&mut self) -> Option<Vec<Stmt>> {
+    fn block(&mut self) -> Option<Vec<StmtId>> {
         let mut statements = vec![];
 
+        // An `else` inside the braces can't belong to an `if` outside them
+        let in_then_branch = std::mem::take(&mut self.in_then_branch);
         while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
-            statements.push(self.declaration()?);
+            statements.push(self.declaration());
         }
+        self.in_then_branch = in_then_branch;
 
         self.consume(TokenType::RightBrace, "Expect '}' after block.")?;
         Some(statements)
     }
 
-    fn expression_statement(&mut self) -> Option<Stmt> {
+    fn expression_statement(&mut self) -> Option<StmtId> {
+        let line = self.peek().line;
         let expr = self.expression()?;
-        self.consume(TokenType::Semicolon, "Expect ';' after expression.")?;
-        Some(Stmt::Expression { expr })
+        if let Some(name) = self.function_without_fun(expr) {
+            self.error(
+                name,
+                DiagnosticCode::MissingFun,
+                "Expect 'fun' before function declaration.",
+            );
+            // Skip the body so it isn't parsed as a block
+            self.consume(TokenType::LeftBrace, "Expect '{' before function body.")?;
+            self.nested(Self::block)?;
+            return Some(self.stmt(Stmt::Error, line));
+        }
+        // The result of the program doesn't need a `;`
+        if !(self.trailing_expression && self.depth == 0 && self.is_at_end()) {
+            self.consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+        }
+        Some(self.stmt(Stmt::Expression { expr }, line))
     }
 
-    fn expression(&mut self) -> Option<Expr> {
-        self.assignment()
+    /// The name of the function if the expression looks like the start of a
+    /// function declaration without `fun`, i.e. a call with only variables as
+    /// arguments, followed by a `{`.
+    fn function_without_fun(&mut self, expr: ExprId) -> Option<Token> {
+        let Expr::Call { callee, paren: _, arguments } = &self.ast[expr] else {
+            return None;
+        };
+        let Expr::Variable { binding: _, name } = &self.ast[*callee] else {
+            return None;
+        };
+        let params = arguments.iter().all(|&arg| matches!(self.ast[arg], Expr::Variable { .. }));
+        let name = name.clone();
+
+        (params && self.check(&TokenType::LeftBrace)).then_some(name)
     }
 
-    fn assignment(&mut self) -> Option<Expr> {
+    fn expression(&mut self) -> Option<ExprId> {
+        self.nested(Self::assignment)
+    }
+
+    fn assignment(&mut self) -> Option<ExprId> {
         let expr = self.or()?;
 
         if self.match_tt(&[TokenType::Equal]) {
-            let equals = self.previous();
-            let value = Box::new(self.assignment()?);
-
-            if let Expr::Variable { name } = expr {
-                return Some(Expr::Assignment { name, value });
-            } else if let Expr::Get { object, name } = expr {
-                return Some(Expr::Set { object, name, value });
+            let equals = self.previous().clone();
+            let value = self.nested(Self::assignment)?;
+
+            // The target stays in the tree, but nothing refers to it anymore
+            match &self.ast[expr] {
+                Expr::Variable { binding: _, name } => {
+                    let name = name.clone();
+                    return Some(self.expr(Expr::Assignment {
+                        binding: Binding::new(),
+                        name,
+                        value,
+                    }));
+                }
+                Expr::Get { object, name, cache: _ } => {
+                    let (object, name) = (*object, name.clone());
+                    return Some(self.expr(Expr::Set { object, name, value }));
+                }
+                Expr::Index { object, bracket, index } => {
+                    let (object, bracket, index) = (*object, bracket.clone(), *index);
+                    return Some(self.expr(Expr::IndexSet { object, bracket, index, value }));
+                }
+                _ => {}
             }
 
-            self.error(equals, "Invalid assignment target.");
+            self.error(
+                equals,
+                DiagnosticCode::InvalidAssignmentTarget,
+                "Invalid assignment target.",
+            );
+            return Some(self.expr(Expr::Error));
         }
 
         Some(expr)
     }
 
-    fn or(&mut self) -> Option<Expr> {
+    fn or(&mut self) -> Option<ExprId> {
         let mut expr = self.and()?;
 
         while self.match_tt(&[TokenType::Or]) {
-            let operator = self.previous();
+            let operator = self.previous().clone();
             let right = self.and()?;
-            expr = Expr::Logical { left: Box::new(expr), operator, right: Box::new(right) };
+            expr = self.expr(Expr::Logical { left: expr, operator, right });
         }
 
         Some(expr)
     }
 
-    fn and(&mut self) -> Option<Expr> {
+    fn and(&mut self) -> Option<ExprId> {
         let mut expr = self.equality()?;
 
         while self.match_tt(&[TokenType::And]) {
-            let operator = self.previous();
+            let operator = self.previous().clone();
             let right = self.and()?;
-            expr = Expr::Logical { left: Box::new(expr), operator, right: Box::new(right) };
+            expr = self.expr(Expr::Logical { left: expr, operator, right });
         }
 
         Some(expr)
     }
 
-    fn equality(&mut self) -> Option<Expr> {
+    fn equality(&mut self) -> Option<ExprId> {
         let mut expr = self.comparison()?;
 
         while self.match_tt(&[TokenType::BangEqual, TokenType::EqualEqual]) {
-            let operator: Token = self.previous();
+            let operator: Token = self.previous().clone();
             let right = self.comparison()?;
-            expr = Expr::Binary { left: Box::new(expr), operator, right: Box::new(right) };
+            expr = self.expr(Expr::Binary { left: expr, operator, right });
         }
         Some(expr)
     }
 
-    fn comparison(&mut self) -> Option<Expr> {
+    fn comparison(&mut self) -> Option<ExprId> {
         let mut expr = self.term()?;
 
         while self.match_tt(&[
@@ -302,46 +776,46 @@ impl Parser {
             TokenType::LessEqual,
             TokenType::Less,
         ]) {
-            let operator: Token = self.previous();
+            let operator: Token = self.previous().clone();
             let right = self.term()?;
-            expr = Expr::Binary { left: Box::new(expr), operator, right: Box::new(right) };
+            expr = self.expr(Expr::Binary { left: expr, operator, right });
         }
         Some(expr)
     }
 
-    fn term(&mut self) -> Option<Expr> {
+    fn term(&mut self) -> Option<ExprId> {
         let mut expr = self.factor()?;
 
         while self.match_tt(&[TokenType::Minus, TokenType::Plus]) {
-            let operator: Token = self.previous();
+            let operator: Token = self.previous().clone();
             let right = self.factor()?;
-            expr = Expr::Binary { left: Box::new(expr), operator, right: Box::new(right) };
+            expr = self.expr(Expr::Binary { left: expr, operator, right });
         }
         Some(expr)
     }
 
-    fn factor(&mut self) -> Option<Expr> {
+    fn factor(&mut self) -> Option<ExprId> {
         let mut expr = self.unary()?;
 
         while self.match_tt(&[TokenType::Slash, TokenType::Star]) {
-            let operator: Token = self.previous();
+            let operator: Token = self.previous().clone();
             let right = self.unary()?;
-            expr = Expr::Binary { left: Box::new(expr), operator, right: Box::new(right) };
+            expr = self.expr(Expr::Binary { left: expr, operator, right });
         }
         Some(expr)
     }
 
-    fn unary(&mut self) -> Option<Expr> {
+    fn unary(&mut self) -> Option<ExprId> {
         if self.match_tt(&[TokenType::Bang, TokenType::Minus]) {
-            let operator = self.previous();
-            let right = self.unary()?;
-            return Some(Expr::Unary { operator, right: Box::new(right) });
+            let operator = self.previous().clone();
+            let right = self.nested(Self::unary)?;
+            return Some(self.expr(Expr::Unary { operator, right }));
         }
 
         self.call()
     }
 
-    fn call(&mut self) -> Option<Expr> {
+    fn call(&mut self) -> Option<ExprId> {
         let mut expr = self.primary()?;
 
         loop {
@@ -350,7 +824,12 @@ impl Parser {
             } else if self.match_tt(&[TokenType::Dot]) {
                 let name =
                     self.consume(TokenType::Identifier, "Expect property name after '.'.")?;
-                expr = Expr::Get { object: Box::new(expr), name };
+                expr = self.expr(Expr::Get { object: expr, name, cache: MethodCache::new() });
+            } else if self.match_tt(&[TokenType::LeftBracket]) {
+                let bracket = self.previous().clone();
+                let index = self.expression()?;
+                self.consume(TokenType::RightBracket, "Expect ']' after index.")?;
+                expr = self.expr(Expr::Index { object: expr, bracket, index });
             } else {
                 break;
             }
@@ -359,68 +838,165 @@ impl Parser {
         Some(expr)
     }
 
-    fn finish_call(&mut self, callee: Expr) -> Option<Expr> {
+    /// A list literal after its `[`, e.g. `[1, 2, 3]`. A trailing comma is
+    /// allowed.
+    fn list(&mut self) -> Option<ExprId> {
+        let bracket = self.previous().clone();
+        let mut elements = vec![];
+
+        if !self.check(&TokenType::RightBracket) {
+            loop {
+                elements.push(self.expression()?);
+
+                if !self.next_item(TokenType::RightBracket) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightBracket, "Expect ']' after list elements.")?;
+        Some(self.expr(Expr::List { bracket, elements }))
+    }
+
+    fn finish_call(&mut self, callee: ExprId) -> Option<ExprId> {
         let mut arguments = vec![];
 
         if !self.check(&TokenType::RightParen) {
             loop {
                 if arguments.len() >= 255 {
                     // Just report the error, but don't return None yet
-                    self.error(self.peek().clone(), "Can't have more than 255 arguments.");
+                    self.error(
+                        self.peek().clone(),
+                        DiagnosticCode::TooManyArguments,
+                        "Can't have more than 255 arguments.",
+                    );
                 }
 
                 arguments.push(self.expression()?);
 
-                if !self.match_tt(&[TokenType::Comma]) {
+                if !self.next_item(TokenType::RightParen) {
                     break;
                 }
             }
         }
 
         let paren = self.consume(TokenType::RightParen, "Expect ')' after arguments.")?;
-        Some(Expr::Call { callee: Box::new(callee), paren, arguments })
+        Some(self.expr(Expr::Call { callee, paren, arguments }))
     }
 
-    fn primary(&mut self) -> Option<Expr> {
+    fn primary(&mut self) -> Option<ExprId> {
         if self.match_tt(&[TokenType::False]) {
-            return Some(Expr::Literal { value: Literal::Boolean(false) });
+            return Some(self.expr(Expr::Literal { value: Literal::Boolean(false) }));
         }
         if self.match_tt(&[TokenType::True]) {
-            return Some(Expr::Literal { value: Literal::Boolean(true) });
+            return Some(self.expr(Expr::Literal { value: Literal::Boolean(true) }));
         }
 
         if self.match_tt(&[TokenType::Super]) {
-            let keyword = self.previous();
+            let keyword = self.previous().clone();
             self.consume(TokenType::Dot, "Expect '.' after 'super'.")?;
             let method = self.consume(TokenType::Identifier, "Expect superclass method name.")?;
-            return Some(Expr::Super { keyword, method });
+            return Some(self.expr(Expr::Super { binding: Binding::new(), keyword, method }));
         }
 
         if self.match_tt(&[TokenType::Nil]) {
-            return Some(Expr::Literal { value: Literal::Null });
+            return Some(self.expr(Expr::Literal { value: Literal::Null }));
         }
 
         if self.match_tt(&[TokenType::Number, TokenType::StringLiteral]) {
-            return Some(Expr::Literal {
-                value: self.previous().literal.expect("expecting a number or string here."),
-            });
+            let token = self.previous().clone();
+            // The scanner always gives these tokens a value
+            let Some(value) = token.literal else {
+                self.error(token, DiagnosticCode::ExpectExpression, "Expect a literal value.");
+                return None;
+            };
+            return Some(self.expr(Expr::Literal { value }));
         }
 
         if self.match_tt(&[TokenType::This]) {
-            return Some(Expr::This { keyword: self.previous() });
+            let keyword = self.previous().clone();
+            return Some(self.expr(Expr::This { binding: Binding::new(), keyword }));
         }
 
         if self.match_tt(&[TokenType::Identifier]) {
-            return Some(Expr::Variable { name: self.previous() });
+            let name = self.previous().clone();
+            return Some(self.expr(Expr::Variable { binding: Binding::new(), name }));
+        }
+
+        // A declaration with a name isn't an expression
+        if self.check(&TokenType::Fun) && self.check_next(&TokenType::LeftParen) {
+            self.advance();
+            return self.anonymous_function();
+        }
+
+        if self.is_arrow_function() {
+            return self.arrow_function();
         }
 
         if self.match_tt(&[TokenType::LeftParen]) {
             let expr = self.expression()?;
             self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
-            return Some(Expr::Grouping { expr: Box::new(expr) });
+            return Some(self.expr(Expr::Grouping { expr }));
         }
 
-        self.error(self.peek().clone(), "Expect expression.");
+        if self.match_tt(&[TokenType::LeftBracket]) {
+            return self.list();
+        }
+
+        // A binary operator without its left operand. The right operand is
+        // parsed anyway, so the parser doesn't need to synchronize.
+        let right_operand: Option<fn(&mut Self) -> Option<ExprId>> = match self.peek().token_type {
+            TokenType::Or => Some(Self::and),
+            TokenType::And => Some(Self::equality),
+            TokenType::BangEqual | TokenType::EqualEqual => Some(Self::comparison),
+            TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => Some(Self::term),
+            TokenType::Plus => Some(Self::factor),
+            TokenType::Slash | TokenType::Star => Some(Self::unary),
+            _ => None,
+        };
+        if let Some(right_operand) = right_operand {
+            let operator = self.advance().clone();
+            self.error(operator, DiagnosticCode::MissingLeftOperand, "Missing left-hand operand.");
+            self.nested(right_operand)?;
+            return Some(self.expr(Expr::Error));
+        }
+
+        if self.is_keyword_call() {
+            return self.keyword_call();
+        }
+
+        self.unexpected(DiagnosticCode::ExpectExpression, "Expect expression.");
+        None
+    }
+
+    /// Whether the next token is a keyword that is called like a function,
+    /// e.g. `print()` in an expression.
+    fn is_keyword_call(&self) -> bool {
+        let keyword = matches!(
+            self.peek().token_type,
+            TokenType::And
+                | TokenType::Break
+                | TokenType::Class
+                | TokenType::Else
+                | TokenType::For
+                | TokenType::If
+                | TokenType::Or
+                | TokenType::Print
+                | TokenType::Return
+                | TokenType::Var
+                | TokenType::While
+        );
+        keyword && self.check_next(&TokenType::LeftParen)
+    }
+
+    /// Report a call of the keyword at the next token, e.g. `class()`.
+    fn keyword_call<T>(&mut self) -> Option<T> {
+        let keyword = self.peek().clone();
+        let message = format!("Can't call '{}' because it is a keyword.", keyword.lexeme);
+        self.error(keyword, DiagnosticCode::KeywordCall, &message);
         None
     }
 
@@ -428,15 +1004,64 @@ impl Parser {
     /// input. Otherwise, print the error message and return `None`.
     fn consume(&mut self, token_type: TokenType, message: &str) -> Option<Token> {
         if self.check(&token_type) {
-            return Some(self.advance());
+            return Some(self.advance().clone());
         }
 
-        self.error(self.peek().clone(), message);
+        self.unexpected(DiagnosticCode::ExpectToken, message);
         None
     }
 
-    fn error(&mut self, token: Token, message: &str) {
-        self.errors.push(ParserError { message: message.to_owned(), token });
+    fn expr(&mut self, expr: Expr) -> ExprId {
+        self.ast.add_expr(expr)
+    }
+
+    /// Add a statement that starts on `line` and ends with the last token
+    /// consumed.
+    fn stmt(&mut self, stmt: Stmt, line: u32) -> StmtId {
+        let end_line = self.previous().line;
+        self.ast.add_stmt(stmt, StmtLocation { line, end_line, synthetic: false })
+    }
+
+    /// Add a statement that isn't in the source code as such.
+    fn synthetic_stmt(&mut self, stmt: Stmt, line: u32) -> StmtId {
+        let end_line = self.previous().line;
+        self.ast.add_stmt(stmt, StmtLocation { line, end_line, synthetic: true })
+    }
+
+    /// Parse something one level deeper. If that is too deep, report it and
+    /// skip the rest of the source, since every enclosing construct would fail
+    /// too.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Option<T>) -> Option<T> {
+        if self.depth >= self.max_depth {
+            self.error(self.peek().clone(), DiagnosticCode::TooDeeplyNested, "Too deeply nested.");
+            self.too_deep = true;
+            self.current = self.tokens.len() - 1;
+            return None;
+        }
+
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn error(&mut self, token: Token, code: DiagnosticCode, message: &str) {
+        if self.too_deep {
+            return;
+        }
+        let expected = Vec::new();
+        self.errors.push(ParserError { message: message.to_owned(), token, code, expected });
+    }
+
+    /// Report an error at the next token, with the token types that were
+    /// tried there.
+    fn unexpected(&mut self, code: DiagnosticCode, message: &str) {
+        if self.too_deep {
+            return;
+        }
+        let token = self.peek().clone();
+        let expected = std::mem::take(&mut self.expected);
+        self.errors.push(ParserError { message: message.to_owned(), token, code, expected });
     }
 
     fn match_tt(&mut self, types: &[TokenType]) -> bool {
@@ -451,17 +1076,33 @@ impl Parser {
     }
 
     /// Check to see if the next token's type matches the given `token_type`.
-    fn check(&self, token_type: &TokenType) -> bool {
-        if self.is_at_end() {
-            return false;
+    /// If it doesn't, the type is remembered for the error message.
+    fn check(&mut self, token_type: &TokenType) -> bool {
+        if !self.is_at_end() && self.peek().token_type == *token_type {
+            return true;
         }
 
-        self.peek().token_type == *token_type
+        if !self.expected.contains(token_type) {
+            self.expected.push(*token_type);
+        }
+        false
+    }
+
+    /// Consume the comma after an item of a list that ends with `end`, and
+    /// tell whether another item follows. The last item can have a comma too.
+    fn next_item(&mut self, end: TokenType) -> bool {
+        self.match_tt(&[TokenType::Comma]) && !self.check(&end)
+    }
+
+    /// Check the type of the token after the next one.
+    fn check_next(&self, token_type: &TokenType) -> bool {
+        self.tokens.get(self.current + 1).is_some_and(|token| token.token_type == *token_type)
     }
 
-    fn advance(&mut self) -> Token {
+    fn advance(&mut self) -> &Token {
         if !self.is_at_end() {
             self.current += 1;
+            self.expected.clear();
         }
         self.previous()
     }
@@ -474,8 +1115,9 @@ impl Parser {
         &self.tokens[self.current]
     }
 
-    fn previous(&mut self) -> Token {
-        self.tokens[self.current - 1].clone()
+    /// The last consumed token, or the first token if none is consumed yet.
+    fn previous(&self) -> &Token {
+        &self.tokens[self.current.saturating_sub(1)]
     }
 
     fn synchronize(&mut self) {
@@ -503,3 +1145,41 @@ impl Parser {
         }
     }
 }
+
+/// Drop the comment tokens, and collect the doc comments: lines of `///`
+/// comments, each alone on its line, that end on the line right above a token.
+/// The doc comments are returned by the index of that token in the remaining
+/// tokens.
+fn doc_comments(tokens: Vec<Token>) -> (Vec<Token>, HashMap<usize, Rc<str>>) {
+    let mut kept: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut docs = HashMap::new();
+    // The lines of the doc comment being read, with the line it ends on
+    let mut lines: Vec<String> = Vec::new();
+    let mut last_line = 0;
+
+    for token in tokens {
+        if token.token_type != TokenType::Comment {
+            if !lines.is_empty() && last_line + 1 == token.line {
+                docs.insert(kept.len(), lines.join("\n").into());
+            }
+            lines.clear();
+            kept.push(token);
+            continue;
+        }
+
+        let text = token.lexeme.trim_end();
+        let alone = kept.last().is_none_or(|previous| previous.line < token.line);
+        match text.strip_prefix("///") {
+            Some(line) if alone && !line.starts_with('/') => {
+                if lines.is_empty() || last_line + 1 != token.line {
+                    lines.clear();
+                }
+                lines.push(line.strip_prefix(' ').unwrap_or(line).to_owned());
+                last_line = token.line;
+            }
+            _ => lines.clear(),
+        }
+    }
+
+    (kept, docs)
+}
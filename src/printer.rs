@@ -1,44 +1,133 @@
 use crate::prelude::*;
 
-#[allow(unused)]
+/// Prints the AST as s-expressions.
+///
+/// Statements nested in other statements (e.g. a block or a function body) are
+/// put on their own lines and indented, so the output reads like a tree.
 pub struct AstPrinter;
 
 impl AstPrinter {
-    #[allow(unused)]
-    pub fn to_string(expr: &Expr) -> String {
-        match expr {
-            Expr::Binary { left, operator, right } => {
-                format!(
-                    "({} {} {})",
-                    operator.lexeme,
-                    Self::to_string(left),
-                    Self::to_string(right)
-                )
-            }
-            Expr::Grouping { expr } => format!("(group {})", Self::to_string(expr)),
+    pub fn to_string(ast: &Ast, expr: ExprId) -> String {
+        let print = |expr| Self::to_string(ast, expr);
+        match &ast[expr] {
+            Expr::Binary { left, operator, right } | Expr::Logical { left, operator, right } => {
+                format!("({} {} {})", operator.lexeme, print(*left), print(*right))
+            }
+            Expr::Grouping { expr } => format!("(group {})", print(*expr)),
+            Expr::Literal { value: Literal::String(s) } => format!("{s:?}"),
             Expr::Literal { value } => format!("{value}"),
-            Expr::Unary { operator, right } => {
-                format!("({} {})", operator.lexeme, Self::to_string(right))
-            }
-            Expr::Variable { name } => format!("{name}"),
-            Expr::Assignment { name, value } => format!("{name} = {}", Self::to_string(value)),
-            Expr::Logical { left, operator, right } => {
-                format!(
-                    "({} {} {})",
-                    operator.lexeme,
-                    Self::to_string(left),
-                    Self::to_string(right)
-                )
+            Expr::Unary { operator, right } => format!("({} {})", operator.lexeme, print(*right)),
+            Expr::Variable { binding: _, name } => name.lexeme.to_string(),
+            Expr::Assignment { binding: _, name, value } => {
+                format!("(= {} {})", name.lexeme, print(*value))
             }
             Expr::Call { callee, paren: _, arguments } => {
-                format!("{:?}({arguments:?})", Self::to_string(callee))
+                let mut parts = vec!["call".to_owned(), print(*callee)];
+                parts.extend(arguments.iter().copied().map(print));
+                format!("({})", parts.join(" "))
+            }
+            Expr::This { binding: _, keyword: _ } => "this".to_owned(),
+            Expr::Error => "(error)".to_owned(),
+            Expr::Get { object, name, cache: _ } => {
+                format!("(. {} {})", print(*object), name.lexeme)
             }
-            Expr::This { keyword } => format!("{keyword}"),
-            Expr::Get { object, name } => format!("{:?}.{name}", Self::to_string(object)),
             Expr::Set { object, name, value } => {
-                format!("{:?}.{name} = {:?}", Self::to_string(object), Self::to_string(value))
+                format!("(set {} {} {})", print(*object), name.lexeme, print(*value))
+            }
+            Expr::Index { object, bracket: _, index } => {
+                format!("([] {} {})", print(*object), print(*index))
+            }
+            Expr::IndexSet { object, bracket: _, index, value } => {
+                format!("([]= {} {} {})", print(*object), print(*index), print(*value))
+            }
+            Expr::List { bracket: _, elements } => {
+                let mut parts = vec!["list".to_owned()];
+                parts.extend(elements.iter().copied().map(print));
+                format!("({})", parts.join(" "))
+            }
+            Expr::Super { binding: _, keyword: _, method } => format!("(super {})", method.lexeme),
+            Expr::Function { keyword: _, declaration } => {
+                let Stmt::Function { name: _, params, body } = &ast[*declaration] else {
+                    return "(error)".to_owned();
+                };
+                let mut parts = vec![];
+                let params = params.iter().map(|p| &*p.lexeme).collect::<Vec<_>>();
+                parts.push(format!("({})", params.join(" ")));
+                parts.extend(body.iter().map(|&stmt| Self::stmt_to_string(ast, stmt)));
+                format!("(fun {})", parts.join(" "))
+            }
+        }
+    }
+
+    /// Print all the statements of a program, one top-level statement per line.
+    pub fn program_to_string(ast: &Ast) -> String {
+        let statements = ast.statements().iter().map(|&stmt| Self::stmt_to_string(ast, stmt));
+        statements.collect::<Vec<_>>().join("\n")
+    }
+
+    pub fn stmt_to_string(ast: &Ast, stmt: StmtId) -> String {
+        Self::indented_stmt(ast, stmt, 0)
+    }
+
+    fn indented_stmt(ast: &Ast, stmt: StmtId, indent: usize) -> String {
+        let print = |expr| Self::to_string(ast, expr);
+        let nested = |statements: &mut dyn Iterator<Item = StmtId>| -> String {
+            statements
+                .map(|s| {
+                    let stmt = Self::indented_stmt(ast, s, indent + 1);
+                    format!("\n{}{stmt}", "  ".repeat(indent + 1))
+                })
+                .collect()
+        };
+
+        match &ast[stmt] {
+            Stmt::Break { token: _ } => "(break)".to_owned(),
+            Stmt::Error => "(error)".to_owned(),
+            Stmt::Return { keyword: _, value: Some(value) } => {
+                format!("(return {})", print(*value))
+            }
+            Stmt::Return { keyword: _, value: None } => "(return)".to_owned(),
+            Stmt::Class { name, methods, superclass } => {
+                let superclass = match superclass {
+                    Some(superclass) => format!(" (< {})", print(*superclass)),
+                    None => String::new(),
+                };
+                let methods = nested(&mut methods.iter().copied());
+                format!("(class {}{superclass}{methods})", name.lexeme)
+            }
+            Stmt::Extend { keyword: _, class, methods } => {
+                let methods = nested(&mut methods.iter().copied());
+                format!("(extend {}{methods})", print(*class))
+            }
+            Stmt::Print { exprs } => {
+                let exprs = exprs.iter().copied().map(print).collect::<Vec<_>>();
+                format!("(print {})", exprs.join(" "))
+            }
+            Stmt::Expression { expr } => format!("(expr {})", print(*expr)),
+            Stmt::Var { name, initializer: Some(initializer) } => {
+                format!("(var {} {})", name.lexeme, print(*initializer))
+            }
+            Stmt::Var { name, initializer: None } => format!("(var {})", name.lexeme),
+            Stmt::Block { statements } => {
+                format!("(block{})", nested(&mut statements.iter().copied()))
+            }
+            Stmt::Function { name, params, body } => {
+                let params = params.iter().map(|p| &*p.lexeme).collect::<Vec<_>>();
+                let body = nested(&mut body.iter().copied());
+                format!("(fun {} ({}){body})", name.lexeme, params.join(" "))
+            }
+            Stmt::If { condition, then_branch, else_branch } => {
+                let mut branches = std::iter::once(*then_branch).chain(*else_branch);
+                format!("(if {}{})", print(*condition), nested(&mut branches))
+            }
+            Stmt::While { keyword: _, condition, body, else_branch } => {
+                let body = nested(&mut std::iter::once(*body).chain(*else_branch));
+                format!("(while {}{body})", print(*condition))
+            }
+            Stmt::Using { keyword: _, name, initializer, body, close: _ } => {
+                let body = nested(&mut std::iter::once(*body));
+                format!("(using {} {}{body})", name.lexeme, print(*initializer))
             }
-            Expr::Super { keyword, method } => format!("{keyword}.{method}"),
         }
     }
 }
@@ -51,16 +140,43 @@ mod tests {
     #[test]
     fn print_an_ast() {
         // This is '-123 * (45.67)'
-        let expr = Expr::Binary {
-            left: Box::new(Expr::Unary {
-                operator: Token::new(TokenType::Minus, "-", None, 1),
-                right: Box::new(Expr::int_literal(123.0)),
-            }),
+        let mut ast = Ast::new();
+        let number = ast.add_expr(Expr::int_literal(123.0));
+        let left = ast.add_expr(Expr::Unary {
+            operator: Token::new(TokenType::Minus, "-", None, 1),
+            right: number,
+        });
+        let number = ast.add_expr(Expr::int_literal(45.67));
+        let right = ast.add_expr(Expr::Grouping { expr: number });
+        let expr = ast.add_expr(Expr::Binary {
+            left,
             operator: Token::new(TokenType::Star, "*", None, 1),
-            right: Box::new(Expr::Grouping { expr: Box::new(Expr::int_literal(45.67)) }),
-        };
+            right,
+        });
 
-        let res = AstPrinter::to_string(&expr);
+        let res = AstPrinter::to_string(&ast, expr);
         assert_eq!(res, "(* (- 123) (group 45.67))".to_owned());
     }
+
+    #[test]
+    fn print_statements() {
+        let source = r#"
+            fun greet(name) {
+                if (name == nil) return;
+                print "Hi ", name;
+            }
+            greet("Bob");
+        "#;
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let (ast, errors) = Parser::new(tokens).parse();
+        assert!(errors.is_empty(), "failed to parse the source");
+
+        let expected = r#"(fun greet (name)
+  (if (== name nil)
+    (return))
+  (print "Hi " name))
+(expr (call greet "Bob"))"#;
+
+        assert_eq!(AstPrinter::program_to_string(&ast), expected);
+    }
 }
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use crate::hooks::InterpreterHooks;
+use crate::object::Object;
+
+/// The time spent in each function called by a program, as recorded by the
+/// interpreter with profiling on.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    /// The functions that were called, the ones that took the most time by
+    /// themselves first
+    pub functions: Vec<FunctionProfile>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionProfile {
+    /// The function as it's printed, e.g. `<fn fib>`, or the name of a class
+    /// for its constructor
+    pub name: String,
+    /// The line the function is declared on, if it's declared in Lox
+    pub line: Option<u32>,
+    pub calls: u64,
+    /// Time spent in the function, including the functions it called.
+    /// Recursive calls are only counted once.
+    pub total: Duration,
+    /// Time spent in the function itself
+    pub own: Duration,
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:>10} {:>12} {:>12}  function", "calls", "total (ms)", "self (ms)")?;
+        for function in &self.functions {
+            let name = match function.line {
+                Some(line) => format!("{} (line {line})", function.name),
+                None => function.name.clone(),
+            };
+            write!(
+                f,
+                "\n{:>10} {:>12.3} {:>12.3}  {name}",
+                function.calls,
+                function.total.as_secs_f64() * 1000.0,
+                function.own.as_secs_f64() * 1000.0,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+type FunctionId = (String, Option<u32>);
+
+#[derive(Debug, Default)]
+struct Totals {
+    calls: u64,
+    total: Duration,
+    own: Duration,
+    /// Number of calls to the function that haven't returned yet
+    active: usize,
+}
+
+/// A call that hasn't returned yet.
+#[derive(Debug)]
+struct Frame {
+    function: FunctionId,
+    start: Instant,
+    /// Time spent in the functions it called
+    children: Duration,
+}
+
+/// Records the calls of a program.
+#[derive(Debug, Default)]
+pub(crate) struct Profiler {
+    frames: Vec<Frame>,
+    functions: HashMap<FunctionId, Totals>,
+}
+
+impl Profiler {
+    pub fn enter(&mut self, name: String, line: Option<u32>) {
+        let function = (name, line);
+        self.functions.entry(function.clone()).or_default().active += 1;
+        self.frames.push(Frame { function, start: Instant::now(), children: Duration::ZERO });
+    }
+
+    pub fn exit(&mut self) {
+        let frame = self.frames.pop().expect("no call to return from");
+        let elapsed = frame.start.elapsed();
+
+        let totals = self.functions.get_mut(&frame.function).expect("call was not recorded");
+        totals.calls += 1;
+        totals.own += elapsed.saturating_sub(frame.children);
+        totals.active -= 1;
+        if totals.active == 0 {
+            totals.total += elapsed;
+        }
+
+        if let Some(caller) = self.frames.last_mut() {
+            caller.children += elapsed;
+        }
+    }
+
+    pub fn profile(&self) -> Profile {
+        let mut functions = self
+            .functions
+            .iter()
+            .map(|((name, line), totals)| FunctionProfile {
+                name: name.clone(),
+                line: *line,
+                calls: totals.calls,
+                total: totals.total,
+                own: totals.own,
+            })
+            .collect::<Vec<_>>();
+        functions.sort_by(|a, b| b.own.cmp(&a.own).then_with(|| a.name.cmp(&b.name)));
+
+        Profile { functions }
+    }
+}
+
+impl InterpreterHooks for Profiler {
+    fn on_call(&mut self, name: &str, line: Option<u32>, _depth: usize) {
+        self.enter(name.to_owned(), line);
+    }
+
+    fn on_return(&mut self, _value: Option<&Object>) {
+        self.exit();
+    }
+}
@@ -0,0 +1,198 @@
+//! Python bindings, enabled by the `pyo3` feature. They make a `lox` module
+//! with a `Lox` class, whose methods raise `lox.LoxError` when a program
+//! fails:
+//!
+//! ```python
+//! import lox
+//!
+//! interpreter = lox.Lox()
+//! interpreter.run("fun add(a, b) { return a + b; }")
+//! interpreter.eval("add(1, 2)")  # 3.0
+//! interpreter.call("add", "a", "b")  # 'ab'
+//! ```
+//!
+//! `nil`, booleans, numbers and strings become `None`, `bool`, `float` and
+//! `str`, and back. Functions, classes and instances are returned as the text
+//! Lox prints for them. Programs run on the tree-walking interpreter and print
+//! to `sys.stdout`, so their output shows up in notebooks.
+
+use std::io::Write;
+
+use pyo3::exceptions::{PyException, PyTypeError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyString, PyTuple};
+use pyo3::{IntoPyObjectExt, create_exception};
+
+use crate::Lox;
+use crate::prelude::*;
+
+create_exception!(lox, LoxError, PyException, "A Lox program failed.");
+
+#[pyclass(name = "Lox", unsendable)]
+struct PyLox {
+    lox: Lox,
+}
+
+#[pymethods]
+impl PyLox {
+    #[new]
+    fn new() -> Self {
+        let mut lox = Lox::new();
+        lox.set_diagnostic_format(DiagnosticFormat::Plain);
+        lox.set_output(PythonStdout);
+        Self { lox }
+    }
+
+    /// Run the source code, keeping the globals of the previous runs.
+    fn run(&mut self, source: &str) -> PyResult<()> {
+        self.lox.run(source).map_err(lox_error)
+    }
+
+    /// Run the source code and return the value of the expression it ends
+    /// with, or `None` if it doesn't end with one.
+    fn eval(&mut self, py: Python<'_>, source: &str) -> PyResult<Py<PyAny>> {
+        let ast = self.lox.compile(source, true).map_err(lox_error)?;
+        self.lox.execute(&ast).map_err(lox_error)?;
+
+        let Some(expr) = ast.result() else {
+            return Ok(py.None());
+        };
+        match self.lox.interpreter.evaluate(&ast, expr) {
+            Ok(value) => to_python(py, &value),
+            Err(errors) => Err(lox_error(self.lox.report_runtime_errors(Err(errors)).unwrap_err())),
+        }
+    }
+
+    /// Call a global function or class with the arguments, and return its
+    /// result.
+    #[pyo3(signature = (name, *args))]
+    fn call(
+        &mut self,
+        py: Python<'_>,
+        name: &str,
+        args: &Bound<'_, PyTuple>,
+    ) -> PyResult<Py<PyAny>> {
+        let arguments = args.iter().map(|arg| from_python(&arg)).collect::<PyResult<Vec<_>>>()?;
+
+        let callee = self.lox.interpreter.globals.borrow().lookup(name);
+        let interpreter = &mut self.lox.interpreter;
+        let (arity, result) = match callee {
+            Some(Object::Callable(callable)) => {
+                let arity = callable.arity();
+                let result = (arity == arguments.len())
+                    .then(|| interpreter.host_call(|i| callable.call(i, &arguments)));
+                (arity, result)
+            }
+            Some(Object::Class(class)) => {
+                let arity = class.borrow().arity();
+                let result = (arity == arguments.len()).then(|| {
+                    interpreter
+                        .host_call(|i| Class::construct(class, &arguments, i))
+                        .map(Object::Instance)
+                });
+                (arity, result)
+            }
+            Some(_) => return Err(PyTypeError::new_err(format!("'{name}' is not callable"))),
+            None => return Err(LoxError::new_err(format!("Undefined variable '{name}'."))),
+        };
+
+        match result {
+            Some(Ok(value)) => to_python(py, &value),
+            Some(Err(error)) => Err(LoxError::new_err(error.message)),
+            None => Err(LoxError::new_err(format!(
+                "Expected {arity} arguments but got {}.",
+                arguments.len()
+            ))),
+        }
+    }
+}
+
+/// Writes what programs print to Python's `sys.stdout`.
+struct PythonStdout;
+
+impl Write for PythonStdout {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        Python::attach(|py| {
+            py.import("sys")?.getattr("stdout")?.call_method1("write", (text,))?;
+            Ok::<_, PyErr>(())
+        })
+        .map_err(std::io::Error::other)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn lox_error(error: anyhow::Error) -> PyErr {
+    LoxError::new_err(error.to_string())
+}
+
+fn to_python(py: Python<'_>, value: &Object) -> PyResult<Py<PyAny>> {
+    match value {
+        Object::Null => Ok(py.None()),
+        Object::Boolean(b) => b.into_py_any(py),
+        Object::Number(n) => n.into_py_any(py),
+        Object::String(s) => s.into_py_any(py),
+        value => value.to_string().into_py_any(py),
+    }
+}
+
+fn from_python(value: &Bound<'_, PyAny>) -> PyResult<Object> {
+    if value.is_none() {
+        Ok(Object::Null)
+    } else if value.is_instance_of::<PyBool>() {
+        Ok(Object::Boolean(value.extract()?))
+    } else if value.is_instance_of::<PyString>() {
+        Ok(Object::String(value.extract::<String>()?.into()))
+    } else if let Ok(n) = value.extract::<f64>() {
+        Ok(Object::Number(n))
+    } else {
+        Err(PyTypeError::new_err(format!("can't pass a {} to Lox", value.get_type().name()?)))
+    }
+}
+
+#[pymodule]
+fn lox(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyLox>()?;
+    module.add("LoxError", module.py().get_type::<LoxError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+
+    #[test]
+    fn python_drives_the_interpreter() {
+        Python::initialize();
+        Python::attach(|py| {
+            let module = PyModule::new(py, "lox").unwrap();
+            lox(&module).unwrap();
+            py.import("sys").unwrap().getattr("modules").unwrap().set_item("lox", module).unwrap();
+
+            let code = CString::new(
+                r#"
+import lox
+interpreter = lox.Lox()
+interpreter.run("fun add(a, b) { return a + b; }")
+assert interpreter.eval("add(1, 2)") == 3.0
+assert interpreter.eval("var a = 1;") is None
+assert interpreter.call("add", "a", "b") == "ab"
+assert interpreter.eval("!nil") is True
+try:
+    interpreter.call("add", 1)
+    assert False
+except lox.LoxError as e:
+    assert str(e) == "Expected 2 arguments but got 1."
+"#,
+            )
+            .unwrap();
+            py.run(&code, None, None).unwrap();
+        });
+    }
+}
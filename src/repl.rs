@@ -0,0 +1,244 @@
+use std::io::IsTerminal;
+use std::path::PathBuf;
+
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+use crate::prelude::{DiagnosticCode, KEYWORDS, Parser, ParserError, Scanner, Value};
+use crate::{Backend, Lox};
+
+static HISTORY_FILE: &str = ".lox_history";
+
+impl Lox {
+    /// Run an interactive prompt until the user presses Ctrl-D or Ctrl-C.
+    ///
+    /// Every input is run on the same interpreter, so definitions carry over to
+    /// the next ones. If an input ends with an expression, its value is
+    /// printed, and the `;` after it is optional. Inputs that end in the middle
+    /// of a statement (e.g. an unclosed brace) are continued on the next
+    /// line. An input that is still unfinished at the end of the stream is run
+    /// anyway, so its errors are reported.
+    ///
+    /// `:doc name` prints the doc comment of a global function or class, and
+    /// `:env` prints the globals defined by the programs with their values.
+    /// `:save file` saves the globals to the file, and `:load file` restores
+    /// them, e.g. in a later session.
+    ///
+    /// When the input comes from a terminal, history is kept in the file named
+    /// by `LOX_HISTORY`, or else in `~/.lox_history`. The Tab key completes
+    /// keywords and global names.
+    pub fn run_prompt(&mut self) -> Result<(), anyhow::Error> {
+        let mut editor = Editor::<ReplHelper, DefaultHistory>::new()?;
+        editor.set_helper(Some(ReplHelper { names: self.completion_names() }));
+
+        let history = history_path();
+        if let Some(ref path) = history {
+            // The file doesn't exist on the first run
+            let _ = editor.load_history(path);
+        }
+
+        let mut buffer = String::new();
+
+        loop {
+            let prompt = if buffer.is_empty() { "> " } else { "... " };
+
+            match editor.readline(prompt) {
+                Ok(line) => {
+                    if buffer.is_empty() && line.trim().is_empty() {
+                        continue;
+                    }
+
+                    buffer.push_str(&line);
+                    buffer.push('\n');
+
+                    // An empty line forces the input to run, so the user can see what is wrong
+                    // with it instead of getting stuck in the continuation prompt.
+                    if !line.trim().is_empty() && is_incomplete(&buffer) {
+                        continue;
+                    }
+
+                    let input = std::mem::take(&mut buffer);
+                    self.run_input(&mut editor, &input)?;
+
+                    let names = self.completion_names();
+                    if let Some(helper) = editor.helper_mut() {
+                        helper.names = names;
+                    }
+                }
+                // Ctrl-C in the middle of a multi-line input only discards that input
+                Err(ReadlineError::Interrupted) if !buffer.is_empty() => buffer.clear(),
+                Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // Only the end of the stream leaves an input behind
+        if !buffer.is_empty() {
+            self.run_input(&mut editor, &buffer)?;
+        }
+
+        if let Some(ref path) = history {
+            editor.save_history(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Run an input of the prompt and add it to the history.
+    fn run_input(
+        &mut self,
+        editor: &mut Editor<ReplHelper, DefaultHistory>,
+        input: &str,
+    ) -> Result<(), anyhow::Error> {
+        editor.add_history_entry(input.trim_end())?;
+        if let Err(e) = self.run_line(input) {
+            eprintln!("{e}");
+        }
+        Ok(())
+    }
+
+    fn run_line(&mut self, line: &str) -> Result<(), anyhow::Error> {
+        if let Some(name) = line.trim().strip_prefix(":doc") {
+            let name = name.trim();
+            match self.doc(name) {
+                Some(doc) => println!("{doc}"),
+                None => println!("No documentation for '{name}'."),
+            }
+            return Ok(());
+        }
+        if line.trim() == ":env" {
+            for (name, value) in self.global_values() {
+                println!("{name} = {value}");
+            }
+            return Ok(());
+        }
+        if let Some(path) = line.trim().strip_prefix(":save") {
+            let snapshot = self.snapshot().ok_or_else(|| {
+                anyhow::anyhow!("Only the tree-walking interpreter can save snapshots.")
+            })?;
+            std::fs::write(path.trim(), snapshot)?;
+            return Ok(());
+        }
+        if let Some(path) = line.trim().strip_prefix(":load") {
+            let snapshot = std::fs::read_to_string(path.trim())?;
+            return self.restore(&snapshot);
+        }
+
+        let value = self.eval(line);
+        if let Some(warnings) = self.render_warnings() {
+            eprintln!("{warnings}");
+        }
+
+        // Echo the value of a trailing expression, e.g. `1 + 2` prints `3`.
+        if let Some(value) = value? {
+            println!("{value}");
+        }
+        Ok(())
+    }
+
+    /// The globals defined by the programs, without the natives, sorted by
+    /// name.
+    fn global_values(&self) -> Vec<(String, String)> {
+        match self.backend {
+            Backend::TreeWalker => crate::debugger::variables(&self.interpreter.globals)
+                .into_iter()
+                .map(|(name, value)| (name, value.to_string()))
+                .collect(),
+            Backend::Vm => {
+                let mut names = self.vm.global_names();
+                names.sort();
+                let values = names.into_iter().filter_map(|name| match self.vm.global(&name)? {
+                    Value::Native(_) => None,
+                    value => Some((name, value.to_string())),
+                });
+                values.collect()
+            }
+        }
+    }
+
+    /// Keywords plus all the global names, sorted.
+    fn completion_names(&self) -> Vec<String> {
+        let mut names = match self.backend {
+            Backend::TreeWalker => self.interpreter.globals.borrow().names(),
+            Backend::Vm => self.vm.global_names(),
+        };
+        names.extend(KEYWORDS.iter().map(|(keyword, _)| keyword.to_string()));
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+/// Check whether the source stops in the middle of a statement, e.g. it has an
+/// unclosed brace or parenthesis, a trailing operator or an unterminated
+/// string.
+fn is_incomplete(source: &str) -> bool {
+    let tokens = match Scanner::new(source).scan_tokens() {
+        Ok(tokens) => tokens,
+        // A string is only unterminated when the scanner reaches the end of the source.
+        Err(errors) => return errors.iter().all(|e| e.code == DiagnosticCode::UnterminatedString),
+    };
+
+    let (_, errors) = Parser::new(tokens).with_trailing_expression(true).parse();
+    let mut errors = errors.iter().filter(|e| !e.is_warning()).peekable();
+    errors.peek().is_some() && errors.all(ParserError::is_at_end)
+}
+
+/// The file to keep the history in. Input piped into the prompt isn't typed
+/// by the user, so it doesn't go into their history.
+fn history_path() -> Option<PathBuf> {
+    if !std::io::stdin().is_terminal() {
+        return None;
+    }
+    std::env::var_os("LOX_HISTORY")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE)))
+}
+
+/// Completes the word under the cursor with a keyword or a global name.
+struct ReplHelper {
+    names: Vec<String>,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .char_indices()
+            .rev()
+            .find(|(_, c)| !(c.is_alphanumeric() || *c == '_'))
+            .map_or(0, |(i, c)| i + c.len_utf8());
+
+        let prefix = &line[start..pos];
+
+        // Don't complete property names, we don't know the type of the object.
+        if prefix.is_empty() || line[..start].ends_with('.') {
+            return Ok((pos, vec![]));
+        }
+
+        let candidates =
+            self.names.iter().filter(|name| name.starts_with(prefix)).cloned().collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
@@ -1,9 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::Display;
 
-use super::Interpreter;
-use crate::prelude::{Expr, Stmt};
+use crate::prelude::{Ast, Binding, DiagnosticCode, Expr, ExprId, Resolution, Stmt, StmtId};
+use crate::sync::Rc;
 use crate::token::Token;
 
 #[derive(Debug, Clone, PartialEq, Copy)]
@@ -19,89 +19,176 @@ enum ClassType {
     None,
     Class,
     SubClass,
+    /// The methods of an `extend` statement, which don't know the superclass
+    Extension,
+}
+
+/// A local variable in a scope.
+#[derive(Debug, Clone)]
+struct Local {
+    /// False while the initializer of the variable is being resolved
+    defined: bool,
+    /// Position of the variable among the variables of its scope
+    slot: usize,
+    /// Whether the variable is read anywhere, assignments don't count
+    used: bool,
+    /// Where the variable is declared, to report it if it's never read. None
+    /// for variables that are fine to leave unused, like parameters.
+    declaration: Option<Token>,
+    /// The number of parameters, if the variable is declared by a function
+    arity: Option<usize>,
+    /// Whether the variable is assigned anywhere, so it may hold something
+    /// else than the function it's declared with
+    assigned: bool,
+    /// The calls of the variable, with their closing parenthesis and their
+    /// number of arguments, checked against the arity at the end of the scope
+    calls: Vec<(Token, usize)>,
+}
+
+impl Local {
+    fn new(slot: usize, declaration: Option<Token>) -> Self {
+        Self {
+            defined: false,
+            slot,
+            used: false,
+            declaration,
+            arity: None,
+            assigned: false,
+            calls: vec![],
+        }
+    }
+}
+
+/// The number of local variables a function can have in scope at once, and
+/// the number of variables it can capture, like in clox, where a single byte
+/// holds the index of each.
+const MAX_LOCALS: usize = 256;
+const MAX_UPVALUES: usize = 256;
+
+/// A function being resolved.
+struct FunctionScope {
+    /// The index of the scope of its parameters in `Resolver::scopes`
+    first_scope: usize,
+    /// The variables of the enclosing functions it captures, by the index of
+    /// their scope and their name
+    upvalues: HashSet<(usize, String)>,
 }
 
 /// Resolver uses static analysis to bind local variables to the correct
-/// envorinment.
-pub struct Resolver<'i> {
-    interpreter: &'i mut Interpreter,
-    scopes: Vec<HashMap<String, bool>>,
+/// envorinment. The result is stored in the [`Binding`] of each variable
+/// expression.
+pub struct Resolver {
+    scopes: Vec<HashMap<String, Local>>,
+    /// The functions being resolved, the innermost one last
+    functions: Vec<FunctionScope>,
+    /// The local functions with where the variables they capture are
+    captures: Vec<(StmtId, Vec<Resolution>)>,
+    /// The global variables used in local scopes, with the names of the
+    /// local variables in scope there
+    scope_names: Vec<(ExprId, Vec<Rc<str>>)>,
     current_function: FunctionType,
     current_class: ClassType,
+    errors: Vec<ResolverError>,
+    /// Problems that don't stop the program from running
+    warnings: Vec<ResolverError>,
+    /// Whether a `return` at the top level ends the script instead of being
+    /// an error
+    top_level_return: bool,
 }
 
-impl<'i> Resolver<'i> {
-    pub fn new(interpreter: &'i mut Interpreter) -> Self {
+impl Resolver {
+    pub fn new() -> Self {
         Self {
-            interpreter,
             scopes: vec![],
+            functions: vec![],
+            captures: vec![],
+            scope_names: vec![],
             current_function: FunctionType::None,
             current_class: ClassType::None,
+            errors: vec![],
+            warnings: vec![],
+            top_level_return: false,
         }
     }
+
+    /// Allow `return` at the top level, to end the script with an exit code.
+    pub fn with_top_level_return(self, enabled: bool) -> Self {
+        Self { top_level_return: enabled, ..self }
+    }
+
+    /// Take the warnings found by the previous calls to [`Resolver::resolve`].
+    pub fn take_warnings(&mut self) -> Vec<ResolverError> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Take the local functions found by the previous calls to
+    /// [`Resolver::resolve`], with where the variables they capture are from
+    /// the environment the function is created in. See [`Ast::set_captures`].
+    pub fn take_captures(&mut self) -> Vec<(StmtId, Vec<Resolution>)> {
+        std::mem::take(&mut self.captures)
+    }
+
+    /// Take the global variables used in local scopes found by the previous
+    /// calls to [`Resolver::resolve`], with the names of the local variables
+    /// in scope there. See [`Ast::set_scope_names`].
+    pub fn take_scope_names(&mut self) -> Vec<(ExprId, Vec<Rc<str>>)> {
+        std::mem::take(&mut self.scope_names)
+    }
 }
 
-impl<'i> Resolver<'i> {
-    pub fn resolve<I, R>(&mut self, statements: I) -> Result<(), Vec<ResolverError>>
-    where
-        I: IntoIterator<Item = R>,
-        R: AsRef<Stmt>,
-    {
-        let mut errors = vec![];
-        for stmt in statements {
-            let res = self.resolve_single_stmt(stmt.as_ref());
-            if res.is_err() {
-                errors.push(res.err().unwrap());
-            }
+impl Resolver {
+    /// Resolve the program and report all the errors found in it.
+    pub fn resolve(&mut self, ast: &Ast) -> Result<(), Vec<ResolverError>> {
+        self.resolve_block(ast, ast.statements());
+        if let Some(result) = ast.result() {
+            self.visit_expr(ast, result);
         }
 
+        let errors = std::mem::take(&mut self.errors);
         if errors.is_empty() { Ok(()) } else { Err(errors) }
     }
 
-    fn resolve_block<I, R>(&mut self, statements: I) -> Result<(), ResolverError>
-    where
-        I: IntoIterator<Item = R>,
-        R: AsRef<Stmt>,
-    {
-        for stmt in statements {
-            self.resolve_single_stmt(stmt.as_ref())?;
+    fn resolve_block(&mut self, ast: &Ast, statements: &[StmtId]) {
+        for &stmt in statements {
+            self.resolve_single_stmt(ast, stmt);
         }
-
-        Ok(())
     }
 
-    fn resolve_single_stmt(&mut self, input: &Stmt) -> Result<(), ResolverError> {
-        match input {
+    fn resolve_single_stmt(&mut self, ast: &Ast, input: StmtId) {
+        match &ast[input] {
+            Stmt::Error => self.errors.push(ResolverError::new(
+                None,
+                DiagnosticCode::InvalidSyntaxTree,
+                "Declaration failed to parse.",
+            )),
             Stmt::Block { statements } => {
                 self.begin_scope();
-                self.resolve_block(statements)?;
+                self.resolve_block(ast, statements);
                 self.end_scope();
-
-                Ok(())
             }
             Stmt::Var { name, initializer } => {
                 // We use a 3 step process, so users can't use the same variable in
                 // variable definition: declare -> initialize -> define
-                self.declare(name)?;
+                self.declare(name);
                 if let Some(initializer) = initializer {
-                    self.resolve_expr(initializer)?;
+                    self.resolve_expr(ast, *initializer);
                 }
                 self.define(name);
-                Ok(())
             }
             Stmt::Class { name, methods, superclass } => {
                 let enclosing_class = self.current_class;
                 self.current_class = ClassType::Class;
 
-                self.declare(name)?;
+                self.declare(name);
                 self.define(name);
 
-                if let Some(superclass) = superclass {
+                if let Some(superclass) = *superclass {
                     // Make sure super class has a different name!
-                    if let Expr::Variable { name: super_name } = superclass {
+                    if let Expr::Variable { binding: _, name: super_name } = &ast[superclass] {
                         if super_name.lexeme == name.lexeme {
-                            return ResolverError::new(
-                                Some(super_name.clone()),
+                            self.error(
+                                super_name,
+                                DiagnosticCode::InheritFromItself,
                                 "A class can't inherit from itself.",
                             );
                         }
@@ -110,128 +197,197 @@ impl<'i> Resolver<'i> {
                     }
 
                     self.current_class = ClassType::SubClass;
-                    self.resolve_expr(superclass)?;
+                    self.resolve_expr(ast, superclass);
                 }
 
                 if superclass.is_some() {
                     self.begin_scope();
-                    // Safe to unwrap, because we're calling begin_scope before it
-                    self.peek_mut_scope().unwrap().insert("super".to_owned(), true);
+                    self.define_implicit("super");
                 }
 
-                self.begin_scope();
-                // Safe to unwrap, because we're calling begin_scope before it
-                self.peek_mut_scope().unwrap().insert("this".to_owned(), true);
-
-                for method in methods {
-                    let is_initializer = match method {
-                        Stmt::Function { name, params: _, body: _ } => name.lexeme == "init",
-                        _ => {
-                            // This should not happen if the parser
-                            // does its job properly!
-                            return ResolverError::new(
-                                None,
-                                "Method must be a function statement.",
-                            );
-                        }
-                    };
-
-                    let func_type = if is_initializer {
-                        FunctionType::Initializer
-                    } else {
-                        FunctionType::Method
-                    };
-
-                    self.resolve_function(method, func_type)?;
-                }
-
-                self.end_scope();
+                self.resolve_methods(ast, methods);
 
                 if superclass.is_some() {
                     self.end_scope();
                 }
 
                 self.current_class = enclosing_class;
-                Ok(())
             }
-            Stmt::Function { name, params: _, body: _ } => {
+            Stmt::Extend { keyword: _, class, methods } => {
+                self.resolve_expr(ast, *class);
+
+                let enclosing_class = self.current_class;
+                self.current_class = ClassType::Extension;
+                self.resolve_methods(ast, methods);
+                self.current_class = enclosing_class;
+            }
+            Stmt::Function { name, params, body: _ } => {
                 // Unlike variables, we declare and define functions before processing
                 // their body. This way, functions can recursively call themselves.
-                self.declare(name)?;
+                self.declare(name);
                 self.define(name);
+                if let Some(local) = self.scopes.last_mut().and_then(|s| s.get_mut(&*name.lexeme)) {
+                    local.arity = Some(params.len());
+                }
 
-                self.resolve_function(input, FunctionType::Function)
+                self.resolve_function(ast, input, FunctionType::Function);
             }
-            Stmt::Expression { expr } => self.resolve_expr(expr),
+            Stmt::Expression { expr } => self.resolve_expr(ast, *expr),
             Stmt::If { condition, then_branch, else_branch } => {
-                self.resolve_expr(condition)?;
-                self.resolve_single_stmt(then_branch)?;
-                if let Some(stmt) = else_branch {
-                    self.resolve_single_stmt(stmt)?;
+                self.resolve_expr(ast, *condition);
+                self.resolve_single_stmt(ast, *then_branch);
+                if let Some(stmt) = *else_branch {
+                    self.resolve_single_stmt(ast, stmt);
                 }
-                Ok(())
             }
             Stmt::Print { exprs } => {
-                for ex in exprs {
-                    self.resolve_expr(ex)?;
+                for &ex in exprs {
+                    self.resolve_expr(ast, ex);
                 }
-                Ok(())
             }
             Stmt::Return { keyword, value } => {
-                if self.current_function == FunctionType::None {
-                    return ResolverError::new(
-                        Some(keyword.clone()),
+                if self.current_function == FunctionType::None && !self.top_level_return {
+                    self.error(
+                        keyword,
+                        DiagnosticCode::ReturnFromTopLevel,
                         "Can't return from top-level code.",
                     );
                 }
 
-                if let Some(expr) = value {
+                if let Some(expr) = *value {
                     // Cannot return anything from "init" function
                     if self.current_function == FunctionType::Initializer {
-                        return ResolverError::new(
-                            Some(keyword.clone()),
+                        self.error(
+                            keyword,
+                            DiagnosticCode::ReturnValueFromInitializer,
                             "Can't return a value from an initializer.",
                         );
                     }
-                    self.resolve_expr(expr)?;
+                    self.resolve_expr(ast, expr);
                 }
-                Ok(())
             }
-            Stmt::While { condition, body } => {
-                self.resolve_expr(condition)?;
-                self.resolve_single_stmt(body)
+            Stmt::Using { keyword: _, name, initializer, body, close } => {
+                // The variable is only in scope in the body
+                self.begin_scope();
+                self.declare(name);
+                self.resolve_expr(ast, *initializer);
+                self.define(name);
+                self.resolve_single_stmt(ast, *body);
+                self.resolve_expr(ast, *close);
+                self.end_scope();
+            }
+            Stmt::While { keyword: _, condition, body, else_branch } => {
+                self.resolve_expr(ast, *condition);
+                self.resolve_single_stmt(ast, *body);
+                if let Some(stmt) = *else_branch {
+                    self.resolve_single_stmt(ast, stmt);
+                }
             }
-            Stmt::Break { token: _ } => Ok(()),
+            Stmt::Break { token: _ } => {}
         }
     }
 }
 
-impl<'a> Resolver<'a> {
+impl Resolver {
     fn begin_scope(&mut self) {
         self.scopes.push(HashMap::new());
     }
 
+    /// Leave the innermost scope, check the calls of its functions and warn
+    /// about its variables that are never read.
     fn end_scope(&mut self) {
-        self.scopes.pop();
+        let Some(scope) = self.scopes.pop() else {
+            return;
+        };
+
+        let mut locals = scope.values().collect::<Vec<_>>();
+        locals.sort_by_key(|local| local.slot);
+        for local in locals.into_iter().filter(|local| !local.assigned) {
+            let Some(arity) = local.arity else {
+                continue;
+            };
+            for (paren, count) in local.calls.iter().filter(|(_, count)| *count != arity) {
+                self.error(
+                    paren,
+                    DiagnosticCode::StaticArityMismatch,
+                    &format!("Expected {arity} arguments but got {count}."),
+                );
+            }
+        }
+
+        let mut unused = scope
+            .into_values()
+            .filter(|local| !local.used)
+            .filter_map(|local| Some((local.slot, local.declaration?)))
+            .collect::<Vec<_>>();
+        unused.sort_by_key(|(slot, _)| *slot);
+
+        for (_, token) in unused {
+            self.warnings.push(ResolverError::new(
+                Some(token),
+                DiagnosticCode::UnusedVariable,
+                "Local variable is never read.",
+            ));
+        }
+    }
+
+    /// Record which variables a function captures, once its body is resolved.
+    fn close_over(&mut self, stmt: StmtId, function: FunctionScope) {
+        // Functions at the top level are created in the global environment
+        // already
+        let Some(declaring) = function.first_scope.checked_sub(1) else {
+            return;
+        };
+
+        let captures = function
+            .upvalues
+            .iter()
+            .map(|(index, var)| Resolution {
+                depth: declaring - index,
+                slot: self.scopes[*index][var].slot,
+            })
+            .collect();
+        self.captures.push((stmt, captures));
+    }
+
+    /// Report an error and keep resolving, so all the errors are found in one
+    /// run.
+    fn error(&mut self, token: &Token, code: DiagnosticCode, msg: &str) {
+        self.errors.push(ResolverError::new(Some(token.clone()), code, msg));
     }
 
-    fn declare(&mut self, name: &Token) -> Result<(), ResolverError> {
+    fn declare(&mut self, name: &Token) {
         if self.scopes.is_empty() {
-            return Ok(());
+            return;
         }
 
         let last_idx = self.scopes.len() - 1;
         let last = self.scopes.get_mut(last_idx).unwrap();
 
-        if last.contains_key(&name.lexeme) {
-            return ResolverError::new(
-                Some(name.clone()),
+        if last.contains_key(&*name.lexeme) {
+            self.error(
+                name,
+                DiagnosticCode::DuplicateVariable,
                 "Already a variable with this name in this scope.",
             );
+            return;
         }
 
-        last.insert(name.lexeme.clone(), false);
-        Ok(())
+        // Slot zero of each function holds the function itself or `this`
+        let first_scope = self.functions.last().map_or(0, |function| function.first_scope);
+        let count = 1 + self.scopes[first_scope..].iter().map(HashMap::len).sum::<usize>();
+        if count >= MAX_LOCALS {
+            self.error(
+                name,
+                DiagnosticCode::TooManyLocals,
+                "Too many local variables in function.",
+            );
+            return;
+        }
+
+        let last = self.scopes.last_mut().unwrap();
+        let slot = last.len();
+        last.insert(name.lexeme.to_string(), Local::new(slot, Some(name.clone())));
     }
 
     fn define(&mut self, name: &Token) {
@@ -242,147 +398,307 @@ impl<'a> Resolver<'a> {
 
         let last_idx = self.scopes.len() - 1;
         let last = self.scopes.get_mut(last_idx).unwrap();
-        last.insert(name.lexeme.clone(), true);
+        if let Some(local) = last.get_mut(&*name.lexeme) {
+            local.defined = true;
+        }
     }
 
-    fn peek_mut_scope(&mut self) -> Option<&mut HashMap<String, bool>> {
-        if self.scopes.is_empty() {
-            return None;
-        }
+    /// Define a variable that isn't declared in the source, like `this`, in the
+    /// innermost scope.
+    fn define_implicit(&mut self, name: &str) {
+        // Safe to unwrap, because we're calling begin_scope before it
+        let last = self.scopes.last_mut().unwrap();
+        let slot = last.len();
+        last.insert(name.to_owned(), Local { defined: true, ..Local::new(slot, None) });
+    }
 
-        let last_idx = self.scopes.len() - 1;
-        Some(self.scopes.get_mut(last_idx).unwrap())
+    /// Don't warn if the variable of the innermost scope is never read.
+    fn allow_unused(&mut self, name: &Token) {
+        if let Some(local) = self.scopes.last_mut().and_then(|scope| scope.get_mut(&*name.lexeme)) {
+            local.declaration = None;
+        }
     }
 
-    fn resolve_expr(&mut self, expr: &Expr) -> Result<(), ResolverError> {
-        self.visit_expr(expr)
+    fn resolve_expr(&mut self, ast: &Ast, expr: ExprId) {
+        self.visit_expr(ast, expr)
     }
 
-    fn resolve_this(&mut self, expr: &Expr, keyword: &Token) -> Result<(), ResolverError> {
+    fn resolve_this(&mut self, binding: &Binding, keyword: &Token) {
         if self.current_class == ClassType::None {
-            return ResolverError::new(
-                Some(keyword.clone()),
+            self.error(
+                keyword,
+                DiagnosticCode::ThisOutsideClass,
                 "Can't use 'this' outside of a class.",
             );
+            return;
         }
 
-        self.resolve_local(expr, keyword)
+        self.resolve_local(binding, keyword, true);
     }
 
-    fn resolve_function(
-        &mut self,
-        stmt: &Stmt,
-        func_type: FunctionType,
-    ) -> Result<(), ResolverError> {
-        if let Stmt::Function { name: _, params, body } = stmt {
+    /// Resolve the methods of a class or an extension, in the scope that
+    /// defines `this`.
+    fn resolve_methods(&mut self, ast: &Ast, methods: &[StmtId]) {
+        self.begin_scope();
+        self.define_implicit("this");
+
+        let mut method_names = HashSet::new();
+        for &method in methods {
+            let is_initializer = match &ast[method] {
+                Stmt::Function { name, params: _, body: _ } => {
+                    // The later method would silently replace the earlier one
+                    if !method_names.insert(&name.lexeme) {
+                        self.error(
+                            name,
+                            DiagnosticCode::DuplicateMethod,
+                            "Already a method with this name in this class.",
+                        );
+                    }
+                    &*name.lexeme == "init"
+                }
+                _ => {
+                    // This should not happen if the parser
+                    // does its job properly!
+                    self.errors.push(ResolverError::new(
+                        None,
+                        DiagnosticCode::InvalidSyntaxTree,
+                        "Method must be a function statement.",
+                    ));
+                    continue;
+                }
+            };
+
+            let func_type =
+                if is_initializer { FunctionType::Initializer } else { FunctionType::Method };
+
+            self.resolve_function(ast, method, func_type);
+        }
+
+        self.end_scope();
+    }
+
+    fn resolve_function(&mut self, ast: &Ast, stmt: StmtId, func_type: FunctionType) {
+        if let Stmt::Function { name: _, params, body } = &ast[stmt] {
             let enclosing_func = self.current_function;
             self.current_function = func_type;
 
             self.begin_scope();
+            let first_scope = self.scopes.len() - 1;
+            self.functions.push(FunctionScope { first_scope, upvalues: HashSet::new() });
             for param in params {
-                self.declare(param)?;
+                if self.scopes.last().is_some_and(|scope| scope.contains_key(&*param.lexeme)) {
+                    self.error(
+                        param,
+                        DiagnosticCode::DuplicateParameter,
+                        // The message of the reference implementation
+                        "Already a variable with this name in this scope.",
+                    );
+                    continue;
+                }
+                self.declare(param);
                 self.define(param);
+                self.allow_unused(param);
             }
 
-            self.resolve_block(body)?;
+            self.resolve_block(ast, body);
             self.end_scope();
+            let function = self.functions.pop().expect("no function to end");
+            if func_type == FunctionType::Function {
+                self.close_over(stmt, function);
+            }
             self.current_function = enclosing_func;
-            Ok(())
         } else {
-            ResolverError::new(None, "Expected a function.")
+            self.errors.push(ResolverError::new(
+                None,
+                DiagnosticCode::InvalidSyntaxTree,
+                "Expected a function.",
+            ));
         }
     }
 }
 
-impl<'a> Resolver<'a> {
-    fn visit_expr(&mut self, input: &Expr) -> Result<(), ResolverError> {
-        match input {
-            Expr::Variable { name } => {
+impl Resolver {
+    fn visit_expr(&mut self, ast: &Ast, input: ExprId) {
+        match &ast[input] {
+            Expr::Error => self.errors.push(ResolverError::new(
+                None,
+                DiagnosticCode::InvalidSyntaxTree,
+                "Expression failed to parse.",
+            )),
+            Expr::Variable { binding, name } => {
                 if !self.scopes.is_empty() {
                     let last_idx = self.scopes.len() - 1;
                     let scope = self.scopes.get(last_idx).unwrap();
 
-                    if let Some(false) = scope.get(&name.lexeme) {
-                        return ResolverError::new(
-                            Some(name.clone()),
+                    if let Some(Local { defined: false, .. }) = scope.get(&*name.lexeme) {
+                        self.error(
+                            name,
+                            DiagnosticCode::ReadInOwnInitializer,
                             "Can't read local variable in its own initializer.",
                         );
+                        return;
                     }
                 }
 
-                self.resolve_local(input, name)
+                if !self.resolve_local(binding, name, true) {
+                    self.remember_scope_names(input);
+                }
             }
-            Expr::Assignment { name, value } => {
-                self.resolve_expr(value)?;
-                self.resolve_local(input, name)
+            Expr::Assignment { binding, name, value } => {
+                self.resolve_expr(ast, *value);
+                if !self.resolve_local(binding, name, false) {
+                    self.remember_scope_names(input);
+                }
             }
-            Expr::Super { keyword, method: _ } => {
+            Expr::Super { binding, keyword, method: _ } => {
                 if self.current_class == ClassType::None {
-                    ResolverError::new(
-                        Some(keyword.clone()),
+                    self.error(
+                        keyword,
+                        DiagnosticCode::SuperOutsideClass,
                         "Can't use 'super' outside of a class.",
                     )
+                } else if self.current_class == ClassType::Extension {
+                    self.error(
+                        keyword,
+                        DiagnosticCode::SuperWithoutSuperclass,
+                        "Can't use 'super' in a class extension.",
+                    )
                 } else if self.current_class != ClassType::SubClass {
-                    ResolverError::new(
-                        Some(keyword.clone()),
+                    self.error(
+                        keyword,
+                        DiagnosticCode::SuperWithoutSuperclass,
                         "Can't use 'super' in a class with no superclass.",
                     )
                 } else {
-                    self.resolve_local(input, keyword)
+                    self.resolve_local(binding, keyword, true);
+                    // The `this` right below `super` is used with it, see
+                    // `Interpreter::evaluate_super`
+                    if let Some(resolution) = binding.resolution() {
+                        let this = Token { lexeme: "this".into(), ..keyword.clone() };
+                        self.capture(self.scopes.len() - resolution.depth, &this);
+                    }
                 }
             }
             Expr::Binary { left, operator: _, right } => {
-                self.resolve_expr(left)?;
-                self.resolve_expr(right)
+                self.resolve_expr(ast, *left);
+                self.resolve_expr(ast, *right)
             }
-            Expr::Call { callee, paren: _, arguments } => {
-                self.resolve_expr(callee)?;
-                for arg in arguments {
-                    self.resolve_expr(arg)?;
+            Expr::Call { callee, paren, arguments } => {
+                self.resolve_expr(ast, *callee);
+                for &arg in arguments {
+                    self.resolve_expr(ast, arg);
+                }
+
+                // Calls of local functions are checked when their scope ends, once all
+                // the assignments that could replace the function are known
+                if let Expr::Variable { binding: _, name } = &ast[*callee] {
+                    let local = self.scopes.iter_mut().rev().find_map(|s| s.get_mut(&*name.lexeme));
+                    if let Some(local) = local {
+                        local.calls.push((paren.clone(), arguments.len()));
+                    }
                 }
-                Ok(())
-            }
-            Expr::Get { object, name: _ } => {
-                self.resolve_expr(object)?;
-                Ok(())
             }
+            Expr::Get { object, name: _, cache: _ } => self.resolve_expr(ast, *object),
             Expr::Set { object, name: _, value } => {
-                self.resolve_expr(object)?;
-                self.resolve_expr(value)?;
-                Ok(())
+                self.resolve_expr(ast, *object);
+                self.resolve_expr(ast, *value);
             }
-            Expr::This { keyword } => self.resolve_this(input, keyword),
-            Expr::Grouping { expr } => self.resolve_expr(expr),
-            Expr::Literal { value: _ } => Ok(()),
-            Expr::Unary { operator: _, right } => self.resolve_expr(right),
+            Expr::Index { object, bracket: _, index } => {
+                self.resolve_expr(ast, *object);
+                self.resolve_expr(ast, *index);
+            }
+            Expr::IndexSet { object, bracket: _, index, value } => {
+                self.resolve_expr(ast, *object);
+                self.resolve_expr(ast, *index);
+                self.resolve_expr(ast, *value);
+            }
+            Expr::List { bracket: _, elements } => {
+                for &element in elements {
+                    self.resolve_expr(ast, element);
+                }
+            }
+            Expr::This { binding, keyword } => self.resolve_this(binding, keyword),
+            Expr::Grouping { expr } => self.resolve_expr(ast, *expr),
+            Expr::Literal { value: _ } => {}
+            Expr::Unary { operator: _, right } => self.resolve_expr(ast, *right),
             Expr::Logical { left, operator: _, right } => {
-                self.resolve_expr(left)?;
-                self.resolve_expr(right)
+                self.resolve_expr(ast, *left);
+                self.resolve_expr(ast, *right)
+            }
+            Expr::Function { keyword: _, declaration } => {
+                self.resolve_function(ast, *declaration, FunctionType::Function)
             }
         }
     }
 
-    fn resolve_local(&mut self, input: &Expr, name: &Token) -> Result<(), ResolverError> {
-        for (i, scope) in self.scopes.iter().enumerate().rev() {
-            if scope.contains_key(&name.lexeme) {
-                self.interpreter.resolve(input, self.scopes.len() - i - 1);
-                return Ok(());
+    /// Bind the variable to the innermost local with its name, and mark the
+    /// local as used if the variable is `read`.
+    /// Resolve a variable to the local variable it refers to. Returns false if
+    /// it's a global variable.
+    fn resolve_local(&mut self, binding: &Binding, name: &Token, read: bool) -> bool {
+        let depth = self.scopes.len();
+        for (i, scope) in self.scopes.iter_mut().enumerate().rev() {
+            if let Some(local) = scope.get_mut(&*name.lexeme) {
+                local.used |= read;
+                local.assigned |= !read;
+                binding.resolve(Resolution { depth: depth - i - 1, slot: local.slot });
+                self.capture(i, name);
+                return true;
             }
         }
+        false
+    }
 
-        Ok(())
+    /// Remember the names of the local variables in scope where a global
+    /// variable is used, to suggest them if it's undefined. A closure doesn't
+    /// keep the variables it doesn't capture, so they can't be found at run
+    /// time.
+    fn remember_scope_names(&mut self, expr: ExprId) {
+        if self.scopes.is_empty() {
+            return;
+        }
+        let names = self.scopes.iter().flat_map(|scope| scope.keys());
+        self.scope_names.push((expr, names.map(|name| name.as_str().into()).collect()));
+    }
+
+    /// Capture the variable declared in the scope at `index` in the closures
+    /// of the functions it's used in.
+    fn capture(&mut self, index: usize, name: &Token) {
+        let mut too_many = false;
+        for function in self.functions.iter_mut().rev() {
+            if function.first_scope <= index {
+                break;
+            }
+            // A method has `this` in its slot zero
+            if &*name.lexeme == "this" && function.first_scope == index + 1 {
+                continue;
+            }
+
+            function.upvalues.insert((index, name.lexeme.to_string()));
+            // Reported once per function
+            too_many |= function.upvalues.len() == MAX_UPVALUES + 1;
+        }
+
+        if too_many {
+            self.error(
+                name,
+                DiagnosticCode::TooManyUpvalues,
+                "Too many closure variables in function.",
+            );
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct ResolverError {
     pub token: Option<Token>,
+    pub code: DiagnosticCode,
     pub msg: String,
 }
 
 impl ResolverError {
-    pub fn new<T>(token: Option<Token>, msg: impl AsRef<str>) -> Result<T, Self> {
-        Err(Self { token, msg: msg.as_ref().to_owned() })
+    pub fn new(token: Option<Token>, code: DiagnosticCode, msg: impl AsRef<str>) -> Self {
+        Self { token, code, msg: msg.as_ref().to_owned() }
     }
 }
 
@@ -1,41 +1,99 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read};
+
 use crate::prelude::*;
 
 /// Scanner reads the source code and breaks it into tokens.
-#[derive(Debug)]
-pub struct Scanner {
-    source_chars: Vec<char>,
+///
+/// The source is read one character at a time, so it doesn't have to be in
+/// memory as a whole, e.g. when it comes from [`Scanner::from_reader`].
+pub struct Scanner<'a> {
+    source: Box<dyn Iterator<Item = std::io::Result<char>> + 'a>,
+    /// The characters read from the source but not consumed yet
+    lookahead: VecDeque<char>,
+    /// The source has no more characters, or couldn't be read
+    exhausted: bool,
+    /// The text of the token being scanned
+    lexeme: String,
     tokens: Vec<Token>,
-    start: usize,
+    /// Number of characters consumed
     current: usize,
     line: u32,
+    /// Index of the first character of the current line
+    line_start: usize,
+    /// Column of the token being scanned
+    start_column: u32,
+    /// Byte offsets of the start of the token being scanned and of `current`
+    start_byte: usize,
+    current_byte: usize,
     errors: Vec<ScannerError>,
+    /// Whether comments are tokens too
+    comment_tokens: bool,
 }
 
 #[derive(Debug)]
 pub struct ScannerError {
     pub line: u32,
+    pub column: u32,
+    pub code: DiagnosticCode,
     pub message: String,
 }
 
-impl Scanner {
-    pub fn new(source: &str) -> Self {
+impl<'a> Scanner<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Self::from_chars(source.chars())
+    }
+
+    /// Scan the characters of an iterator.
+    pub fn from_chars(chars: impl Iterator<Item = char> + 'a) -> Self {
+        Self::from_source(Box::new(chars.map(Ok)))
+    }
+
+    /// Scan UTF-8 text as it's read, e.g. from the standard input. Failing to
+    /// read it, or invalid UTF-8, is reported as a scanner error.
+    pub fn from_reader(reader: impl Read + 'a) -> Self {
+        Self::from_source(Box::new(Utf8Chars(BufReader::new(reader))))
+    }
+
+    fn from_source(source: Box<dyn Iterator<Item = std::io::Result<char>> + 'a>) -> Self {
         Self {
-            source_chars: source.chars().collect(),
-            start: 0,
+            source,
+            lookahead: VecDeque::new(),
+            exhausted: false,
+            lexeme: String::new(),
             current: 0,
             line: 1,
+            line_start: 0,
+            start_column: 1,
+            start_byte: 0,
+            current_byte: 0,
             tokens: Vec::new(),
             errors: Vec::new(),
+            comment_tokens: false,
         }
     }
 
+    /// Emit each comment as a [`TokenType::Comment`] token, with the comment
+    /// as its lexeme, instead of skipping it, e.g. for the formatter. The
+    /// parser skips these tokens.
+    pub fn with_comment_tokens(self) -> Self {
+        Self { comment_tokens: true, ..self }
+    }
+
     pub fn scan_tokens(&mut self) -> Result<Vec<Token>, Vec<ScannerError>> {
+        self.skip_shebang();
+
         while !self.is_at_end() {
-            self.start = self.current;
+            self.lexeme.clear();
+            self.start_byte = self.current_byte;
+            self.start_column = self.column();
             self.scan_token();
         }
 
-        self.tokens.push(Token::new(TokenType::EOF, "", None, self.line));
+        let end = Span::new(self.current_byte, self.current_byte);
+        self.tokens.push(
+            Token::new(TokenType::EOF, "", None, self.line).with_position(self.column(), end),
+        );
 
         if !self.errors.is_empty() {
             Err(std::mem::take(&mut self.errors))
@@ -44,8 +102,46 @@ impl Scanner {
         }
     }
 
-    fn is_at_end(&self) -> bool {
-        self.current >= self.source_chars.len()
+    /// Skip a `#!/usr/bin/env lox` line at the very beginning of the source, so
+    /// scripts can be made executable on Unix.
+    fn skip_shebang(&mut self) {
+        if self.current == 0 && self.peek() == '#' && self.peek_next() == '!' {
+            // Leave the newline, so it's counted as usual
+            while self.peek() != '\n' && !self.is_at_end() {
+                self.advance();
+            }
+        }
+    }
+
+    /// 1-based column of the next character
+    fn column(&self) -> u32 {
+        (self.current - self.line_start + 1) as u32
+    }
+
+    fn is_at_end(&mut self) -> bool {
+        self.peek_at(0).is_none()
+    }
+
+    /// The character `offset` characters after the current one, reading it
+    /// from the source if needed.
+    fn peek_at(&mut self, offset: usize) -> Option<char> {
+        while self.lookahead.len() <= offset && !self.exhausted {
+            match self.source.next() {
+                Some(Ok(c)) => self.lookahead.push_back(c),
+                Some(Err(e)) => {
+                    self.exhausted = true;
+                    self.errors.push(ScannerError {
+                        line: self.line,
+                        column: self.column() + self.lookahead.len() as u32,
+                        code: DiagnosticCode::InvalidSource,
+                        message: format!("Could not read the source: {e}."),
+                    });
+                }
+                None => self.exhausted = true,
+            }
+        }
+
+        self.lookahead.get(offset).copied()
     }
 
     fn scan_token(&mut self) {
@@ -56,9 +152,15 @@ impl Scanner {
             ')' => self.add_token(TokenType::RightParen),
             '{' => self.add_token(TokenType::LeftBrace),
             '}' => self.add_token(TokenType::RightBrace),
+            '[' => self.add_token(TokenType::LeftBracket),
+            ']' => self.add_token(TokenType::RightBracket),
             ',' => self.add_token(TokenType::Comma),
             '.' => self.add_token(TokenType::Dot),
-            '-' => self.add_token(TokenType::Minus),
+            '-' => {
+                let token_type =
+                    if self.match_next('>') { TokenType::Arrow } else { TokenType::Minus };
+                self.add_token(token_type);
+            }
             '+' => self.add_token(TokenType::Plus),
             ';' => self.add_token(TokenType::Semicolon),
             '*' => self.add_token(TokenType::Star),
@@ -88,88 +190,91 @@ impl Scanner {
                     while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
+
+                    if self.comment_tokens {
+                        // Without the `\r` of a Windows line ending
+                        self.lexeme.truncate(self.lexeme.trim_end().len());
+                        self.add_token(TokenType::Comment);
+                    }
                 } else {
                     self.add_token(TokenType::Slash);
                 }
             }
             ' ' | '\r' | '\t' => {}
-            '\n' => {
-                self.line += 1;
-            }
+            '\n' => self.new_line(),
             '"' => self.string(),
             '0'..='9' => self.number(),
             c if is_alpha(c) => self.identifier(),
-            _ => self.error(self.line, "Unexpected character."),
+            _ => self.error(DiagnosticCode::UnexpectedCharacter, "Unexpected character."),
         }
     }
 
-    fn error(&mut self, line: u32, message: &str) {
-        self.errors.push(ScannerError { line, message: message.to_owned() });
+    fn error(&mut self, code: DiagnosticCode, message: &str) {
+        let error = ScannerError {
+            line: self.line,
+            column: self.start_column,
+            code,
+            message: message.to_owned(),
+        };
+        self.errors.push(error);
     }
 
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
+    /// Consume the next character. At the end of the source, this is `'\0'`
+    /// and nothing is consumed.
     fn advance(&mut self) -> char {
-        let ch = self.source_chars.get(self.current);
+        self.peek_at(0);
+        let Some(ch) = self.lookahead.pop_front() else {
+            return '\0';
+        };
+        self.lexeme.push(ch);
         self.current += 1;
+        self.current_byte += ch.len_utf8();
 
-        *ch.expect("failed to read char!")
+        ch
     }
 
     fn add_token(&mut self, token_type: TokenType) {
         self.add_token_with_literal(token_type, None);
     }
 
-    // TODO: Any way to perform better here?
-    fn source_substring(&self, start: usize, end: usize) -> String {
-        self.source_chars.get(start..end).unwrap().iter().collect()
-    }
-
     fn add_token_with_literal(&mut self, token_type: TokenType, literal_value: Option<Literal>) {
-        let text = self.source_substring(self.start, self.current);
-        let token = Token::new(token_type, &text, literal_value, self.line);
+        let span = Span::new(self.start_byte, self.current_byte);
+        let token = Token::new(token_type, &self.lexeme, literal_value, self.line)
+            .with_position(self.start_column, span);
         self.tokens.push(token);
     }
 
     fn match_next(&mut self, expected: char) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-
-        if let Some(c) = self.source_chars.get(self.current) {
-            if c == &expected {
-                self.current += 1;
-                return true;
-            }
+        if self.peek_at(0) == Some(expected) {
+            self.advance();
+            return true;
         }
 
         false
     }
 
     fn peek(&mut self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-
-        *self.source_chars.get(self.current).unwrap_or(&'\0')
+        self.peek_at(0).unwrap_or('\0')
     }
 
     fn peek_next(&mut self) -> char {
-        if self.current + 1 >= self.source_chars.len() {
-            return '\0';
-        }
-
-        *self.source_chars.get(self.current + 1).unwrap_or(&'\0')
+        self.peek_at(1).unwrap_or('\0')
     }
 
     fn string(&mut self) {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.new_line();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
-            self.error(self.line, "Unterminated string.");
+            self.error(DiagnosticCode::UnterminatedString, "Unterminated string.");
             return;
         }
 
@@ -177,7 +282,7 @@ impl Scanner {
         self.advance();
 
         // Skip the quote marks
-        let text = self.source_substring(self.start + 1, self.current - 1);
+        let text = self.lexeme[1..self.lexeme.len() - 1].into();
         self.add_token_with_literal(TokenType::StringLiteral, Some(Literal::String(text)));
     }
 
@@ -195,11 +300,13 @@ impl Scanner {
             }
         }
 
-        let text = self.source_substring(self.start, self.current);
-        let value =
-            text.parse::<f64>().unwrap_or_else(|_| panic!("failed to parse number: {text}"));
-
-        self.add_token_with_literal(TokenType::Number, Some(Literal::Number(value)));
+        // Digits with an optional fraction always parse, huge numbers as infinity
+        match self.lexeme.parse::<f64>() {
+            Ok(value) => {
+                self.add_token_with_literal(TokenType::Number, Some(Literal::Number(value)))
+            }
+            Err(_) => self.error(DiagnosticCode::UnexpectedCharacter, "Invalid number."),
+        }
     }
 
     fn identifier(&mut self) {
@@ -207,39 +314,97 @@ impl Scanner {
             self.advance();
         }
 
-        let text = self.source_substring(self.start, self.current);
-        let token_type = get_keyword(&text).unwrap_or(TokenType::Identifier);
+        let token_type = get_keyword(&self.lexeme).unwrap_or(TokenType::Identifier);
         self.add_token(token_type);
     }
 }
 
+impl std::fmt::Debug for Scanner<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Scanner")
+            .field("line", &self.line)
+            .field("tokens", &self.tokens)
+            .field("errors", &self.errors)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Decodes the characters of a UTF-8 stream.
+struct Utf8Chars<R>(BufReader<R>);
+
+impl<R: Read> Utf8Chars<R> {
+    fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        let byte = self.0.fill_buf()?.first().copied();
+        if byte.is_some() {
+            self.0.consume(1);
+        }
+        Ok(byte)
+    }
+}
+
+impl<R: Read> Iterator for Utf8Chars<R> {
+    type Item = std::io::Result<char>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid UTF-8");
+
+        let first = match self.read_byte() {
+            Ok(Some(byte)) => byte,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let width = match first.leading_ones() {
+            0 => 1,
+            n @ 2..=4 => n as usize,
+            _ => return Some(Err(invalid())),
+        };
+
+        let mut bytes = [first, 0, 0, 0];
+        for byte in &mut bytes[1..width] {
+            match self.read_byte() {
+                Ok(Some(b)) => *byte = b,
+                Ok(None) => return Some(Err(invalid())),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        let c = std::str::from_utf8(&bytes[..width]).ok().and_then(|s| s.chars().next());
+        Some(c.ok_or_else(invalid))
+    }
+}
+
+/// Whether `c` can start an identifier. Letters of any script are allowed,
+/// e.g. `größe` or `名前`.
 fn is_alpha(c: char) -> bool {
-    c.is_ascii_alphabetic() || c == '_'
+    c.is_alphabetic() || c == '_'
 }
 
 fn is_alpha_numeric(c: char) -> bool {
-    is_alpha(c) || c.is_ascii_digit()
+    c.is_alphanumeric() || c == '_'
 }
 
+/// All reserved words of the language.
+pub const KEYWORDS: &[(&str, TokenType)] = &[
+    ("and", TokenType::And),
+    ("break", TokenType::Break),
+    ("class", TokenType::Class),
+    ("else", TokenType::Else),
+    ("false", TokenType::False),
+    ("for", TokenType::For),
+    ("fun", TokenType::Fun),
+    ("if", TokenType::If),
+    ("nil", TokenType::Nil),
+    ("or", TokenType::Or),
+    ("print", TokenType::Print),
+    ("return", TokenType::Return),
+    ("super", TokenType::Super),
+    ("this", TokenType::This),
+    ("true", TokenType::True),
+    ("var", TokenType::Var),
+    ("while", TokenType::While),
+];
+
 fn get_keyword(text: &str) -> Option<TokenType> {
-    match text {
-        "and" => Some(TokenType::And),
-        "break" => Some(TokenType::Break),
-        "class" => Some(TokenType::Class),
-        "else" => Some(TokenType::Else),
-        "false" => Some(TokenType::False),
-        "for" => Some(TokenType::For),
-        "fun" => Some(TokenType::Fun),
-        "if" => Some(TokenType::If),
-        "nil" => Some(TokenType::Nil),
-        "or" => Some(TokenType::Or),
-        "print" => Some(TokenType::Print),
-        "return" => Some(TokenType::Return),
-        "super" => Some(TokenType::Super),
-        "this" => Some(TokenType::This),
-        "true" => Some(TokenType::True),
-        "var" => Some(TokenType::Var),
-        "while" => Some(TokenType::While),
-        _ => None,
-    }
+    KEYWORDS.iter().find(|(keyword, _)| *keyword == text).map(|(_, token_type)| *token_type)
 }
@@ -0,0 +1,43 @@
+//! Stricter or looser rules than canonical Lox for conditions and `==`, which
+//! embedders choose with [`Lox::set_truthiness`](crate::Lox::set_truthiness)
+//! and [`Lox::set_equality`](crate::Lox::set_equality).
+
+/// Which values count as true in conditions and for `!`, `and` and `or`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Truthiness {
+    /// Everything but `nil` and `false` is true, like in canonical Lox.
+    #[default]
+    Lox,
+    /// Only `true` is true: `if (0)`, `if ("")` and `if (nil)` all take the
+    /// else branch, and `!0` is `true`.
+    Strict,
+}
+
+/// How `==` and `!=` compare values of different types.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Equality {
+    /// Values of different types are never equal, so `0 == "0"` is `false`,
+    /// like in canonical Lox.
+    #[default]
+    Strict,
+    /// A number equals a string that is written like it, e.g. `1 == "1.0"`,
+    /// and `true` and `false` equal `1` and `0`. Other values of different
+    /// types are still never equal.
+    Coercing,
+}
+
+/// The number a string is written like, for [`Equality::Coercing`]. Only the
+/// digits and the dot of Lox number literals are accepted, with an optional
+/// minus sign, so `"inf"` and `" 1"` aren't numbers.
+pub(crate) fn number_of(string: &str) -> Option<f64> {
+    let digits = string.strip_prefix('-').unwrap_or(string);
+    let is_literal = digits.starts_with(|c: char| c.is_ascii_digit())
+        && digits.ends_with(|c: char| c.is_ascii_digit())
+        && digits.chars().all(|c| c.is_ascii_digit() || c == '.');
+    is_literal.then(|| string.parse().ok()).flatten()
+}
+
+/// The number a boolean equals, for [`Equality::Coercing`].
+pub(crate) fn number_of_boolean(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
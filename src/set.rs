@@ -0,0 +1,114 @@
+//! Sets of `nil`, booleans, numbers and strings, made by the `Set()` native.
+//!
+//! Sets have methods, like instances, but no fields:
+//!
+//! - `add(value)` adds a value, `has(value)` tells if it's in the set and
+//!   `remove(value)` removes it, returning whether it was there.
+//! - `union(other)` and `intersect(other)` return a new set.
+//! - `size()` is the number of values.
+//! - `forEach(function)` calls the function with each value, in the order they
+//!   were added.
+
+use std::collections::HashSet;
+use std::fmt::Display;
+
+use crate::prelude::Key;
+
+#[derive(Debug, Clone, Default)]
+pub struct LoxSet {
+    /// The values in the order they were added
+    values: Vec<Key>,
+    index: HashSet<Key>,
+}
+
+/// The methods of sets, with their number of arguments.
+const METHODS: [(&str, usize); 7] = [
+    ("add", 1),
+    ("has", 1),
+    ("remove", 1),
+    ("union", 1),
+    ("intersect", 1),
+    ("size", 0),
+    ("forEach", 1),
+];
+
+/// The message of the error for passing something that isn't a set to
+/// `union` or `intersect`.
+pub(crate) const NOT_A_SET: &str = "The argument must be a set.";
+
+impl LoxSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a value, and return whether it wasn't in the set yet.
+    pub fn add(&mut self, value: Key) -> bool {
+        let added = self.index.insert(value.clone());
+        if added {
+            self.values.push(value);
+        }
+        added
+    }
+
+    pub fn has(&self, value: &Key) -> bool {
+        self.index.contains(value)
+    }
+
+    /// Remove a value, and return whether it was in the set.
+    pub fn remove(&mut self, value: &Key) -> bool {
+        let removed = self.index.remove(value);
+        if removed {
+            self.values.retain(|v| v != value);
+        }
+        removed
+    }
+
+    /// The values of both sets, those of this one first.
+    pub fn union(&self, other: &LoxSet) -> LoxSet {
+        let mut union = self.clone();
+        other.iter().for_each(|value| {
+            union.add(value.clone());
+        });
+        union
+    }
+
+    /// The values of this set that are in the other one too.
+    pub fn intersect(&self, other: &LoxSet) -> LoxSet {
+        let mut intersection = LoxSet::new();
+        self.iter().filter(|value| other.has(value)).for_each(|value| {
+            intersection.add(value.clone());
+        });
+        intersection
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The values in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = &Key> {
+        self.values.iter()
+    }
+
+    /// The number of arguments of the method with this name, if sets have
+    /// one.
+    pub(crate) fn arity(method: &str) -> Option<usize> {
+        METHODS.iter().find(|(name, _)| *name == method).map(|(_, arity)| *arity)
+    }
+
+    /// The names of the methods, for the suggestions of the errors.
+    pub(crate) fn method_names() -> Vec<String> {
+        METHODS.iter().map(|(name, _)| name.to_string()).collect()
+    }
+}
+
+impl Display for LoxSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let values = self.iter().map(ToString::to_string).collect::<Vec<_>>();
+        write!(f, "Set({})", values.join(", "))
+    }
+}
@@ -0,0 +1,258 @@
+//! Saving the globals of the tree-walking interpreter, to resume a session
+//! later.
+//!
+//! A snapshot is the JSON object `{"version":1,"globals":[...]}`, with an
+//! object per global, e.g. `{"name":"a","value":1}`. Nil, booleans, strings and
+//! numbers are saved as themselves, except the numbers JSON doesn't have, which
+//! are saved as `{"number":"inf"}`, `{"number":"-inf"}` or `{"number":"nan"}`.
+//!
+//! Functions and classes are saved as the formatted source code of their
+//! declaration, e.g. `{"name":"f","source":"fun f() {...}"}`, and are declared
+//! again when the snapshot is restored, so they use the restored globals. A
+//! class declares its superclass by name, so the superclass must still be a
+//! global with that name. Doc comments are lost.
+//!
+//! Instances, native functions, bound methods and functions that close over
+//! local variables can't be declared again from their source, so they are left
+//! out of the snapshot.
+
+use std::error::Error;
+use std::fmt::Display;
+
+use serde_json::{Value, json};
+
+use crate::prelude::*;
+use crate::sync::Rc;
+
+/// The version of the format. Snapshots of other versions can't be restored.
+const VERSION: u64 = 1;
+
+/// A snapshot that can't be restored.
+#[derive(Debug, PartialEq)]
+pub struct SnapshotError(String);
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid snapshot: {}", self.0)
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl Interpreter {
+    /// Save the globals as JSON: plain values as themselves, and functions and
+    /// classes as the source code of their declaration. Instances, sets,
+    /// lists, natives and functions that close over local variables are left
+    /// out.
+    pub fn snapshot(&self) -> String {
+        let mut definitions = vec![];
+        let mut values = vec![];
+        for (name, value) in self.globals.borrow().bindings() {
+            match &value {
+                Object::Callable(callable) => {
+                    let Some(function) = callable.as_lox_function() else {
+                        continue;
+                    };
+                    let (ast, declaration, closure) = function.definition();
+                    if Rc::ptr_eq(closure, &self.globals) {
+                        definitions.push((0, name, function_source(ast, declaration)));
+                    }
+                }
+                Object::Class(class) => {
+                    let class = class.borrow();
+                    if let Some(source) = self.class_source(&class) {
+                        definitions.push((superclass_depth(&class), name, source));
+                    }
+                }
+                Object::Instance(_)
+                | Object::Set(_)
+                | Object::List(_)
+                | Object::StringBuilder(_)
+                | Object::WeakRef(_) => {}
+                value => values.push(json!({ "name": name, "value": value_to_json(value) })),
+            }
+        }
+
+        // Superclasses are declared before their subclasses, and the plain
+        // values last, in case a declaration replaced one of them
+        definitions.sort();
+        let definitions = definitions
+            .into_iter()
+            .map(|(_, name, source)| json!({ "name": name, "source": source }));
+        let globals = definitions.chain(values).collect::<Vec<_>>();
+
+        json!({ "version": VERSION, "globals": globals }).to_string()
+    }
+
+    /// Define the globals of a snapshot made by [`Interpreter::snapshot`],
+    /// replacing the globals with the same names. The globals restored before
+    /// a failure are kept.
+    pub fn restore(&mut self, snapshot: &str) -> Result<(), SnapshotError> {
+        let json = serde_json::from_str::<Value>(snapshot)
+            .map_err(|e| SnapshotError(format!("not JSON ({e})")))?;
+
+        match json["version"].as_u64() {
+            Some(VERSION) => {}
+            Some(version) => {
+                return Err(SnapshotError(format!(
+                    "unsupported version {version}, expected {VERSION}"
+                )));
+            }
+            None => return Err(SnapshotError("missing version".to_owned())),
+        }
+        let globals = json["globals"]
+            .as_array()
+            .ok_or_else(|| SnapshotError("'globals' must be an array".to_owned()))?;
+
+        for global in globals {
+            let name = global["name"]
+                .as_str()
+                .ok_or_else(|| SnapshotError("the name of a global must be a string".to_owned()))?;
+            let value = match (global.get("value"), global.get("source")) {
+                (Some(value), None) => value_from_json(value)?,
+                (None, Some(Value::String(source))) => self.declare(name, source)?,
+                _ => {
+                    return Err(SnapshotError(format!(
+                        "global '{name}' must have either a value or a source"
+                    )));
+                }
+            };
+            self.globals.borrow_mut().define(name, value);
+        }
+
+        Ok(())
+    }
+
+    /// Run the declaration of a function or class and return what it
+    /// declares. A declaration saved for another name, e.g. for `var g = f;`,
+    /// doesn't change the global it declares.
+    fn declare(&mut self, name: &str, source: &str) -> Result<Object, SnapshotError> {
+        let error = |message: &str| SnapshotError(format!("can't declare '{name}': {message}"));
+
+        let tokens = Scanner::new(source).scan_tokens().map_err(|e| error(&e[0].message))?;
+        let (ast, errors) = Parser::new(tokens).parse();
+        if let Some(e) = errors.iter().find(|e| !e.is_warning()) {
+            return Err(error(&e.message));
+        }
+        let declared = match ast.statements() {
+            [stmt] => match &ast[*stmt] {
+                Stmt::Function { name, .. } | Stmt::Class { name, .. } => name.lexeme.clone(),
+                _ => return Err(error("not a function or class declaration")),
+            },
+            _ => return Err(error("not a function or class declaration")),
+        };
+        Resolver::new().resolve(&ast).map_err(|e| error(&e[0].msg))?;
+
+        let previous = self.globals.borrow().lookup(&declared);
+        self.interpret(&Rc::new(ast)).map_err(|e| error(&e[0].message))?;
+        let value = self.globals.borrow().lookup(&declared).expect("the declaration ran");
+
+        if *declared != *name {
+            let mut globals = self.globals.borrow_mut();
+            match previous {
+                Some(previous) => globals.define(declared, previous),
+                None => globals.remove(&declared),
+            }
+        }
+        Ok(value)
+    }
+
+    /// The source code of a class declared at the top level, with its methods
+    /// in the order they are declared.
+    fn class_source(&self, class: &Class) -> Option<String> {
+        let mut methods = class.methods().values().map(|m| m.definition()).collect::<Vec<_>>();
+        // Methods close over the globals, or over the environment that defines
+        // `super` in a subclass
+        let top_level = methods.iter().all(|(_, _, closure)| {
+            if Rc::ptr_eq(closure, &self.globals) {
+                return true;
+            }
+            let closure = closure.borrow();
+            let enclosing = closure.enclosing.as_ref();
+            closure.names() == ["super"] && enclosing.is_some_and(|e| Rc::ptr_eq(e, &self.globals))
+        });
+        if !top_level {
+            return None;
+        }
+        methods.sort_by_key(|(ast, declaration, _)| ast.line(*declaration));
+
+        let mut copy = Ast::new();
+        let methods = methods
+            .into_iter()
+            .map(|(ast, declaration, _)| copy_stmt(&mut copy, ast, declaration))
+            .collect();
+        let superclass = class.superclass().map(|superclass| {
+            let name = identifier(superclass.borrow().name());
+            copy.add_expr(Expr::Variable { binding: Binding::new(), name })
+        });
+        let class = Stmt::Class { name: identifier(class.name()), methods, superclass };
+        let class = copy.add_stmt(class, StmtLocation { line: 1, end_line: 1, synthetic: false });
+        copy.push_statement(class);
+
+        Some(Formatter::format(&copy, &[]))
+    }
+}
+
+/// The source code of a function declaration.
+fn function_source(ast: &Ast, declaration: StmtId) -> String {
+    let mut copy = Ast::new();
+    let function = copy_stmt(&mut copy, ast, declaration);
+    copy.push_statement(function);
+    Formatter::format(&copy, &[])
+}
+
+/// Copy a statement and its children to another tree.
+fn copy_stmt(to: &mut Ast, from: &Ast, stmt: StmtId) -> StmtId {
+    AstJson::stmt_from_json(to, &AstJson::stmt_to_json(from, stmt))
+        .expect("the JSON of a statement can be read back")
+}
+
+fn identifier(name: &str) -> Token {
+    Token::new(TokenType::Identifier, name, None, 1)
+}
+
+/// Number of superclasses above the class.
+fn superclass_depth(class: &Class) -> usize {
+    match class.superclass() {
+        Some(superclass) => 1 + superclass_depth(&superclass.borrow()),
+        None => 0,
+    }
+}
+
+fn value_to_json(value: &Object) -> Value {
+    match value {
+        Object::Null => Value::Null,
+        Object::Boolean(b) => json!(b),
+        Object::Number(n) if n.is_finite() => json!(n),
+        Object::Number(n) if n.is_nan() => json!({ "number": "nan" }),
+        Object::Number(n) if *n > 0.0 => json!({ "number": "inf" }),
+        Object::Number(_) => json!({ "number": "-inf" }),
+        Object::String(s) => json!(&**s),
+        Object::Callable(_)
+        | Object::Class(_)
+        | Object::Instance(_)
+        | Object::Set(_)
+        | Object::List(_)
+        | Object::StringBuilder(_)
+        | Object::WeakRef(_) => {
+            unreachable!("only plain values are saved as values")
+        }
+    }
+}
+
+fn value_from_json(json: &Value) -> Result<Object, SnapshotError> {
+    let value = match json {
+        Value::Null => Object::Null,
+        Value::Bool(b) => Object::Boolean(*b),
+        Value::Number(n) => Object::Number(n.as_f64().unwrap_or(f64::NAN)),
+        Value::String(s) => Object::String(s.as_str().into()),
+        Value::Object(number) => match number.get("number").and_then(Value::as_str) {
+            Some("inf") => Object::Number(f64::INFINITY),
+            Some("-inf") => Object::Number(f64::NEG_INFINITY),
+            Some("nan") => Object::Number(f64::NAN),
+            _ => return Err(SnapshotError(format!("unsupported value {json}"))),
+        },
+        Value::Array(_) => return Err(SnapshotError(format!("unsupported value {json}"))),
+    };
+    Ok(value)
+}
@@ -0,0 +1,26 @@
+//! String builders, made by the `StringBuilder()` native.
+//!
+//! Strings are immutable, so `s = s + piece;` copies `s` each time and
+//! building a string that way in a loop takes quadratic time. A builder grows
+//! one buffer instead:
+//!
+//! - `append(value)` adds the value as `print` shows it, and returns the
+//!   builder so calls can be chained.
+//! - `length()` is the number of characters built so far.
+//! - `toString()` returns the string built so far.
+//!
+//! A builder prints as the string built so far.
+
+/// The methods of builders, with their number of arguments.
+const METHODS: [(&str, usize); 3] = [("append", 1), ("length", 0), ("toString", 0)];
+
+/// The number of arguments of the method with this name, if builders have
+/// one.
+pub(crate) fn arity(method: &str) -> Option<usize> {
+    METHODS.iter().find(|(name, _)| *name == method).map(|(_, arity)| *arity)
+}
+
+/// The names of the methods, for the suggestions of the errors.
+pub(crate) fn method_names() -> Vec<String> {
+    METHODS.iter().map(|(name, _)| name.to_string()).collect()
+}
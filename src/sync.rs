@@ -0,0 +1,128 @@
+//! The shared pointers and cells used by the interpreters.
+//!
+//! By default, they are the single-threaded `std::rc` and `std::cell` types.
+//! With the `sync` feature, they are replaced by thread-safe types with the
+//! same API: `Rc` is an `Arc`, and the cells are backed by locks. Values are
+//! then `Send` and `Sync`, so a [`Lox`](crate::Lox) instance can be moved to
+//! another thread, at the cost of slower reference counting and borrowing.
+//!
+//! An interpreter is still meant to be used by a single thread at a time:
+//! borrowing a cell mutably while it is borrowed panics with `RefCell`, but
+//! may deadlock with the `sync` feature.
+
+#[cfg(not(feature = "sync"))]
+pub use std::cell::{Cell, RefCell};
+#[cfg(not(feature = "sync"))]
+pub use std::rc::{Rc, Weak};
+#[cfg(feature = "sync")]
+pub use std::sync::{Arc as Rc, Weak};
+
+#[cfg(feature = "sync")]
+pub use self::locked::{Cell, RefCell};
+
+/// Bounds that only apply with the `sync` feature, for the trait objects
+/// shared between threads.
+#[cfg(feature = "sync")]
+pub trait MaybeSync: Send + Sync {}
+#[cfg(feature = "sync")]
+impl<T: Send + Sync + ?Sized> MaybeSync for T {}
+
+/// Bounds that only apply with the `sync` feature, for the trait objects
+/// shared between threads.
+#[cfg(not(feature = "sync"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> MaybeSync for T {}
+
+#[cfg(feature = "sync")]
+mod locked {
+    use std::fmt::Debug;
+    use std::sync::{Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+    /// A `RefCell` backed by a read-write lock.
+    #[derive(Default)]
+    pub struct RefCell<T: ?Sized>(RwLock<T>);
+
+    /// The cell is already borrowed in a way that conflicts with the request.
+    #[derive(Debug)]
+    pub struct BorrowError;
+
+    impl<T> RefCell<T> {
+        pub fn new(value: T) -> Self {
+            Self(RwLock::new(value))
+        }
+    }
+
+    impl<T: ?Sized> RefCell<T> {
+        pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+            self.0.read().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, T> {
+            self.0.write().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn try_borrow(&self) -> Result<RwLockReadGuard<'_, T>, BorrowError> {
+            match self.0.try_read() {
+                Ok(guard) => Ok(guard),
+                Err(TryLockError::Poisoned(e)) => Ok(e.into_inner()),
+                Err(TryLockError::WouldBlock) => Err(BorrowError),
+            }
+        }
+
+        pub fn try_borrow_mut(&self) -> Result<RwLockWriteGuard<'_, T>, BorrowError> {
+            match self.0.try_write() {
+                Ok(guard) => Ok(guard),
+                Err(TryLockError::Poisoned(e)) => Ok(e.into_inner()),
+                Err(TryLockError::WouldBlock) => Err(BorrowError),
+            }
+        }
+    }
+
+    impl<T: ?Sized + Debug> Debug for RefCell<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self.try_borrow() {
+                Ok(value) => f.debug_tuple("RefCell").field(&&*value).finish(),
+                Err(_) => f.write_str("RefCell(<borrowed>)"),
+            }
+        }
+    }
+
+    /// A `Cell` backed by a mutex.
+    #[derive(Default)]
+    pub struct Cell<T>(Mutex<T>);
+
+    impl<T: Copy> Cell<T> {
+        pub fn new(value: T) -> Self {
+            Self(Mutex::new(value))
+        }
+
+        pub fn get(&self) -> T {
+            *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn set(&self, value: T) {
+            *self.0.lock().unwrap_or_else(PoisonError::into_inner) = value;
+        }
+    }
+
+    impl<T: Copy> Clone for Cell<T> {
+        fn clone(&self) -> Self {
+            Self::new(self.get())
+        }
+    }
+
+    impl<T: Copy + PartialEq> PartialEq for Cell<T> {
+        fn eq(&self, other: &Self) -> bool {
+            self.get() == other.get()
+        }
+    }
+
+    impl<T: Copy + Eq> Eq for Cell<T> {}
+
+    impl<T: Copy + Debug> Debug for Cell<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_tuple("Cell").field(&self.get()).finish()
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Unit tests written in Lox, with the `test`, `assertEq` and `assertTrue`
+//! native functions.
+
+use std::fmt::Display;
+
+use crate::diagnostic::DiagnosticCode;
+
+/// The outcome of a `test("name", function)` call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    /// The error that made the test fail, e.g. `[line 3] Expected 2 but got
+    /// 3.`
+    pub failure: Option<String>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// Whether an error stops the whole run instead of failing the test it
+/// happens in.
+pub(crate) fn stops_run(code: DiagnosticCode) -> bool {
+    matches!(
+        code,
+        DiagnosticCode::StepLimitExceeded
+            | DiagnosticCode::TimeLimitExceeded
+            | DiagnosticCode::Interrupted
+    )
+}
+
+pub(crate) fn assert_eq_message(actual: impl Display, expected: impl Display) -> String {
+    format!("Expected {expected} but got {actual}.")
+}
+
+pub(crate) fn assert_true_message(value: impl Display) -> String {
+    format!("Expected a true value but got {value}.")
+}
+
+pub(crate) const INVALID_TEST_NAME: &str = "The name of a test must be a string.";
+
+pub(crate) const INVALID_TEST_FUNCTION: &str = "A test must be a function without parameters.";
@@ -1,5 +1,7 @@
 use std::fmt::{Debug, Display};
 
+use crate::sync::Rc;
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TokenType {
@@ -8,6 +10,8 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -25,6 +29,7 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    Arrow,
 
     // Literals.
     Identifier,
@@ -50,15 +55,72 @@ pub enum TokenType {
     Var,
     While,
 
+    // Trivia, only if the scanner is asked for it.
+    Comment,
+
     EOF,
 }
 
+impl Display for TokenType {
+    /// The source text of the token, or what kind of token it is if it has no
+    /// fixed text.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::LeftParen => "'('",
+            Self::RightParen => "')'",
+            Self::LeftBrace => "'{'",
+            Self::RightBrace => "'}'",
+            Self::LeftBracket => "'['",
+            Self::RightBracket => "']'",
+            Self::Comma => "','",
+            Self::Dot => "'.'",
+            Self::Minus => "'-'",
+            Self::Plus => "'+'",
+            Self::Semicolon => "';'",
+            Self::Slash => "'/'",
+            Self::Star => "'*'",
+            Self::Bang => "'!'",
+            Self::BangEqual => "'!='",
+            Self::Equal => "'='",
+            Self::EqualEqual => "'=='",
+            Self::Greater => "'>'",
+            Self::GreaterEqual => "'>='",
+            Self::Less => "'<'",
+            Self::LessEqual => "'<='",
+            Self::Arrow => "'->'",
+            Self::Identifier => "an identifier",
+            Self::StringLiteral => "a string",
+            Self::Number => "a number",
+            Self::And => "'and'",
+            Self::Break => "'break'",
+            Self::Class => "'class'",
+            Self::Else => "'else'",
+            Self::False => "'false'",
+            Self::Fun => "'fun'",
+            Self::For => "'for'",
+            Self::If => "'if'",
+            Self::Nil => "'nil'",
+            Self::Or => "'or'",
+            Self::Print => "'print'",
+            Self::Return => "'return'",
+            Self::Super => "'super'",
+            Self::This => "'this'",
+            Self::True => "'true'",
+            Self::Var => "'var'",
+            Self::While => "'while'",
+            Self::Comment => "a comment",
+            Self::EOF => "the end",
+        };
+        write!(f, "{text}")
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Literal {
     Null,
     Boolean(bool),
     Number(f64),
-    String(String),
+    String(Rc<str>),
 }
 
 impl Display for Literal {
@@ -74,17 +136,40 @@ impl Display for Literal {
     }
 }
 
+/// Byte offsets of a token in the source code, `end` being exclusive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+}
+
+/// A token of the source code. Tokens are copied into the syntax tree and the
+/// errors, so the lexeme is shared instead of copied.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     pub token_type: TokenType,
-    pub lexeme: String,
+    pub lexeme: Rc<str>,
     pub literal: Option<Literal>,
     pub line: u32,
+    /// 1-based column of the first character of the token, or 0 if the token
+    /// doesn't come from the source code.
+    pub column: u32,
+    pub span: Span,
 }
 
 impl Token {
     pub fn new(token_type: TokenType, lexeme: &str, literal: Option<Literal>, line: u32) -> Self {
-        Self { token_type, lexeme: lexeme.to_owned(), literal, line }
+        Self { token_type, lexeme: lexeme.into(), literal, line, column: 0, span: Span::default() }
+    }
+
+    pub fn with_position(self, column: u32, span: Span) -> Self {
+        Self { column, span, ..self }
     }
 }
 
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use super::value::Value;
+use crate::sync::Rc;
+use crate::token::{Span, Token};
+
+/// An instruction of the virtual machine. Operands are indices into the
+/// constants of the chunk, stack slots, upvalue indices, jump targets or
+/// argument counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Constant(usize),
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal(usize),
+    SetLocal(usize),
+    GetGlobal(usize),
+    DefineGlobal(usize),
+    SetGlobal(usize),
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    GetProperty(usize),
+    SetProperty(usize),
+    GetSuper(usize),
+    /// Replace a list and an index with the value at the index
+    GetIndex,
+    /// Replace a list, an index and a value with the value, stored at the
+    /// index
+    SetIndex,
+    /// Replace this many values on the top of the stack with a list of them
+    List(usize),
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    /// Print a value, without a newline
+    Print,
+    PrintLine,
+    Jump(usize),
+    JumpIfFalse(usize),
+    /// Jump back to the start of a loop
+    Loop(usize),
+    /// Fail if the value on the top of the stack can't be called with this
+    /// many arguments. Runs before the arguments are evaluated.
+    CheckCall(usize),
+    Call(usize),
+    Closure(usize),
+    CloseUpvalue,
+    Return,
+    /// End the script with the value on the top of the stack as the exit
+    /// code, for a `return` at the top level
+    Exit,
+    Class(usize),
+    /// Create a class whose superclass is on the top of the stack
+    Subclass(usize),
+    Method(usize),
+    /// Set the doc comment of the class on the top of the stack
+    ClassDoc(usize),
+    /// Fail unless the value on the top of the stack is a class, which the
+    /// next `Method` instructions add methods to
+    Extend,
+    /// Remember the local variable on the top of the stack as the value of a
+    /// `using` statement, to close it if an error leaves the statement
+    Using,
+    /// Forget the value of the innermost `using` statement, and push it to
+    /// call its `close()` method
+    Close,
+}
+
+/// Where an instruction comes from in the source code, for error messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Position {
+    pub line: u32,
+    pub column: u32,
+    pub span: Span,
+}
+
+impl From<&Token> for Position {
+    fn from(token: &Token) -> Self {
+        Self { line: token.line, column: token.column, span: token.span }
+    }
+}
+
+/// The compiled code of a function.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    /// The position of each instruction
+    pub positions: Vec<Position>,
+    pub constants: Vec<Value>,
+    /// The local variables visible at each global variable access inside a
+    /// scope, to suggest them when the global is undefined
+    pub locals_in_scope: HashMap<usize, Rc<[Rc<str>]>>,
+}
+
+impl Chunk {
+    /// Append an instruction and return its offset.
+    pub fn write(&mut self, op: Op, position: Position) -> usize {
+        self.code.push(op);
+        self.positions.push(position);
+        self.code.len() - 1
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+}
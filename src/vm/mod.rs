@@ -0,0 +1,1320 @@
+pub(crate) mod chunk;
+pub(crate) mod serialize;
+pub(crate) mod value;
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use self::chunk::{Chunk, Op};
+pub use self::value::Value;
+use self::value::{
+    BoundMethod, Class, Closure, Function, Instance, Memoized, Native, NativeMethod, Upvalue,
+};
+use crate::budget::Budget;
+use crate::compiler::Compiler;
+use crate::diagnostic::with_suggestion;
+use crate::gc::{Heap, HeapStats};
+use crate::key::{self, Key};
+use crate::output::Output;
+use crate::prelude::{
+    Ast, Capability, DEFAULT_MAX_CALL_DEPTH, DiagnosticCode, Equality, ExprId, InterpreterError,
+    InterruptHandle, Truthiness,
+};
+use crate::set::{self, LoxSet};
+use crate::sync::{MaybeSync, Rc, RefCell, Weak};
+use crate::testing::{self, TestResult};
+use crate::{Shared, list, native, string_builder, weak_ref};
+
+/// A function being executed.
+struct CallFrame {
+    closure: Rc<Closure>,
+    /// The next instruction to run
+    ip: usize,
+    /// The stack slot of the function, followed by its arguments and locals
+    base: usize,
+}
+
+/// A stack-based virtual machine that runs the bytecode of the
+/// [`Compiler`](crate::compiler::Compiler).
+///
+/// It runs the same programs as the
+/// [`Interpreter`](crate::prelude::Interpreter), which is the reference
+/// implementation, with the same output and errors.
+pub struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: HashMap<Rc<str>, Value>,
+    /// The upvalues that still point to the stack
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    max_call_depth: usize,
+    truthiness: Truthiness,
+    equality: Equality,
+    /// Counts the instructions executed in the current run
+    budget: Budget,
+    heap: Heap,
+    /// Where `print` writes
+    output: Output,
+    /// The results of the `test` calls, not taken yet
+    test_results: Vec<TestResult>,
+    /// The exit code a `return` at the top level ended the last run with
+    exit_code: Option<i32>,
+    /// The stack slots of the values of the `using` statements being run,
+    /// the innermost last
+    resources: Vec<usize>,
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        let mut globals = HashMap::new();
+        globals
+            .insert("clock".into(), Value::Native(Rc::new(Native { arity: 0, function: clock })));
+        globals.insert(
+            "gcCollect".into(),
+            Value::Native(Rc::new(Native { arity: 0, function: gc_collect })),
+        );
+        globals.insert(
+            "memoryStats".into(),
+            Value::Native(Rc::new(Native { arity: 0, function: memory_stats })),
+        );
+        globals.insert("doc".into(), Value::Native(Rc::new(Native { arity: 1, function: doc })));
+        globals.insert("test".into(), Value::Native(Rc::new(Native { arity: 2, function: test })));
+        globals.insert(
+            "assertEq".into(),
+            Value::Native(Rc::new(Native { arity: 2, function: assert_eq })),
+        );
+        globals.insert(
+            "assertTrue".into(),
+            Value::Native(Rc::new(Native { arity: 1, function: assert_true })),
+        );
+        globals.insert(
+            "bindMethod".into(),
+            Value::Native(Rc::new(Native { arity: 2, function: bind_method })),
+        );
+        globals
+            .insert("arity".into(), Value::Native(Rc::new(Native { arity: 1, function: arity })));
+        globals.insert("name".into(), Value::Native(Rc::new(Native { arity: 1, function: name })));
+        globals.insert(
+            "memoize".into(),
+            Value::Native(Rc::new(Native { arity: 1, function: memoize })),
+        );
+        globals
+            .insert("Set".into(), Value::Native(Rc::new(Native { arity: 0, function: new_set })));
+        globals
+            .insert("List".into(), Value::Native(Rc::new(Native { arity: 0, function: new_list })));
+        globals.insert(
+            "StringBuilder".into(),
+            Value::Native(Rc::new(Native { arity: 0, function: new_string_builder })),
+        );
+        globals.insert(
+            "WeakRef".into(),
+            Value::Native(Rc::new(Native { arity: 1, function: new_weak_ref })),
+        );
+        globals
+            .insert("split".into(), Value::Native(Rc::new(Native { arity: 2, function: split })));
+        globals.insert("join".into(), Value::Native(Rc::new(Native { arity: 2, function: join })));
+        #[cfg(feature = "net")]
+        {
+            globals.insert(
+                "httpGet".into(),
+                Value::Native(Rc::new(Native { arity: 1, function: http_get })),
+            );
+            globals.insert(
+                "httpPost".into(),
+                Value::Native(Rc::new(Native { arity: 2, function: http_post })),
+            );
+        }
+
+        Self {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            globals,
+            open_upvalues: Vec::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            truthiness: Truthiness::default(),
+            equality: Equality::default(),
+            budget: Budget::default(),
+            heap: Heap::default(),
+            output: Output::default(),
+            test_results: Vec::new(),
+            exit_code: None,
+            resources: vec![],
+        }
+    }
+
+    /// Set the number of nested calls after which a program fails with a
+    /// "Stack overflow." error.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Choose which values are true in conditions.
+    pub fn set_truthiness(&mut self, truthiness: Truthiness) {
+        self.truthiness = truthiness;
+    }
+
+    /// Choose how `==` compares values of different types.
+    pub fn set_equality(&mut self, equality: Equality) {
+        self.equality = equality;
+    }
+
+    /// Replace the natives that need the capability with ones that fail with
+    /// a runtime error.
+    pub(crate) fn deny(&mut self, capability: Capability) {
+        for &name in capability.natives() {
+            if let Some(Value::Native(native)) = self.globals.get(name) {
+                let denied = Native { arity: native.arity, function: denied(capability) };
+                self.globals.insert(name.into(), Value::Native(Rc::new(denied)));
+            }
+        }
+    }
+
+    /// Fail a run with a runtime error after executing this many
+    /// instructions. `None`, the default, means there is no limit.
+    pub fn set_max_steps(&mut self, steps: Option<u64>) {
+        self.budget.max_steps = steps;
+    }
+
+    /// Fail a run with a runtime error if it takes longer than this. `None`,
+    /// the default, means there is no limit.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.budget.timeout = timeout;
+    }
+
+    /// Write what programs print to this writer instead of the standard
+    /// output.
+    pub fn set_output(&mut self, writer: impl std::io::Write + MaybeSync + 'static) {
+        self.output = Output::new(writer);
+    }
+
+    /// Print to this output from now on, and return the previous one.
+    pub(crate) fn replace_output(&mut self, output: Output) -> Output {
+        std::mem::replace(&mut self.output, output)
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.budget.interrupt.clone()
+    }
+
+    /// Free the objects that are only kept alive by reference cycles, and
+    /// return their number. This also happens automatically as the program
+    /// allocates.
+    pub fn collect_garbage(&mut self) -> usize {
+        self.heap.collect()
+    }
+
+    /// Count the objects alive in the heap, e.g. to find leaks.
+    pub fn heap_stats(&self) -> HeapStats {
+        let mut stats = self.heap.stats();
+        // The heap only knows about the strings captured by closures or stored
+        // in fields
+        let values = self.globals.values().chain(&self.stack);
+        stats.strings += values.filter(|value| matches!(value, Value::String(_))).count();
+        stats
+    }
+
+    /// The results of the tests run by the `test` native function since the
+    /// last call.
+    pub fn take_test_results(&mut self) -> Vec<TestResult> {
+        std::mem::take(&mut self.test_results)
+    }
+
+    /// Take the exit code of the last run, if a `return` at the top level
+    /// ended it.
+    pub fn take_exit_code(&mut self) -> Option<i32> {
+        self.exit_code.take()
+    }
+
+    /// Names of the global variables.
+    pub fn global_names(&self) -> Vec<String> {
+        self.globals.keys().map(|name| name.to_string()).collect()
+    }
+
+    /// The value of a global variable.
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(name)
+    }
+
+    /// Compile and run a resolved program. Like the interpreter, it keeps
+    /// running the next top-level statements after a runtime error.
+    pub fn interpret(&mut self, ast: &Ast) -> Result<(), Vec<InterpreterError>> {
+        let scripts = Compiler::compile(ast)
+            .map_err(|errors| errors.into_iter().map(InterpreterError::from).collect::<Vec<_>>())?;
+        self.run_program(scripts)
+    }
+
+    /// Run the scripts of a compiled program, e.g. one read from a `.loxc`
+    /// file.
+    pub(crate) fn run_program(
+        &mut self,
+        scripts: Vec<Rc<Function>>,
+    ) -> Result<(), Vec<InterpreterError>> {
+        self.budget.reset();
+        self.exit_code = None;
+
+        let mut errors = vec![];
+        for script in scripts {
+            if let Err(e) = self.run_script(script) {
+                errors.push(e);
+            }
+            // A `return` at the top level ends the script
+            if self.exit_code.is_some() {
+                break;
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    /// Compile and evaluate a resolved expression.
+    pub fn evaluate(&mut self, ast: &Ast, expr: ExprId) -> Result<Value, InterpreterError> {
+        self.budget.reset();
+        let script = Compiler::compile_expression(ast, expr)
+            .map_err(|mut errors| InterpreterError::from(errors.remove(0)))?;
+        self.run_script(script)
+    }
+
+    fn run_script(&mut self, function: Rc<Function>) -> Result<Value, InterpreterError> {
+        let closure = Rc::new(Closure { function, upvalues: vec![] });
+        self.stack.push(Value::Closure(closure.clone()));
+        self.frames.push(CallFrame { closure, ip: 0, base: 0 });
+
+        let result = self.run(0);
+        if result.is_err() {
+            self.close_resources(0);
+            // Closures that outlive the failed script keep the values they captured
+            self.close_upvalues(0);
+            self.stack.clear();
+            self.frames.clear();
+        }
+
+        result
+    }
+
+    /// Call a function from a native function and run it to completion. If it
+    /// fails, the stack is back to how it was before the call.
+    fn call_value(
+        &mut self,
+        callee: Value,
+        arguments: &[Value],
+    ) -> Result<Value, InterpreterError> {
+        let depth = self.frames.len();
+        let base = self.stack.len();
+
+        self.stack.push(callee);
+        let result = self.check_call(arguments.len()).and_then(|()| {
+            self.stack.extend_from_slice(arguments);
+            self.call(arguments.len())?;
+            // Natives and classes without an initializer are done already
+            if self.frames.len() == depth { Ok(self.pop()) } else { self.run(depth) }
+        });
+
+        if result.is_err() {
+            self.close_resources(base);
+            self.close_upvalues(base);
+            self.stack.truncate(base);
+            self.frames.truncate(depth);
+        }
+        result
+    }
+
+    /// Call `close()` on the values of the `using` statements that an error
+    /// left, which are above `base` on the stack. The first error is the one
+    /// reported, so the errors of these calls are dropped.
+    fn close_resources(&mut self, base: usize) {
+        while let Some(slot) = self.resources.pop_if(|slot| *slot >= base) {
+            let Value::Instance(instance) = self.stack[slot].clone() else {
+                continue;
+            };
+            let field = instance.fields.borrow().get("close").cloned();
+            let close = match field {
+                Some(close) => close,
+                None => match instance.class.find_method("close") {
+                    Some(method) => self.bind(Value::Instance(instance), method),
+                    None => continue,
+                },
+            };
+            let _ = self.call_value(close, &[]);
+        }
+    }
+}
+
+impl Vm {
+    /// Execute instructions until the function of the frame above `depth`
+    /// returns, and return its result.
+    fn run(&mut self, depth: usize) -> Result<Value, InterpreterError> {
+        loop {
+            self.budget.steps += 1;
+
+            let frame = self.frames.last_mut().expect("no function is running");
+            let op = frame.closure.function.chunk.code[frame.ip];
+            let base = frame.base;
+            frame.ip += 1;
+
+            match op {
+                Op::Constant(index) => {
+                    let value = self.chunk().constants[index].clone();
+                    self.stack.push(value);
+                }
+                Op::Nil => self.stack.push(Value::Nil),
+                Op::True => self.stack.push(Value::Boolean(true)),
+                Op::False => self.stack.push(Value::Boolean(false)),
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::GetLocal(slot) => self.stack.push(self.stack[base + slot].clone()),
+                Op::SetLocal(slot) => self.stack[base + slot] = self.peek(0).clone(),
+                Op::GetGlobal(index) => {
+                    let name = self.name(index);
+                    match self.globals.get(&name) {
+                        Some(value) => self.stack.push(value.clone()),
+                        None => return Err(self.undefined_variable(&name)),
+                    }
+                }
+                Op::DefineGlobal(index) => {
+                    let name = self.name(index);
+                    let value = self.pop();
+                    self.globals.insert(name, value);
+                }
+                Op::SetGlobal(index) => {
+                    let name = self.name(index);
+                    let value = self.peek(0).clone();
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => return Err(self.undefined_variable(&name)),
+                    }
+                }
+                Op::GetUpvalue(index) => {
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                Op::SetUpvalue(index) => {
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    let value = self.peek(0).clone();
+                    let mut upvalue = upvalue.borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                Op::GetProperty(index) => {
+                    let name = self.name(index);
+                    let value = match self.peek(0).clone() {
+                        Value::Instance(instance) => {
+                            let field = instance.fields.borrow().get(&name).cloned();
+                            if let Some(value) = field {
+                                value
+                            } else if let Some(method) = instance.class.find_method(&name) {
+                                self.bind(Value::Instance(instance.clone()), method)
+                            } else {
+                                return Err(
+                                    self.undefined_property(&name, &instance.property_names())
+                                );
+                            }
+                        }
+                        receiver @ (Value::Set(_)
+                        | Value::List(_)
+                        | Value::StringBuilder(_)
+                        | Value::WeakRef(_)) => {
+                            let names = match &receiver {
+                                Value::Set(_) => LoxSet::method_names(),
+                                Value::List(_) => list::method_names(),
+                                Value::StringBuilder(_) => string_builder::method_names(),
+                                _ => weak_ref::method_names(),
+                            };
+                            match NativeMethod::new(receiver, name.clone()) {
+                                Some(method) => Value::NativeMethod(Rc::new(method)),
+                                None => return Err(self.undefined_property(&name, &names)),
+                            }
+                        }
+                        _ => {
+                            return Err(self.error(
+                                DiagnosticCode::NotAnInstance,
+                                "Only instances have properties.",
+                            ));
+                        }
+                    };
+
+                    self.pop();
+                    self.stack.push(value);
+                }
+                Op::SetProperty(index) => {
+                    let name = self.name(index);
+                    let Value::Instance(instance) = self.peek(1).clone() else {
+                        return Err(self
+                            .error(DiagnosticCode::NotAnInstance, "Only instances have fields."));
+                    };
+
+                    let value = self.pop();
+                    instance.fields.borrow_mut().insert(name, value.clone());
+                    self.pop();
+                    self.stack.push(value);
+                }
+                Op::GetIndex => {
+                    let (list, position) = self.list_position(self.peek(1), self.peek(0))?;
+                    let value = list.borrow()[position].clone();
+                    self.pop();
+                    self.pop();
+                    self.stack.push(value);
+                }
+                Op::SetIndex => {
+                    let (list, position) = self.list_position(self.peek(2), self.peek(1))?;
+                    let value = self.pop();
+                    list.borrow_mut()[position] = value.clone();
+                    self.pop();
+                    self.pop();
+                    self.stack.push(value);
+                }
+                Op::List(count) => {
+                    let values = self.stack.split_off(self.stack.len() - count);
+                    let list = self.new_list(values);
+                    self.stack.push(list);
+                }
+                Op::GetSuper(index) => {
+                    let name = self.name(index);
+                    let Value::Class(superclass) = self.pop() else {
+                        panic!("Superclass is not wrapped in Value::Class");
+                    };
+                    let receiver = self.pop();
+
+                    match superclass.find_method(&name) {
+                        Some(method) => {
+                            let bound = self.bind(receiver, method);
+                            self.stack.push(bound);
+                        }
+                        None => {
+                            // Fields belong to the instance, not to a class
+                            let is_field = matches!(&receiver, Value::Instance(instance)
+                                if instance.fields.borrow().contains_key(&name));
+                            return Err(if is_field {
+                                self.field_through_super(&name)
+                            } else {
+                                self.undefined_property(&name, &superclass.method_names())
+                            });
+                        }
+                    }
+                }
+                Op::Equal => {
+                    let (left, right) = self.pop_pair();
+                    self.stack.push(Value::Boolean(left.equals(&right, self.equality)));
+                }
+                Op::NotEqual => {
+                    let (left, right) = self.pop_pair();
+                    self.stack.push(Value::Boolean(!left.equals(&right, self.equality)));
+                }
+                Op::Greater => self.comparison(|l, r| l > r)?,
+                Op::GreaterEqual => self.comparison(|l, r| l >= r)?,
+                Op::Less => self.comparison(|l, r| l < r)?,
+                Op::LessEqual => self.comparison(|l, r| l <= r)?,
+                Op::Add => {
+                    let value = match (self.peek(1), self.peek(0)) {
+                        (Value::Number(l), Value::Number(r)) => Value::Number(l + r),
+                        (Value::String(l), Value::String(r)) => {
+                            Value::String(format!("{l}{r}").into())
+                        }
+                        _ => {
+                            return Err(self.error(
+                                DiagnosticCode::InvalidOperand,
+                                "Operands must be two numbers or two strings.",
+                            ));
+                        }
+                    };
+                    self.pop_pair();
+                    self.stack.push(value);
+                }
+                Op::Subtract => self.arithmetic(|l, r| l - r)?,
+                Op::Multiply => self.arithmetic(|l, r| l * r)?,
+                Op::Divide => self.arithmetic(|l, r| l / r)?,
+                Op::Not => {
+                    let value = self.pop();
+                    self.stack.push(Value::Boolean(!value.is_true(self.truthiness)));
+                }
+                Op::Negate => {
+                    let Value::Number(n) = self.peek(0) else {
+                        return Err(
+                            self.error(DiagnosticCode::InvalidOperand, "Operand must be a number.")
+                        );
+                    };
+                    let value = Value::Number(-n);
+                    self.pop();
+                    self.stack.push(value);
+                }
+                Op::Print => {
+                    let value = self.pop();
+                    self.output.print(format_args!("{value}"));
+                }
+                Op::PrintLine => self.output.print(format_args!("\n")),
+                Op::Jump(target) => self.frame_mut().ip = target,
+                Op::JumpIfFalse(target) => {
+                    if !self.peek(0).is_true(self.truthiness) {
+                        self.frame_mut().ip = target;
+                    }
+                }
+                Op::Loop(target) => {
+                    self.check_budget()?;
+                    self.frame_mut().ip = target;
+                }
+                Op::CheckCall(arg_count) => self.check_call(arg_count)?,
+                Op::Call(arg_count) => self.call(arg_count)?,
+                Op::Closure(index) => {
+                    let Value::Function(function) = self.chunk().constants[index].clone() else {
+                        panic!("Closure constant is not a function");
+                    };
+
+                    let upvalues = function
+                        .upvalues
+                        .iter()
+                        .map(|upvalue| {
+                            if upvalue.is_local {
+                                self.capture_upvalue(base + upvalue.index)
+                            } else {
+                                self.frame().closure.upvalues[upvalue.index].clone()
+                            }
+                        })
+                        .collect();
+
+                    let closure = Rc::new(Closure { function, upvalues });
+                    self.heap.track(&closure);
+                    self.stack.push(Value::Closure(closure));
+                }
+                Op::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                Op::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("no function to return from");
+                    self.close_upvalues(frame.base);
+                    self.stack.truncate(frame.base);
+
+                    if self.frames.len() == depth {
+                        return Ok(result);
+                    }
+                    self.stack.push(result);
+                }
+                Op::Exit => {
+                    let code = match self.peek(0) {
+                        Value::Number(code) => *code as i32,
+                        Value::Nil => 0,
+                        _ => {
+                            return Err(self.error(
+                                DiagnosticCode::InvalidExitCode,
+                                "Can only return a number from top-level code.",
+                            ));
+                        }
+                    };
+                    self.exit_code = Some(code);
+
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("no script to end");
+                    self.close_upvalues(frame.base);
+                    self.stack.truncate(frame.base);
+                    return Ok(result);
+                }
+                Op::Using => self.resources.push(self.stack.len() - 1),
+                Op::Close => {
+                    let slot = self.resources.pop().expect("no value to close");
+                    self.stack.push(self.stack[slot].clone());
+                }
+                Op::Class(index) => {
+                    let class = Rc::new(Class::new(self.name(index), None));
+                    self.heap.track(&class);
+                    self.stack.push(Value::Class(class));
+                }
+                Op::Subclass(index) => {
+                    let Value::Class(superclass) = self.peek(0).clone() else {
+                        return Err(self.error(
+                            DiagnosticCode::SuperclassNotClass,
+                            "Superclass must be a class.",
+                        ));
+                    };
+
+                    let class = Rc::new(Class::new(self.name(index), Some(superclass)));
+                    self.heap.track(&class);
+                    self.stack.push(Value::Class(class));
+                }
+                Op::Method(index) => {
+                    let name = self.name(index);
+                    let Value::Closure(method) = self.pop() else {
+                        panic!("Method is not a closure");
+                    };
+                    let Value::Class(class) = self.peek(0) else {
+                        panic!("Method is not defined on a class");
+                    };
+                    class.methods.borrow_mut().insert(name, method);
+                }
+                Op::Extend => {
+                    if !matches!(self.peek(0), Value::Class(_)) {
+                        return Err(
+                            self.error(DiagnosticCode::NotAClass, "Can only extend a class.")
+                        );
+                    }
+                }
+                Op::ClassDoc(index) => {
+                    let doc = self.name(index);
+                    let Value::Class(class) = self.peek(0) else {
+                        panic!("Doc comment is not attached to a class");
+                    };
+                    *class.doc.borrow_mut() = Some(doc);
+                }
+            }
+        }
+    }
+
+    /// Fail unless the callee on the top of the stack can be called with this
+    /// many arguments. Like the interpreter, this is checked before the
+    /// arguments are evaluated.
+    fn check_call(&self, arg_count: usize) -> Result<(), InterpreterError> {
+        let arity = match self.peek(0) {
+            Value::Closure(closure) => closure.function.arity,
+            Value::BoundMethod(bound) => bound.method.function.arity,
+            Value::Native(native) => native.arity,
+            Value::Memoized(memoized) => {
+                memoized.function.arity().expect("only functions and classes are memoized")
+            }
+            Value::NativeMethod(method) => method.arity().expect("the receiver has this method"),
+            Value::Class(class) => class.arity(),
+            _ => {
+                return Err(
+                    self.error(DiagnosticCode::NotCallable, "Can only call functions and classes.")
+                );
+            }
+        };
+
+        if arity != arg_count {
+            let message = format!("Expected {arity} arguments but got {arg_count}.");
+            return Err(self.error(DiagnosticCode::ArityMismatch, message));
+        }
+
+        Ok(())
+    }
+
+    /// Call the callee below the arguments on the top of the stack, which has
+    /// been checked by [`Vm::check_call`].
+    fn call(&mut self, arg_count: usize) -> Result<(), InterpreterError> {
+        // The frame of the script doesn't count
+        if self.frames.len() > self.max_call_depth {
+            return Err(self.error(DiagnosticCode::StackOverflow, "Stack overflow."));
+        }
+
+        self.check_budget()?;
+
+        let callee_slot = self.stack.len() - arg_count - 1;
+        match self.stack[callee_slot].clone() {
+            Value::Closure(closure) => self.call_closure(closure, callee_slot),
+            Value::BoundMethod(bound) => {
+                self.stack[callee_slot] = bound.receiver.clone();
+                self.call_closure(bound.method.clone(), callee_slot);
+            }
+            Value::Class(class) => {
+                let initializer = class.find_method("init");
+                let instance = Rc::new(Instance::new(class));
+                self.heap.track(&instance);
+                self.stack[callee_slot] = Value::Instance(instance);
+                if let Some(initializer) = initializer {
+                    self.call_closure(initializer, callee_slot);
+                }
+            }
+            Value::Native(native) => {
+                let arguments = self.stack.split_off(callee_slot + 1);
+                let result = (native.function)(self, &arguments)?;
+                self.stack.truncate(callee_slot);
+                self.stack.push(result);
+            }
+            Value::Memoized(memoized) => {
+                let arguments = self.stack.split_off(callee_slot + 1);
+                let result = self.call_memoized(&memoized, &arguments)?;
+                self.stack.truncate(callee_slot);
+                self.stack.push(result);
+            }
+            Value::NativeMethod(method) => {
+                let arguments = self.stack.split_off(callee_slot + 1);
+                let result = self.call_native_method(&method, &arguments)?;
+                self.stack.truncate(callee_slot);
+                self.stack.push(result);
+            }
+            _ => panic!("Callee was not checked before the call"),
+        }
+
+        Ok(())
+    }
+
+    fn call_closure(&mut self, closure: Rc<Closure>, base: usize) {
+        self.frames.push(CallFrame { closure, ip: 0, base });
+    }
+
+    /// Return the result of the previous call with the same arguments, or
+    /// call the memoized function and keep its result.
+    fn call_memoized(
+        &mut self,
+        memoized: &Memoized,
+        arguments: &[Value],
+    ) -> Result<Value, InterpreterError> {
+        let key = arguments.iter().map(|value| self.key(value)).collect::<Result<Vec<_>, _>>()?;
+        if let Some(result) = memoized.cache.borrow().get(&key) {
+            return Ok(result.clone());
+        }
+
+        let result = self.call_value(memoized.function.clone(), arguments)?;
+        memoized.cache.borrow_mut().insert(key, result.clone());
+        Ok(result)
+    }
+
+    /// Run a method of a set or a list, see [`crate::set`] and [`crate::list`].
+    fn call_native_method(
+        &mut self,
+        method: &NativeMethod,
+        arguments: &[Value],
+    ) -> Result<Value, InterpreterError> {
+        match &method.receiver {
+            Value::Set(set) => self.call_set_method(set, &method.name, arguments),
+            Value::List(list) => self.call_list_method(list, &method.name, arguments),
+            Value::StringBuilder(builder) => {
+                Ok(Self::call_string_builder_method(builder, &method.name, arguments))
+            }
+            Value::WeakRef(weak) => Ok(Self::call_weak_ref_method(weak, &method.name)),
+            _ => unreachable!("only sets, lists, builders and weak references have native methods"),
+        }
+    }
+
+    fn call_string_builder_method(
+        builder: &Rc<RefCell<String>>,
+        name: &str,
+        arguments: &[Value],
+    ) -> Value {
+        match name {
+            "append" => {
+                match &arguments[0] {
+                    Value::String(s) => builder.borrow_mut().push_str(s),
+                    // The value may be the builder itself
+                    value => {
+                        let text = value.to_string();
+                        builder.borrow_mut().push_str(&text);
+                    }
+                }
+                Value::StringBuilder(builder.clone())
+            }
+            "length" => Value::Number(builder.borrow().chars().count() as f64),
+            "toString" => Value::String(builder.borrow().as_str().into()),
+            _ => unreachable!("builders have no method '{name}'"),
+        }
+    }
+
+    fn call_weak_ref_method(weak: &Weak<Instance>, name: &str) -> Value {
+        match name {
+            "get" => weak.upgrade().map_or(Value::Nil, Value::Instance),
+            _ => unreachable!("weak references have no method '{name}'"),
+        }
+    }
+
+    fn call_set_method(
+        &mut self,
+        set: &RefCell<LoxSet>,
+        name: &str,
+        arguments: &[Value],
+    ) -> Result<Value, InterpreterError> {
+        let other = |vm: &Self, argument: &Value| match argument {
+            Value::Set(other) => Ok(other.clone()),
+            _ => Err(vm.error(DiagnosticCode::InvalidArgument, set::NOT_A_SET)),
+        };
+
+        let result = match name {
+            // The key is made before borrowing the set, whose error message
+            // prints the argument, which may be the set itself
+            "add" => {
+                let key = self.key(&arguments[0])?;
+                set.borrow_mut().add(key);
+                Value::Nil
+            }
+            "has" => {
+                let key = self.key(&arguments[0])?;
+                Value::Boolean(set.borrow().has(&key))
+            }
+            "remove" => {
+                let key = self.key(&arguments[0])?;
+                Value::Boolean(set.borrow_mut().remove(&key))
+            }
+            "union" => {
+                let union = set.borrow().union(&other(self, &arguments[0])?.borrow());
+                Value::Set(Rc::new(RefCell::new(union)))
+            }
+            "intersect" => {
+                let intersection = set.borrow().intersect(&other(self, &arguments[0])?.borrow());
+                Value::Set(Rc::new(RefCell::new(intersection)))
+            }
+            "size" => Value::Number(set.borrow().len() as f64),
+            "forEach" => {
+                self.check_callback(&arguments[0])?;
+                // The function may change the set
+                let values = set.borrow().iter().cloned().collect::<Vec<_>>();
+                for value in values {
+                    self.call_value(arguments[0].clone(), &[Value::from(value)])?;
+                }
+                Value::Nil
+            }
+            _ => unreachable!("sets have no method '{name}'"),
+        };
+        Ok(result)
+    }
+
+    fn call_list_method(
+        &mut self,
+        values: &RefCell<Vec<Value>>,
+        name: &str,
+        arguments: &[Value],
+    ) -> Result<Value, InterpreterError> {
+        let position = |vm: &Self, index: &Value| {
+            let Value::Number(index) = index else {
+                return Err(vm.error(DiagnosticCode::InvalidArgument, list::NOT_AN_INDEX));
+            };
+            list::position(*index, values.borrow().len())
+                .map_err(|message| vm.error(DiagnosticCode::InvalidArgument, message))
+        };
+
+        let result = match name {
+            "push" => {
+                values.borrow_mut().push(arguments[0].clone());
+                Value::Nil
+            }
+            "pop" => match values.borrow_mut().pop() {
+                Some(value) => value,
+                None => return Err(self.error(DiagnosticCode::InvalidArgument, list::EMPTY)),
+            },
+            "get" => values.borrow()[position(self, &arguments[0])?].clone(),
+            "set" => {
+                let position = position(self, &arguments[0])?;
+                values.borrow_mut()[position] = arguments[1].clone();
+                arguments[1].clone()
+            }
+            "length" => Value::Number(values.borrow().len() as f64),
+            "forEach" => {
+                self.check_callback(&arguments[0])?;
+                // The function may change the list
+                let values = values.borrow().clone();
+                for value in values {
+                    self.call_value(arguments[0].clone(), &[value])?;
+                }
+                Value::Nil
+            }
+            _ => unreachable!("lists have no method '{name}'"),
+        };
+        Ok(result)
+    }
+
+    /// Fail unless the argument of `forEach` can be called with a value.
+    fn check_callback(&self, callback: &Value) -> Result<(), InterpreterError> {
+        if callback.arity() != Some(1) {
+            return Err(self.error(DiagnosticCode::InvalidArgument, native::INVALID_CALLBACK));
+        }
+        Ok(())
+    }
+
+    /// The list an index instruction reads or writes, and the position of the
+    /// index in it.
+    fn list_position(
+        &self,
+        list: &Value,
+        index: &Value,
+    ) -> Result<(Shared<Vec<Value>>, usize), InterpreterError> {
+        let Value::List(list) = list else {
+            return Err(self.error(DiagnosticCode::InvalidOperand, list::NOT_INDEXABLE));
+        };
+        let Value::Number(index) = index else {
+            return Err(self.error(DiagnosticCode::InvalidOperand, list::NOT_AN_INDEX));
+        };
+
+        let position = list::position(*index, list.borrow().len())
+            .map_err(|message| self.error(DiagnosticCode::IndexOutOfBounds, message))?;
+        Ok((list.clone(), position))
+    }
+
+    /// A new list, tracked by the garbage collector.
+    fn new_list(&mut self, values: Vec<Value>) -> Value {
+        let list = Rc::new(RefCell::new(values));
+        self.heap.track(&list);
+        Value::List(list)
+    }
+
+    /// The value as the key of a hash map, or an error if it can't be one.
+    fn key(&self, value: &Value) -> Result<Key, InterpreterError> {
+        value.key().ok_or_else(|| self.error(DiagnosticCode::UnhashableKey, key::unhashable(value)))
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let open = self
+            .open_upvalues
+            .iter()
+            .find(|u| matches!(*u.borrow(), Upvalue::Open(s) if s == slot));
+        if let Some(upvalue) = open {
+            return upvalue.clone();
+        }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.heap.track(&upvalue);
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    /// Move the values of the stack slots from `first` up into the upvalues
+    /// that point to them.
+    fn close_upvalues(&mut self, first: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue {
+                Upvalue::Open(slot) if slot >= first => {
+                    *upvalue = Upvalue::Closed(stack[slot].clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    fn check_budget(&mut self) -> Result<(), InterpreterError> {
+        self.budget.check().map_err(|(code, message)| self.error(code, message))
+    }
+}
+
+impl Vm {
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no function is running")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("no function is running")
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.frame().closure.function.chunk
+    }
+
+    /// The name in the constant at `index`.
+    fn name(&self, index: usize) -> Rc<str> {
+        match &self.chunk().constants[index] {
+            Value::String(name) => name.clone(),
+            value => panic!("Constant {value} is not a name"),
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("the stack is empty")
+    }
+
+    fn pop_pair(&mut self) -> (Value, Value) {
+        let right = self.pop();
+        let left = self.pop();
+        (left, right)
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn number_operands(&self) -> Result<(f64, f64), InterpreterError> {
+        match (self.peek(1), self.peek(0)) {
+            (Value::Number(l), Value::Number(r)) => Ok((*l, *r)),
+            _ => Err(self.error(DiagnosticCode::InvalidOperand, "Operands must be numbers.")),
+        }
+    }
+
+    fn arithmetic(&mut self, op: fn(f64, f64) -> f64) -> Result<(), InterpreterError> {
+        let (l, r) = self.number_operands()?;
+        self.pop_pair();
+        self.stack.push(Value::Number(op(l, r)));
+        Ok(())
+    }
+
+    fn comparison(&mut self, op: fn(f64, f64) -> bool) -> Result<(), InterpreterError> {
+        let (l, r) = self.number_operands()?;
+        self.pop_pair();
+        self.stack.push(Value::Boolean(op(l, r)));
+        Ok(())
+    }
+
+    /// A runtime error located at the instruction being executed.
+    fn error(&self, code: DiagnosticCode, message: impl AsRef<str>) -> InterpreterError {
+        let frame = self.frame();
+        let position = frame.closure.function.chunk.positions[frame.ip - 1];
+        InterpreterError {
+            line: position.line,
+            column: position.column,
+            span: position.span,
+            code,
+            message: message.as_ref().to_owned(),
+            cause: None,
+        }
+    }
+
+    /// The error for using an undefined variable, with a hint if a variable
+    /// visible from here has a similar name.
+    fn undefined_variable(&self, name: &str) -> InterpreterError {
+        let frame = self.frame();
+        let locals = frame.closure.function.chunk.locals_in_scope.get(&(frame.ip - 1));
+        let locals = locals.into_iter().flat_map(|names| names.iter());
+
+        let message = with_suggestion(
+            format!("Undefined variable '{name}'."),
+            name,
+            self.globals.keys().chain(locals).map(|name| &**name),
+        );
+        self.error(DiagnosticCode::UndefinedVariable, message)
+    }
+
+    /// Bind a method to the instance it's called on.
+    fn bind(&mut self, receiver: Value, method: Rc<Closure>) -> Value {
+        let bound = Rc::new(BoundMethod { receiver, method });
+        self.heap.track(&bound);
+        Value::BoundMethod(bound)
+    }
+
+    fn undefined_property(&self, name: &str, properties: &[String]) -> InterpreterError {
+        let message = with_suggestion(
+            format!("Undefined property '{name}'."),
+            name,
+            properties.iter().map(String::as_str),
+        );
+        self.error(DiagnosticCode::UndefinedProperty, message)
+    }
+
+    fn field_through_super(&self, name: &str) -> InterpreterError {
+        let message = format!("Can't access field '{name}' through 'super'. Use 'this.{name}'.");
+        self.error(DiagnosticCode::UndefinedProperty, message)
+    }
+}
+
+fn gc_collect(vm: &mut Vm, _arguments: &[Value]) -> Result<Value, InterpreterError> {
+    Ok(Value::Number(vm.collect_garbage() as f64))
+}
+
+fn memory_stats(vm: &mut Vm, _arguments: &[Value]) -> Result<Value, InterpreterError> {
+    let stats = vm.heap_stats();
+
+    let class = Rc::new(Class::new("MemoryStats".into(), None));
+    vm.heap.track(&class);
+
+    let instance = Rc::new(Instance::new(class));
+    for (name, value) in stats.fields() {
+        instance.fields.borrow_mut().insert(name.into(), Value::Number(value as f64));
+    }
+    vm.heap.track(&instance);
+
+    Ok(Value::Instance(instance))
+}
+
+fn doc(_vm: &mut Vm, arguments: &[Value]) -> Result<Value, InterpreterError> {
+    Ok(arguments[0].doc().map_or(Value::Nil, Value::String))
+}
+
+fn bind_method(vm: &mut Vm, arguments: &[Value]) -> Result<Value, InterpreterError> {
+    let Value::Instance(instance) = &arguments[0] else {
+        return Err(vm.error(DiagnosticCode::NotAnInstance, native::NOT_AN_INSTANCE));
+    };
+    let Value::String(name) = &arguments[1] else {
+        return Err(vm.error(DiagnosticCode::InvalidArgument, native::INVALID_METHOD_NAME));
+    };
+
+    match instance.class.find_method(name) {
+        Some(method) => Ok(vm.bind(arguments[0].clone(), method)),
+        None => Err(vm.error(DiagnosticCode::UndefinedProperty, native::undefined_method(name))),
+    }
+}
+
+fn arity(vm: &mut Vm, arguments: &[Value]) -> Result<Value, InterpreterError> {
+    match arguments[0].arity() {
+        Some(arity) => Ok(Value::Number(arity as f64)),
+        None => Err(vm.error(DiagnosticCode::InvalidArgument, native::NOT_CALLABLE)),
+    }
+}
+
+fn name(vm: &mut Vm, arguments: &[Value]) -> Result<Value, InterpreterError> {
+    if arguments[0].arity().is_none() {
+        return Err(vm.error(DiagnosticCode::InvalidArgument, native::NOT_CALLABLE));
+    }
+    Ok(arguments[0].name().map_or(Value::Nil, Value::String))
+}
+
+fn memoize(vm: &mut Vm, arguments: &[Value]) -> Result<Value, InterpreterError> {
+    if arguments[0].arity().is_none() {
+        return Err(vm.error(DiagnosticCode::InvalidArgument, native::NOT_CALLABLE));
+    }
+
+    let memoized = Rc::new(Memoized { function: arguments[0].clone(), cache: RefCell::default() });
+    vm.heap.track(&memoized);
+    Ok(Value::Memoized(memoized))
+}
+
+fn new_list(vm: &mut Vm, _arguments: &[Value]) -> Result<Value, InterpreterError> {
+    Ok(vm.new_list(vec![]))
+}
+
+fn split(vm: &mut Vm, arguments: &[Value]) -> Result<Value, InterpreterError> {
+    let Value::String(string) = &arguments[0] else {
+        return Err(vm.error(DiagnosticCode::InvalidArgument, list::NOT_A_STRING));
+    };
+    let Value::String(separator) = &arguments[1] else {
+        return Err(vm.error(DiagnosticCode::InvalidArgument, list::INVALID_SEPARATOR));
+    };
+
+    let parts = list::split(string, separator).into_iter().map(|s| Value::String(s.into()));
+    Ok(vm.new_list(parts.collect()))
+}
+
+fn join(vm: &mut Vm, arguments: &[Value]) -> Result<Value, InterpreterError> {
+    let Value::List(values) = &arguments[0] else {
+        return Err(vm.error(DiagnosticCode::InvalidArgument, list::NOT_A_LIST));
+    };
+    let Value::String(separator) = &arguments[1] else {
+        return Err(vm.error(DiagnosticCode::InvalidArgument, list::INVALID_SEPARATOR));
+    };
+
+    let values = values.borrow();
+    let strings = values.iter().map(|value| match value {
+        Value::String(s) => Ok(&**s),
+        _ => Err(vm.error(DiagnosticCode::InvalidArgument, list::NOT_ALL_STRINGS)),
+    });
+    Ok(Value::String(strings.collect::<Result<Vec<_>, _>>()?.join(separator).into()))
+}
+
+fn new_string_builder(_vm: &mut Vm, _arguments: &[Value]) -> Result<Value, InterpreterError> {
+    Ok(Value::StringBuilder(Rc::new(RefCell::new(String::new()))))
+}
+
+fn new_weak_ref(vm: &mut Vm, arguments: &[Value]) -> Result<Value, InterpreterError> {
+    let Value::Instance(instance) = &arguments[0] else {
+        return Err(vm.error(DiagnosticCode::NotAnInstance, native::NOT_WEAKLY_REFERABLE));
+    };
+    Ok(Value::WeakRef(Rc::downgrade(instance)))
+}
+
+fn new_set(_vm: &mut Vm, _arguments: &[Value]) -> Result<Value, InterpreterError> {
+    Ok(Value::Set(Rc::new(RefCell::new(LoxSet::new()))))
+}
+
+fn clock(_vm: &mut Vm, _arguments: &[Value]) -> Result<Value, InterpreterError> {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backward");
+    Ok(Value::Number(since_epoch.as_millis() as f64 / 1000.0))
+}
+
+/// Run a function as a named test, recording whether it fails, and return
+/// whether it passed.
+fn test(vm: &mut Vm, arguments: &[Value]) -> Result<Value, InterpreterError> {
+    let Value::String(name) = &arguments[0] else {
+        return Err(vm.error(DiagnosticCode::InvalidArgument, testing::INVALID_TEST_NAME));
+    };
+    let arity = match &arguments[1] {
+        Value::Closure(closure) => Some(closure.function.arity),
+        Value::BoundMethod(bound) => Some(bound.method.function.arity),
+        Value::Native(native) => Some(native.arity),
+        Value::Memoized(memoized) => memoized.function.arity(),
+        Value::NativeMethod(method) => method.arity(),
+        _ => None,
+    };
+    if arity != Some(0) {
+        return Err(vm.error(DiagnosticCode::InvalidArgument, testing::INVALID_TEST_FUNCTION));
+    }
+
+    let failure = match vm.call_value(arguments[1].clone(), &[]) {
+        Ok(_) => None,
+        Err(e) if testing::stops_run(e.code) => return Err(e),
+        Err(e) => Some(format!("[line {}] {}", e.line, e.message)),
+    };
+
+    let passed = failure.is_none();
+    vm.test_results.push(TestResult { name: name.to_string(), failure });
+    Ok(Value::Boolean(passed))
+}
+
+fn assert_eq(vm: &mut Vm, arguments: &[Value]) -> Result<Value, InterpreterError> {
+    let (actual, expected) = (&arguments[0], &arguments[1]);
+    if actual != expected {
+        let message = testing::assert_eq_message(actual, expected);
+        return Err(vm.error(DiagnosticCode::AssertionFailed, message));
+    }
+    Ok(Value::Nil)
+}
+
+fn assert_true(vm: &mut Vm, arguments: &[Value]) -> Result<Value, InterpreterError> {
+    if !arguments[0].is_true(vm.truthiness) {
+        let message = testing::assert_true_message(&arguments[0]);
+        return Err(vm.error(DiagnosticCode::AssertionFailed, message));
+    }
+    Ok(Value::Nil)
+}
+
+/// The function of a native whose capability is denied. Natives are plain
+/// functions, so there is one per capability.
+fn denied(capability: Capability) -> fn(&mut Vm, &[Value]) -> Result<Value, InterpreterError> {
+    fn fail<const CAPABILITY: usize>(
+        vm: &mut Vm,
+        _arguments: &[Value],
+    ) -> Result<Value, InterpreterError> {
+        let message = Capability::ALL[CAPABILITY].denied_message();
+        Err(vm.error(DiagnosticCode::CapabilityDenied, message))
+    }
+
+    match capability {
+        Capability::Filesystem => fail::<{ Capability::Filesystem as usize }>,
+        Capability::Network => fail::<{ Capability::Network as usize }>,
+        Capability::Environment => fail::<{ Capability::Environment as usize }>,
+        Capability::Clock => fail::<{ Capability::Clock as usize }>,
+        Capability::Process => fail::<{ Capability::Process as usize }>,
+    }
+}
+
+/// Send a GET request and return the response, see [`crate::net`].
+#[cfg(feature = "net")]
+fn http_get(vm: &mut Vm, arguments: &[Value]) -> Result<Value, InterpreterError> {
+    let Value::String(url) = &arguments[0] else {
+        return Err(vm.error(DiagnosticCode::InvalidArgument, crate::net::not_a_string("The URL")));
+    };
+    http_response(vm, crate::net::get(url))
+}
+
+/// Send a POST request with the body and return the response.
+#[cfg(feature = "net")]
+fn http_post(vm: &mut Vm, arguments: &[Value]) -> Result<Value, InterpreterError> {
+    let (Value::String(url), Value::String(body)) = (&arguments[0], &arguments[1]) else {
+        let what = if matches!(arguments[0], Value::String(_)) { "The body" } else { "The URL" };
+        return Err(vm.error(DiagnosticCode::InvalidArgument, crate::net::not_a_string(what)));
+    };
+    http_response(vm, crate::net::post(url, body))
+}
+
+/// Turn the response into a `Response` instance.
+#[cfg(feature = "net")]
+fn http_response(
+    vm: &mut Vm,
+    response: Result<crate::net::Response, crate::net::RequestError>,
+) -> Result<Value, InterpreterError> {
+    let response = response
+        .map_err(|e| vm.error(DiagnosticCode::RequestFailed, e.message).with_cause(e.cause))?;
+
+    let headers =
+        response.headers.into_iter().map(|(name, value)| (name, Value::String(value.into())));
+    let headers = new_instance(vm, "Headers", headers.collect());
+    let fields = vec![
+        ("status".to_owned(), Value::Number(response.status as f64)),
+        ("headers".to_owned(), headers),
+        ("body".to_owned(), Value::String(response.body.into())),
+    ];
+    Ok(new_instance(vm, "Response", fields))
+}
+
+/// Make an instance of a new class with the fields.
+#[cfg(feature = "net")]
+fn new_instance(vm: &mut Vm, class: &str, fields: Vec<(String, Value)>) -> Value {
+    let class = Rc::new(Class::new(class.into(), None));
+    vm.heap.track(&class);
+
+    let instance = Rc::new(Instance::new(class));
+    for (name, value) in fields {
+        instance.fields.borrow_mut().insert(name.into(), value);
+    }
+    vm.heap.track(&instance);
+
+    Value::Instance(instance)
+}
@@ -0,0 +1,407 @@
+//! The `.loxc` format of compiled programs.
+//!
+//! A file starts with the magic number `LOXC` and a version, followed by the
+//! scripts of the program, one per top-level statement. Each function is
+//! written as its name, arity, upvalues and doc comment, then its chunk: the
+//! instructions with their positions, the constant pool and the local names
+//! used for suggestions. Numbers are little-endian.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+
+use super::chunk::{Chunk, Op, Position};
+use super::value::{Function, UpvalueRef, Value};
+use crate::sync::Rc;
+use crate::token::Span;
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+
+/// The version of the format. Files of other versions can't be read.
+pub const VERSION: u16 = 2;
+
+const NUMBER: u8 = 0;
+const STRING: u8 = 1;
+const FUNCTION: u8 = 2;
+
+/// A file that is not a compiled program of this version.
+#[derive(Debug, PartialEq)]
+pub struct BytecodeError(String);
+
+impl Display for BytecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid bytecode file: {}", self.0)
+    }
+}
+
+impl Error for BytecodeError {}
+
+/// Whether the bytes start like a compiled program.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn write_program(scripts: &[Rc<Function>]) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.bytes.extend_from_slice(&VERSION.to_le_bytes());
+
+    writer.usize(scripts.len());
+    for script in scripts {
+        writer.function(script);
+    }
+
+    writer.bytes
+}
+
+pub fn read_program(bytes: &[u8]) -> Result<Vec<Rc<Function>>, BytecodeError> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(BytecodeError("not a compiled Lox program".to_owned()));
+    }
+
+    let version = u16::from_le_bytes(reader.array()?);
+    if version != VERSION {
+        return Err(BytecodeError(format!("unsupported version {version}, expected {VERSION}")));
+    }
+
+    let count = reader.usize()?;
+    let scripts = (0..count).map(|_| reader.function().map(Rc::new)).collect::<Result<_, _>>()?;
+
+    if reader.offset != bytes.len() {
+        return Err(BytecodeError("unexpected data after the program".to_owned()));
+    }
+
+    Ok(scripts)
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.u32(u32::try_from(value).expect("program is too large to serialize"));
+    }
+
+    fn str(&mut self, s: &str) {
+        self.usize(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.str(&function.name);
+        self.usize(function.arity);
+
+        self.usize(function.upvalues.len());
+        for upvalue in &function.upvalues {
+            self.u8(upvalue.is_local as u8);
+            self.usize(upvalue.index);
+        }
+
+        match &function.doc {
+            Some(doc) => {
+                self.u8(1);
+                self.str(doc);
+            }
+            None => self.u8(0),
+        }
+
+        self.chunk(&function.chunk);
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) {
+        self.usize(chunk.code.len());
+        for (op, position) in chunk.code.iter().zip(&chunk.positions) {
+            self.op(*op);
+            self.u32(position.line);
+            self.u32(position.column);
+            self.usize(position.span.start);
+            self.usize(position.span.end);
+        }
+
+        self.usize(chunk.constants.len());
+        for constant in &chunk.constants {
+            match constant {
+                Value::Number(n) => {
+                    self.u8(NUMBER);
+                    self.bytes.extend_from_slice(&n.to_le_bytes());
+                }
+                Value::String(s) => {
+                    self.u8(STRING);
+                    self.str(s);
+                }
+                Value::Function(function) => {
+                    self.u8(FUNCTION);
+                    self.function(function);
+                }
+                value => panic!("{value} can't be a constant"),
+            }
+        }
+
+        // Sorted, so the same program is always written the same way
+        let mut locals = chunk.locals_in_scope.iter().collect::<Vec<_>>();
+        locals.sort_by_key(|(offset, _)| **offset);
+        self.usize(locals.len());
+        for (offset, names) in locals {
+            self.usize(*offset);
+            self.usize(names.len());
+            for name in names.iter() {
+                self.str(name);
+            }
+        }
+    }
+
+    fn op(&mut self, op: Op) {
+        let (code, operand) = match op {
+            Op::Constant(n) => (0, Some(n)),
+            Op::Nil => (1, None),
+            Op::True => (2, None),
+            Op::False => (3, None),
+            Op::Pop => (4, None),
+            Op::GetLocal(n) => (5, Some(n)),
+            Op::SetLocal(n) => (6, Some(n)),
+            Op::GetGlobal(n) => (7, Some(n)),
+            Op::DefineGlobal(n) => (8, Some(n)),
+            Op::SetGlobal(n) => (9, Some(n)),
+            Op::GetUpvalue(n) => (10, Some(n)),
+            Op::SetUpvalue(n) => (11, Some(n)),
+            Op::GetProperty(n) => (12, Some(n)),
+            Op::SetProperty(n) => (13, Some(n)),
+            Op::GetSuper(n) => (14, Some(n)),
+            Op::Equal => (15, None),
+            Op::NotEqual => (16, None),
+            Op::Greater => (17, None),
+            Op::GreaterEqual => (18, None),
+            Op::Less => (19, None),
+            Op::LessEqual => (20, None),
+            Op::Add => (21, None),
+            Op::Subtract => (22, None),
+            Op::Multiply => (23, None),
+            Op::Divide => (24, None),
+            Op::Not => (25, None),
+            Op::Negate => (26, None),
+            Op::Print => (27, None),
+            Op::PrintLine => (28, None),
+            Op::Jump(n) => (29, Some(n)),
+            Op::JumpIfFalse(n) => (30, Some(n)),
+            Op::Loop(n) => (31, Some(n)),
+            Op::CheckCall(n) => (32, Some(n)),
+            Op::Call(n) => (33, Some(n)),
+            Op::Closure(n) => (34, Some(n)),
+            Op::CloseUpvalue => (35, None),
+            Op::Return => (36, None),
+            Op::Class(n) => (37, Some(n)),
+            Op::Subclass(n) => (38, Some(n)),
+            Op::Method(n) => (39, Some(n)),
+            Op::ClassDoc(n) => (40, Some(n)),
+            Op::GetIndex => (41, None),
+            Op::SetIndex => (42, None),
+            Op::Exit => (43, None),
+            Op::Extend => (44, None),
+            Op::Using => (45, None),
+            Op::Close => (46, None),
+            Op::List(n) => (47, Some(n)),
+        };
+
+        self.u8(code);
+        if let Some(operand) = operand {
+            self.usize(operand);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], BytecodeError> {
+        let end = self.offset.checked_add(count).filter(|&end| end <= self.bytes.len());
+        let end = end.ok_or_else(|| BytecodeError("unexpected end of file".to_owned()))?;
+
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], BytecodeError> {
+        Ok(self.take(N)?.try_into().expect("took the wrong number of bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, BytecodeError> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn str(&mut self) -> Result<Rc<str>, BytecodeError> {
+        let length = self.usize()?;
+        let bytes = self.take(length)?;
+        let s =
+            std::str::from_utf8(bytes).map_err(|_| BytecodeError("invalid string".to_owned()))?;
+        Ok(s.into())
+    }
+
+    fn function(&mut self) -> Result<Function, BytecodeError> {
+        let name = self.str()?;
+        let arity = self.usize()?;
+
+        let count = self.usize()?;
+        let upvalues = (0..count)
+            .map(|_| Ok(UpvalueRef { is_local: self.u8()? != 0, index: self.usize()? }))
+            .collect::<Result<_, BytecodeError>>()?;
+        let doc = if self.u8()? != 0 { Some(self.str()?) } else { None };
+
+        let chunk = self.chunk()?;
+        Ok(Function { name, arity, upvalues, chunk, doc })
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, BytecodeError> {
+        let mut chunk = Chunk::default();
+
+        let count = self.usize()?;
+        for _ in 0..count {
+            let op = self.op()?;
+            let line = self.u32()?;
+            let column = self.u32()?;
+            let span = Span::new(self.usize()?, self.usize()?);
+            chunk.write(op, Position { line, column, span });
+        }
+
+        let count = self.usize()?;
+        for _ in 0..count {
+            let constant = match self.u8()? {
+                NUMBER => Value::Number(f64::from_le_bytes(self.array()?)),
+                STRING => Value::String(self.str()?),
+                FUNCTION => Value::Function(Rc::new(self.function()?)),
+                tag => return Err(BytecodeError(format!("unknown constant type {tag}"))),
+            };
+            chunk.add_constant(constant);
+        }
+
+        let count = self.usize()?;
+        let mut locals_in_scope = HashMap::new();
+        for _ in 0..count {
+            let offset = self.usize()?;
+            let names = self.usize()?;
+            let names = (0..names).map(|_| self.str()).collect::<Result<_, _>>()?;
+            locals_in_scope.insert(offset, names);
+        }
+        chunk.locals_in_scope = locals_in_scope;
+
+        self.validate(&chunk)?;
+        Ok(chunk)
+    }
+
+    fn op(&mut self) -> Result<Op, BytecodeError> {
+        let code = self.u8()?;
+        let op = match code {
+            1 => Op::Nil,
+            2 => Op::True,
+            3 => Op::False,
+            4 => Op::Pop,
+            15 => Op::Equal,
+            16 => Op::NotEqual,
+            17 => Op::Greater,
+            18 => Op::GreaterEqual,
+            19 => Op::Less,
+            20 => Op::LessEqual,
+            21 => Op::Add,
+            22 => Op::Subtract,
+            23 => Op::Multiply,
+            24 => Op::Divide,
+            25 => Op::Not,
+            26 => Op::Negate,
+            27 => Op::Print,
+            28 => Op::PrintLine,
+            35 => Op::CloseUpvalue,
+            36 => Op::Return,
+            41 => Op::GetIndex,
+            42 => Op::SetIndex,
+            43 => Op::Exit,
+            44 => Op::Extend,
+            45 => Op::Using,
+            46 => Op::Close,
+            _ => {
+                let operand = self.usize()?;
+                match code {
+                    0 => Op::Constant(operand),
+                    5 => Op::GetLocal(operand),
+                    6 => Op::SetLocal(operand),
+                    7 => Op::GetGlobal(operand),
+                    8 => Op::DefineGlobal(operand),
+                    9 => Op::SetGlobal(operand),
+                    10 => Op::GetUpvalue(operand),
+                    11 => Op::SetUpvalue(operand),
+                    12 => Op::GetProperty(operand),
+                    13 => Op::SetProperty(operand),
+                    14 => Op::GetSuper(operand),
+                    29 => Op::Jump(operand),
+                    30 => Op::JumpIfFalse(operand),
+                    31 => Op::Loop(operand),
+                    32 => Op::CheckCall(operand),
+                    33 => Op::Call(operand),
+                    34 => Op::Closure(operand),
+                    47 => Op::List(operand),
+                    37 => Op::Class(operand),
+                    38 => Op::Subclass(operand),
+                    39 => Op::Method(operand),
+                    40 => Op::ClassDoc(operand),
+                    _ => return Err(BytecodeError(format!("unknown instruction {code}"))),
+                }
+            }
+        };
+
+        Ok(op)
+    }
+
+    /// Check that the instructions only refer to constants and instructions
+    /// of the chunk. The VM trusts the compiler on everything else.
+    fn validate(&self, chunk: &Chunk) -> Result<(), BytecodeError> {
+        for op in &chunk.code {
+            let valid = match *op {
+                Op::Constant(n) => n < chunk.constants.len(),
+                Op::Closure(n) => matches!(chunk.constants.get(n), Some(Value::Function(_))),
+                Op::GetGlobal(n)
+                | Op::DefineGlobal(n)
+                | Op::SetGlobal(n)
+                | Op::GetProperty(n)
+                | Op::SetProperty(n)
+                | Op::GetSuper(n)
+                | Op::Class(n)
+                | Op::Subclass(n)
+                | Op::Method(n)
+                | Op::ClassDoc(n) => matches!(chunk.constants.get(n), Some(Value::String(_))),
+                Op::Jump(n) | Op::JumpIfFalse(n) | Op::Loop(n) => n < chunk.code.len(),
+                _ => true,
+            };
+
+            if !valid {
+                return Err(BytecodeError(format!("invalid instruction {op:?}")));
+            }
+        }
+
+        if chunk.code.last() != Some(&Op::Return) {
+            return Err(BytecodeError("a function doesn't end with a return".to_owned()));
+        }
+
+        Ok(())
+    }
+}
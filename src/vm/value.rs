@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use super::Vm;
+use super::chunk::Chunk;
+use crate::gc::{self, Kind, Trace};
+use crate::prelude::{InterpreterError, Key, LoxSet};
+use crate::semantics::{self, Equality, Truthiness};
+use crate::sync::{Rc, RefCell, Weak};
+use crate::{list, string_builder, weak_ref};
+
+/// A value of the virtual machine.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(Rc<str>),
+    /// A compiled function, only found in the constants of a chunk
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    BoundMethod(Rc<BoundMethod>),
+    Native(Rc<Native>),
+    /// A function or a class wrapped by `memoize`
+    Memoized(Rc<Memoized>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    Set(Rc<RefCell<LoxSet>>),
+    List(Rc<RefCell<Vec<Value>>>),
+    StringBuilder(Rc<RefCell<String>>),
+    /// A weak reference to an instance, see [`crate::weak_ref`]
+    WeakRef(Weak<Instance>),
+    /// A method of a set or a list, bound to it
+    NativeMethod(Rc<NativeMethod>),
+}
+
+impl Value {
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    /// Whether the value is true in a condition, following the truthiness
+    /// rule.
+    pub(crate) fn is_true(&self, truthiness: Truthiness) -> bool {
+        match truthiness {
+            Truthiness::Lox => self.is_truthy(),
+            Truthiness::Strict => matches!(self, Value::Boolean(true)),
+        }
+    }
+
+    /// Whether `==` is true for the two values, following the equality rule.
+    pub(crate) fn equals(&self, other: &Self, equality: Equality) -> bool {
+        match (equality, self, other) {
+            (Equality::Coercing, Self::Number(n), Self::String(s))
+            | (Equality::Coercing, Self::String(s), Self::Number(n)) => {
+                semantics::number_of(s) == Some(*n)
+            }
+            (Equality::Coercing, Self::Number(n), Self::Boolean(b))
+            | (Equality::Coercing, Self::Boolean(b), Self::Number(n)) => {
+                semantics::number_of_boolean(*b) == *n
+            }
+            _ => self == other,
+        }
+    }
+
+    /// Report the tracked object this value refers to, if any.
+    pub(crate) fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        match self {
+            Self::Closure(closure) => visit(gc::address(closure)),
+            Self::BoundMethod(bound) => visit(gc::address(bound)),
+            Self::Memoized(memoized) => visit(gc::address(memoized)),
+            Self::Class(class) => visit(gc::address(class)),
+            Self::Instance(instance) => visit(gc::address(instance)),
+            Self::List(list) => visit(gc::address(list)),
+            Self::Nil
+            | Self::Boolean(_)
+            | Self::Number(_)
+            | Self::String(_)
+            | Self::Function(_)
+            | Self::Native(_)
+            // Sets only hold plain values, and builders only text
+            | Self::Set(_)
+            | Self::StringBuilder(_)
+            // Not reporting the instance lets the collector free it
+            | Self::WeakRef(_)
+            // Methods aren't tracked, so the set or list they are bound to
+            // stays alive as long as they do
+            | Self::NativeMethod(_) => {}
+        }
+    }
+
+    /// The doc comment of a function, method or class.
+    pub fn doc(&self) -> Option<Rc<str>> {
+        match self {
+            Self::Closure(closure) => closure.function.doc.clone(),
+            Self::BoundMethod(bound) => bound.method.function.doc.clone(),
+            Self::Class(class) => class.doc.borrow().clone(),
+            _ => None,
+        }
+    }
+
+    /// This value as the key of a hash map, or `None` if it can't be one.
+    pub fn key(&self) -> Option<Key> {
+        match self {
+            Self::Nil => Some(Key::Nil),
+            Self::Boolean(b) => Some(Key::Boolean(*b)),
+            Self::Number(n) => Some(Key::number(*n)),
+            Self::String(s) => Some(Key::String(s.clone())),
+            _ => None,
+        }
+    }
+
+    /// The number of arguments of a function or a class, or `None` if this
+    /// can't be called.
+    pub fn arity(&self) -> Option<usize> {
+        match self {
+            Self::Closure(closure) => Some(closure.function.arity),
+            Self::BoundMethod(bound) => Some(bound.method.function.arity),
+            Self::Native(native) => Some(native.arity),
+            Self::Memoized(memoized) => memoized.function.arity(),
+            Self::NativeMethod(method) => method.arity(),
+            Self::Class(class) => Some(class.arity()),
+            _ => None,
+        }
+    }
+
+    /// The name of a function or a class, or `None` for natives and what
+    /// can't be called.
+    pub fn name(&self) -> Option<Rc<str>> {
+        match self {
+            Self::Closure(closure) => Some(closure.function.name.clone()),
+            Self::BoundMethod(bound) => Some(bound.method.function.name.clone()),
+            Self::Memoized(memoized) => memoized.function.name(),
+            Self::Class(class) => Some(class.name.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Nil, Self::Nil) => true,
+            (Self::Boolean(left), Self::Boolean(right)) => left == right,
+            (Self::Number(left), Self::Number(right)) => left == right,
+            (Self::String(left), Self::String(right)) => left == right,
+            (Self::Function(left), Self::Function(right)) => Rc::ptr_eq(left, right),
+            (Self::Closure(left), Self::Closure(right)) => Rc::ptr_eq(left, right),
+            (Self::BoundMethod(left), Self::BoundMethod(right)) => Rc::ptr_eq(left, right),
+            (Self::Native(left), Self::Native(right)) => Rc::ptr_eq(left, right),
+            (Self::Memoized(left), Self::Memoized(right)) => Rc::ptr_eq(left, right),
+            (Self::Class(left), Self::Class(right)) => Rc::ptr_eq(left, right),
+            (Self::Instance(left), Self::Instance(right)) => Rc::ptr_eq(left, right),
+            (Self::Set(left), Self::Set(right)) => Rc::ptr_eq(left, right),
+            (Self::List(left), Self::List(right)) => Rc::ptr_eq(left, right),
+            (Self::StringBuilder(left), Self::StringBuilder(right)) => Rc::ptr_eq(left, right),
+            (Self::WeakRef(left), Self::WeakRef(right)) => Weak::ptr_eq(left, right),
+            (Self::NativeMethod(left), Self::NativeMethod(right)) => Rc::ptr_eq(left, right),
+            _ => false,
+        }
+    }
+}
+
+impl From<Key> for Value {
+    fn from(key: Key) -> Self {
+        match key {
+            Key::Nil => Self::Nil,
+            Key::Boolean(b) => Self::Boolean(b),
+            Key::Number(bits) => Self::Number(f64::from_bits(bits)),
+            Key::String(s) => Self::String(s),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Nil => write!(f, "nil"),
+            Self::Boolean(b) => write!(f, "{b}"),
+            Self::Number(n) => write!(f, "{n}"),
+            Self::String(s) => write!(f, "{s}"),
+            Self::Function(function) => write!(f, "<fn {}>", function.name),
+            Self::Closure(closure) => write!(f, "<fn {}>", closure.function.name),
+            Self::BoundMethod(bound) => write!(f, "<fn {}>", bound.method.function.name),
+            Self::Native(_) => write!(f, "<native fn>"),
+            Self::Memoized(memoized) => write!(f, "{}", memoized.function),
+            Self::Class(class) => write!(f, "{}", class.name),
+            Self::Instance(instance) => write!(f, "{} instance", instance.class.name),
+            Self::Set(set) => write!(f, "{}", set.borrow()),
+            Self::List(l) => write!(f, "{}", list::display(l)),
+            Self::StringBuilder(b) => write!(f, "{}", b.borrow()),
+            Self::WeakRef(_) => write!(f, "<weak ref>"),
+            Self::NativeMethod(_) => write!(f, "<native fn>"),
+        }
+    }
+}
+
+/// How a closure finds a captured variable when it is created: in a local
+/// slot of the enclosing function, or in an upvalue of the enclosing closure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpvalueRef {
+    pub is_local: bool,
+    pub index: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Function {
+    pub name: Rc<str>,
+    pub arity: usize,
+    pub upvalues: Vec<UpvalueRef>,
+    pub chunk: Chunk,
+    /// The doc comment above the declaration
+    pub doc: Option<Rc<str>>,
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+/// A variable captured by a closure. It points to the stack while the
+/// variable is in scope, and holds the value itself after that.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>,
+}
+
+#[derive(Debug)]
+pub struct Native {
+    pub arity: usize,
+    pub function: fn(&mut Vm, &[Value]) -> Result<Value, InterpreterError>,
+}
+
+#[derive(Debug)]
+pub struct NativeMethod {
+    /// A set or a list
+    pub receiver: Value,
+    pub name: Rc<str>,
+}
+
+impl NativeMethod {
+    /// The method of a set, a list, a builder or a weak reference with this
+    /// name, if it has one.
+    pub fn new(receiver: Value, name: Rc<str>) -> Option<Self> {
+        let method = Self { receiver, name };
+        method.arity().map(|_| method)
+    }
+
+    pub fn arity(&self) -> Option<usize> {
+        match &self.receiver {
+            Value::Set(_) => LoxSet::arity(&self.name),
+            Value::List(_) => list::arity(&self.name),
+            Value::StringBuilder(_) => string_builder::arity(&self.name),
+            Value::WeakRef(_) => weak_ref::arity(&self.name),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Memoized {
+    pub function: Value,
+    /// The results of the calls, by their arguments
+    pub cache: RefCell<HashMap<Vec<Key>, Value>>,
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: Rc<str>,
+    pub methods: RefCell<HashMap<Rc<str>, Rc<Closure>>>,
+    pub superclass: Option<Rc<Class>>,
+    /// The doc comment above the declaration, set by [`Op::ClassDoc`]
+    ///
+    /// [`Op::ClassDoc`]: super::chunk::Op::ClassDoc
+    pub doc: RefCell<Option<Rc<str>>>,
+}
+
+impl Class {
+    pub fn new(name: Rc<str>, superclass: Option<Rc<Class>>) -> Self {
+        Self { name, methods: RefCell::default(), superclass, doc: RefCell::default() }
+    }
+
+    pub fn find_method(&self, name: &str) -> Option<Rc<Closure>> {
+        match (self.methods.borrow().get(name), &self.superclass) {
+            (Some(method), _) => Some(method.clone()),
+            (None, Some(superclass)) => superclass.find_method(name),
+            (None, None) => None,
+        }
+    }
+
+    /// Names of the methods of this class and its superclasses.
+    pub fn method_names(&self) -> Vec<String> {
+        let mut names =
+            self.methods.borrow().keys().map(|name| name.to_string()).collect::<Vec<_>>();
+        if let Some(superclass) = &self.superclass {
+            names.extend(superclass.method_names());
+        }
+        names
+    }
+
+    pub fn arity(&self) -> usize {
+        self.find_method("init").map_or(0, |init| init.function.arity)
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: RefCell<HashMap<Rc<str>, Value>>,
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Self {
+        Self { class, fields: RefCell::default() }
+    }
+
+    /// Names of the fields and methods of this instance.
+    pub fn property_names(&self) -> Vec<String> {
+        let mut names =
+            self.fields.borrow().keys().map(|name| name.to_string()).collect::<Vec<_>>();
+        names.extend(self.class.method_names());
+        names
+    }
+}
+
+impl Trace for Closure {
+    fn kind(&self) -> Kind {
+        Kind::Function
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        self.upvalues.iter().for_each(|upvalue| visit(gc::address(upvalue)));
+    }
+
+    /// The upvalues of a closure can't be changed, but they are cleared
+    /// instead.
+    fn clear(&self) {}
+}
+
+impl Trace for RefCell<Upvalue> {
+    fn kind(&self) -> Kind {
+        Kind::Upvalue
+    }
+
+    fn strings(&self) -> usize {
+        matches!(self.try_borrow().as_deref(), Ok(Upvalue::Closed(Value::String(_)))) as usize
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Ok(Upvalue::Closed(value)) = self.try_borrow().as_deref() {
+            value.trace(visit);
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut upvalue) = self.try_borrow_mut() {
+            if let Upvalue::Closed(value) = &mut *upvalue {
+                *value = Value::Nil;
+            }
+        }
+    }
+}
+
+impl Trace for BoundMethod {
+    fn kind(&self) -> Kind {
+        Kind::Function
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        self.receiver.trace(visit);
+        visit(gc::address(&self.method));
+    }
+
+    /// A bound method can only be part of a cycle through its receiver or its
+    /// closure, which are cleared instead.
+    fn clear(&self) {}
+}
+
+impl Trace for Memoized {
+    fn kind(&self) -> Kind {
+        Kind::Function
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        self.function.trace(visit);
+        if let Ok(cache) = self.cache.try_borrow() {
+            cache.values().for_each(|result| result.trace(visit));
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut cache) = self.cache.try_borrow_mut() {
+            cache.clear();
+        }
+    }
+}
+
+impl Trace for RefCell<Vec<Value>> {
+    fn kind(&self) -> Kind {
+        Kind::List
+    }
+
+    fn strings(&self) -> usize {
+        let Ok(values) = self.try_borrow() else { return 0 };
+        values.iter().filter(|value| matches!(value, Value::String(_))).count()
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Ok(values) = self.try_borrow() {
+            values.iter().for_each(|value| value.trace(visit));
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut values) = self.try_borrow_mut() {
+            values.clear();
+        }
+    }
+}
+
+impl Trace for Class {
+    fn kind(&self) -> Kind {
+        Kind::Class
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Ok(methods) = self.methods.try_borrow() {
+            methods.values().for_each(|method| visit(gc::address(method)));
+        }
+        if let Some(superclass) = &self.superclass {
+            visit(gc::address(superclass));
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut methods) = self.methods.try_borrow_mut() {
+            methods.clear();
+        }
+    }
+}
+
+impl Trace for Instance {
+    fn kind(&self) -> Kind {
+        Kind::Instance
+    }
+
+    fn strings(&self) -> usize {
+        let Ok(fields) = self.fields.try_borrow() else { return 0 };
+        fields.values().filter(|value| matches!(value, Value::String(_))).count()
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        visit(gc::address(&self.class));
+        if let Ok(fields) = self.fields.try_borrow() {
+            fields.values().for_each(|value| value.trace(visit));
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut fields) = self.fields.try_borrow_mut() {
+            fields.clear();
+        }
+    }
+}
@@ -0,0 +1,24 @@
+//! Weak references to instances, made by the `WeakRef(instance)` native.
+//!
+//! A weak reference doesn't keep its instance alive: once nothing else refers
+//! to the instance, reference counting or the next collection of a cycle
+//! frees it. This lets programs write caches that don't leak.
+//!
+//! - `get()` returns the instance, or nil once it has been freed.
+//!
+//! A weak reference prints as `<weak ref>`, and two weak references are equal
+//! if they refer to the same instance.
+
+/// The methods of weak references, with their number of arguments.
+const METHODS: [(&str, usize); 1] = [("get", 0)];
+
+/// The number of arguments of the method with this name, if weak references
+/// have one.
+pub(crate) fn arity(method: &str) -> Option<usize> {
+    METHODS.iter().find(|(name, _)| *name == method).map(|(_, arity)| *arity)
+}
+
+/// The names of the methods, for the suggestions of the errors.
+pub(crate) fn method_names() -> Vec<String> {
+    METHODS.iter().map(|(name, _)| name.to_string()).collect()
+}
@@ -0,0 +1,518 @@
+use assert_cmd::Command;
+
+fn lox() -> Command {
+    Command::cargo_bin("lox").unwrap()
+}
+
+#[test]
+fn reads_program_from_stdin() {
+    let output = lox().arg("-").write_stdin("print 1 + 2;\nprint \"done\";").output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\ndone\n");
+}
+
+#[test]
+fn stdin_program_errors_set_exit_code() {
+    let output = lox().arg("-").write_stdin("print nil.field;").output().unwrap();
+
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[line 1] Only instances have properties.\n"
+    );
+}
+
+#[test]
+fn stdin_program_is_scanned_as_it_is_read() {
+    let output = lox().arg("-").write_stdin(&b"print 1;\nprint \"\xff\";"[..]).output().unwrap();
+
+    // Nothing runs, even the line before the invalid UTF-8
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("[line 2] Error: Could not read the source: invalid UTF-8.\n"));
+}
+
+#[test]
+fn stdin_program_diagnostics_show_the_source_line() {
+    let output = lox()
+        .args(["--diagnostics=pretty", "-"])
+        .write_stdin("print 1;\nprint nil.field;")
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(70));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("2 | print nil.field;\n"));
+}
+
+/// The interactive prompt, with a home of its own so it can't touch the
+/// history of the user running the tests.
+fn prompt(name: &str) -> (Command, std::path::PathBuf) {
+    let home = std::env::temp_dir().join(format!("lox-cli-{}-{name}", std::process::id()));
+    let mut command = lox();
+    command.env("HOME", &home).env("LOX_HISTORY", home.join(".lox_history"));
+    (command, home)
+}
+
+#[test]
+fn prompt_continues_an_unterminated_string() {
+    let (mut command, _) = prompt("string");
+    let output = command.write_stdin("print \"one\ntwo\";\n").output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "one\ntwo\n");
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[test]
+fn prompt_runs_the_input_left_at_the_end() {
+    let (mut command, _) = prompt("eof");
+    let output = command.write_stdin("print 1;\nprint \"open\n").output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n");
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "[line 2] Error: Unterminated string.\n");
+}
+
+#[test]
+fn piped_prompt_keeps_no_history() {
+    let (mut command, home) = prompt("history");
+    let output = command.write_stdin("print 1;\n").output().unwrap();
+
+    assert!(output.status.success());
+    assert!(!home.join(".lox_history").exists());
+}
+
+#[test]
+fn evaluates_inline_code() {
+    for flag in ["-e", "--eval"] {
+        let output = lox().args([flag, "print 1 + 2;"]).output().unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n");
+    }
+}
+
+#[test]
+fn eval_requires_code() {
+    let output = lox().arg("-e").output().unwrap();
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn dumps_tokens() {
+    let output = lox().args(["--dump-tokens", "-e", "print\n\"hi\";"]).output().unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines =
+        stdout.lines().map(|l| l.split_whitespace().collect::<Vec<_>>()).collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        vec![
+            vec!["1:1", "Print", "print"],
+            vec!["2:1", "StringLiteral", "\"hi\"", "String(\"hi\")"],
+            vec!["2:5", "Semicolon", ";"],
+            vec!["2:6", "EOF"],
+        ]
+    );
+}
+
+#[test]
+fn dump_tokens_does_not_run_the_program() {
+    let output = lox().args(["--dump-tokens", "-e", "print nil.field;"]).output().unwrap();
+    assert!(output.status.success());
+}
+
+#[test]
+fn dumps_ast() {
+    let output = lox().args(["--dump-ast", "-e", "var a = 1; { print a + 2; }"]).output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "(var a 1)\n(block\n  (print (+ a 2)))\n"
+    );
+}
+
+#[test]
+fn pretty_diagnostics_show_the_source_line() {
+    let output =
+        lox().args(["--diagnostics=pretty", "-e", "var a = 1;\nprint a +;"]).output().unwrap();
+
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[line 2] Error[E2001] at ';': Expect expression.\n  |\n2 | print a +;\n  |          ^\n  = note: Expected an expression, found ';'.\n"
+    );
+}
+
+#[test]
+fn json_diagnostics() {
+    let output = lox().args(["--diagnostics=json", "-e", "print nil.field;"]).output().unwrap();
+
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        r#"{"file":null,"line":1,"column":11,"code":"E4004","severity":"error","phase":"runtime","location":null,"message":"Only instances have properties."}"#.to_owned()
+            + "\n"
+    );
+}
+
+#[test]
+fn runs_compiled_programs() {
+    let output_file = std::env::temp_dir().join(format!("lox-cli-{}.loxc", std::process::id()));
+    let source = "fun add(a, b) { return a + b; }\nprint add(1, 2);\nprint nil.field;";
+
+    let output = lox().args(["compile", "-e", source, "-o"]).arg(&output_file).output().unwrap();
+    assert!(output.status.success());
+
+    let output = lox().arg(&output_file).output().unwrap();
+    std::fs::remove_file(&output_file).unwrap();
+
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[line 3] Only instances have properties.\n"
+    );
+}
+
+#[test]
+fn top_level_return_sets_the_exit_code() {
+    let output = lox().args(["-e", "print 1; return 3; print 2;"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(65));
+
+    for backend in [&[][..], &["--vm"]] {
+        let output = lox()
+            .args(backend)
+            .args(["--top-level-return", "-e", "print 1; return 3; print 2;"])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n");
+    }
+}
+
+#[test]
+fn rejects_bytecode_of_other_versions() {
+    let file = std::env::temp_dir().join(format!("lox-cli-{}-v99.loxc", std::process::id()));
+    std::fs::write(&file, b"LOXC\x63\x00").unwrap();
+
+    let output = lox().arg(&file).output().unwrap();
+    std::fs::remove_file(&file).unwrap();
+
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Invalid bytecode file: unsupported version 99, expected 2\n"
+    );
+}
+
+#[test]
+fn prints_profile() {
+    let output = lox().args(["--profile", "-e", "fun f() {} f(); f();"]).output().unwrap();
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let lines = stderr.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with("calls   total (ms)    self (ms)  function"));
+    assert!(lines[1].trim_start().starts_with("2 "));
+    assert!(lines[1].ends_with("<fn f> (line 1)"));
+}
+
+#[test]
+fn profile_is_not_supported_on_the_vm() {
+    let output = lox().args(["--profile", "--vm", "-e", "print 1;"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn emits_the_ast_as_json() {
+    let output = lox().args(["--emit-ast=json", "-e", "print nil;"]).output().unwrap();
+
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "statements": [{
+                "kind": "Print",
+                "line": 1,
+                "end_line": 1,
+                "exprs": [{ "kind": "Literal", "value": null }],
+            }],
+        })
+    );
+}
+
+#[test]
+fn prints_coverage() {
+    let output = lox().args(["--coverage", "-e", "if (false) {\n  print 1;\n}"]).output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "<script>: 1 of 2 lines ran (50.0%)\n      hits  line\n         1  1\n         0  2\n"
+    );
+
+    let output = lox().args(["--coverage=lcov", "--vm", "-e", "print 1;"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn traces_statements() {
+    let source = "var a = 1;\n{\n  print a;\n}";
+    let output = lox().args(["--trace=depth", "-e", source]).output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[line 1, depth 0] (var a 1)\n[line 2, depth 0] (block\n[line 3, depth 1] (print a)\n"
+    );
+}
+
+#[test]
+fn debugs_scripts() {
+    let file = std::env::temp_dir().join(format!("lox-cli-{}-debug.lox", std::process::id()));
+    let source = "fun add(a, b) {\n  var sum = a + b;\n  return sum;\n}\nprint add(1, 2);\n";
+    std::fs::write(&file, source).unwrap();
+
+    let commands = "break 2\ncontinue\nbacktrace\nprint b\nnext\ncontinue\n";
+    let output = lox().arg("debug").arg(&file).write_stdin(commands).output().unwrap();
+    std::fs::remove_file(&file).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Stopped at line 1: fun add(a, b) {\n\
+         (debug) Breakpoint at line 2.\n\
+         (debug) Stopped at line 2: var sum = a + b;\n\
+         (debug) #0 <fn add> at line 2\n\
+         #1 <script> at line 5\n\
+         (debug) b = 2\n\
+         (debug) Stopped at line 3: return sum;\n\
+         (debug) 3\n"
+    );
+}
+
+#[test]
+fn quitting_the_debugger_stops_the_script() {
+    let file = std::env::temp_dir().join(format!("lox-cli-{}-quit.lox", std::process::id()));
+    std::fs::write(&file, "print 1;\nprint 2;\n").unwrap();
+
+    let output = lox().arg("debug").arg(&file).write_stdin("step\nquit\n").output().unwrap();
+    std::fs::remove_file(&file).unwrap();
+
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Stopped at line 1: print 1;\n(debug) 1\nStopped at line 2: print 2;\n(debug) "
+    );
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "[line 2] Interrupted.\n");
+}
+
+/// Frame the requests of a DAP client.
+fn dap_requests(requests: &[serde_json::Value]) -> String {
+    let requests = requests.iter().enumerate().map(|(i, request)| {
+        let mut request = request.clone();
+        request["seq"] = serde_json::json!(i + 1);
+        request["type"] = serde_json::json!("request");
+        let request = request.to_string();
+        format!("Content-Length: {}\r\n\r\n{request}", request.len())
+    });
+    requests.collect()
+}
+
+/// The messages sent by a DAP server, without their headers.
+fn dap_messages(output: &str) -> Vec<serde_json::Value> {
+    let messages = output.split("Content-Length: ").skip(1);
+    let messages = messages.map(|message| message.split_once("\r\n\r\n").unwrap().1);
+    messages.map(|message| serde_json::from_str(message).unwrap()).collect()
+}
+
+#[test]
+fn serves_the_debug_adapter_protocol() {
+    use serde_json::json;
+
+    let file = std::env::temp_dir().join(format!("lox-cli-{}-dap.lox", std::process::id()));
+    let source = "fun add(a, b) {\n  var sum = a + b;\n  return sum;\n}\nprint add(1, 2);\n";
+    std::fs::write(&file, source).unwrap();
+    let path = file.to_str().unwrap();
+
+    let input = dap_requests(&[
+        json!({ "command": "initialize", "arguments": { "adapterID": "lox" } }),
+        json!({ "command": "launch", "arguments": { "program": path } }),
+        json!({
+            "command": "setBreakpoints",
+            "arguments": { "source": { "path": path }, "breakpoints": [{ "line": 2 }] }
+        }),
+        json!({ "command": "configurationDone" }),
+        json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+        json!({ "command": "scopes", "arguments": { "frameId": 1 } }),
+        json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+        json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+    ]);
+    let output = lox().arg("dap").write_stdin(input).output().unwrap();
+    std::fs::remove_file(&file).unwrap();
+
+    assert!(output.status.success());
+    let messages = dap_messages(&String::from_utf8(output.stdout).unwrap());
+    let kinds = messages
+        .iter()
+        .map(|m| m["command"].as_str().or(m["event"].as_str()).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [
+            "initialize",
+            "initialized",
+            "launch",
+            "setBreakpoints",
+            "configurationDone",
+            "stopped",
+            "stackTrace",
+            "scopes",
+            "variables",
+            "continue",
+            "output",
+            "exited",
+            "terminated"
+        ]
+    );
+
+    assert_eq!(messages[5]["body"]["reason"], "breakpoint");
+    let frames = &messages[6]["body"]["stackFrames"];
+    assert_eq!(frames[0]["name"], "<fn add>");
+    assert_eq!(frames[0]["line"], 2);
+    assert_eq!(frames[1]["line"], 5);
+    assert_eq!(
+        messages[8]["body"]["variables"],
+        json!([
+            { "name": "a", "value": "1", "variablesReference": 0 },
+            { "name": "b", "value": "2", "variablesReference": 0 }
+        ])
+    );
+    assert_eq!(messages[10]["body"]["output"], "3\n");
+    assert_eq!(messages[11]["body"]["exitCode"], 0);
+}
+
+#[test]
+fn fmt_checks_and_formats_code() {
+    let output = lox().args(["fmt", "--check", "-e", "print  1;"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "The code is not formatted.\n");
+
+    let output = lox().args(["fmt", "--check", "-e", "print 1;\n"]).output().unwrap();
+    assert!(output.status.success());
+
+    let output = lox().args(["fmt", "-"]).write_stdin("var a=1;").output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "var a = 1;\n");
+}
+
+#[test]
+fn lint_prints_warnings_and_fails() {
+    let output = lox().args(["lint", "-e", "{ var a = 1; }"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[line 1] Warning at 'a': Local variable is never read.\n"
+    );
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "Found 1 warning.\n");
+
+    let output = lox().args(["lint", "--allow=unused", "-e", "{ var a = 1; }"]).output().unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn prints_warnings_unless_disabled() {
+    let output = lox().args(["-e", "{ var a = 1; }"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[line 1] Warning at 'a': Local variable is never read.\n"
+    );
+
+    let output = lox().args(["--no-warnings", "-e", "{ var a = 1; }"]).output().unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn runs_the_test_files_of_a_directory() {
+    let dir = std::env::temp_dir().join(format!("lox-cli-{}-tests", std::process::id()));
+    std::fs::create_dir_all(dir.join("nested")).unwrap();
+    std::fs::write(
+        dir.join("math_test.lox"),
+        "fun adds() { assertEq(1 + 2, 3); }\ntest(\"adds\", adds);",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("nested").join("string_test.lox"),
+        "fun joins() { assertEq(\"a\" + \"b\", \"ba\"); }\ntest(\"joins\", joins);",
+    )
+    .unwrap();
+    std::fs::write(dir.join("helper.lox"), "print \"not a test\";").unwrap();
+
+    for backend in [None, Some("--vm")] {
+        let output = lox().arg("test").args(backend).arg(&dir).output().unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        let expected = format!(
+            "PASS {math}: adds\nFAIL {string}: joins\n  [line 1] Expected ba but got ab.\n1 passed, 1 failed.\n",
+            math = dir.join("math_test.lox").display(),
+            string = dir.join("nested").join("string_test.lox").display(),
+        );
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+        assert_eq!(String::from_utf8(output.stderr).unwrap(), "1 test failed.\n");
+    }
+
+    let output = lox().arg("test").arg(dir.join("math_test.lox")).output().unwrap();
+    assert!(output.status.success());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn benchmarks_compare_with_a_baseline() {
+    let dir = std::env::temp_dir().join(format!("lox-cli-{}-bench", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("loop.lox"), "for (var i = 0; i < 100; i = i + 1) print i;").unwrap();
+    let baseline = dir.join("baseline.json");
+
+    let output = lox()
+        .args(["bench", "--runs=2"])
+        .arg(format!("--save={}", baseline.display()))
+        .arg(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("loop.lox "), "{stdout}");
+    assert!(stdout.contains(" ms ± "));
+
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&baseline).unwrap()).unwrap();
+    assert!(json["loop.lox"]["mean"].as_f64().unwrap() > 0.0);
+
+    // A baseline that took no time at all makes any run a regression
+    std::fs::write(&baseline, r#"{"loop.lox": {"mean": 0.000001, "stddev": 0}}"#).unwrap();
+    let output = lox()
+        .args(["bench", "--runs=1"])
+        .arg(format!("--baseline={}", baseline.display()))
+        .arg(dir.join("loop.lox"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stdout).unwrap().trim_end().ends_with("REGRESSED"));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "1 benchmark got slower than its baseline.\n"
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
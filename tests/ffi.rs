@@ -0,0 +1,70 @@
+#![cfg(feature = "lox-ffi")]
+
+use std::ffi::{CStr, c_int, c_void};
+
+use lox::ffi::*;
+
+extern "C" fn add(
+    data: *mut c_void,
+    arguments: *const LoxValue,
+    count: usize,
+    result: *mut LoxValue,
+) -> c_int {
+    let calls = unsafe { &mut *(data as *mut u32) };
+    *calls += 1;
+
+    let arguments = unsafe { std::slice::from_raw_parts(arguments, count) };
+    if arguments.iter().any(|a| a.value_type != LoxValueType::Number) {
+        return 1;
+    }
+    unsafe {
+        *result = LoxValue { number: arguments[0].number + arguments[1].number, ..arguments[0] };
+    }
+    0
+}
+
+#[test]
+fn hosts_run_programs_with_their_natives() {
+    let mut calls = 0u32;
+    unsafe {
+        let lox = lox_new();
+        let data = &mut calls as *mut u32 as *mut c_void;
+        assert_eq!(lox_register_native(lox, c"add".as_ptr(), 2, add, data), 0);
+
+        assert_eq!(lox_run(lox, c"var total = add(1, 2);".as_ptr()), 0);
+        assert!(lox_last_error(lox).is_null());
+        let mut total = 0.0;
+        assert_eq!(lox_get_number(lox, c"total".as_ptr(), &mut total), 0);
+        assert_eq!(total, 3.0);
+        assert_eq!(lox_get_number(lox, c"add".as_ptr(), &mut total), -1);
+
+        assert_eq!(lox_run(lox, c"add(1, \"2\");".as_ptr()), 70);
+        let error = CStr::from_ptr(lox_last_error(lox)).to_str().unwrap();
+        assert_eq!(error, "[line 1] Native function 'add' failed with status 1.");
+        assert_eq!(lox_run(lox, c"var;".as_ptr()), 65);
+
+        lox_free(lox);
+    }
+    assert_eq!(calls, 2);
+}
+
+#[test]
+fn null_pointers_are_errors() {
+    let null = std::ptr::null_mut();
+    unsafe {
+        assert_eq!(lox_run(null, c"print 1;".as_ptr()), -1);
+        assert!(lox_last_error(null).is_null());
+        let mut value = 0.0;
+        assert_eq!(lox_get_number(null, c"x".as_ptr(), &mut value), -1);
+        let data = std::ptr::null_mut();
+        assert_eq!(lox_register_native(null, c"add".as_ptr(), 2, add, data), -1);
+
+        let lox = lox_new();
+        assert_eq!(lox_run(lox, std::ptr::null()), -1);
+        assert_eq!(lox_run(lox, c"var x = 1;".as_ptr()), 0);
+        assert_eq!(lox_get_number(lox, std::ptr::null(), &mut value), -1);
+        assert_eq!(lox_get_number(lox, c"x".as_ptr(), std::ptr::null_mut()), -1);
+        assert_eq!(lox_register_native(lox, std::ptr::null(), 2, add, data), -1);
+        lox_free(lox);
+    }
+}
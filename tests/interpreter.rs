@@ -1,17 +1,16 @@
 use lox::prelude::*;
+use lox::sync::Rc;
 
-fn make_expression(source: &'static str) -> Expr {
+fn make_expression(source: &'static str) -> (Rc<Ast>, ExprId) {
     let mut scanner = Scanner::new(source);
     let tokens = scanner.scan_tokens().expect("failed to scan for the tokens");
     let mut parser = Parser::new(tokens);
-    let stmt = parser
-        .parse()
-        .expect("failed to parse the source")
-        .pop()
-        .expect("no statement was created");
-
-    match stmt {
-        Stmt::Expression { expr } => expr,
+    let (ast, errors) = parser.parse();
+    assert!(errors.is_empty(), "failed to parse the source");
+    let stmt = *ast.statements().last().expect("no statement was created");
+
+    match ast[stmt] {
+        Stmt::Expression { expr } => (Rc::new(ast), expr),
         _ => panic!("statement is not an expression"),
     }
 }
@@ -19,8 +18,8 @@ fn make_expression(source: &'static str) -> Expr {
 macro_rules! assert_literal {
     ($source:literal, $expected:expr, $lit_type:path) => {
         let mut ipr = Interpreter::new();
-        let expr = make_expression($source);
-        let res = ipr.evaluate_expr(&expr);
+        let (ast, expr) = make_expression($source);
+        let res = ipr.evaluate_expr(&ast, expr);
         assert!(res.is_ok());
         assert_eq!(res.unwrap(), $lit_type($expected));
     };
@@ -45,6 +44,7 @@ macro_rules! assert_boolean {
 }
 
 #[test]
+#[allow(clippy::approx_constant)]
 fn unary_minus() {
     assert_number!("-3.14;", -3.14);
 }
@@ -62,7 +62,7 @@ fn binary_plus_numbers() {
 
 #[test]
 fn binary_plus_strings() {
-    assert_string!(r#" "Hello " + "World!"; "#, "Hello World!".to_string());
+    assert_string!(r#" "Hello " + "World!"; "#, "Hello World!".into());
 }
 
 #[test]
@@ -115,3 +115,118 @@ fn binary_bang_equal() {
     assert_boolean!("10 != 20;", true);
     assert_boolean!("10 != 10;", false);
 }
+
+#[test]
+fn resolution_survives_cloning_the_tree() {
+    let source = "var x = 1; { var y = 2; { fun f() { return x + y; } if (f() != 3) undefined; } }";
+    let tokens = Scanner::new(source).scan_tokens().expect("failed to scan for the tokens");
+    let (ast, errors) = Parser::new(tokens).parse();
+    assert!(errors.is_empty(), "failed to parse the source");
+
+    Resolver::new().resolve(&ast).expect("failed to resolve");
+
+    // The copies live at other addresses, but keep the resolved bindings
+    let copy = Rc::new(ast.clone());
+    drop(ast);
+    assert!(Interpreter::new().interpret(&copy).is_ok());
+}
+
+#[test]
+fn objects_are_three_words() {
+    // Strings are shared instead of copied, so an object is no larger than a
+    // string pointer and its tag
+    assert_eq!(std::mem::size_of::<Object>(), 3 * std::mem::size_of::<usize>());
+}
+
+#[test]
+fn deeply_nested_statements_dont_overflow_the_stack() {
+    // Deeper than the parser allows, and than a small stack could recurse
+    const DEPTH: usize = 100_000;
+
+    let run = || {
+        let token = |token_type, lexeme| Token::new(token_type, lexeme, None, 1);
+        let literal = |ast: &mut Ast, value| ast.add_expr(Expr::Literal { value });
+        let mut ast = Ast::new();
+        let location = StmtLocation::default();
+
+        // var reached = false;
+        let initializer = literal(&mut ast, Literal::Boolean(false));
+        let name = token(TokenType::Identifier, "reached");
+        let declaration = ast
+            .add_stmt(Stmt::Var { name: name.clone(), initializer: Some(initializer) }, location);
+
+        // while (true) { if (true) { ... { reached = true; break; } ... } }
+        let value = literal(&mut ast, Literal::Boolean(true));
+        let assignment = ast.add_expr(Expr::Assignment { binding: Binding::new(), name, value });
+        let statements = vec![
+            ast.add_stmt(Stmt::Expression { expr: assignment }, location),
+            ast.add_stmt(Stmt::Break { token: token(TokenType::Break, "break") }, location),
+        ];
+        let mut nested = ast.add_stmt(Stmt::Block { statements }, location);
+        for depth in 0..DEPTH {
+            let stmt = if depth % 2 == 0 {
+                let condition = literal(&mut ast, Literal::Boolean(true));
+                Stmt::If { condition, then_branch: nested, else_branch: None }
+            } else {
+                Stmt::Block { statements: vec![nested] }
+            };
+            nested = ast.add_stmt(stmt, location);
+        }
+        let condition = literal(&mut ast, Literal::Boolean(true));
+        let keyword = token(TokenType::While, "while");
+        let loop_stmt = ast.add_stmt(
+            Stmt::While { keyword, condition, body: nested, else_branch: None },
+            location,
+        );
+
+        ast.push_statement(declaration);
+        ast.push_statement(loop_stmt);
+        let mut interpreter = Interpreter::new();
+        assert!(interpreter.interpret(&Rc::new(ast)).is_ok());
+        assert_eq!(interpreter.globals.borrow().lookup("reached"), Some(Object::Boolean(true)));
+    };
+
+    let thread = std::thread::Builder::new().stack_size(256 * 1024).spawn(run).unwrap();
+    thread.join().expect("the interpreter overflowed the stack");
+}
+
+/// A native function that fails like a host function reading a missing file.
+#[derive(Debug)]
+struct ReadFile;
+
+impl Callable for ReadFile {
+    fn arity(&self) -> usize {
+        0
+    }
+
+    fn call(&self, _: &mut Interpreter, _: &[Object]) -> Result<Object, RuntimeError> {
+        let error = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        Err(RuntimeError::from_error(DiagnosticCode::NativeFailed, error))
+    }
+}
+
+impl std::fmt::Display for ReadFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+#[test]
+fn runtime_errors_keep_their_cause() {
+    let tokens = Scanner::new("\nreadFile();").scan_tokens().unwrap();
+    let (ast, errors) = Parser::new(tokens).parse();
+    assert!(errors.is_empty(), "failed to parse the source");
+
+    let mut interpreter = Interpreter::new();
+    interpreter.globals.borrow_mut().define("readFile", Object::Callable(Rc::new(ReadFile)));
+    let mut errors = interpreter.interpret(&Rc::new(ast)).unwrap_err();
+    let error = errors.remove(0);
+    assert_eq!(error.to_string(), "[line 2] no such file");
+
+    // The cause is the source of the error, also once it becomes an
+    // `anyhow::Error`
+    let error = anyhow::Error::from(error);
+    let cause = error.chain().nth(1).expect("the error has no source");
+    let cause = cause.downcast_ref::<std::io::Error>().expect("the source is not the I/O error");
+    assert_eq!(cause.kind(), std::io::ErrorKind::NotFound);
+}
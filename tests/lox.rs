@@ -0,0 +1,1068 @@
+use lox::prelude::{AstJson, AstPrinter, Capability, DiagnosticCode, LoxError, Object};
+use lox::{Backend, Lox};
+
+#[test]
+fn run_keeps_globals_between_calls() {
+    let mut lox = Lox::new();
+    lox.run("var a = 1; fun inc(n) { return n + 1; }").expect("first run failed");
+    lox.run("a = inc(a);").expect("second run failed");
+    lox.run("if (a != 2) undefined_name;").expect("globals were not kept");
+}
+
+#[test]
+fn run_reports_errors() {
+    let mut lox = Lox::new();
+    let err = lox.run("print undefined_name;").unwrap_err();
+    assert_eq!(format!("{err}"), "[line 1] Undefined variable 'undefined_name'.");
+
+    let err = lox.run("print 1 +;").unwrap_err();
+    assert_eq!(format!("{err}"), "[line 1] Error at ';': Expect expression.");
+}
+
+#[test]
+fn runs_can_capture_their_output() {
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lox = Lox::new().with_backend(backend);
+        let output = lox.run_capture("print 1; print \"two\";");
+        assert_eq!(output.stdout, "1\ntwo\n");
+        assert_eq!(output.stderr, "");
+        assert!(output.result.is_ok());
+
+        let output = lox.run_capture("print 3; print nil.field;");
+        assert_eq!(output.stdout, "3\n");
+        assert_eq!(output.stderr, "[line 1] Only instances have properties.\n");
+        assert!(output.result.is_err());
+    }
+}
+
+#[test]
+fn errors_are_classified() {
+    let mut lox = Lox::new();
+
+    let err = lox.run("print 1 +;").unwrap_err();
+    assert_eq!(err.downcast_ref::<LoxError>().map(LoxError::exit_code), Some(65));
+
+    let err = lox.run("print nil.field;").unwrap_err();
+    assert_eq!(err.downcast_ref::<LoxError>().map(LoxError::exit_code), Some(70));
+}
+
+#[test]
+fn diagnostics_have_codes() {
+    let codes = |source: &str| {
+        let err = Lox::new().run(source).unwrap_err();
+        let err = err.downcast::<LoxError>().unwrap();
+        err.diagnostics().iter().map(|d| d.code.as_str()).collect::<Vec<_>>()
+    };
+
+    assert_eq!(codes("\"abc"), ["E1001"]);
+    assert_eq!(codes("1 = 2;"), ["E2003"]);
+    assert_eq!(codes("return 1;"), ["E3002"]);
+    assert_eq!(codes("{ fun f() {} f(1); }"), ["E3009"]);
+    assert_eq!(codes("print -nil;"), ["E4003"]);
+    assert_eq!(DiagnosticCode::UnusedVariable.as_str(), "W0001");
+}
+
+#[test]
+fn unused_locals_only_fail_with_warnings_as_errors() {
+    let mut lox = Lox::new();
+    lox.run("{ var a = 1; }").unwrap();
+    assert_eq!(
+        lox.render_warnings().as_deref(),
+        Some("[line 1] Warning at 'a': Local variable is never read.")
+    );
+
+    lox.set_warnings_as_errors(true);
+    let err = lox.run("{ var a = 1; }").unwrap_err();
+    assert_eq!(err.to_string(), "[line 1] Error at 'a': Local variable is never read.");
+}
+
+#[test]
+fn chunks_have_at_most_256_constants_on_the_vm() {
+    let constants = (0..256).map(|i| format!("{i};")).collect::<String>();
+    let source = format!("fun f() {{\n{constants}\n\"oops\";\n}}");
+
+    let err = Lox::new().with_backend(Backend::Vm).run(&source).unwrap_err();
+    assert_eq!(err.to_string(), "[line 3] Error at '\"oops\"': Too many constants in one chunk.");
+    assert_eq!(err.downcast_ref::<LoxError>().map(LoxError::exit_code), Some(65));
+
+    // The tree-walker has no chunks
+    assert!(Lox::new().run(&source).is_ok());
+}
+
+#[test]
+fn code_nested_up_to_the_limit_runs() {
+    let source = format!("print {}1{};", "(".repeat(255), ")".repeat(255));
+
+    // Debug builds need more than the stack of test threads, like the CLI
+    let run = move || {
+        assert!(Lox::new().run(&source).is_ok());
+        assert!(Lox::new().with_backend(Backend::Vm).run(&source).is_ok());
+    };
+    let thread = std::thread::Builder::new().stack_size(64 * 1024 * 1024).spawn(run).unwrap();
+    thread.join().unwrap();
+}
+
+#[test]
+fn eval_returns_the_value_of_a_trailing_expression() {
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lox = Lox::new().with_backend(backend);
+
+        assert_eq!(lox.eval("1 + 2").unwrap().as_deref(), Some("3"));
+        assert_eq!(lox.eval("var a = \"x\"; a + a;").unwrap().as_deref(), Some("xx"));
+        assert_eq!(lox.eval("fun f() { return a; } f()").unwrap().as_deref(), Some("x"));
+        assert_eq!(lox.eval("var b = 1;").unwrap(), None);
+        assert_eq!(lox.eval("b = b + 1").unwrap().as_deref(), Some("2"));
+
+        let err = lox.eval("nil.field").unwrap_err();
+        assert_eq!(err.to_string(), "[line 1] Only instances have properties.");
+        // Only the expression at the end can leave out the `;`
+        assert!(lox.eval("1 2").is_err());
+    }
+}
+
+#[test]
+fn doc_comments_are_attached_to_functions_and_classes() {
+    let source = r#"
+        /// Add two numbers.
+        ///
+        ///   Indentation is kept.
+        fun add(a, b) { return a + b; }
+
+        /// Not a doc comment, there is a blank line after it.
+
+        fun plain() {}
+
+        //// Not a doc comment either.
+        fun ruled() {}
+
+        /// A point in the plane.
+        class Point < Object {
+            /// The distance to the origin.
+            length() { return 0; }
+        }
+    "#;
+
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lox = Lox::new().with_backend(backend);
+        lox.run("class Object {}").unwrap();
+        lox.run(source).unwrap();
+
+        assert_eq!(lox.doc("add").as_deref(), Some("Add two numbers.\n\n  Indentation is kept."));
+        assert_eq!(lox.doc("plain"), None);
+        assert_eq!(lox.doc("ruled"), None);
+        assert_eq!(lox.doc("Point").as_deref(), Some("A point in the plane."));
+        assert_eq!(lox.doc("missing"), None);
+
+        assert_eq!(lox.eval("doc(Point)").unwrap().as_deref(), Some("A point in the plane."));
+        assert_eq!(
+            lox.eval("doc(Point().length)").unwrap().as_deref(),
+            Some("The distance to the origin.")
+        );
+        assert_eq!(lox.eval("doc(plain)").unwrap().as_deref(), Some("nil"));
+        assert_eq!(lox.eval("doc(clock)").unwrap().as_deref(), Some("nil"));
+        assert_eq!(lox.eval("doc(1)").unwrap().as_deref(), Some("nil"));
+    }
+}
+
+#[test]
+fn doc_comments_survive_bytecode() {
+    let mut lox = Lox::new().with_backend(Backend::Vm);
+    let bytes =
+        lox.compile_to_bytecode("/// Say hi.\nfun hi() {}\n/// Empty.\nclass A {}").unwrap();
+
+    let mut other = Lox::new().with_backend(Backend::Vm);
+    other.run_bytecode(&bytes).unwrap();
+    assert_eq!(other.doc("hi").as_deref(), Some("Say hi."));
+    assert_eq!(other.doc("A").as_deref(), Some("Empty."));
+}
+
+#[test]
+fn tests_written_in_lox_record_their_results() {
+    let source = r#"
+        fun half(n) {
+            if (n < 0) return -nil;
+            return n / 2;
+        }
+        class Counter {
+            init() { this.count = 0; }
+            increment() { this.count = this.count + 1; }
+        }
+
+        var counter = Counter();
+        {
+            var local = "kept";
+            fun fails() { assertEq(half(3), 1); }
+            // The error of a call deep in the test only fails the test
+            fun errors() { half(-1); }
+            fun isFalsy() { assertTrue(false); }
+
+            test("passes", counter.increment);
+            test("fails", fails);
+            test("errors", errors);
+            test("is falsy", isFalsy);
+            assertEq(local, "kept");
+        }
+    "#;
+
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lox = Lox::new().with_backend(backend);
+        lox.run(source).unwrap();
+        assert_eq!(lox.eval("counter.count").unwrap().as_deref(), Some("1"));
+
+        let results = lox
+            .take_test_results()
+            .into_iter()
+            .map(|test| (test.name, test.failure))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            vec![
+                ("passes".to_owned(), None),
+                ("fails".to_owned(), Some("[line 14] Expected 1 but got 1.5.".to_owned())),
+                ("errors".to_owned(), Some("[line 3] Operand must be a number.".to_owned())),
+                (
+                    "is falsy".to_owned(),
+                    Some("[line 17] Expected a true value but got false.".to_owned())
+                ),
+            ]
+        );
+        assert!(lox.take_test_results().is_empty());
+
+        assert_eq!(lox.eval("test(\"ok\", clock)").unwrap().as_deref(), Some("true"));
+        let err = lox.run("test(\"wrong\", half);").unwrap_err();
+        assert_eq!(err.to_string(), "[line 1] A test must be a function without parameters.");
+        let err = lox.run("\n assertEq(1, nil);").unwrap_err();
+        assert_eq!(err.to_string(), "[line 2] Expected nil but got 1.");
+    }
+}
+
+#[test]
+fn undefined_names_get_suggestions() {
+    let message = |source: &str| Lox::new().run(source).unwrap_err().to_string();
+
+    assert_eq!(
+        message("var count = 1; { var total = 2; print totl + cont; }"),
+        "[line 1] Undefined variable 'totl'. Did you mean 'total'?"
+    );
+    assert_eq!(message("clok();"), "[line 1] Undefined variable 'clok'. Did you mean 'clock'?");
+    assert_eq!(
+        message("class A { area() {} } A().are();"),
+        "[line 1] Undefined property 'are'. Did you mean 'area'?"
+    );
+    assert_eq!(message("print unrelated;"), "[line 1] Undefined variable 'unrelated'.");
+}
+
+#[test]
+fn all_resolver_errors_are_reported() {
+    let source = "fun f() {\n  var a = 1;\n  var a = 2;\n  print this;\n}\nreturn 1;";
+    let err = Lox::new().run(source).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "[line 3] Error at 'a': Already a variable with this name in this scope.\n\
+         [line 4] Error at 'this': Can't use 'this' outside of a class.\n\
+         [line 6] Error at 'return': Can't return from top-level code."
+    );
+}
+
+#[test]
+fn deep_recursion_is_a_runtime_error() {
+    let mut lox = Lox::new();
+    lox.set_max_call_depth(50);
+
+    let source = "fun f(n) { if (n == 0) return 0; return f(n - 1) + 1; }";
+    lox.run(source).unwrap();
+    lox.run("if (f(49) != 49) undefined_name;").expect("calls under the limit failed");
+
+    let err = lox.run("f(50);").unwrap_err();
+    assert_eq!(err.to_string(), "[line 1] Stack overflow.");
+    // The depth is reset after the error
+    lox.run("f(49);").expect("depth was not reset");
+}
+
+#[test]
+fn runs_can_be_limited() {
+    let mut lox = Lox::new();
+    lox.set_max_steps(Some(1000));
+    lox.run("for (var i = 0; i < 10; i = i + 1) {}").expect("a short loop was stopped");
+
+    let err = lox.run("while (true) {}").unwrap_err();
+    assert_eq!(err.to_string(), "[line 1] Execution step limit exceeded.");
+    lox.set_max_steps(Some(50));
+    let err = lox.run("fun f() { f(); }\nf();").unwrap_err();
+    assert_eq!(err.to_string(), "[line 1] Execution step limit exceeded.");
+
+    let mut lox = Lox::new();
+    lox.set_timeout(Some(std::time::Duration::from_millis(10)));
+    let err = lox.run("var i = 0;\nwhile (true) i = i + 1;").unwrap_err();
+    assert_eq!(err.to_string(), "[line 2] Execution time limit exceeded.");
+}
+
+#[test]
+fn running_programs_can_be_interrupted() {
+    let mut lox = Lox::new();
+    let handle = lox.interrupt_handle();
+
+    let interrupter = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        handle.interrupt();
+    });
+    let err = lox.run("while (true) {}").unwrap_err();
+    interrupter.join().unwrap();
+
+    assert_eq!(err.to_string(), "[line 1] Interrupted.");
+    lox.run("for (var i = 0; i < 10; i = i + 1) {}").expect("the interrupt was not cleared");
+}
+
+#[test]
+fn denied_capabilities_fail_at_runtime() {
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lox = Lox::builder().backend(backend).sandboxed().build();
+        lox.run("var start = 1; print start;").unwrap();
+        let err = lox.run("print clock();").unwrap_err();
+        assert_eq!(
+            format!("{err}"),
+            "[line 1] This native function needs the clock capability, which is disabled."
+        );
+        let err = err.downcast::<LoxError>().unwrap();
+        assert_eq!(err.diagnostics()[0].code, DiagnosticCode::CapabilityDenied);
+
+        let mut lox = Lox::builder().backend(backend).sandboxed().allow(Capability::Clock).build();
+        lox.run("if (clock() <= 0) undefined_name;").unwrap();
+    }
+}
+
+#[test]
+fn vm_backend_keeps_globals_and_limits() {
+    let mut lox = Lox::new().with_backend(Backend::Vm);
+    lox.set_max_call_depth(50);
+
+    lox.run("var a = 1; fun f(n) { if (n == 0) return 0; return f(n - 1) + 1; }").unwrap();
+    lox.run("a = f(49) + a;").expect("calls under the limit failed");
+    lox.run("if (a != 50) undefined_name;").expect("globals were not kept");
+
+    let err = lox.run("f(50);\nprint f(1);").unwrap_err();
+    assert_eq!(err.to_string(), "[line 1] Stack overflow.");
+
+    lox.set_max_steps(Some(1000));
+    let err = lox.run("while (true) {}").unwrap_err();
+    assert_eq!(err.to_string(), "[line 1] Execution step limit exceeded.");
+}
+
+#[test]
+fn bytecode_runs_in_another_instance() {
+    let bytes = Lox::new().compile_to_bytecode("var a = 1; fun f() { return a + 1; }").unwrap();
+
+    let mut lox = Lox::new().with_backend(Backend::Vm);
+    lox.run_bytecode(&bytes).unwrap();
+    lox.run("if (f() != 2) undefined_name;").expect("definitions were not kept");
+
+    let err = lox.run_bytecode(&bytes[..bytes.len() - 1]).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid bytecode file: unexpected end of file");
+}
+
+#[test]
+fn trees_read_from_json_run() {
+    let ast = Lox::new().parse_source("var a = 1; fun f() { return a + 1; }").unwrap();
+    let json = serde_json::to_string(&AstJson::program_to_json(&ast)).unwrap();
+
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let ast = AstJson::program_from_json(&serde_json::from_str(&json).unwrap()).unwrap();
+        let mut lox = Lox::new().with_backend(backend);
+        lox.run_ast(ast).unwrap();
+        lox.run("if (f() != 2) undefined_name;").expect("definitions were not kept");
+    }
+
+    // The tree is resolved before it runs
+    let json = serde_json::json!({ "statements": [{
+        "kind": "Return",
+        "line": 3,
+        "keyword": { "type": "Return", "lexeme": "return", "line": 3 },
+        "value": null,
+    }] });
+    let err = Lox::new().run_ast(AstJson::program_from_json(&json).unwrap()).unwrap_err();
+    assert_eq!(err.to_string(), "[line 3] Error at 'return': Can't return from top-level code.");
+
+    let json = serde_json::json!({ "statements": [{ "kind": "Loop", "line": 1 }] });
+    let err = AstJson::program_from_json(&json).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid syntax tree: unknown statement kind 'Loop'");
+}
+
+#[test]
+fn the_test_programs_survive_json() {
+    for file in walkdir("tests/data") {
+        let source = std::fs::read_to_string(&file).unwrap();
+        let Ok(ast) = Lox::new().parse_source(&source) else {
+            continue;
+        };
+
+        let json = AstJson::program_to_json(&ast);
+        let read = AstJson::program_from_json(&json).unwrap();
+        assert_eq!(
+            AstPrinter::program_to_string(&read),
+            AstPrinter::program_to_string(&ast),
+            "{} changed in JSON",
+            file.display()
+        );
+        assert_eq!(AstJson::program_to_json(&read), json, "{} changed in JSON", file.display());
+    }
+}
+
+#[test]
+fn functions_see_globals_defined_later() {
+    let mut lox = Lox::new();
+    lox.run("fun get() { return later; }").unwrap();
+    assert!(lox.run("get();").is_err());
+
+    lox.run("var later = 1;").unwrap();
+    assert_eq!(lox.eval("get()").unwrap().unwrap(), "1");
+}
+
+#[test]
+fn snapshots_restore_the_globals() {
+    let mut lox = Lox::new();
+    lox.run(
+        "var n = 1.5; var s = \"text\"; var b = true; var none = nil; var inf = 1 / 0;
+         fun add(a, b) { return a + b + n; }
+         class Animal { init(name) { this.name = name; } speak() { return this.name; } }
+         class Dog < Animal { speak() { return super.speak() + \" barks\"; } }
+         var alias = add;
+         var rex = Dog(\"Rex\");
+         fun counter() { var count = 0; fun next() { count = count + 1; return count; } return next; }
+         var next = counter();",
+    )
+    .unwrap();
+    let snapshot = lox.snapshot().unwrap();
+
+    let mut restored = Lox::new();
+    restored.restore(&snapshot).unwrap();
+    let check = |lox: &mut Lox, code: &str| lox.eval(code).unwrap().unwrap();
+    assert_eq!(check(&mut restored, "add(1, 2)"), "4.5");
+    assert_eq!(check(&mut restored, "alias(1, 2)"), "4.5");
+    assert_eq!(check(&mut restored, "s + \" \" + Dog(\"Max\").speak()"), "text Max barks");
+    assert_eq!(check(&mut restored, "b == true and none == nil and inf > 1000"), "true");
+    // The restored functions use the restored globals
+    restored.run("n = 10;").unwrap();
+    assert_eq!(check(&mut restored, "add(1, 2)"), "13");
+
+    // Instances and functions that close over locals are left out
+    for name in ["rex", "next"] {
+        assert!(restored.eval(name).is_err(), "{name} was restored");
+    }
+    assert!(Lox::new().restore("{\"version\": 99, \"globals\": []}").is_err());
+}
+
+#[test]
+fn snapshots_keep_the_methods_of_extensions() {
+    let mut lox = Lox::new();
+    lox.run("class Animal { name() { return \"animal\"; } }").unwrap();
+    lox.run("class Dog < Animal {}").unwrap();
+    // Like in a later line of the prompt
+    lox.run("extend Dog { speak() { return this.name() + \" barks\"; } }").unwrap();
+
+    let mut restored = Lox::new();
+    restored.restore(&lox.snapshot().unwrap()).unwrap();
+    assert_eq!(restored.eval("Dog().speak()").unwrap().unwrap(), "animal barks");
+}
+
+#[test]
+fn environments_can_be_inspected_by_name() {
+    use lox::prelude::{Environment, Resolution};
+
+    let mut globals = Environment::new();
+    globals.define("a", Object::Number(1.0));
+    globals.define("b", Object::String("two".into()));
+    let globals = globals.as_shared();
+    let mut local = Environment::new().with_enclosing(globals.clone());
+    local.define("x", Object::Boolean(true));
+
+    assert!(globals.borrow().contains("a"));
+    assert_eq!(globals.borrow().value("b"), Some(Object::String("two".into())));
+    let mut names = globals.borrow().iter().map(|(name, _)| name.to_owned()).collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["a", "b"]);
+
+    // Only `lookup` looks in the enclosing environments
+    assert!(!local.contains("a"));
+    assert_eq!(local.value("a"), None);
+    assert_eq!(local.lookup("a"), Some(Object::Number(1.0)));
+    assert_eq!(local.iter().collect::<Vec<_>>(), [("x", Object::Boolean(true))]);
+
+    // Variables can be defined, read and assigned without tokens
+    local.define_at(1, "c", Object::Null).unwrap();
+    assert!(globals.borrow().contains("c"));
+    assert!(local.define_at(2, "d", Object::Null).is_err());
+    local.assign("c", Object::Number(3.0)).unwrap();
+    assert_eq!(local.get("c").unwrap(), Object::Number(3.0));
+    assert_eq!(local.get_at(Resolution { depth: 0, slot: 0 }, "x").unwrap(), Object::Boolean(true));
+    let err = local.get("undefined_name").unwrap_err();
+    assert_eq!(err.message, "Undefined variable 'undefined_name'.");
+}
+
+#[test]
+fn garbage_cycles_are_collected() {
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lox = Lox::new().with_backend(backend);
+        lox.run(
+            "class Node { init() { this.next = this; this.method = this.get; } get() { return 1; } }
+             fun cycle() { var f; fun g() { return f; } f = g; }
+             for (var i = 0; i < 10; i = i + 1) { Node(); cycle(); }
+             var kept = Node();
+             fun counter() { var n = 0; fun inc() { n = n + 1; return n; } return inc; }
+             var inc = counter();",
+        )
+        .unwrap();
+
+        let objects = lox.heap_stats().objects;
+        assert!(lox.collect_garbage() > 0, "no garbage was collected by {backend:?}");
+        assert!(lox.heap_stats().objects < objects);
+        assert_eq!(lox.collect_garbage(), 0);
+
+        lox.run("inc(); if (inc() != 2 or kept.method() != 1) undefined_name;")
+            .expect("objects in use were collected");
+        lox.run("if (gcCollect() != 0) undefined_name;").unwrap();
+    }
+}
+
+#[test]
+fn loop_variables_can_be_fresh_in_each_iteration() {
+    let source = "var f0; var f1;
+                  for (var i = 0; i < 2; i = i + 1) {
+                    fun f() { return i; }
+                    if (i == 0) f0 = f; else f1 = f;
+                  }";
+
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        // By default the iterations share the variable
+        let mut lox = Lox::new().with_backend(backend);
+        lox.run(source).unwrap();
+        lox.run("if (f0() != 2 or f1() != 2) undefined_name;").expect("the variable is not shared");
+
+        let mut lox = Lox::new().with_backend(backend);
+        lox.set_fresh_loop_variables(true);
+        lox.run(source).unwrap();
+        lox.run("if (f0() != 0 or f1() != 1) undefined_name;")
+            .unwrap_or_else(|_| panic!("the variable is shared on {backend:?}"));
+    }
+}
+
+#[test]
+fn top_level_return_sets_the_exit_code() {
+    let source = "var done = false; if (true) return -2.5; done = true;";
+
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lox = Lox::new().with_backend(backend);
+        let err = lox.run(source).unwrap_err();
+        assert!(err.to_string().contains("Can't return from top-level code."));
+
+        let mut lox = Lox::new().with_backend(backend);
+        lox.set_top_level_return(true);
+        lox.run(source).unwrap();
+        assert_eq!(lox.exit_code(), Some(-2), "on {backend:?}");
+        assert_eq!(lox.eval("done").unwrap(), Some("false".to_owned()));
+        assert_eq!(lox.exit_code(), None);
+
+        // Nothing is evaluated after the script ends
+        assert_eq!(lox.eval("return 1; 2").unwrap(), None);
+        assert_eq!(lox.exit_code(), Some(1));
+    }
+
+    let mut lox = Lox::new();
+    lox.set_top_level_return(true);
+    let bytecode = lox.compile_to_bytecode("print 1; return 7; print 2;").unwrap();
+    lox.run_bytecode(&bytecode).unwrap();
+    assert_eq!(lox.exit_code(), Some(7));
+}
+
+#[test]
+fn closures_keep_only_the_variables_they_capture() {
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lox = Lox::new().with_backend(backend);
+        lox.run(
+            "fun make() {
+               var table = \"unrelated\";
+               var kept = \"kept\";
+               if (table == nil) return nil;
+               return () -> kept;
+             }
+             var get = make();
+             gcCollect();",
+        )
+        .unwrap();
+
+        assert_eq!(lox.heap_stats().strings, 1, "the closure keeps more on {backend:?}");
+        lox.run("if (get() != \"kept\") undefined_name;").unwrap();
+    }
+}
+
+#[test]
+fn closures_share_the_variables_they_capture() {
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lox = Lox::new().with_backend(backend);
+        lox.run(
+            "var inc; var get;
+             fun make() {
+               var label = \"unrelated\";
+               var n = 0;
+               fun increment() { n = n + 1; }
+               fun value() { return n; }
+               inc = increment;
+               get = value;
+               if (label == nil) return;
+             }
+             make();
+             gcCollect();",
+        )
+        .unwrap();
+
+        let stats = lox.heap_stats();
+        assert_eq!(stats.upvalues, 1, "{backend:?}");
+        assert_eq!(stats.strings, 0, "the closures keep more on {backend:?}");
+        lox.run("inc(); inc(); if (get() != 2) undefined_name;")
+            .expect("the closures don't share the variable");
+    }
+}
+
+#[test]
+fn method_caches_dont_keep_classes_alive() {
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lox = Lox::new().with_backend(backend);
+        lox.run(
+            "fun call(object) { return object.method(); }
+             class Temporary { method() { return 1; } }
+             var object = Temporary();
+             if (call(object) != 1) undefined_name;
+             Temporary = nil;
+             object = nil;
+             gcCollect();",
+        )
+        .unwrap();
+
+        assert_eq!(lox.heap_stats().classes, 0, "{backend:?}");
+        lox.run("class Other { method() { return 2; } } if (call(Other()) != 2) undefined_name;")
+            .expect("the cached method was used for another class");
+    }
+}
+
+#[test]
+fn environments_of_calls_are_reused() {
+    let mut lox = Lox::new();
+    let environments = lox.heap_stats().environments;
+    // More calls than the heap tracks objects before collecting
+    lox.run(
+        "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); }
+         for (var i = 0; i < 20; i = i + 1) { fib(i); }",
+    )
+    .unwrap();
+
+    let stats = lox.heap_stats();
+    assert_eq!(stats.collections, 0, "the environments were not reused");
+    // The environments waiting to be reused don't count
+    assert_eq!(stats.environments, environments);
+}
+
+#[test]
+fn heap_stats_count_live_objects() {
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lox = Lox::new().with_backend(backend);
+        lox.run(
+            "var greeting = \"hi\";
+             class Point { init() { this.label = \"origin\"; } }
+             var point = Point();
+             fun make() { var name = \"inner\"; fun get() { return name; } return get; }
+             var get = make();",
+        )
+        .unwrap();
+
+        let stats = lox.heap_stats();
+        assert_eq!(stats.instances, 1, "{backend:?}");
+        assert_eq!(stats.classes, 1, "{backend:?}");
+        assert_eq!(stats.functions, 3, "{backend:?}");
+        assert_eq!(stats.strings, 3, "{backend:?}");
+
+        lox.run("point = nil; if (memoryStats().instances != 0) undefined_name;")
+            .expect("the instance was not freed");
+    }
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn lox_can_move_to_another_thread() {
+    let mut lox = Lox::new();
+    lox.run("var a = 1; class Counter { init() { this.n = 0; } }").unwrap();
+
+    let mut lox = std::thread::spawn(move || {
+        lox.run("a = a + 1; var counter = Counter();").unwrap();
+        lox
+    })
+    .join()
+    .unwrap();
+
+    lox.run("if (a != 2 or counter.n != 0) undefined_name;").expect("globals were not kept");
+}
+
+/// A future that is pending the first time it's polled.
+struct YieldOnce(bool);
+
+impl std::future::Future for YieldOnce {
+    type Output = ();
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<()> {
+        if self.0 {
+            return std::task::Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        std::task::Poll::Pending
+    }
+}
+
+fn define_double(lox: &mut Lox) {
+    lox.define_async_native("double", 1, |arguments| {
+        Box::pin(async move {
+            YieldOnce(false).await;
+            match arguments[0] {
+                Object::Number(n) => Ok(Object::Number(n * 2.0)),
+                _ => Err("Expected a number.".to_owned()),
+            }
+        })
+    });
+}
+
+#[test]
+fn async_natives_block_in_run() {
+    let mut lox = Lox::new();
+    define_double(&mut lox);
+    assert_eq!(lox.eval("double(21)").unwrap().unwrap(), "42");
+
+    let err = lox.run("double(\"a\");").unwrap_err();
+    assert_eq!(format!("{err}"), "[line 1] Expected a number.");
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn async_natives_suspend_the_run() {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct Count(std::sync::atomic::AtomicUsize);
+    impl Wake for Count {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    let mut lox = Lox::new();
+    define_double(&mut lox);
+    let mut run = std::pin::pin!(lox.run_async("var a = double(double(1));"));
+
+    // Poll the run without ever blocking, like an executor would
+    let count = Arc::new(Count(0.into()));
+    let waker = Waker::from(count.clone());
+    let mut cx = Context::from_waker(&waker);
+    let (mut lox, result) = loop {
+        if let Poll::Ready(output) = run.as_mut().poll(&mut cx) {
+            break output;
+        }
+        std::thread::yield_now();
+    };
+    result.unwrap();
+    assert!(count.0.load(std::sync::atomic::Ordering::SeqCst) > 0, "the run was never woken");
+
+    // The interpreter comes back with its globals, and blocks again in `run`
+    assert_eq!(lox.eval("double(a)").unwrap().unwrap(), "8");
+}
+
+/// Serve the requests, answering each with its method and body.
+#[cfg(feature = "net")]
+fn serve(requests: usize) -> String {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().take(requests) {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let method = line.split(' ').next().unwrap().to_owned();
+
+            let mut length = 0;
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let status = if method == "GET" { "200 OK" } else { "201 Created" };
+            let body = format!("{method} {}", String::from_utf8(body).unwrap());
+            let response = format!(
+                "HTTP/1.1 {status}\r\nX-Echo-Method: {method}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+        }
+    });
+    format!("http://{address}")
+}
+
+#[cfg(feature = "net")]
+#[test]
+fn http_natives_return_the_response() {
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let url = serve(2);
+        let mut lox = Lox::new().with_backend(backend);
+        lox.run(&format!(
+            "var get = httpGet(\"{url}/\");
+             var post = httpPost(\"{url}/\", \"hello\");"
+        ))
+        .unwrap();
+
+        let check = |lox: &mut Lox, code: &str| lox.eval(code).unwrap().unwrap();
+        assert_eq!(check(&mut lox, "get.status"), "200");
+        assert_eq!(check(&mut lox, "get.body"), "GET ");
+        assert_eq!(check(&mut lox, "get.headers.x_echo_method"), "GET");
+        assert_eq!(check(&mut lox, "post.status"), "201");
+        assert_eq!(check(&mut lox, "post.body"), "POST hello");
+
+        let err = lox.run("httpGet(1);").unwrap_err();
+        assert_eq!(format!("{err}"), "[line 1] The URL must be a string.");
+        // Nothing listens on port 1
+        let err = lox.run("httpGet(\"http://127.0.0.1:1/\");").unwrap_err();
+        let err = err.downcast::<LoxError>().unwrap();
+        assert_eq!(err.diagnostics()[0].code, DiagnosticCode::RequestFailed);
+        assert!(std::error::Error::source(&err).is_some(), "the error of the client is lost");
+    }
+}
+
+#[test]
+fn profile_counts_calls() {
+    let mut lox = Lox::new();
+    assert!(lox.profile().is_none());
+
+    lox.set_profiling(true);
+    lox.run(
+        "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+         class Point { init() { fib(2); } }
+         fib(10);
+         Point();",
+    )
+    .unwrap();
+
+    let profile = lox.profile().unwrap();
+    let calls = profile.functions.iter().map(|f| (f.name.as_str(), f.line, f.calls));
+    let mut calls = calls.collect::<Vec<_>>();
+    calls.sort();
+    assert_eq!(calls, vec![("<fn fib>", Some(1), 180), ("Point", None, 1)]);
+
+    let fib = profile.functions.iter().find(|f| f.name == "<fn fib>").unwrap();
+    assert!(fib.own <= fib.total);
+}
+
+#[test]
+fn hooks_see_statements_calls_and_errors() {
+    use std::ops::ControlFlow;
+    use std::sync::{Arc, Mutex};
+
+    use lox::Shared;
+    use lox::prelude::{Ast, Environment, InterpreterHooks, RuntimeError, StmtId};
+
+    #[derive(Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl InterpreterHooks for Recorder {
+        fn on_statement(
+            &mut self,
+            _ast: &lox::sync::Rc<Ast>,
+            _stmt: StmtId,
+            line: u32,
+            _environment: &Shared<Environment>,
+        ) -> ControlFlow<()> {
+            self.0.lock().unwrap().push(format!("line {line}"));
+            ControlFlow::Continue(())
+        }
+
+        fn on_call(&mut self, name: &str, _line: Option<u32>, depth: usize) {
+            self.0.lock().unwrap().push(format!("call {name} at {depth}"));
+        }
+
+        fn on_return(&mut self, value: Option<&Object>) {
+            let value = value.map_or("nothing".to_owned(), Object::to_string);
+            self.0.lock().unwrap().push(format!("return {value}"));
+        }
+
+        fn on_error(&mut self, error: &RuntimeError) {
+            self.0.lock().unwrap().push(format!("error {}", error.message));
+        }
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut lox = Lox::new();
+    lox.add_hooks(Recorder(events.clone()));
+    let _ = lox.run("fun id(x) {\n  return x;\n}\nid(1);\nnil.field;");
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            "line 1",
+            "line 4",
+            "call <fn id> at 0",
+            "line 2",
+            "return 1",
+            "line 5",
+            "error Only instances have properties."
+        ]
+    );
+}
+
+#[test]
+fn coverage_counts_the_lines_that_ran() {
+    let mut lox = Lox::new();
+    assert!(lox.coverage().is_none());
+
+    lox.set_coverage(true);
+    lox.run(
+        "fun f(n) {
+           if (n > 1) {
+             return n;
+           }
+           return 0;
+         }
+         class A { m() {
+           return 1;
+         } }
+         for (var i = 0; i < 3; i = i + 1) {
+           f(i);
+         }",
+    )
+    .unwrap();
+
+    let coverage = lox.coverage().unwrap();
+    assert_eq!(coverage.files.len(), 1);
+    let file = &coverage.files[0];
+    assert_eq!(file.file, "<script>");
+    let hits = file.lines.iter().map(|l| (l.line, l.hits)).collect::<Vec<_>>();
+    assert_eq!(hits, vec![(1, 1), (2, 3), (3, 1), (5, 2), (7, 1), (8, 0), (10, 2), (11, 3)]);
+    assert_eq!(file.lines_hit(), 7);
+
+    let lcov = coverage.lcov();
+    assert!(lcov.starts_with("TN:\nSF:<script>\nDA:1,1\nDA:2,3\n"));
+    assert!(lcov.ends_with("DA:11,3\nLF:8\nLH:7\nend_of_record\n"));
+}
+
+#[test]
+fn format_keeps_comments_and_blank_lines() {
+    let source = "// Adds\nfun add(a,b){return a+b;} // inline\n\n\n\
+                  for(var i=0;i<2;i=i+1){print add(i,1);}\nif (true) print 1; else print 2;";
+
+    let formatted = Lox::new().format(source).unwrap();
+    assert_eq!(
+        formatted,
+        "// Adds\nfun add(a, b) {\n  return a + b;\n} // inline\n\n\
+         for (var i = 0; i < 2; i = i + 1) {\n  print add(i, 1);\n}\n\
+         if (true) print 1;\nelse print 2;\n"
+    );
+}
+
+#[test]
+fn format_puts_loop_else_after_the_body() {
+    let source = "while(false){print 1;}else print 2;\nfor(;false;) print 3; else {print 4;}";
+
+    let formatted = Lox::new().format(source).unwrap();
+    assert_eq!(
+        formatted,
+        "while (false) {\n  print 1;\n} else print 2;\n\
+         for (; false;) print 3;\nelse {\n  print 4;\n}\n"
+    );
+}
+
+#[test]
+fn format_writes_using_statements() {
+    let source = "using(var file=open()){print file;}\nusing (var r = open())  r.read();";
+
+    let formatted = Lox::new().format(source).unwrap();
+    assert_eq!(
+        formatted,
+        "using (var file = open()) {\n  print file;\n}\nusing (var r = open()) r.read();\n"
+    );
+}
+
+#[test]
+fn formatting_the_test_programs_is_idempotent() {
+    let files = walkdir("tests/data");
+    assert!(!files.is_empty());
+
+    for file in files {
+        let source = std::fs::read_to_string(&file).unwrap();
+        // Programs with syntax errors can't be formatted
+        let Ok(formatted) = Lox::new().format(&source) else {
+            continue;
+        };
+        let again = Lox::new().format(&formatted).unwrap();
+        assert_eq!(formatted, again, "formatting {} is not idempotent", file.display());
+    }
+}
+
+#[test]
+fn lint_reports_each_rule() {
+    let source = "fun f(a) {\n  var unused;\n  var f = a;\n  {}\n  f = f;\n  print 1 == \"1\";\n  \
+                  return f;\n  print 2;\n}\n{ fun g() {} }";
+
+    let diagnostics = Lox::new().lint(source).unwrap();
+    let codes = diagnostics.iter().map(|d| (d.line, d.code.as_str())).collect::<Vec<_>>();
+    assert_eq!(
+        codes,
+        [
+            (2, "W0001"),
+            (3, "W0003"),
+            (4, "W0005"),
+            (5, "W0007"),
+            (6, "W0006"),
+            (8, "W0004"),
+            (10, "W0002")
+        ]
+    );
+}
+
+#[test]
+fn lint_rules_can_be_ignored_by_comments() {
+    let source =
+        "{\n  // lox-ignore: unused\n  var a;\n  var b; // lox-ignore: shadow, unused\n  var c;\n}";
+
+    let diagnostics = Lox::new().lint(source).unwrap();
+    let lines = diagnostics.iter().map(|d| d.line).collect::<Vec<_>>();
+    assert_eq!(lines, [5]);
+}
+
+#[test]
+fn dead_globals_are_unreachable_from_the_top_level_code() {
+    let source = "var used = 1;\nvar dead;\nfun a() { b(); }\nfun b() { a(); }\n\
+                  fun main() { print used; }\n// lox-ignore: dead-global\nvar kept;\nmain();";
+
+    let diagnostics = Lox::new().dead_globals(source).unwrap();
+    let names = diagnostics.iter().map(|d| d.location.as_deref().unwrap()).collect::<Vec<_>>();
+    assert_eq!(names, ["at 'dead'", "at 'a'", "at 'b'"]);
+    assert!(diagnostics.iter().all(|d| d.code.as_str() == "W0008"));
+}
+
+fn walkdir(dir: impl AsRef<std::path::Path>) -> Vec<std::path::PathBuf> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(walkdir(&path));
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            files.push(path);
+        }
+    }
+    files
+}
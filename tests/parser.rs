@@ -0,0 +1,124 @@
+use lox::prelude::*;
+
+fn parse_errors(source: &str) -> Vec<ParserError> {
+    let tokens = Scanner::new(source).scan_tokens().expect("failed to scan for the tokens");
+    let (_, errors) = Parser::new(tokens).parse();
+    assert!(!errors.is_empty(), "expected the parser to fail");
+    errors
+}
+
+#[test]
+fn errors_at_end_of_input() {
+    for source in ["{ print 1;", "print (1 + 2", "var a = 1 +", "fun f(a, b) {"] {
+        let errors = parse_errors(source);
+        assert!(errors.iter().all(ParserError::is_at_end), "{source}");
+    }
+}
+
+#[test]
+fn errors_before_end_of_input() {
+    for source in ["print 1 + ;", "var = 2;", "} print 1;"] {
+        let errors = parse_errors(source);
+        assert!(!errors.iter().all(ParserError::is_at_end), "{source}");
+    }
+}
+
+#[test]
+fn failed_declarations_are_kept_as_error_nodes() {
+    let source = "var a = 1;\nvar = 2;\n{ print a; print ; }\n1 = a;\nprint a;";
+    let tokens = Scanner::new(source).scan_tokens().expect("failed to scan for the tokens");
+    let (ast, errors) = Parser::new(tokens).parse();
+
+    assert_eq!(errors.len(), 3);
+    let expected = "(var a 1)\n(error)\n(block\n  (print a)\n  (error))\n(expr (error))\n(print a)";
+    assert_eq!(AstPrinter::program_to_string(&ast), expected);
+}
+
+#[test]
+fn errors_list_the_expected_tokens() {
+    let cases = [
+        ("print a var b;", "Expected '(', '[', ',', ';' or an operator, found 'var'."),
+        ("print a +;", "Expected an expression, found ';'."),
+        ("fun f(a b) {}", "Expected ',' or ')', found 'b'."),
+        ("var 1;", "Expected an identifier, found '1'."),
+        ("{ print 1;", "Expected '}', found the end."),
+    ];
+    for (source, expected) in cases {
+        let errors = parse_errors(source);
+        assert_eq!(errors[0].expectation().as_deref(), Some(expected), "{source}");
+    }
+}
+
+#[test]
+fn binary_operators_without_left_operand_are_skipped() {
+    let source = "* 3;\nvar a = == 1 + 2;\nprint a;";
+    let tokens = Scanner::new(source).scan_tokens().expect("failed to scan for the tokens");
+    let (ast, errors) = Parser::new(tokens).parse();
+
+    let messages: Vec<_> =
+        errors.iter().map(|e| (e.token.lexeme.as_ref(), e.message.as_str())).collect();
+    assert_eq!(
+        messages,
+        [("*", "Missing left-hand operand."), ("==", "Missing left-hand operand.")]
+    );
+    let expected = "(expr (error))\n(var a (error))\n(print a)";
+    assert_eq!(AstPrinter::program_to_string(&ast), expected);
+}
+
+/// Run `f` with a stack large enough for the deepest nesting the parser
+/// allows, which debug builds don't have on test threads.
+fn with_large_stack(f: impl FnOnce() + Send + 'static) {
+    let thread = std::thread::Builder::new().stack_size(64 * 1024 * 1024).spawn(f).unwrap();
+    thread.join().unwrap();
+}
+
+#[test]
+fn deeply_nested_code_is_an_error() {
+    // Hand-built nesting of each construct that recurses in the parser, deep
+    // enough to overflow the stack without a limit
+    let sources = [
+        "(".repeat(100_000),
+        "!".repeat(100_000),
+        "a = ".repeat(100_000),
+        "{".repeat(100_000),
+        "if (true) ".repeat(100_000),
+        "fun f() {".repeat(100_000),
+    ];
+    with_large_stack(move || {
+        for source in sources {
+            let errors = parse_errors(&source);
+            assert_eq!(errors.len(), 1, "{}", &source[..10]);
+            assert_eq!(errors[0].code, DiagnosticCode::TooDeeplyNested);
+            assert_eq!(errors[0].message, "Too deeply nested.");
+        }
+    });
+}
+
+#[test]
+fn nesting_depth_is_configurable() {
+    let parse = |max_depth| {
+        let tokens = Scanner::new("print ((1));").scan_tokens().unwrap();
+        Parser::new(tokens).with_max_depth(max_depth).parse().1
+    };
+
+    assert_eq!(parse(2)[0].code, DiagnosticCode::TooDeeplyNested);
+    assert!(parse(3).is_empty());
+}
+
+#[test]
+fn any_tokens_can_be_parsed() {
+    let token = |token_type| Token::new(token_type, "x", None, 1);
+    // The number has no value, and the EOF tokens are missing or doubled
+    let cases = [
+        (vec![], 0),
+        (vec![token(TokenType::Comment)], 0),
+        (vec![token(TokenType::Print)], 1),
+        (vec![token(TokenType::Number), token(TokenType::Semicolon)], 1),
+        (vec![token(TokenType::EOF), token(TokenType::EOF)], 0),
+    ];
+
+    for (tokens, error_count) in cases {
+        let (_, errors) = Parser::new(tokens.clone()).parse();
+        assert_eq!(errors.len(), error_count, "{tokens:?}");
+    }
+}
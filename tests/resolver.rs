@@ -0,0 +1,97 @@
+use lox::prelude::*;
+
+fn resolve(source: &str) -> Ast {
+    let tokens = Scanner::new(source).scan_tokens().expect("failed to scan for the tokens");
+    let (ast, errors) = Parser::new(tokens).parse();
+    assert!(errors.is_empty(), "failed to parse the source");
+    Resolver::new().resolve(&ast).expect("failed to resolve");
+    ast
+}
+
+#[test]
+fn bindings_are_resolved_in_the_tree() {
+    let ast = resolve("var g; { var a; var b; { print b + g; } }");
+
+    let Stmt::Block { statements } = &ast[ast.statements()[1]] else { panic!("expected a block") };
+    let Stmt::Block { statements } = &ast[statements[2]] else { panic!("expected a block") };
+    let Stmt::Print { exprs } = &ast[statements[0]] else { panic!("expected a print") };
+    let Expr::Binary { left, right, .. } = &ast[exprs[0]] else { panic!("expected a binary") };
+
+    let Expr::Variable { binding, .. } = &ast[*left] else { panic!("expected a variable") };
+    assert_eq!(binding.resolution(), Some(Resolution { depth: 1, slot: 1 }));
+
+    // Globals are not resolved
+    let Expr::Variable { binding, .. } = &ast[*right] else { panic!("expected a variable") };
+    assert_eq!(binding.resolution(), None);
+}
+
+#[test]
+fn unused_locals_are_warnings() {
+    let source = "var g; fun f(unused) { var a; var b = 1; a = b; { var c; } }";
+    let tokens = Scanner::new(source).scan_tokens().unwrap();
+    let (ast, errors) = Parser::new(tokens).parse();
+    assert!(errors.is_empty(), "failed to parse the source");
+
+    let mut resolver = Resolver::new();
+    resolver.resolve(&ast).expect("warnings are not errors");
+
+    // Assigning to a variable doesn't read it, parameters may be unused
+    let warnings = resolver.take_warnings();
+    let names = warnings.iter().map(|w| w.token.as_ref().unwrap().lexeme.to_string());
+    assert_eq!(names.collect::<Vec<_>>(), ["c", "a"]);
+    assert!(warnings.iter().all(|w| w.code == DiagnosticCode::UnusedVariable));
+}
+
+#[test]
+fn calls_of_local_functions_are_checked() {
+    let errors = |source: &str| {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let (ast, errors) = Parser::new(tokens).parse();
+        assert!(errors.is_empty(), "failed to parse the source");
+        match Resolver::new().resolve(&ast) {
+            Ok(()) => vec![],
+            Err(errors) => errors.into_iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+        }
+    };
+
+    assert_eq!(
+        errors("{\n  fun f(a, b) {}\n  f(1);\n}"),
+        ["[line 3] Error at ')': Expected 2 arguments but got 1."]
+    );
+    // Globals and reassigned locals are only checked at runtime
+    assert!(errors("fun f(a) {} f();").is_empty());
+    assert!(errors("{ fun f(a) {} f = nil; f(); }").is_empty());
+}
+
+#[test]
+fn duplicate_parameters_and_methods_are_errors() {
+    let source = "fun f(a, b, a) {}\nclass A {\n  m() {}\n  m(x) {}\n}";
+    let tokens = Scanner::new(source).scan_tokens().unwrap();
+    let (ast, errors) = Parser::new(tokens).parse();
+    assert!(errors.is_empty(), "failed to parse the source");
+
+    let errors = Resolver::new().resolve(&ast).unwrap_err();
+    let errors = errors.iter().map(|e| (e.code, e.token.as_ref().unwrap().line));
+    assert_eq!(
+        errors.collect::<Vec<_>>(),
+        [(DiagnosticCode::DuplicateParameter, 1), (DiagnosticCode::DuplicateMethod, 4)]
+    );
+}
+
+#[test]
+fn functions_have_at_most_256_locals() {
+    let errors = |count: usize| {
+        let vars = (0..count).map(|i| format!("var v{i};")).collect::<String>();
+        let tokens = Scanner::new(&format!("fun f() {{ {vars} }}")).scan_tokens().unwrap();
+        let (ast, errors) = Parser::new(tokens).parse();
+        assert!(errors.is_empty(), "failed to parse the source");
+        Resolver::new().resolve(&ast).err().unwrap_or_default()
+    };
+
+    // Slot zero is taken by the function itself
+    assert!(errors(255).is_empty());
+    let errors = errors(256);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, DiagnosticCode::TooManyLocals);
+    assert_eq!(&*errors[0].token.as_ref().unwrap().lexeme, "v255");
+}
@@ -1,4 +1,4 @@
-use lox::prelude::Scanner;
+use lox::prelude::{Parser, Scanner, TokenType};
 
 #[test]
 fn scanner_works() {
@@ -21,3 +21,102 @@ fn can_read_unicode() {
     let tokens = scanner.scan_tokens().expect("scanner failed");
     assert_eq!(tokens.len(), 4);
 }
+
+#[test]
+fn skips_shebang_line() {
+    let input = "#!/usr/bin/env lox\nprint 1;";
+    let mut scanner = Scanner::new(input);
+    let tokens = scanner.scan_tokens().expect("scanner failed");
+    assert_eq!(tokens.len(), 4);
+    assert_eq!(tokens[0].line, 2);
+}
+
+#[test]
+fn shebang_only_allowed_on_first_line() {
+    let input = "print 1;\n#!/usr/bin/env lox";
+    let mut scanner = Scanner::new(input);
+    assert!(scanner.scan_tokens().is_err());
+}
+
+#[test]
+fn tokens_have_columns_and_spans() {
+    let input = "var a = \"ü\";\n  print a;";
+    let mut scanner = Scanner::new(input);
+    let tokens = scanner.scan_tokens().expect("scanner failed");
+
+    let positions = tokens.iter().map(|t| (t.line, t.column)).collect::<Vec<_>>();
+    assert_eq!(
+        positions,
+        vec![(1, 1), (1, 5), (1, 7), (1, 9), (1, 12), (2, 3), (2, 9), (2, 10), (2, 11)]
+    );
+
+    for token in &tokens {
+        assert_eq!(&input[token.span.start..token.span.end], &*token.lexeme);
+    }
+}
+
+#[test]
+fn identifiers_can_have_letters_of_any_script() {
+    let input = "var größe = 1;\nprint 名前 + größe2;";
+    let tokens = Scanner::new(input).scan_tokens().expect("scanner failed");
+
+    let identifiers = tokens
+        .iter()
+        .filter(|t| t.token_type == TokenType::Identifier)
+        .map(|t| (&*t.lexeme, t.line, t.column))
+        .collect::<Vec<_>>();
+    assert_eq!(identifiers, vec![("größe", 1, 5), ("名前", 2, 7), ("größe2", 2, 12)]);
+
+    // Columns count characters, spans count bytes
+    for token in &tokens {
+        assert_eq!(&input[token.span.start..token.span.end], &*token.lexeme);
+    }
+
+    // Symbols still aren't identifiers
+    assert!(Scanner::new("var 🦀 = 1;").scan_tokens().is_err());
+}
+
+#[test]
+fn errors_have_columns() {
+    let mut scanner = Scanner::new("print 1;\n  @");
+    let errors = scanner.scan_tokens().expect_err("scanner should fail");
+    assert_eq!((errors[0].line, errors[0].column), (2, 3));
+}
+
+#[test]
+fn scans_from_a_reader() {
+    let input = "var a = \"é\";\nprint a + \"🦀\";";
+    let expected = Scanner::new(input).scan_tokens().expect("scanner failed");
+
+    let mut scanner = Scanner::from_reader(input.as_bytes());
+    assert_eq!(scanner.scan_tokens().expect("scanner failed"), expected);
+}
+
+#[test]
+fn reader_errors_are_scanner_errors() {
+    let mut scanner = Scanner::from_reader(&b"print 1;\nprint \xff;"[..]);
+    let errors = scanner.scan_tokens().expect_err("scanner should fail");
+
+    assert_eq!((errors[0].line, errors[0].column), (2, 7));
+    assert_eq!(errors[0].message, "Could not read the source: invalid UTF-8.");
+}
+
+#[test]
+fn emits_comment_tokens_when_asked() {
+    let source = "var a; // first\n// second\nprint a;";
+    assert_eq!(Scanner::new(source).scan_tokens().unwrap().len(), 7);
+
+    let tokens = Scanner::new(source).with_comment_tokens().scan_tokens().unwrap();
+    let comments = tokens
+        .iter()
+        .filter(|t| t.token_type == TokenType::Comment)
+        .map(|t| (&*t.lexeme, t.line, t.column, &source[t.span.start..t.span.end]))
+        .collect::<Vec<_>>();
+    assert_eq!(comments, [("// first", 1, 8, "// first"), ("// second", 2, 1, "// second")]);
+    assert_eq!(tokens.len(), 9);
+
+    // The parser skips them
+    let (ast, errors) = Parser::new(tokens).parse();
+    assert!(errors.is_empty());
+    assert_eq!(ast.statements().len(), 2);
+}
@@ -1,46 +1,257 @@
+use std::io::Write;
 use std::path::Path;
-use std::process::Output;
+use std::sync::{Arc, Mutex};
 
 use assert_cmd::Command;
+use lox::prelude::{DiagnosticFormat, Equality, LoxError, Truthiness};
+use lox::{Backend, Lox};
 
 include!(concat!(env!("OUT_DIR"), "/test_files.rs"));
 
 // These functions are used by the included tests above
 // See `build.rs` for the code that generates the tests.
 
-fn do_test(filename: &Path) {
+/// Run the test file with the given command line options, e.g. to select the
+/// backend. A file that is expected to fail runs through the `lox` binary, to
+/// check its exit code and what it prints on the standard error. The others
+/// run in the test process, or through the binary too if `LOX_TEST_BINARY` is
+/// set.
+///
+/// With `LOX_BLESS` set, the test rewrites the expected output of the file if
+/// `blesses` is set, which is the case for one test of each file.
+fn do_test(filename: &Path, options: &[&str], blesses: bool) {
     let output = find_expects(filename, "expect: ", false, false);
 
     let mut errors = vec![];
     // Resolver errors
-    errors.extend(find_expects(filename, "[line ", false, true));
+    let mut static_errors = find_expects(filename, "[line ", false, true);
+    // The upstream test suite marks the errors that only the Java implementation
+    // reports, which this one follows. The ones marked `[c line N]` are ignored.
+    let java_errors = find_expects(filename, "[java line ", false, true);
+    static_errors.extend(java_errors.iter().map(|e| e.replacen("[java line ", "[line ", 1)));
+    errors.extend(static_errors.iter().cloned());
 
     // Runtime errors
-    errors.extend(find_expects(filename, "expect runtime error: ", true, false));
+    let mut runtime_errors = find_expects(filename, "expect runtime error: ", true, false);
 
     // Parser or resolver errors that are put on a specific line (so no '[line X]
     // prefix')
-    errors.extend(find_expects(filename, "Error at ", true, true));
+    let mut line_errors = find_expects(filename, "Error at ", true, true);
+
+    // Errors with a column, e.g. `// expect error at col 9: Error at 'a': ...`
+    let column_errors = find_column_expects(filename);
+    for (error, _, _) in &column_errors {
+        if error.contains("] Error") {
+            line_errors.push(error.clone());
+        } else {
+            runtime_errors.push(error.clone());
+        }
+    }
+    errors.extend(runtime_errors.iter().cloned());
+    errors.extend(line_errors.iter().cloned());
+
+    // The exit code of a script that ends with a `return` at the top level
+    let exit_code = find_expects(filename, "expect exit code: ", false, false);
+
+    // Static errors stop the program before it runs. Warnings don't change the
+    // exit code.
+    let has_static_errors =
+        !line_errors.is_empty() || static_errors.iter().any(|e| !e.contains("] Warning"));
+    let expected_code = if has_static_errors {
+        65
+    } else if !runtime_errors.is_empty() {
+        70
+    } else if let Some(code) = exit_code.first() {
+        code.parse().expect("the exit code is not a number")
+    } else {
+        0
+    };
 
     // Join them
     let expected = output.join("\n");
     let expected_error = errors.join("\n");
 
-    let output = run_file(filename);
+    let binary = expected_code != 0
+        || !expected_error.is_empty()
+        || std::env::var_os("LOX_TEST_BINARY").is_some();
+    let output = run_file(filename, options, binary);
+    let stdout = output.stdout.trim_end();
+    let stderr = output.stderr.trim_end();
 
-    let stdout = String::from_utf8(output.stdout).unwrap();
-    let stdout = stdout.trim_end();
+    if std::env::var_os("LOX_BLESS").is_some() && blesses && stdout != expected {
+        bless(filename, stdout);
+    } else {
+        assert_eq!(stdout, expected, "generated output != expected output");
+    }
+    assert_eq!(stderr, expected_error, "generated error != expected error");
+    assert_eq!(output.code, expected_code, "wrong exit code");
 
-    let stderr = String::from_utf8(output.stderr).unwrap();
-    let stderr = stderr.trim_end();
+    if !column_errors.is_empty() {
+        let options = [options, &["--diagnostics=json"]].concat();
+        let output = run_file(filename, &options, binary);
+        let positions = diagnostic_positions(&output.stderr);
+        for (error, line, column) in column_errors {
+            assert!(positions.contains(&(line, column)), "no error at column {column}: {error}");
+        }
+    }
+}
 
-    assert_eq!(stdout, expected, "generated output != expected output");
-    assert_eq!(stderr, expected_error, "generated error != expected error");
+/// Find the errors expected at a column, e.g. `// expect error at col 9:
+/// Error at 'a': Expect ';' after value.` Returns each error as it's printed
+/// without a column, with its line and column.
+fn find_column_expects(filename: &Path) -> Vec<(String, u64, u64)> {
+    let expects = find_expects(filename, "expect error at col ", true, false);
+    expects
+        .into_iter()
+        .map(|expect| {
+            let (line, rest) = expect.split_once("] ").unwrap();
+            let line = line.trim_start_matches("[line ").parse().unwrap();
+            let (column, error) = rest.split_once(": ").expect("missing ':' after the column");
+            let column = column.parse().expect("the column is not a number");
+            (format!("[line {line}] {error}"), line, column)
+        })
+        .collect()
 }
 
-fn run_file(filename: &Path) -> Output {
+/// The lines and columns of the errors printed by `--diagnostics=json`.
+fn diagnostic_positions(stderr: &str) -> Vec<(u64, u64)> {
+    stderr
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .filter(|diagnostic| diagnostic["severity"] == "error")
+        .map(|diagnostic| {
+            (diagnostic["line"].as_u64().unwrap(), diagnostic["column"].as_u64().unwrap())
+        })
+        .collect()
+}
+
+/// Rewrite the `// expect:` comments of the test file to match the output, in
+/// order. Comments without an output line are removed, and the output lines
+/// without a comment are expected at the end of the file.
+fn bless(filename: &Path, stdout: &str) {
+    let content = std::fs::read_to_string(filename).unwrap();
+    let pattern = "// expect: ";
+    let mut outputs = stdout.lines();
+
+    let mut lines = vec![];
+    for line in content.lines() {
+        let Some(idx) = line.rfind(pattern) else {
+            lines.push(line.to_owned());
+            continue;
+        };
+
+        let code = &line[..idx];
+        match outputs.next() {
+            Some(output) => lines.push(format!("{code}{pattern}{output}")),
+            None if code.trim().is_empty() => {}
+            None => lines.push(code.trim_end().to_owned()),
+        }
+    }
+    lines.extend(outputs.map(|output| format!("{pattern}{output}")));
+
+    let mut content = lines.join("\n");
+    content.push('\n');
+    std::fs::write(filename, content).unwrap();
+}
+
+/// What a test program printed, and the exit code of the `lox` binary for it.
+struct RunOutput {
+    stdout: String,
+    stderr: String,
+    code: i32,
+}
+
+fn run_file(filename: &Path, options: &[&str], binary: bool) -> RunOutput {
+    // The expectations are mostly the output of the reference implementation,
+    // which has no warnings
+    let content = std::fs::read_to_string(filename).unwrap_or_default();
+    let warnings = content.contains("] Warning");
+
+    if binary {
+        run_binary(filename, options, warnings)
+    } else {
+        run_in_process(filename, options, warnings)
+    }
+}
+
+fn run_binary(filename: &Path, options: &[&str], warnings: bool) -> RunOutput {
     let mut cmd = Command::cargo_bin("lox").unwrap();
-    cmd.arg(filename).output().unwrap()
+    if !warnings {
+        cmd.arg("--no-warnings");
+    }
+    let output = cmd.args(options).arg(filename).output().unwrap();
+
+    RunOutput {
+        stdout: String::from_utf8(output.stdout).unwrap(),
+        stderr: String::from_utf8(output.stderr).unwrap(),
+        code: output.status.code().unwrap(),
+    }
+}
+
+/// Run the file like the `lox` binary does, but through the library. The
+/// options can only select the backend, the diagnostic format, the
+/// semantics of conditions and `==`, and allow a `return` at the top level.
+fn run_in_process(filename: &Path, options: &[&str], warnings: bool) -> RunOutput {
+    let filename = filename.to_str().unwrap().to_owned();
+    let options = options.iter().map(|option| option.to_string()).collect::<Vec<_>>();
+
+    // Deep recursion needs as much stack as the binary has
+    let run = move || {
+        let mut lox = Lox::new();
+        for option in options {
+            match option.as_str() {
+                "--vm" => lox = lox.with_backend(Backend::Vm),
+                "--diagnostics=json" => lox.set_diagnostic_format(DiagnosticFormat::Json),
+                "--truthiness=lox" => lox.set_truthiness(Truthiness::Lox),
+                "--truthiness=strict" => lox.set_truthiness(Truthiness::Strict),
+                "--equality=strict" => lox.set_equality(Equality::Strict),
+                "--equality=coercing" => lox.set_equality(Equality::Coercing),
+                "--top-level-return" => lox.set_top_level_return(true),
+                option => panic!("unsupported option {option}"),
+            }
+        }
+        let stdout = Buffer::default();
+        lox.set_output(stdout.clone());
+
+        let result = lox.run_file(&filename);
+
+        let mut stderr = String::new();
+        if let Some(rendered) = lox.render_warnings().filter(|_| warnings) {
+            stderr.push_str(&format!("{rendered}\n"));
+        }
+        let code = match result {
+            Ok(()) => lox.exit_code().unwrap_or(0),
+            Err(e) => {
+                stderr.push_str(&format!("{e}\n"));
+                e.downcast_ref::<LoxError>().map_or(1, LoxError::exit_code)
+            }
+        };
+
+        RunOutput { stdout: stdout.contents(), stderr, code }
+    };
+
+    let thread = std::thread::Builder::new().stack_size(256 * 1024 * 1024).spawn(run).unwrap();
+    thread.join().unwrap()
+}
+
+/// Collects what a program prints.
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 fn find_expects(